@@ -17,7 +17,7 @@ use tokio_io::AsyncWrite;
 
 use decompressor::DecompressorType;
 use noop::NoopEncoder;
-use raw::{AsyncZstdEncoder, RawEncoder};
+use raw::{AsyncZlibEncoder, AsyncZstdEncoder, RawEncoder};
 use retry::retry_write;
 
 #[derive(Clone, Copy, Debug)]
@@ -56,10 +56,7 @@ where
             c_type: ct,
             inner: match ct {
                 CompressorType::Bzip2(level) => Box::new(BzEncoder::new(w, level)),
-                // TODO: need
-                // https://github.com/alexcrichton/flate2-rs/issues/62 to be
-                // fixed
-                CompressorType::Gzip => unimplemented!(),
+                CompressorType::Gzip => Box::new(AsyncZlibEncoder::new(w)),
                 CompressorType::Zstd { level } => Box::new(AsyncZstdEncoder::new(w, level)),
                 CompressorType::Uncompressed => Box::new(NoopEncoder::new(w)),
             },
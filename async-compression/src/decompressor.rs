@@ -11,6 +11,7 @@ use std::io;
 use std::io::Read;
 
 use bzip2::read::BzDecoder;
+use flate2::read::ZlibDecoder;
 use tokio_io::AsyncRead;
 use zstd::Decoder as ZstdDecoder;
 
@@ -42,10 +43,7 @@ where
             d_type: dt,
             inner: match dt {
                 DecompressorType::Bzip2 => Box::new(BzDecoder::new(r)),
-                // TODO: need
-                // https://github.com/alexcrichton/flate2-rs/issues/62 to be
-                // fixed
-                DecompressorType::Gzip => unimplemented!(),
+                DecompressorType::Gzip => Box::new(ZlibDecoder::new(r)),
                 // ZstdDecoder::new() should only fail on OOM, so just call unwrap here.
                 DecompressorType::Zstd => Box::new(ZstdDecoder::new(r).unwrap()),
                 DecompressorType::Uncompressed => Box::new(NoopDecoder::new(r)),
@@ -10,6 +10,7 @@
 extern crate assert_matches;
 extern crate bytes;
 extern crate bzip2;
+extern crate flate2;
 #[macro_use]
 extern crate futures;
 #[macro_use]
@@ -9,11 +9,14 @@
 use std::io::{self, Read, Write};
 use std::result;
 
-use futures::Poll;
+use futures::{Async, Poll};
 use tokio_io::AsyncWrite;
 
 use bzip2::read::BzDecoder;
 use bzip2::write::BzEncoder;
+use flate2;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
 use zstd::{Decoder as ZstdDecoder, Encoder as ZstdEncoder};
 
 pub trait RawDecoder<R: Read>: Read {
@@ -39,6 +42,23 @@ impl<R: Read> RawDecoder<R> for BzDecoder<R> {
     }
 }
 
+impl<R: Read> RawDecoder<R> for ZlibDecoder<R> {
+    #[inline]
+    fn get_ref(&self) -> &R {
+        ZlibDecoder::get_ref(self)
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> &mut R {
+        ZlibDecoder::get_mut(self)
+    }
+
+    #[inline]
+    fn into_inner(self: Box<Self>) -> R {
+        ZlibDecoder::into_inner(*self)
+    }
+}
+
 impl<R: Read> RawDecoder<R> for ZstdDecoder<R> {
     #[inline]
     fn get_ref(&self) -> &R {
@@ -78,6 +98,54 @@ where
     }
 }
 
+/// A wrapper around ZlibEncoder which depends on and implements AsyncWrite, for the same reason
+/// as AsyncZstdEncoder below.
+pub struct AsyncZlibEncoder<W: AsyncWrite>(ZlibEncoder<W>);
+
+impl<W: AsyncWrite> AsyncZlibEncoder<W> {
+    pub fn new(obj: W) -> Self {
+        AsyncZlibEncoder(ZlibEncoder::new(obj, flate2::Compression::Default))
+    }
+}
+
+impl<W> RawEncoder<W> for AsyncZlibEncoder<W>
+where
+    W: AsyncWrite + Send + 'static,
+{
+    fn try_finish(
+        mut self: Box<Self>,
+    ) -> result::Result<W, (Box<RawEncoder<W> + Send>, io::Error)> {
+        // try_finish writes out everything buffered, and can be retried if that would block.
+        // After it succeeds, finish has nothing left to write.
+        match self.0.try_finish() {
+            Ok(()) => Ok(self.0.finish().unwrap()),
+            Err(e) => Err((self, e)),
+        }
+    }
+}
+
+impl<W: AsyncWrite> Write for AsyncZlibEncoder<W> {
+    #[inline]
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for AsyncZlibEncoder<W> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self.0.try_finish() {
+            Ok(()) => self.0.get_mut().shutdown(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
 /// A wrapper around ZstdEncoder which depends on and implements AsyncWrite.
 ///
 /// The sole purpose of this struct is to work around the orphan rule: you
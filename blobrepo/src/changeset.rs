@@ -33,6 +33,9 @@ struct RawCSBlob<'a> {
 pub struct BlobChangeset {
     nodeid: NodeHash, // redundant - can be computed from revlogcs?
     revlogcs: RevlogChangeset,
+    // The revlog text the changeset was received with, if known. It's stored as is rather than
+    // regenerated, so that it's guaranteed to still hash to `nodeid`.
+    text: Option<Vec<u8>>,
 }
 
 fn cskey(nodeid: &NodeHash) -> String {
@@ -44,9 +47,22 @@ impl BlobChangeset {
         Self {
             nodeid: *nodeid,
             revlogcs,
+            text: None,
         }
     }
 
+    pub fn new_with_text(nodeid: &NodeHash, revlogcs: RevlogChangeset, text: Vec<u8>) -> Self {
+        Self {
+            nodeid: *nodeid,
+            revlogcs,
+            text: Some(text),
+        }
+    }
+
+    pub fn nodeid(&self) -> &NodeHash {
+        &self.nodeid
+    }
+
     pub fn load<B>(
         blobstore: &B,
         nodeid: &NodeHash,
@@ -70,12 +86,34 @@ impl BlobChangeset {
                     let cs = BlobChangeset {
                         nodeid: nodeid,
                         revlogcs: RevlogChangeset::new(node)?,
+                        text: None,
                     };
                     Ok(Some(cs))
                 }
             })
     }
 
+    /// Load the revlog text of a changeset exactly as it was stored, without parsing it.
+    pub fn load_raw<B>(
+        blobstore: &B,
+        nodeid: &NodeHash,
+    ) -> impl Future<Item = Option<BlobNode>, Error = Error> + Send + 'static
+    where
+        B: Blobstore<Key = String>,
+    {
+        blobstore
+            .get(&cskey(nodeid))
+            .map_err(blobstore_err)
+            .and_then(|got| match got {
+                None => Ok(None),
+                Some(blob) => {
+                    let RawCSBlob { parents, blob } = bincode::deserialize(blob.as_ref())?;
+                    let (p1, p2) = parents.get_nodes();
+                    Ok(Some(BlobNode::new(blob.into_owned(), p1, p2)))
+                }
+            })
+    }
+
     pub fn save<B>(&self, blobstore: B) -> impl Future<Item = (), Error = Error> + Send + 'static
     where
         B: Blobstore<Key = String> + Send + 'static,
@@ -85,20 +123,25 @@ impl BlobChangeset {
     {
         let key = cskey(&self.nodeid);
 
-        self.revlogcs.get_node() // FIXME: generate from scratch
-            .map_err(Error::from)
-            .and_then(|node| {
-                let data = node
-                    .as_blob()
-                    .as_slice()
-                    .ok_or(Error::from("missing changeset blob"))?;
-                let blob = RawCSBlob {
-                    parents: *self.revlogcs.parents(),
-                    blob: Cow::Borrowed(data),
-                };
-                bincode::serialize(&blob, bincode::Infinite).map_err(Error::from)
-            })
-            .into_future()
+        let text = match self.text {
+            Some(ref text) => Ok(text.clone()),
+            None => self.revlogcs.get_node() // FIXME: generate from scratch
+                .map_err(Error::from)
+                .and_then(|node| {
+                    node.as_blob()
+                        .as_slice()
+                        .map(|data| data.to_vec())
+                        .ok_or(Error::from("missing changeset blob"))
+                }),
+        };
+
+        text.and_then(|text| {
+            let blob = RawCSBlob {
+                parents: *self.revlogcs.parents(),
+                blob: Cow::Owned(text),
+            };
+            bincode::serialize(&blob, bincode::Infinite).map_err(Error::from)
+        }).into_future()
             .and_then(move |blob| blobstore.put(key, blob.into())
                                 .map_err(blobstore_err))
     }
@@ -16,6 +16,9 @@ pub enum StateOpenError {
     Heads,
     Bookmarks,
     Blobstore,
+    Linknodes,
+    Phases,
+    ChangesetIndex,
 }
 
 impl fmt::Display for StateOpenError {
@@ -26,6 +29,9 @@ impl fmt::Display for StateOpenError {
             Heads => write!(f, "heads"),
             Bookmarks => write!(f, "bookmarks"),
             Blobstore => write!(f, "blob store"),
+            Linknodes => write!(f, "linknodes"),
+            Phases => write!(f, "phases"),
+            ChangesetIndex => write!(f, "changeset index"),
         }
     }
 }
@@ -42,6 +48,15 @@ error_chain! {
         Bookmarks {
             description("Bookmarks error")
         }
+        Linknodes {
+            description("Linknodes error")
+        }
+        Phases {
+            description("Phases error")
+        }
+        ChangesetIndex {
+            description("Changeset index error")
+        }
         StateOpen(kind: StateOpenError) {
             description("Error while opening state")
             display("Error while opening state for {}", kind)
@@ -89,3 +104,15 @@ pub fn blobstore_err<E: error::Error + Send + 'static>(err: E) -> Error {
 pub fn bookmarks_err<E: error::Error + Send + 'static>(err: E) -> Error {
     ChainedError::with_chain(err, ErrorKind::Bookmarks)
 }
+
+pub fn linknodes_err<E: error::Error + Send + 'static>(err: E) -> Error {
+    ChainedError::with_chain(err, ErrorKind::Linknodes)
+}
+
+pub fn phases_err<E: error::Error + Send + 'static>(err: E) -> Error {
+    ChainedError::with_chain(err, ErrorKind::Phases)
+}
+
+pub fn csindex_err<E: error::Error + Send + 'static>(err: E) -> Error {
+    ChainedError::with_chain(err, ErrorKind::ChangesetIndex)
+}
@@ -32,6 +32,23 @@ pub fn fetch_file_blob_from_blobstore<B>(
     blobstore: B,
     nodeid: NodeHash,
 ) -> BoxFuture<Vec<u8>, Error>
+where
+    B: Blobstore<Key = String> + Clone,
+{
+    fetch_raw_content_from_blobstore(blobstore, nodeid)
+        .map(|blob| {
+            let (_, off) = file::File::extract_meta(blob.as_ref());
+            Vec::from(&blob[off..])
+        })
+        .boxify()
+}
+
+/// Fetch the content of a manifest or file node as stored in its revlog, including any
+/// file metadata.
+pub fn fetch_raw_content_from_blobstore<B>(
+    blobstore: B,
+    nodeid: NodeHash,
+) -> BoxFuture<Vec<u8>, Error>
 where
     B: Blobstore<Key = String> + Clone,
 {
@@ -46,10 +63,7 @@ where
                     .map_err(blobstore_err)
                     .and_then(move |blob| {
                         blob.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
-                            .map(|blob| {
-                                let (_, off) = file::File::extract_meta(blob.as_ref());
-                                Vec::from(&blob.as_ref()[off..])
-                            })
+                            .map(|blob| Vec::from(blob.as_ref()))
                     })
             }
         })
@@ -25,18 +25,28 @@ extern crate heapsize_derive;
 
 extern crate blobstore;
 extern crate bookmarks;
+extern crate csindex;
 extern crate fileblob;
 extern crate filebookmarks;
+extern crate filecsindex;
 extern crate fileheads;
+extern crate filelinknodes;
+extern crate filephases;
 extern crate futures_ext;
 extern crate heads;
+extern crate linknodes;
 extern crate manifoldblob;
 extern crate memblob;
 extern crate membookmarks;
+extern crate memcsindex;
 extern crate memheads;
+extern crate memlinknodes;
+extern crate memphases;
 extern crate mercurial;
 extern crate mercurial_types;
+extern crate phases;
 extern crate rocksblob;
+extern crate storage_types;
 
 mod repo;
 mod changeset;
@@ -52,7 +62,8 @@ pub use changeset::BlobChangeset;
 pub use manifest::BlobManifest;
 pub use repo::BlobRepo;
 pub use state::{BlobState, FilesBlobState, MemBlobState, RocksBlobState, TestManifoldBlobState};
+pub use phases::Phase;
 //
 // TODO: (jsgf) T21597565 This is exposed here for blobimport -- don't use it for anything else.
 
-pub use utils::RawNodeBlob;
+pub use utils::{file_meta_key, RawNodeBlob};
@@ -8,20 +8,34 @@ use std::collections::HashSet;
 use std::mem;
 use std::sync::Arc;
 
+use bytes::Bytes;
 use futures::{Async, Poll};
-use futures::future::Future;
+use futures::future::{self, Future};
 use futures::stream::{self, Stream};
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
-use bookmarks::BoxedBookmarks;
+use blobstore::Blobstore;
+use bookmarks::{Bookmarks, BookmarksMut, BoxedBookmarks};
+use csindex::ChangesetIndex;
 use heads::Heads;
-use mercurial_types::{repo, Changeset, Manifest, NodeHash, Repo};
+use linknodes::{self, Linknodes};
+use mercurial::file::File;
+use mercurial_types::{repo, BlobNode, Changeset, Entry, MPath, Manifest, NodeHash, Parents, Repo,
+                      RepoPath};
+use phases::{Phase, Phases};
+use storage_types::Version;
 
 use BlobChangeset;
 use BlobManifest;
 use BlobState;
 use errors::*;
-use file::fetch_file_blob_from_blobstore;
+use file::{fetch_file_blob_from_blobstore, fetch_raw_content_from_blobstore};
+use utils::{file_meta_key, get_node, put_node};
+
+// Blobstore key of the bundle served to clients that support clonebundles.
+const CLONEBUNDLE_KEY: &str = "clonebundle.hg";
+// Prefix of the blobstore keys of the pre-generated store files served as stream clones.
+const STREAMCLONE_PREFIX: &str = "streamclone.";
 
 pub struct BlobRepo<State> {
     inner: Arc<State>,
@@ -42,6 +56,308 @@ where
     pub fn get_file_blob(&self, key: &NodeHash) -> BoxFuture<Vec<u8>, Error> {
         fetch_file_blob_from_blobstore(self.inner.blobstore().clone(), *key)
     }
+
+    /// Get the revlog text of a manifest or file node. Unlike `get_file_blob`, file metadata
+    /// is not stripped.
+    pub fn get_raw_content(&self, key: &NodeHash) -> BoxFuture<Vec<u8>, Error> {
+        fetch_raw_content_from_blobstore(self.inner.blobstore().clone(), *key)
+    }
+
+    /// Get the revlog text of a changeset as it was stored, along with its parents.
+    pub fn get_changeset_blob(&self, nodeid: &NodeHash) -> BoxFuture<BlobNode, Error> {
+        let nodeid = *nodeid;
+        BlobChangeset::load_raw(self.inner.blobstore(), &nodeid)
+            .and_then(move |node| node.ok_or(ErrorKind::ChangesetMissing(nodeid).into()))
+            .boxify()
+    }
+
+    /// Get the path and node that the file node `key` was copied from, if it was copied. This
+    /// only reads the file's metadata, except for nodes stored before the metadata was kept
+    /// apart from the text.
+    pub fn get_copy_info(&self, key: &NodeHash) -> BoxFuture<Option<(MPath, NodeHash)>, Error> {
+        let blobstore = self.inner.blobstore().clone();
+        let key = *key;
+        self.inner
+            .blobstore()
+            .get(&file_meta_key(&key))
+            .map_err(blobstore_err)
+            .and_then(move |meta| match meta {
+                Some(meta) => future::result(File::copied_from_meta(meta.as_ref()))
+                    .from_err()
+                    .boxify(),
+                None => fetch_raw_content_from_blobstore(blobstore, key)
+                    .and_then(|raw| File::copied_from_meta(&raw).map_err(Error::from))
+                    .boxify(),
+            })
+            .boxify()
+    }
+
+    pub fn get_parents(&self, key: &NodeHash) -> BoxFuture<Parents, Error> {
+        get_node(self.inner.blobstore(), *key)
+            .map(|node| node.parents)
+            .boxify()
+    }
+
+    /// Get the changeset that introduced the manifest or file node `node` at `path`.
+    pub fn get_linknode(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<NodeHash, Error> {
+        self.inner
+            .linknodes()
+            .get(path, node)
+            .map_err(linknodes_err)
+            .boxify()
+    }
+
+    pub fn add_linknode(
+        &self,
+        path: RepoPath,
+        node: &NodeHash,
+        linknode: &NodeHash,
+    ) -> BoxFuture<(), Error> {
+        self.inner
+            .linknodes()
+            .add(path, node, linknode)
+            .map_err(linknodes_err)
+            .boxify()
+    }
+
+    /// Like `add_linknode`, but keep the linknode a node already has.
+    fn add_missing_linknode(
+        &self,
+        path: RepoPath,
+        node: &NodeHash,
+        linknode: &NodeHash,
+    ) -> BoxFuture<(), Error> {
+        self.inner
+            .linknodes()
+            .add(path, node, linknode)
+            .then(|res| match res {
+                Err(linknodes::Error(linknodes::ErrorKind::AlreadyExists(..), _)) => Ok(()),
+                res => res.map_err(linknodes_err),
+            })
+            .boxify()
+    }
+
+    /// Record the changesets that introduced the root manifests and file nodes of the repo,
+    /// which is needed for repos that had changesets before linknodes were recorded. Linknodes
+    /// that are already there are kept, so a node that several changesets introduced is linked
+    /// to whichever of them is seen first. Resolves to the number of changesets.
+    pub fn backfill_linknodes(&self) -> BoxFuture<usize, Error> {
+        let repo = self.clone();
+        self.get_changesets()
+            .map(move |csid| repo.backfill_changeset_linknodes(csid))
+            .buffer_unordered(100)
+            .fold(0, |count, ()| Ok::<_, Error>(count + 1))
+            .boxify()
+    }
+
+    fn backfill_changeset_linknodes(&self, csid: NodeHash) -> BoxFuture<(), Error> {
+        let repo = self.clone();
+        self.get_changeset_by_nodeid(&csid)
+            .and_then(move |cs| {
+                let mfid = *cs.manifestid();
+                let root = repo.add_missing_linknode(RepoPath::root(), &mfid, &csid);
+                let files = repo.get_manifest_by_nodeid(&mfid).and_then(move |manifest| {
+                    let adds: Vec<_> = cs.files()
+                        .iter()
+                        .map(|path| {
+                            let repo = repo.clone();
+                            let repopath = RepoPath::file(path.clone());
+                            manifest.lookup(path).and_then(move |entry| match entry {
+                                // Files that the changeset deleted have nothing to link.
+                                None => future::ok(()).boxify(),
+                                Some(entry) => match repopath {
+                                    Ok(repopath) => {
+                                        repo.add_missing_linknode(repopath, entry.get_hash(), &csid)
+                                    }
+                                    Err(err) => future::err(err.into()).boxify(),
+                                },
+                            })
+                        })
+                        .collect();
+                    future::join_all(adds)
+                });
+                root.join(files).map(|_| ())
+            })
+            .boxify()
+    }
+
+    /// Get the changesets whose recorded phase is draft. Some of them may be public anyway,
+    /// depending on the repo's bookmarks.
+    pub fn get_drafts(&self) -> BoxStream<NodeHash, Error> {
+        self.inner.phases().drafts().map_err(phases_err).boxify()
+    }
+
+    pub fn set_phase(&self, node: &NodeHash, phase: Phase) -> BoxFuture<(), Error> {
+        self.inner
+            .phases()
+            .set(node, phase)
+            .map_err(phases_err)
+            .boxify()
+    }
+
+    /// Forget the phase recorded for a changeset.
+    pub fn remove_phase(&self, node: &NodeHash) -> BoxFuture<(), Error> {
+        self.inner
+            .phases()
+            .remove(node)
+            .map_err(phases_err)
+            .boxify()
+    }
+
+    /// Add every changeset in the repo to the changeset index, which is needed for repos that
+    /// had changesets before the index existed. Resolves to the number of changesets.
+    pub fn index_changesets(&self) -> BoxFuture<usize, Error> {
+        let inner = self.inner.clone();
+        self.get_changesets()
+            .map(move |node| inner.csindex().add(&node).map_err(csindex_err))
+            .buffer_unordered(100)
+            .fold(0, |count, ()| Ok::<_, Error>(count + 1))
+            .boxify()
+    }
+
+    /// Get the pre-built bundle of the whole repo, if one has been generated.
+    pub fn get_clonebundle(&self) -> BoxFuture<Option<Vec<u8>>, Error> {
+        self.inner
+            .blobstore()
+            .get(&CLONEBUNDLE_KEY.to_string())
+            .map(|blob| blob.map(|blob| Vec::from(blob.as_ref())))
+            .map_err(blobstore_err)
+            .boxify()
+    }
+
+    /// Get a piece of the pre-generated stream clone data, if there is one called `name`.
+    pub fn get_streamclone_blob(&self, name: &str) -> BoxFuture<Option<Bytes>, Error> {
+        self.inner
+            .blobstore()
+            .get(&format!("{}{}", STREAMCLONE_PREFIX, name))
+            .map(|blob| blob.map(|blob| Bytes::from(blob.as_ref())))
+            .map_err(blobstore_err)
+            .boxify()
+    }
+
+    pub fn add_head(&self, key: &NodeHash) -> BoxFuture<(), Error> {
+        self.inner.heads().add(key).map_err(heads_err).boxify()
+    }
+
+    pub fn remove_head(&self, key: &NodeHash) -> BoxFuture<(), Error> {
+        self.inner.heads().remove(key).map_err(heads_err).boxify()
+    }
+
+    /// Point bookmark `key` at `new`, or delete it if `new` is `None`, provided it currently
+    /// points at `old` (`None` meaning that it doesn't exist). Resolves to `false` if the
+    /// bookmark doesn't have the expected value, or was changed concurrently.
+    pub fn update_bookmark(
+        &self,
+        key: &AsRef<[u8]>,
+        old: Option<NodeHash>,
+        new: Option<NodeHash>,
+    ) -> BoxFuture<bool, Error> {
+        let key = key.as_ref().to_vec();
+        let bookmarks = self.inner.bookmarks().clone();
+
+        self.inner
+            .bookmarks()
+            .get(&key)
+            .and_then(move |current| match current {
+                Some((ref value, _)) if Some(*value) != old => future::ok(None).boxify(),
+                Some((_, version)) => match new {
+                    Some(new) => bookmarks.set(&key, &new, &version).boxify(),
+                    None => bookmarks.delete(&key, &version).boxify(),
+                },
+                None => match (old, new) {
+                    (None, Some(new)) => bookmarks.create(&key, &new).boxify(),
+                    // Either the bookmark was expected to exist, or this is deleting a
+                    // bookmark that doesn't exist.
+                    _ => future::ok(None).boxify(),
+                },
+            })
+            .map(|version| version.is_some())
+            .map_err(bookmarks_err)
+            .boxify()
+    }
+
+    /// Point bookmark `key` at `value`, provided it's still at `version`. Resolves to `false` if
+    /// the bookmark was changed concurrently.
+    pub fn set_bookmark(
+        &self,
+        key: &AsRef<[u8]>,
+        value: &NodeHash,
+        version: &Version,
+    ) -> BoxFuture<bool, Error> {
+        self.inner
+            .bookmarks()
+            .set(key, value, version)
+            .map(|version| version.is_some())
+            .map_err(bookmarks_err)
+            .boxify()
+    }
+}
+
+impl<State> BlobRepo<State>
+where
+    State: BlobState,
+    <State::Blobstore as Blobstore>::ValueIn: From<Vec<u8>>,
+{
+    pub fn put_changeset(&self, cs: &BlobChangeset) -> BoxFuture<(), Error> {
+        let inner = self.inner.clone();
+        let nodeid = *cs.nodeid();
+
+        // The changeset is only indexed once it's stored, so that every changeset in the index
+        // can be loaded.
+        cs.save(self.inner.blobstore().clone())
+            .and_then(move |()| inner.csindex().add(&nodeid).map_err(csindex_err))
+            .boxify()
+    }
+
+    /// Store a manifest or file node with its revlog text.
+    pub fn put_node(
+        &self,
+        key: &NodeHash,
+        parents: Parents,
+        content: Vec<u8>,
+    ) -> BoxFuture<(), Error> {
+        put_node(self.inner.blobstore().clone(), *key, parents, content)
+    }
+
+    /// Store a file node with its revlog text. Its metadata header is also stored on its own,
+    /// before the node, so that `get_copy_info` can read it.
+    pub fn put_file_node(
+        &self,
+        key: &NodeHash,
+        parents: Parents,
+        content: Vec<u8>,
+    ) -> BoxFuture<(), Error> {
+        let blobstore = self.inner.blobstore().clone();
+        let key = *key;
+        let meta = {
+            let (_, metasize) = File::extract_meta(&content);
+            content[..metasize].to_vec()
+        };
+        self.inner
+            .blobstore()
+            .put(file_meta_key(&key), meta.into())
+            .map_err(blobstore_err)
+            .and_then(move |()| put_node(blobstore, key, parents, content))
+            .boxify()
+    }
+
+    /// Replace the pre-built bundle of the whole repo.
+    pub fn put_clonebundle(&self, bundle: Vec<u8>) -> BoxFuture<(), Error> {
+        self.inner
+            .blobstore()
+            .put(CLONEBUNDLE_KEY.to_string(), bundle.into())
+            .map_err(blobstore_err)
+            .boxify()
+    }
+
+    /// Store a piece of the stream clone data, replacing any existing one called `name`.
+    pub fn put_streamclone_blob(&self, name: &str, data: Vec<u8>) -> BoxFuture<(), Error> {
+        self.inner
+            .blobstore()
+            .put(format!("{}{}", STREAMCLONE_PREFIX, name), data.into())
+            .map_err(blobstore_err)
+            .boxify()
+    }
 }
 
 impl<State> Repo for BlobRepo<State>
@@ -52,9 +368,7 @@ where
 
     fn get_changesets(&self) -> BoxStream<NodeHash, Self::Error> {
         BlobChangesetStream {
-            repo: BlobRepo {
-                inner: self.inner.clone(),
-            },
+            repo: self.clone(),
             heads: self.inner.heads().heads().map_err(heads_err).boxify(),
             state: BCState::Idle,
             seen: HashSet::new(),
@@ -99,6 +413,14 @@ where
 
         Ok(BoxedBookmarks::new_cvt(res, bookmarks_err))
     }
+
+    fn get_changesets_by_prefix(&self, prefix: &str) -> BoxFuture<Vec<NodeHash>, Self::Error> {
+        self.inner
+            .csindex()
+            .find_prefix(prefix)
+            .map_err(csindex_err)
+            .boxify()
+    }
 }
 
 impl<State> Clone for BlobRepo<State> {
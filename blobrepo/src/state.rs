@@ -8,17 +8,26 @@ use std::path::Path;
 use std::sync::Arc;
 
 use blobstore::Blobstore;
-use bookmarks::Bookmarks;
+use bookmarks::BookmarksMut;
 use bytes::Bytes;
+use csindex::ChangesetIndex;
 use fileblob::Fileblob;
 use filebookmarks::FileBookmarks;
+use filecsindex::FileChangesetIndex;
 use fileheads::FileHeads;
+use filelinknodes::FileLinknodes;
+use filephases::FilePhases;
 use heads::Heads;
+use linknodes::Linknodes;
 use manifoldblob::ManifoldBlob;
 use memblob::Memblob;
 use membookmarks::MemBookmarks;
+use memcsindex::MemChangesetIndex;
 use memheads::MemHeads;
+use memlinknodes::MemLinknodes;
+use memphases::MemPhases;
 use mercurial_types::NodeHash;
+use phases::Phases;
 use rocksblob::Rocksblob;
 use tokio_core::reactor::Remote;
 
@@ -27,12 +36,39 @@ use errors::*;
 /// Represents all the state used by a blob store.
 pub trait BlobState: 'static + Send + Sync {
     type Heads: Heads<Key = NodeHash> + Sync;
-    type Bookmarks: Bookmarks<Value = NodeHash> + Clone + Sync;
+    type Bookmarks: BookmarksMut<Value = NodeHash> + Clone + Sync;
     type Blobstore: Blobstore<Key = String> + Clone + Sync;
+    type Linknodes: Linknodes + Sync;
+    type Phases: Phases + Sync;
+    type ChangesetIndex: ChangesetIndex + Sync;
 
     fn heads(&self) -> &Self::Heads;
     fn bookmarks(&self) -> &Self::Bookmarks;
     fn blobstore(&self) -> &Self::Blobstore;
+    fn linknodes(&self) -> &Self::Linknodes;
+    fn phases(&self) -> &Self::Phases;
+    fn csindex(&self) -> &Self::ChangesetIndex;
+}
+
+/// Repos that were created before linknodes were recorded don't have a store for them yet, so
+/// start them off with an empty one. Shallow clients can't fetch the files of such repos until
+/// it's filled in with `BlobRepo::backfill_linknodes`.
+fn open_linknodes(path: &Path) -> Result<FileLinknodes> {
+    FileLinknodes::create(path.join("linknodes"))
+        .chain_err(|| ErrorKind::StateOpen(StateOpenError::Linknodes))
+}
+
+/// Likewise for repos created before phases were recorded, in which every changeset is public.
+fn open_phases(path: &Path) -> Result<FilePhases> {
+    FilePhases::create(path.join("phases"))
+        .chain_err(|| ErrorKind::StateOpen(StateOpenError::Phases))
+}
+
+/// Likewise for repos created before the changeset index existed. Until it's filled in with
+/// `BlobRepo::index_changesets`, the changesets they already had can't be found by prefix.
+fn open_csindex(path: &Path) -> Result<FileChangesetIndex> {
+    FileChangesetIndex::create(path.join("csindex"))
+        .chain_err(|| ErrorKind::StateOpen(StateOpenError::ChangesetIndex))
 }
 
 macro_rules! impl_blob_state {
@@ -41,18 +77,27 @@ macro_rules! impl_blob_state {
             heads: $head_type: ty,
             bookmarks: $book_type: ty,
             blobstore: $blob_type: ty,
+            linknodes: $link_type: ty,
+            phases: $phases_type: ty,
+            csindex: $csindex_type: ty,
         }
     } => {
         pub struct $struct_type {
             heads: $head_type,
             bookmarks: $book_type,
             blobstore: $blob_type,
+            linknodes: $link_type,
+            phases: $phases_type,
+            csindex: $csindex_type,
         }
 
         impl BlobState for $struct_type {
             type Heads = $head_type;
             type Bookmarks = $book_type;
             type Blobstore = $blob_type;
+            type Linknodes = $link_type;
+            type Phases = $phases_type;
+            type ChangesetIndex = $csindex_type;
 
             #[inline]
             fn heads(&self) -> &Self::Heads {
@@ -68,6 +113,21 @@ macro_rules! impl_blob_state {
             fn blobstore(&self) -> &Self::Blobstore {
                 &self.blobstore
             }
+
+            #[inline]
+            fn linknodes(&self) -> &Self::Linknodes {
+                &self.linknodes
+            }
+
+            #[inline]
+            fn phases(&self) -> &Self::Phases {
+                &self.phases
+            }
+
+            #[inline]
+            fn csindex(&self) -> &Self::ChangesetIndex {
+                &self.csindex
+            }
         }
     }
 }
@@ -77,6 +137,9 @@ impl_blob_state! {
         heads: FileHeads<NodeHash>,
         bookmarks: Arc<FileBookmarks<NodeHash>>,
         blobstore: Fileblob<String, Vec<u8>>,
+        linknodes: FileLinknodes,
+        phases: FilePhases,
+        csindex: FileChangesetIndex,
     }
 }
 
@@ -90,11 +153,17 @@ impl FilesBlobState {
         );
         let blobstore = Fileblob::open(path.join("blobs"))
             .chain_err(|| ErrorKind::StateOpen(StateOpenError::Blobstore))?;
+        let linknodes = open_linknodes(path)?;
+        let phases = open_phases(path)?;
+        let csindex = open_csindex(path)?;
 
         Ok(FilesBlobState {
             heads,
             bookmarks,
             blobstore,
+            linknodes,
+            phases,
+            csindex,
         })
     }
 }
@@ -104,6 +173,9 @@ impl_blob_state! {
         heads: FileHeads<NodeHash>,
         bookmarks: Arc<FileBookmarks<NodeHash>>,
         blobstore: Rocksblob<String>,
+        linknodes: FileLinknodes,
+        phases: FilePhases,
+        csindex: FileChangesetIndex,
     }
 }
 
@@ -117,11 +189,17 @@ impl RocksBlobState {
         );
         let blobstore = Rocksblob::open(path.join("blobs"))
             .chain_err(|| ErrorKind::StateOpen(StateOpenError::Blobstore))?;
+        let linknodes = open_linknodes(path)?;
+        let phases = open_phases(path)?;
+        let csindex = open_csindex(path)?;
 
         Ok(RocksBlobState {
             heads,
             bookmarks,
             blobstore,
+            linknodes,
+            phases,
+            csindex,
         })
     }
 }
@@ -131,6 +209,9 @@ impl_blob_state! {
         heads: MemHeads<NodeHash>,
         bookmarks: Arc<MemBookmarks<NodeHash>>,
         blobstore: Memblob,
+        linknodes: MemLinknodes,
+        phases: MemPhases,
+        csindex: MemChangesetIndex,
     }
 }
 
@@ -139,11 +220,17 @@ impl MemBlobState {
         heads: MemHeads<NodeHash>,
         bookmarks: MemBookmarks<NodeHash>,
         blobstore: Memblob,
+        linknodes: MemLinknodes,
+        phases: MemPhases,
+        csindex: MemChangesetIndex,
     ) -> Self {
         MemBlobState {
             heads,
             bookmarks: Arc::new(bookmarks),
             blobstore,
+            linknodes,
+            phases,
+            csindex,
         }
     }
 }
@@ -153,6 +240,9 @@ impl_blob_state! {
         heads: FileHeads<NodeHash>,
         bookmarks: Arc<FileBookmarks<NodeHash>>,
         blobstore: ManifoldBlob<String, Bytes>,
+        linknodes: FileLinknodes,
+        phases: FilePhases,
+        csindex: FileChangesetIndex,
     }
 }
 
@@ -165,10 +255,16 @@ impl TestManifoldBlobState {
                 .chain_err(|| ErrorKind::StateOpen(StateOpenError::Bookmarks))?,
         );
         let blobstore = ManifoldBlob::new_may_panic("mononoke", remote);
+        let linknodes = open_linknodes(path)?;
+        let phases = open_phases(path)?;
+        let csindex = open_csindex(path)?;
         Ok(TestManifoldBlobState {
             heads,
             bookmarks,
             blobstore,
+            linknodes,
+            phases,
+            csindex,
         })
     }
 }
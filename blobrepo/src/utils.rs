@@ -21,6 +21,43 @@ pub struct RawNodeBlob {
     pub blob: BlobHash,
 }
 
+/// The key under which the metadata header of a file node's revlog text is kept, apart from
+/// the text itself, so that copy information can be read without fetching the whole file.
+pub fn file_meta_key(nodeid: &NodeHash) -> String {
+    format!("filemeta-{}", nodeid)
+}
+
+pub fn put_node<B>(
+    blobstore: B,
+    nodeid: NodeHash,
+    parents: Parents,
+    content: Vec<u8>,
+) -> BoxFuture<(), Error>
+where
+    B: Blobstore<Key = String> + Clone,
+    B::ValueIn: From<Vec<u8>>,
+{
+    let nodeblob = RawNodeBlob {
+        parents: parents,
+        blob: BlobHash::from(content.as_slice()),
+    };
+    let nodekey = format!("node-{}.bincode", nodeid);
+    let blobkey = format!("sha1-{}", nodeblob.blob.sha1());
+
+    bincode::serialize(&nodeblob, bincode::Bounded(4096))
+        .into_future()
+        .from_err()
+        .and_then(move |nodeblob| {
+            // Write the content before the node that refers to it, so a node is never
+            // visible without its content.
+            blobstore
+                .put(blobkey, content.into())
+                .and_then(move |()| blobstore.put(nodekey, nodeblob.into()))
+                .map_err(blobstore_err)
+        })
+        .boxify()
+}
+
 pub fn get_node<B>(blobstore: &B, nodeid: NodeHash) -> BoxFuture<RawNodeBlob, Error>
 where
     B: Blobstore<Key = String>,
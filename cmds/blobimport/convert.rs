@@ -13,8 +13,10 @@ use slog::Logger;
 use tokio_core::reactor::Core;
 
 use blobrepo::BlobChangeset;
+use csindex::ChangesetIndex;
 use futures_ext::{FutureExt, StreamExt};
 use heads::Heads;
+use linknodes::Linknodes;
 use mercurial::{RevlogManifest, RevlogRepo};
 use mercurial::revlog::RevIdx;
 use mercurial_types::{Changeset, Manifest, NodeHash};
@@ -25,19 +27,23 @@ use STATS;
 use errors::*;
 use manifest;
 
-pub(crate) struct ConvertContext<H> {
+pub(crate) struct ConvertContext<H, L, I> {
     pub repo: RevlogRepo,
     pub sender: SyncSender<BlobstoreEntry>,
     pub headstore: H,
+    pub linknodes: Arc<L>,
+    pub csindex: Arc<I>,
     pub core: Core,
     pub cpupool: Arc<CpuPool>,
     pub logger: Logger,
 }
 
-impl<H> ConvertContext<H>
+impl<H, L, I> ConvertContext<H, L, I>
 where
     H: Heads<Key = String>,
     H::Error: Into<Error>,
+    L: Linknodes + Sync,
+    I: ChangesetIndex + Sync,
 {
     pub fn convert(self) -> Result<()> {
         let mut core = self.core;
@@ -45,6 +51,8 @@ where
         let logger = &logger_owned;
         let cpupool = self.cpupool;
         let headstore = self.headstore;
+        let linknodes = self.linknodes;
+        let csindex = self.csindex;
 
         // Generate stream of changesets. For each changeset, save the cs blob, and the manifest
         // blob, and the files.
@@ -57,7 +65,12 @@ where
                 move |(seq, csid)| {
                     debug!(logger, "{}: changeset {}", seq, csid);
                     STATS::changesets.add_value(1);
-                    copy_changeset(repo.clone(), sender.clone(), csid)
+                    let index = csindex.add(&csid).map_err(move |err| {
+                        Error::with_chain(err, format!("Failed to index changeset {}", csid))
+                    });
+                    copy_changeset(repo.clone(), sender.clone(), linknodes.clone(), csid)
+                        .join(index)
+                        .map(|_| ())
                 }
             }) // Stream<Future<()>>
             .map(|copy| cpupool.spawn(copy))
@@ -95,14 +108,17 @@ where
 ///
 /// The files are more complex. For each manifest, we generate a stream of entries, then flatten
 /// the entry streams from all changesets into a single stream. Then each entry is filtered
-/// against a set of entries that have already been copied, and any remaining are actually copied.
-fn copy_changeset(
+/// against a set of entries that have already been copied, and any remaining are actually copied,
+/// along with the changeset as their linknode.
+fn copy_changeset<L>(
     revlog_repo: RevlogRepo,
     sender: SyncSender<BlobstoreEntry>,
+    linknodes: Arc<L>,
     csid: NodeHash,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static
 where
     Error: Send + 'static,
+    L: Linknodes + Sync,
 {
     let put = {
         let sender = sender.clone();
@@ -110,9 +126,15 @@ where
 
         revlog_repo
             .get_changeset_by_nodeid(&csid)
+            .join(revlog_repo.get_changeset_blob_by_nodeid(&csid))
             .from_err()
-            .and_then(move |cs| {
-                let bcs = BlobChangeset::new(&csid, cs);
+            .and_then(move |(cs, node)| {
+                // Keep the changeset's text as it is in the revlog, so that it still hashes to
+                // the same node.
+                let bcs = match node.as_blob().as_slice() {
+                    Some(text) => BlobChangeset::new_with_text(&csid, cs, text.to_vec()),
+                    None => BlobChangeset::new(&csid, cs),
+                };
                 sender
                     .send(BlobstoreEntry::Changeset(bcs))
                     .map_err(|e| Error::from(e.to_string()))
@@ -127,7 +149,7 @@ where
             let mfid = *cs.manifestid();
             let linkrev = entry.linkrev;
 
-            put_blobs(revlog_repo, sender, mfid, linkrev)
+            put_blobs(revlog_repo, sender, linknodes, csid, mfid, linkrev)
         })
         .map_err(move |err| {
             Error::with_chain(err, format!("Can't copy manifest for cs {}", csid))
@@ -142,12 +164,17 @@ where
 /// Copy manifest and filelog entries into the blob store.
 ///
 /// See the help for copy_changeset for a full description.
-fn put_blobs(
+fn put_blobs<L>(
     revlog_repo: RevlogRepo,
     sender: SyncSender<BlobstoreEntry>,
+    linknodes: Arc<L>,
+    csid: NodeHash,
     mfid: NodeHash,
     linkrev: RevIdx,
-) -> impl Future<Item = (), Error = Error> + Send + 'static {
+) -> impl Future<Item = (), Error = Error> + Send + 'static
+where
+    L: Linknodes + Sync,
+{
     revlog_repo
         .get_manifest_blob_by_nodeid(&mfid)
         .from_err()
@@ -158,6 +185,8 @@ fn put_blobs(
                 blob.as_blob().clone(),
                 blob.parents().clone(),
             );
+            let rootlink =
+                manifest::add_root_linknode(&revlog_repo, &*linknodes, mfid, &csid, linkrev);
 
             // Get the listing of entries and fetch each of those
             let files = RevlogManifest::new(revlog_repo.clone(), blob)
@@ -178,7 +207,12 @@ fn put_blobs(
                             }
                         })
                         .flatten()
-                        .for_each(move |entry| manifest::copy_entry(entry, sender.clone()))
+                        .for_each(move |entry| {
+                            let linknode = manifest::add_linknode(&*entry, &linknodes, &csid);
+                            manifest::copy_entry(entry, sender.clone())
+                                .join(linknode)
+                                .map(|_| ())
+                        })
                 })
                 .into_future()
                 .flatten();
@@ -187,7 +221,7 @@ fn put_blobs(
             // Huh? No idea why this is needed to avoid an error below.
             let files = files.boxify();
 
-            putmf.join(files).map(|_| ())
+            putmf.join3(rootlink, files).map(|_| ())
         })
 }
 
@@ -25,10 +25,14 @@ extern crate tokio_core;
 
 extern crate blobrepo;
 extern crate blobstore;
+extern crate csindex;
 extern crate fileblob;
+extern crate filecsindex;
 extern crate fileheads;
+extern crate filelinknodes;
 extern crate futures_ext;
 extern crate heads;
+extern crate linknodes;
 extern crate manifoldblob;
 extern crate mercurial;
 extern crate mercurial_types;
@@ -59,7 +63,9 @@ use tokio_core::reactor::{Core, Remote};
 use blobrepo::BlobChangeset;
 use blobstore::Blobstore;
 use fileblob::Fileblob;
+use filecsindex::FileChangesetIndex;
 use fileheads::FileHeads;
+use filelinknodes::FileLinknodes;
 use futures_ext::{BoxFuture, FutureExt};
 use manifoldblob::ManifoldBlob;
 use mercurial::RevlogRepo;
@@ -121,6 +127,8 @@ where
 
     info!(logger, "Opening headstore: {:?}", output);
     let headstore = open_headstore(&output, &cpupool)?;
+    let linknodes = open_linknodes(&output, &cpupool)?;
+    let csindex = open_csindex(&output, &cpupool)?;
 
     if let BlobstoreType::Manifold(ref bucket) = blobtype {
         info!(logger, "Using ManifoldBlob with bucket: {:?}", bucket);
@@ -166,6 +174,8 @@ where
         repo,
         sender,
         headstore,
+        linknodes: Arc::new(linknodes),
+        csindex: Arc::new(csindex),
         core,
         cpupool,
         logger: logger.clone(),
@@ -196,6 +206,26 @@ fn open_headstore<P: AsRef<Path>>(heads: P, pool: &Arc<CpuPool>) -> Result<FileH
     Ok(headstore)
 }
 
+fn open_linknodes<P: AsRef<Path>>(output: P, pool: &Arc<CpuPool>) -> Result<FileLinknodes> {
+    let mut linknodes = PathBuf::from(output.as_ref());
+
+    linknodes.push("linknodes");
+    let linknodes = FileLinknodes::create_with_pool(linknodes, pool.clone())
+        .chain_err(|| "Failed to open linknodes store")?;
+
+    Ok(linknodes)
+}
+
+fn open_csindex<P: AsRef<Path>>(output: P, pool: &Arc<CpuPool>) -> Result<FileChangesetIndex> {
+    let mut csindex = PathBuf::from(output.as_ref());
+
+    csindex.push("csindex");
+    let csindex = FileChangesetIndex::create_with_pool(csindex, pool.clone())
+        .chain_err(|| "Failed to open changeset index")?;
+
+    Ok(csindex)
+}
+
 fn open_blobstore(
     mut output: PathBuf,
     ty: BlobstoreType,
@@ -11,11 +11,14 @@ use bincode;
 use bytes::Bytes;
 use futures::{self, Future, IntoFuture, Stream};
 
-use blobrepo::RawNodeBlob;
-use futures_ext::StreamExt;
+use blobrepo::{file_meta_key, RawNodeBlob};
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
+use linknodes::Linknodes;
 use mercurial::{self, RevlogRepo};
+use mercurial::file::File;
 use mercurial::revlog::RevIdx;
-use mercurial_types::{self, Blob, BlobHash, Entry, NodeHash, Parents, Type};
+use mercurial_types::{self, Blob, BlobHash, Entry, NodeHash, Parents, RepoPath, Type,
+                      NULL_HASH};
 
 use BlobstoreEntry;
 use errors::*;
@@ -66,16 +69,108 @@ where
     E: error::Error + Send + 'static,
 {
     let hash = *entry.get_hash();
+    let is_file = entry.get_type() != Type::Tree;
 
     let blobfuture = entry.get_raw_content().map_err(Error::from);
 
     blobfuture
         .join(entry.get_parents().map_err(Error::from))
         .and_then(move |(blob, parents)| {
-            put_entry(sender, hash, blob, parents)
+            let meta = if is_file {
+                put_file_meta(&sender, hash, &blob)
+            } else {
+                Ok(())
+            };
+            meta.into_future()
+                .and_then(move |()| put_entry(sender, hash, blob, parents))
         })
 }
 
+/// Store the metadata header of a file's revlog text apart from the text, which is where the
+/// server looks for copy information.
+fn put_file_meta(
+    sender: &SyncSender<BlobstoreEntry>,
+    entry_hash: NodeHash,
+    blob: &Blob<Vec<u8>>,
+) -> Result<()> {
+    let meta = match blob.as_slice() {
+        Some(content) => {
+            let (_, metasize) = File::extract_meta(content);
+            Bytes::from(&content[..metasize])
+        }
+        None => bail!("missing blob data"),
+    };
+    sender
+        .send(BlobstoreEntry::ManifestEntry((file_meta_key(&entry_hash), meta)))
+        .map_err(|err| Error::from(format!("{}", err)))
+}
+
+/// Record `linknode` as the changeset that introduced a manifest entry.
+pub(crate) fn add_linknode<E, L>(
+    entry: &Entry<Error = E>,
+    linknodes: &L,
+    linknode: &NodeHash,
+) -> BoxFuture<(), Error>
+where
+    L: Linknodes,
+{
+    let hash = *entry.get_hash();
+    let path = match entry.get_type() {
+        Type::Tree => RepoPath::dir(entry.get_path().clone()),
+        Type::File | Type::Executable | Type::Symlink => RepoPath::file(entry.get_path().clone()),
+    };
+
+    match path {
+        Ok(path) => linknodes
+            .add(path, &hash, linknode)
+            .map_err(move |err| {
+                Error::with_chain(err, format!("cannot add linknode of {}", hash))
+            })
+            .boxify(),
+        Err(err) => futures::future::err(Error::with_chain(err, "invalid entry path")).boxify(),
+    }
+}
+
+/// Record `linknode` as the changeset that introduced the root manifest `mfid`, provided that
+/// it's the changeset at `cs_rev`. Changesets that only differ in their metadata share a
+/// manifest, which only the first of them introduced.
+pub(crate) fn add_root_linknode<L>(
+    revlog_repo: &RevlogRepo,
+    linknodes: &L,
+    mfid: NodeHash,
+    linknode: &NodeHash,
+    cs_rev: RevIdx,
+) -> BoxFuture<(), Error>
+where
+    L: Linknodes,
+{
+    if mfid == NULL_HASH {
+        return futures::future::ok(()).boxify();
+    }
+
+    let linkrev = revlog_repo
+        .get_manifest_revlog()
+        .get_entry_by_nodeid(&mfid)
+        .map(|e| e.linkrev)
+        .map_err(|e| Error::with_chain(e, format!("cannot get linkrev of {}", mfid)));
+
+    match linkrev {
+        Ok(linkrev) => if linkrev != cs_rev {
+            return futures::future::ok(()).boxify();
+        },
+        Err(e) => {
+            return futures::future::err(e).boxify();
+        }
+    }
+
+    linknodes
+        .add(RepoPath::root(), &mfid, linknode)
+        .map_err(move |err| {
+            Error::with_chain(err, format!("cannot add linknode of {}", mfid))
+        })
+        .boxify()
+}
+
 pub(crate) fn get_entry_stream(
     entry: Box<Entry<Error = mercurial::Error>>,
     revlog_repo: RevlogRepo,
@@ -0,0 +1,141 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate futures;
+extern crate futures_cpupool;
+
+extern crate csindex;
+extern crate filekv;
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate storage_types;
+
+use std::path::Path;
+use std::sync::Arc;
+
+use futures::{future, stream, Future, Stream};
+use futures::future::{Either, Loop};
+use futures_cpupool::CpuPool;
+
+use csindex::{prefix_bounds, ChangesetIndex, Error as IndexError, ErrorKind as IndexErrorKind,
+              ResultExt};
+use filekv::FileKV;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use mercurial_types::NodeHash;
+use storage_types::Version;
+
+static PREFIX: &str = "csindex:";
+
+/// How many hex digits of a changeset's hash pick the bucket it's recorded in.
+const BUCKET_LEN: usize = 3;
+
+/// A basic file-based persistent changeset index.
+///
+/// Changesets are split into buckets by the first few hex digits of their hash, and each bucket
+/// is stored as a file in the specified base directory holding a sorted list of hashes. Looking
+/// up a prefix at least as long as a bucket's name only needs to read one file.
+pub struct FileChangesetIndex {
+    kv: Arc<FileKV<Vec<NodeHash>>>,
+}
+
+impl FileChangesetIndex {
+    #[inline]
+    pub fn open<P: AsRef<Path>>(path: P) -> filekv::Result<Self> {
+        Ok(FileChangesetIndex {
+            kv: Arc::new(FileKV::open(path, PREFIX)?),
+        })
+    }
+
+    #[inline]
+    pub fn open_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> filekv::Result<Self> {
+        Ok(FileChangesetIndex {
+            kv: Arc::new(FileKV::open_with_pool(path, PREFIX, pool)?),
+        })
+    }
+
+    #[inline]
+    pub fn create<P: AsRef<Path>>(path: P) -> filekv::Result<Self> {
+        Ok(FileChangesetIndex {
+            kv: Arc::new(FileKV::create(path, PREFIX)?),
+        })
+    }
+
+    #[inline]
+    pub fn create_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> filekv::Result<Self> {
+        Ok(FileChangesetIndex {
+            kv: Arc::new(FileKV::create_with_pool(path, PREFIX, pool)?),
+        })
+    }
+}
+
+impl ChangesetIndex for FileChangesetIndex {
+    type Effect = BoxFuture<(), IndexError>;
+    type Find = BoxFuture<Vec<NodeHash>, IndexError>;
+
+    fn add(&self, node: &NodeHash) -> Self::Effect {
+        let kv = self.kv.clone();
+        let node = *node;
+        let bucket = node.to_hex().as_str()[..BUCKET_LEN].to_string();
+
+        future::loop_fn((), move |()| {
+            let kv = kv.clone();
+            let bucket = bucket.clone();
+            kv.get(bucket.clone()).and_then(move |current| {
+                let (mut nodes, version) = current.unwrap_or((Vec::new(), Version::absent()));
+                match nodes.binary_search(&node) {
+                    Ok(_) => Either::A(future::ok(Loop::Break(()))),
+                    Err(pos) => {
+                        nodes.insert(pos, node);
+                        Either::B(kv.set(bucket, &nodes, &version).map(|version| {
+                            match version {
+                                Some(_) => Loop::Break(()),
+                                // The bucket was changed concurrently, so try again.
+                                None => Loop::Continue(()),
+                            }
+                        }))
+                    }
+                }
+            })
+        }).then(|res| res.chain_err(|| IndexErrorKind::StorageError))
+            .boxify()
+    }
+
+    fn find_prefix(&self, prefix: &str) -> Self::Find {
+        let (lo, hi) = match prefix_bounds(prefix) {
+            Some(bounds) => bounds,
+            None => return future::ok(Vec::new()).boxify(),
+        };
+
+        let prefix = prefix.to_lowercase();
+        let buckets: BoxStream<String, filekv::Error> = if prefix.len() >= BUCKET_LEN {
+            stream::once(Ok(prefix[..BUCKET_LEN].to_string())).boxify()
+        } else {
+            // Shorter prefixes span several buckets.
+            self.kv
+                .keys()
+                .filter(move |bucket| bucket.starts_with(&prefix))
+                .boxify()
+        };
+
+        let kv = self.kv.clone();
+        buckets
+            .and_then(move |bucket| kv.get(bucket))
+            .fold(Vec::new(), move |mut found, bucket| {
+                if let Some((nodes, _version)) = bucket {
+                    found.extend(nodes.into_iter().filter(|node| lo <= *node && *node <= hi));
+                }
+                Ok::<_, filekv::Error>(found)
+            })
+            .map(|mut found| {
+                found.sort();
+                found
+            })
+            .then(|res| res.chain_err(|| IndexErrorKind::StorageError))
+            .boxify()
+    }
+}
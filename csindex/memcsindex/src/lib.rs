@@ -0,0 +1,57 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate futures;
+
+extern crate csindex;
+extern crate mercurial_types;
+
+use std::collections::BTreeSet;
+use std::collections::Bound::Included;
+use std::sync::Mutex;
+
+use futures::future::{ok, FutureResult};
+
+use csindex::{prefix_bounds, ChangesetIndex, Error as IndexError};
+use mercurial_types::NodeHash;
+
+/// In-memory changeset index backed by a BTreeSet, intended to be used in tests.
+pub struct MemChangesetIndex {
+    nodes: Mutex<BTreeSet<NodeHash>>,
+}
+
+impl MemChangesetIndex {
+    pub fn new() -> Self {
+        MemChangesetIndex {
+            nodes: Mutex::new(BTreeSet::new()),
+        }
+    }
+}
+
+impl ChangesetIndex for MemChangesetIndex {
+    type Effect = FutureResult<(), IndexError>;
+    type Find = FutureResult<Vec<NodeHash>, IndexError>;
+
+    fn add(&self, node: &NodeHash) -> Self::Effect {
+        self.nodes.lock().unwrap().insert(*node);
+        ok(())
+    }
+
+    fn find_prefix(&self, prefix: &str) -> Self::Find {
+        let found = match prefix_bounds(prefix) {
+            Some((lo, hi)) => self.nodes
+                .lock()
+                .unwrap()
+                .range((Included(lo), Included(hi)))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        ok(found)
+    }
+}
@@ -0,0 +1,79 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate error_chain;
+extern crate futures;
+
+extern crate mercurial_types;
+
+use std::str::FromStr;
+use std::sync::Arc;
+
+use futures::Future;
+
+use mercurial_types::NodeHash;
+
+mod errors {
+    error_chain! {
+        errors {
+            StorageError {
+                description("changeset index storage error")
+                display("changeset index storage error")
+            }
+        }
+    }
+}
+
+pub use errors::*;
+
+/// Trait representing the interface to a changeset index, which records the hashes of all the
+/// changesets in a repo so that they can be found from a prefix of their hex representation.
+/// Blobstores can only look changesets up by their full hash.
+pub trait ChangesetIndex: Send + 'static {
+    type Effect: Future<Item = (), Error = Error> + Send + 'static;
+    type Find: Future<Item = Vec<NodeHash>, Error = Error> + Send + 'static;
+
+    /// Record a changeset. Adding one that's already there does nothing.
+    fn add(&self, node: &NodeHash) -> Self::Effect;
+    /// Find the changesets whose hex hash starts with `prefix`, in order. The prefix isn't case
+    /// sensitive, and anything that isn't a valid hex prefix matches nothing.
+    fn find_prefix(&self, prefix: &str) -> Self::Find;
+}
+
+impl<I> ChangesetIndex for Arc<I>
+where
+    I: ChangesetIndex + Sync,
+{
+    type Effect = I::Effect;
+    type Find = I::Find;
+
+    #[inline]
+    fn add(&self, node: &NodeHash) -> Self::Effect {
+        (**self).add(node)
+    }
+
+    #[inline]
+    fn find_prefix(&self, prefix: &str) -> Self::Find {
+        (**self).find_prefix(prefix)
+    }
+}
+
+/// The lowest and highest hashes that start with `prefix`, or `None` if it isn't a valid hex
+/// prefix. Hashes sort in the same order as their hex representation, so the changesets that
+/// match are exactly those in between.
+pub fn prefix_bounds(prefix: &str) -> Option<(NodeHash, NodeHash)> {
+    if prefix.len() > 40 || !prefix.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+
+    let prefix = prefix.to_lowercase();
+    let lo = NodeHash::from_str(&format!("{:0<40}", prefix)).expect("valid hex");
+    let hi = NodeHash::from_str(&format!("{:f<40}", prefix)).expect("valid hex");
+    Some((lo, hi))
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests run against all changeset index implementations.
+
+#![deny(warnings)]
+
+extern crate futures;
+extern crate tempdir;
+
+extern crate csindex;
+extern crate filecsindex;
+extern crate memcsindex;
+extern crate mercurial_types;
+extern crate mercurial_types_mocks;
+
+use std::str::FromStr;
+
+use futures::Future;
+use tempdir::TempDir;
+
+use csindex::ChangesetIndex;
+use filecsindex::FileChangesetIndex;
+use memcsindex::MemChangesetIndex;
+use mercurial_types::NodeHash;
+use mercurial_types_mocks::nodehash::*;
+
+fn node(hex: &str) -> NodeHash {
+    NodeHash::from_str(hex).unwrap()
+}
+
+fn find_prefix<I: ChangesetIndex>(index: I) {
+    let a = node("1111111111111111111111111111111111111111");
+    let b = node("1112222222222222222222222222222222222222");
+    let c = node("abcdef0000000000000000000000000000000000");
+
+    for node in &[c, a, b, a] {
+        index.add(node).wait().unwrap();
+    }
+
+    let find = |prefix: &str| index.find_prefix(prefix).wait().unwrap();
+    assert_eq!(find("11"), vec![a, b]);
+    assert_eq!(find("111"), vec![a, b]);
+    assert_eq!(find("1112"), vec![b]);
+    assert_eq!(find("ABCDEF"), vec![c]);
+    assert_eq!(find(&c.to_string()), vec![c]);
+    assert_eq!(find("2"), vec![]);
+    assert_eq!(find("xyz"), vec![]);
+    assert_eq!(find(""), vec![a, b, c]);
+}
+
+fn persistence<F, I>(mut new_index: F)
+where
+    F: FnMut() -> I,
+    I: ChangesetIndex,
+{
+    {
+        let index = new_index();
+        index.add(&ONES_HASH).wait().unwrap();
+    }
+
+    let index = new_index();
+    assert_eq!(index.find_prefix("1111").wait().unwrap(), vec![ONES_HASH]);
+}
+
+macro_rules! csindex_test_impl {
+    ($mod_name: ident => {
+        state: $state: expr,
+        new: $new_cb: expr,
+        persistent: $persistent: expr,
+    }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_find_prefix() {
+                let state = $state;
+                find_prefix($new_cb(&state));
+            }
+
+            #[test]
+            fn test_persistence() {
+                // Not all changeset index implementations support persistence.
+                if $persistent {
+                    let state = $state;
+                    persistence(|| $new_cb(&state));
+                }
+            }
+        }
+    }
+}
+
+csindex_test_impl! {
+    memcsindex_test => {
+        state: (),
+        new: |_| MemChangesetIndex::new(),
+        persistent: false,
+    }
+}
+
+csindex_test_impl! {
+    filecsindex_test => {
+        state: TempDir::new("filecsindex_test").unwrap(),
+        new: |dir| FileChangesetIndex::open(&dir).unwrap(),
+        persistent: true,
+    }
+}
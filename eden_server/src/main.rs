@@ -13,6 +13,7 @@
 /// # Request examples
 /// ```
 /// /REPO/cs/HASH/roottreemanifestid - returns root tree manifest node for the HASH
+/// /REPO/clonebundle - returns the pre-built bundle of the whole repo, for clonebundles
 /// ```
 extern crate ascii;
 extern crate blobrepo;
@@ -93,6 +94,11 @@ fn parse_blob_content_url(caps: Captures) -> Result<ParsedUrl> {
     Ok(ParsedUrl::BlobContent(repo, hash))
 }
 
+fn parse_clonebundle_url(caps: Captures) -> Result<ParsedUrl> {
+    let repo = parse_capture::<String>(&caps, 1)?;
+    Ok(ParsedUrl::Clonebundle(repo))
+}
+
 /// Generic url-handling function
 /// Accepts vector of tuples (regex, url handling function)
 /// If url matches regex then url handling function is called
@@ -109,6 +115,7 @@ enum ParsedUrl {
     RootTreeManifestId(String, NodeHash),
     TreeContent(String, NodeHash),
     BlobContent(String, NodeHash),
+    Clonebundle(String),
 }
 
 lazy_static! {
@@ -119,6 +126,7 @@ lazy_static! {
             parse_root_treemanifest_id_url as UrlParseFunc),
             (r"^/(\w+)/treenode/(\w+)/$", parse_tree_content_url as UrlParseFunc),
             (r"^/(\w+)/blob/(\w+)/$", parse_blob_content_url as UrlParseFunc),
+            (r"^/(\w+)/clonebundle$", parse_clonebundle_url as UrlParseFunc),
         ].into_iter().map(|(re, func)| Route(Regex::new(re).expect("bad regex"), func)).collect()
     };
 }
@@ -233,6 +241,23 @@ where
             .and_then(|content| futures::future::ok(content))
             .boxify()
     }
+
+    fn get_clonebundle(
+        &self,
+        reponame: String,
+    ) -> Box<futures::Future<Item = Vec<u8>, Error = Error> + Send> {
+        let repo = match self.name_to_repo.get(&reponame) {
+            Some(repo) => repo,
+            None => {
+                return futures::future::err("unknown repo".into()).boxify();
+            }
+        };
+
+        repo.get_clonebundle()
+            .map_err(Error::from)
+            .and_then(|bundle| bundle.ok_or("no clonebundle has been generated".into()))
+            .boxify()
+    }
 }
 
 impl<State> Service for EdenServer<State>
@@ -274,6 +299,7 @@ where
                 })
                 .boxify(),
             ParsedUrl::BlobContent(reponame, hash) => self.get_blob_content(reponame, &hash),
+            ParsedUrl::Clonebundle(reponame) => self.get_clonebundle(reponame),
         };
         result_future
             .then(|res| {
@@ -394,5 +420,7 @@ mod test {
         let badhash = std::iter::repeat("x").take(40).collect::<String>();
         let incorrect_url = format!("/repo/cs/{}/roottreemanifestid", badhash);
         assert!(parse_url(&incorrect_url, &routes).is_err());
+
+        assert!(parse_url("/repo/clonebundle", &routes).is_ok());
     }
 }
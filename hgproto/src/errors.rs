@@ -28,6 +28,10 @@ error_chain! {
             description("unknown escape character in batch command")
             display("unknown escape character in batch command '{}'", ch)
         }
+        UnbundleTooLarge(limit: usize) {
+            description("push payload too large")
+            display("push payload is larger than the limit of {} bytes", limit)
+        }
         Repo {
             description("Repo error")
         }
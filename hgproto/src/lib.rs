@@ -47,7 +47,8 @@ use std::fmt::{self, Debug};
 
 use bytes::Bytes;
 
-use mercurial_types::NodeHash;
+use futures_ext::BoxStream;
+use mercurial_types::{MPath, NodeHash};
 
 mod batch;
 mod errors;
@@ -63,6 +64,21 @@ pub struct BranchRes {
     p1: Option<NodeHash>,
 }
 
+impl BranchRes {
+    /// `node` is the first ancestor of `top` that is a merge or a root, following first parents.
+    pub fn new(top: NodeHash, node: NodeHash, p0: Option<NodeHash>, p1: Option<NodeHash>) -> Self {
+        BranchRes { top, node, p0, p1 }
+    }
+}
+
+// result from `lookup()`
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub enum LookupRes {
+    Found(NodeHash),
+    /// The key couldn't be resolved. The message is shown to the user.
+    Failed(String),
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub enum Request {
     Batch { cmds: Vec<(Vec<u8>, Vec<u8>)> },
@@ -82,19 +98,33 @@ pub enum Request {
         all_args: HashMap<Vec<u8>, Vec<u8>>,
     },
     Getbundle(GetbundleArgs),
+    /// From the remotefilelog extension. Over ssh, the command is followed by the files the
+    /// client wants, which the decoder hands back as one `Getfile` each.
+    Getfiles,
+    Getfile { node: NodeHash, path: MPath },
+    /// From the remotefilelog extension.
+    Getflogheads { path: MPath },
+    /// From the treemanifest extension.
+    Gettreepack(GettreepackArgs),
     Heads,
     Hello,
     Listkeys { namespace: String },
     Lookup { key: String },
     Known { nodes: Vec<NodeHash> },
+    /// The meaning of `old` and `new` depends on the namespace. For bookmarks they're hex
+    /// node hashes, with an empty value meaning the bookmark doesn't exist.
     Pushkey {
         namespace: String,
         key: String,
-        old: NodeHash,
-        new: NodeHash,
+        old: Vec<u8>,
+        new: Vec<u8>,
     },
     Streamout,
-    Unbundle { heads: Vec<NodeHash>, /* stream: Stream<Vec<u8>, Error> TBD: Stream */ },
+    /// `heads` are the heads the client expects the repo to have, or empty if it's forcing the
+    /// push.
+    Unbundle { heads: Vec<NodeHash> },
+    /// The bundle sent by the client after it has been told to go ahead with `Unbundle`.
+    UnbundleData { heads: Vec<NodeHash>, data: Bytes },
 }
 
 /// The arguments that `getbundle` accepts, in a separate struct for
@@ -105,6 +135,8 @@ pub struct GetbundleArgs {
     pub common: Vec<NodeHash>,
     pub bundlecaps: Vec<Vec<u8>>,
     pub listkeys: Vec<Vec<u8>>,
+    /// Whether to send the phases of the changesets in the bundle, in a `phase-heads` part.
+    pub phases: bool,
 }
 
 impl Debug for GetbundleArgs {
@@ -122,10 +154,25 @@ impl Debug for GetbundleArgs {
             .field("common", &self.common)
             .field("bundlecaps", &bcaps)
             .field("listkeys", &listkeys)
+            .field("phases", &self.phases)
             .finish()
     }
 }
 
+/// The arguments that `gettreepack` accepts.
+#[derive(Debug, Eq, PartialEq)]
+pub struct GettreepackArgs {
+    /// The directory that `mfnodes` and `basemfnodes` are tree manifests of. Empty for the root.
+    pub rootdir: MPath,
+    /// The tree manifests to send, along with all the subtrees they refer to.
+    pub mfnodes: Vec<NodeHash>,
+    /// Tree manifests the client already has. Subtrees that are the same as in these aren't
+    /// sent.
+    pub basemfnodes: Vec<NodeHash>,
+    /// If not empty, only the tree manifests of these directories under `rootdir` are sent.
+    pub directories: Vec<MPath>,
+}
+
 #[derive(Debug)]
 pub enum Response {
     Batch(Vec<Bytes>),
@@ -137,15 +184,34 @@ pub enum Response {
     Changegroup,
     Changegroupsubset,
     Debugwireargs(Bytes),
-    Getbundle(Bytes),
+    Getbundle(BytesStream),
+    /// Empty acknowledgement of `getfiles`. Nothing is sent back until the client asks for
+    /// files.
+    Getfiles,
+    /// A file's contents and history, in remotefilelog's format.
+    Getfile(Bytes),
+    Getflogheads(Vec<NodeHash>),
+    Gettreepack(BytesStream),
     Heads(HashSet<NodeHash>),
     Hello(HashMap<String, Vec<String>>),
     Listkeys(HashMap<Vec<u8>, Vec<u8>>),
-    Lookup(NodeHash),
+    Lookup(LookupRes),
     Known(Vec<bool>),
-    Pushkey,
-    Streamout, /* (BoxStream<Vec<u8>, Error>) */
-    Unbundle,
+    Pushkey(bool),
+    /// The store files, in the stream clone format.
+    Streamout(BytesStream),
+    /// Empty acknowledgement telling the client to start sending its payload.
+    ReadyForStream,
+    Unbundle(Bytes),
+}
+
+/// A response body that's sent as it's generated, rather than being built up in memory first.
+pub struct BytesStream(pub BoxStream<Bytes, Error>);
+
+impl Debug for BytesStream {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "BytesStream")
+    }
 }
 
 impl Response {
@@ -155,11 +221,14 @@ impl Response {
 
         match self {
             &Getbundle(_) => true,
-            &Unbundle => true,
+            &Getfiles => true,
+            &Gettreepack(_) => true,
+            &Streamout(_) => true,
+            &Unbundle(_) => true,
             _ => false,
         }
     }
 }
 
-pub use service::{HgCommandRes, HgCommands, HgService};
+pub use service::{HgCommandRes, HgCommands, HgService, DEFAULT_BATCH_CONCURRENCY};
 pub use errors::{Error, ErrorKind, Result, ResultExt};
@@ -14,19 +14,23 @@ use slog::Logger;
 
 use bytes::{Bytes, BytesMut};
 use futures::future::{self, Future};
-use futures::stream::Stream;
+use futures::stream::{self, Stream};
 use tokio_service::Service;
 
-use futures_ext::{futures_ordered, BoxFuture, BoxStream, FutureExt};
-use mercurial_types::NodeHash;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use mercurial_types::{MPath, NodeHash};
 
-use {BranchRes, GetbundleArgs, Request, Response};
+use {BranchRes, BytesStream, GetbundleArgs, GettreepackArgs, LookupRes, Request, Response};
 use errors::*;
 use sshproto;
 
+/// How many of the commands in a batch are run at once, unless configured otherwise.
+pub const DEFAULT_BATCH_CONCURRENCY: usize = 10;
+
 pub struct HgService<H> {
     commands: H,
     logger: Option<Logger>,
+    batch_concurrency: usize,
 }
 
 impl<H: HgCommands> HgService<H> {
@@ -34,6 +38,7 @@ impl<H: HgCommands> HgService<H> {
         HgService {
             commands: hgcmds,
             logger: None,
+            batch_concurrency: DEFAULT_BATCH_CONCURRENCY,
         }
     }
 
@@ -41,9 +46,18 @@ impl<H: HgCommands> HgService<H> {
         HgService {
             commands: hgcmds,
             logger: Some(logger.new(o!())),
+            batch_concurrency: DEFAULT_BATCH_CONCURRENCY,
         }
     }
 
+    /// Set the maximum number of commands in a batch that are run concurrently. Panics if `limit`
+    /// is zero.
+    pub fn with_batch_concurrency(mut self, limit: usize) -> Self {
+        assert!(limit > 0, "batch concurrency must be positive");
+        self.batch_concurrency = limit;
+        self
+    }
+
     pub fn command(&self, req: Request) -> BoxFuture<Response, Error>
     where
         H: HgCommands,
@@ -102,7 +116,24 @@ impl<H: HgCommands> HgService<H> {
                 .boxify(),
             Request::Getbundle(args) => hgcmds
                 .getbundle(args)
-                .map(Response::Getbundle)
+                .map(|stream| Response::Getbundle(BytesStream(stream)))
+                .map_err(self::Error::into)
+                .boxify(),
+            // The files themselves are requested separately, as `Getfile`.
+            Request::Getfiles => future::ok(Response::Getfiles).boxify(),
+            Request::Getfile { node, path } => hgcmds
+                .getfile(node, path)
+                .map(Response::Getfile)
+                .map_err(self::Error::into)
+                .boxify(),
+            Request::Getflogheads { path } => hgcmds
+                .getflogheads(path)
+                .map(Response::Getflogheads)
+                .map_err(self::Error::into)
+                .boxify(),
+            Request::Gettreepack(args) => hgcmds
+                .gettreepack(args)
+                .map(|stream| Response::Gettreepack(BytesStream(stream)))
                 .map_err(self::Error::into)
                 .boxify(),
             Request::Heads => hgcmds
@@ -137,19 +168,22 @@ impl<H: HgCommands> HgService<H> {
                 new,
             } => hgcmds
                 .pushkey(namespace, key, old, new)
-                .map(|_| Response::Pushkey)
+                .map(Response::Pushkey)
                 .map_err(self::Error::into)
                 .boxify(),
             Request::Streamout => hgcmds
                 .stream_out()
-                .map(|_| Response::Streamout)
+                .map(|stream| Response::Streamout(BytesStream(stream)))
                 .map_err(self::Error::into)
                 .boxify(),
-            Request::Unbundle { heads } => hgcmds
-                .unbundle(heads)
-                .map(|_| Response::Unbundle)
+            // The client waits for an empty reply before sending the bundle itself, which
+            // the decoder then hands back as `UnbundleData`.
+            Request::Unbundle { .. } => future::ok(Response::ReadyForStream).boxify(),
+            Request::UnbundleData { heads, data } => hgcmds
+                .unbundle(heads, stream::once(Ok(data)).boxify())
+                .map(Response::Unbundle)
                 .map_err(self::Error::into)
-                .boxify(), //_ => unimplemented!()
+                .boxify(),
         }
     }
 
@@ -189,10 +223,15 @@ impl<H: HgCommands> HgService<H> {
             .map(|cmd| self.command(cmd))
             .collect();
 
+        // The commands are independent, so run several at once, but the results have to be in
+        // the same order as the commands.
         let encoded_futures = response_futures
             .into_iter()
             .map(|cmd| cmd.map(|res| sshproto::response::encode_cmd(&res)));
-        futures_ordered(encoded_futures).collect().boxify()
+        stream::iter_ok(encoded_futures)
+            .buffered(self.batch_concurrency)
+            .collect()
+            .boxify()
     }
 
     // @wireprotocommand('debugwireargs', 'one two *')
@@ -302,11 +341,29 @@ pub trait HgCommands {
     }
 
     // @wireprotocommand('getbundle', '*')
-    // TODO: make this streaming
-    fn getbundle(&self, _args: GetbundleArgs) -> HgCommandRes<Bytes> {
+    // The bundle is sent as the stream produces it, without any framing.
+    fn getbundle(&self, _args: GetbundleArgs) -> HgCommandRes<BoxStream<Bytes, Error>> {
         unimplemented("getbundle")
     }
 
+    // @wireprotocommand('getfiles', '') from remotefilelog
+    // Called for each file the client asks for after `getfiles`. The result is the file's
+    // contents and history, in remotefilelog's format.
+    fn getfile(&self, _node: NodeHash, _path: MPath) -> HgCommandRes<Bytes> {
+        unimplemented("getfiles")
+    }
+
+    // @wireprotocommand('getflogheads', 'path') from remotefilelog
+    fn getflogheads(&self, _path: MPath) -> HgCommandRes<Vec<NodeHash>> {
+        unimplemented("getflogheads")
+    }
+
+    // @wireprotocommand('gettreepack', '*') from treemanifest
+    // Like `getbundle`, the bundle is sent without any framing.
+    fn gettreepack(&self, _args: GettreepackArgs) -> HgCommandRes<BoxStream<Bytes, Error>> {
+        unimplemented("gettreepack")
+    }
+
     // @wireprotocommand('heads')
     fn heads(&self) -> HgCommandRes<HashSet<NodeHash>> {
         unimplemented("heads")
@@ -323,7 +380,8 @@ pub trait HgCommands {
     }
 
     // @wireprotocommand('lookup', 'key')
-    fn lookup(&self, _key: String) -> HgCommandRes<NodeHash> {
+    // A key that can't be resolved isn't an error, but a `LookupRes::Failed`.
+    fn lookup(&self, _key: String) -> HgCommandRes<LookupRes> {
         unimplemented("lookup")
     }
 
@@ -333,27 +391,30 @@ pub trait HgCommands {
     }
 
     // @wireprotocommand('pushkey', 'namespace key old new')
+    // Returns whether the key was updated.
     fn pushkey(
         &self,
         _namespace: String,
         _key: String,
-        _old: NodeHash,
-        _new: NodeHash,
-    ) -> HgCommandRes<()> {
+        _old: Vec<u8>,
+        _new: Vec<u8>,
+    ) -> HgCommandRes<bool> {
         unimplemented("pushkey")
     }
 
     // @wireprotocommand('stream_out')
-    fn stream_out(&self) -> HgCommandRes<BoxStream<Vec<u8>, Error>> {
-        // XXX raw streaming?
+    // The stream is sent as is, without any framing.
+    fn stream_out(&self) -> HgCommandRes<BoxStream<Bytes, Error>> {
         unimplemented("stream_out")
     }
 
     // @wireprotocommand('unbundle', 'heads')
+    // The stream is the raw bundle sent by the client, and the result is the bundle2 reply.
     fn unbundle(
         &self,
-        _heads: Vec<NodeHash>, /* , _stream: BoxStream<Vec<u8>, Error> */
-    ) -> HgCommandRes<()> {
+        _heads: Vec<NodeHash>,
+        _stream: BoxStream<Bytes, Error>,
+    ) -> HgCommandRes<Bytes> {
         unimplemented("unbundle")
     }
 }
@@ -30,28 +30,62 @@
 //! <byte>{numbytes}
 //! ```
 //!
-//! Each command has its own encoding of the response.
+//! Each command has its own encoding of the response. The exceptions are `getbundle`,
+//! `gettreepack`, `stream_out` and `unbundle`, whose responses are sent as they are, without
+//! the length.
+//!
+//! Commands which take a payload from the client (`unbundle`) get an empty response first,
+//! after which the client sends the payload as a sequence of chunks:
+//! ```
+//! chunk := <numbytes> '\n' <byte>{numbytes}
+//! ```
+//! terminated by an empty chunk. The payload is held in memory until it's complete, so pushes
+//! larger than a limit are rejected.
+//!
+//! `getfiles` is similar, except that it gets no response itself, and what follows it is a
+//! line per file the client wants:
+//! ```
+//! file := <hash><path> '\n'
+//! ```
+//! terminated by an empty line. Each of those gets a response of its own as soon as it's read,
+//! as the client waits for them before asking for more.
 
 use bytes::BytesMut;
-use tokio_io::codec::{Decoder, Encoder};
+use tokio_io::codec::Decoder;
 
-use {Request, Response};
+use mercurial_types::NodeHash;
+
+use Request;
 use errors::*;
 
 pub mod request;
 pub mod response;
 
+/// The largest `unbundle` payload accepted, in bytes, unless configured otherwise.
+pub const DEFAULT_MAX_UNBUNDLE_SIZE: usize = 1 << 30;
 
-pub struct HgSshCommandEncode;
-pub struct HgSshCommandDecode;
+#[derive(Debug)]
+pub struct HgSshCommandDecode {
+    // Set while reading the payload of an `unbundle` command
+    unbundle: Option<(Vec<NodeHash>, BytesMut)>,
+    // Set while reading the files requested after a `getfiles` command
+    getfiles: bool,
+    max_unbundle_size: usize,
+}
 
-impl Encoder for HgSshCommandEncode {
-    type Item = Response;
-    type Error = Error;
+impl HgSshCommandDecode {
+    pub fn new() -> Self {
+        HgSshCommandDecode {
+            unbundle: None,
+            getfiles: false,
+            max_unbundle_size: DEFAULT_MAX_UNBUNDLE_SIZE,
+        }
+    }
 
-    fn encode(&mut self, v: Response, out: &mut BytesMut) -> Result<()> {
-        response::encode(&v, out);
-        Ok(())
+    /// Set the size of the largest `unbundle` payload that's accepted, in bytes.
+    pub fn with_max_unbundle_size(mut self, limit: usize) -> Self {
+        self.max_unbundle_size = limit;
+        self
     }
 }
 
@@ -60,6 +94,84 @@ impl Decoder for HgSshCommandDecode {
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Request>> {
-        request::parse(buf)
+        if let Some((heads, mut data)) = self.unbundle.take() {
+            loop {
+                match request::parse_chunk(buf)? {
+                    None => {
+                        self.unbundle = Some((heads, data));
+                        return Ok(None);
+                    }
+                    Some(ref chunk) if chunk.is_empty() => {
+                        return Ok(Some(Request::UnbundleData {
+                            heads: heads,
+                            data: data.freeze(),
+                        }));
+                    }
+                    Some(chunk) => {
+                        if data.len() + chunk.len() > self.max_unbundle_size {
+                            bail!(ErrorKind::UnbundleTooLarge(self.max_unbundle_size));
+                        }
+                        data.extend_from_slice(&chunk)
+                    }
+                }
+            }
+        }
+
+        if self.getfiles {
+            match request::parse_getfile(buf)? {
+                None => return Ok(None),
+                Some(Some((node, path))) => return Ok(Some(Request::Getfile { node, path })),
+                Some(None) => self.getfiles = false,
+            }
+        }
+
+        match request::parse(buf)? {
+            Some(Request::Unbundle { heads }) => {
+                self.unbundle = Some((heads.clone(), BytesMut::new()));
+                Ok(Some(Request::Unbundle { heads }))
+            }
+            Some(Request::Getfiles) => {
+                self.getfiles = true;
+                Ok(Some(Request::Getfiles))
+            }
+            req => Ok(req),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const UNBUNDLE: &[u8] = b"unbundle\nheads 10\n666f7263654\nabcd3\nefg0\n";
+
+    #[test]
+    fn test_unbundle() {
+        let mut buf = BytesMut::from(UNBUNDLE);
+        let mut decoder = HgSshCommandDecode::new().with_max_unbundle_size(7);
+
+        match decoder.decode(&mut buf) {
+            Ok(Some(Request::Unbundle { ref heads })) if heads.is_empty() => (),
+            bad => panic!("unexpected result {:?}", bad),
+        }
+        match decoder.decode(&mut buf) {
+            Ok(Some(Request::UnbundleData { ref data, .. })) if data.as_ref() == b"abcdefg" => (),
+            bad => panic!("unexpected result {:?}", bad),
+        }
+    }
+
+    #[test]
+    fn test_unbundle_too_large() {
+        let mut buf = BytesMut::from(UNBUNDLE);
+        let mut decoder = HgSshCommandDecode::new().with_max_unbundle_size(6);
+
+        match decoder.decode(&mut buf) {
+            Ok(Some(Request::Unbundle { .. })) => (),
+            bad => panic!("unexpected result {:?}", bad),
+        }
+        match decoder.decode(&mut buf) {
+            Err(Error(ErrorKind::UnbundleTooLarge(6), _)) => (),
+            bad => panic!("unexpected result {:?}", bad),
+        }
     }
 }
@@ -8,16 +8,16 @@ use std::collections::HashMap;
 use std::iter;
 use std::str::{self, FromStr};
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
 
 use nom::{ErrorKind, FindSubstring, IResult, Needed, Slice, is_digit};
 
-use mercurial_types::NodeHash;
+use mercurial_types::{MPath, NodeHash};
 
 use batch;
 use errors;
 use errors::*;
-use {GetbundleArgs, Request};
+use {GetbundleArgs, GettreepackArgs, Request};
 
 
 /// Parse an unsigned decimal integer. If it reaches the end of input, it returns Incomplete,
@@ -174,6 +174,26 @@ named!(hashlist<Vec<NodeHash>>,
     separated_list!(complete!(tag!(" ")), nodehash)
 );
 
+/// A space-separated list of node hashes that takes up the whole input, which may be empty.
+fn hashlist_complete(input: &[u8]) -> IResult<&[u8], Vec<NodeHash>> {
+    if input.len() == 0 {
+        IResult::Done(input, vec![])
+    } else {
+        hashlist(input)
+    }
+}
+
+/// The heads argument of `unbundle`. Bundle2 clients send the hex encoding of "force" instead of
+/// the heads they expect, and check the heads with a `check:heads` part if they need to. That's
+/// parsed as an empty list, which is otherwise never sent.
+fn unbundle_heads(input: &[u8]) -> IResult<&[u8], Vec<NodeHash>> {
+    if input == b"666f726365" {
+        IResult::Done(b"", vec![])
+    } else {
+        hashlist(input)
+    }
+}
+
 /// A comma-separated list of arbitrary values. The input is assumed to be
 /// complete and exact.
 fn commavalues(input: &[u8]) -> IResult<&[u8], Vec<Vec<u8>>> {
@@ -192,6 +212,40 @@ fn commavalues(input: &[u8]) -> IResult<&[u8], Vec<Vec<u8>>> {
     }
 }
 
+/// A comma-separated list of paths, each escaped the same way as batch parameters. The input
+/// is assumed to be complete and exact.
+fn pathlist(input: &[u8]) -> IResult<&[u8], Vec<MPath>> {
+    let values = match commavalues(input) {
+        IResult::Done(_, values) => values,
+        IResult::Incomplete(n) => return IResult::Incomplete(n),
+        IResult::Error(e) => return IResult::Error(e),
+    };
+
+    let paths: Option<Vec<_>> = values
+        .iter()
+        .map(|val| {
+            batch::unescape(val)
+                .ok()
+                .and_then(|val| MPath::new(val).ok())
+        })
+        .collect();
+    match paths {
+        Some(paths) => IResult::Done(b"", paths),
+        None => IResult::Error(ErrorKind::MapRes),
+    }
+}
+
+/// A boolean, which is sent as "0" or "1".
+fn boolean(input: &[u8]) -> IResult<&[u8], bool> {
+    if input == b"0" {
+        IResult::Done(b"", false)
+    } else if input == b"1" {
+        IResult::Done(b"", true)
+    } else {
+        IResult::Error(ErrorKind::MapRes)
+    }
+}
+
 fn notsemi(b: u8) -> bool {
     b != b';'
 }
@@ -294,6 +348,24 @@ where
     }
 }
 
+/// Take the entire input as raw bytes.
+fn bytes_complete(inp: &[u8]) -> IResult<&[u8], Vec<u8>> {
+    IResult::Done(b"", inp.to_vec())
+}
+
+/// Take the entire input and map it to `String`.
+fn string_complete(inp: &[u8]) -> IResult<&[u8], String> {
+    IResult::Done(b"", String::from_utf8_lossy(inp).into_owned())
+}
+
+/// Take the entire input as a repo path.
+fn path_complete(inp: &[u8]) -> IResult<&[u8], MPath> {
+    match MPath::new(inp) {
+        Ok(path) => IResult::Done(b"", path),
+        Err(_) => IResult::Error(ErrorKind::MapRes),
+    }
+}
+
 /// Parse an ident, and map it to `String`.
 fn ident_string(inp: &[u8]) -> IResult<&[u8], String> {
     match ident_complete(inp) {
@@ -403,6 +475,18 @@ fn parse_common(
                     common: parseval_default(&kv, "common", hashlist)?,
                     bundlecaps: parseval_default(&kv, "bundlecaps", commavalues)?,
                     listkeys: parseval_default(&kv, "listkeys", commavalues)?,
+                    phases: parseval_default(&kv, "phases", boolean)?,
+                })))
+            | command!("getfiles", Getfiles, parse_params, {})
+            | command!("getflogheads", Getflogheads, parse_params, {
+                  path => path_complete,
+              })
+            | call!(parse_command, "gettreepack", parse_params, 0+1,
+                |kv| Ok(Gettreepack(GettreepackArgs {
+                    rootdir: parseval(&kv, "rootdir", path_complete)?,
+                    mfnodes: parseval(&kv, "mfnodes", hashlist_complete)?,
+                    basemfnodes: parseval_default(&kv, "basemfnodes", hashlist_complete)?,
+                    directories: parseval_default(&kv, "directories", pathlist)?,
                 })))
             | command!("heads", Heads, parse_params, {})
             | command!("hello", Hello, parse_params, {})
@@ -410,20 +494,20 @@ fn parse_common(
                   namespace => ident_string,
               })
             | command!("lookup", Lookup, parse_params, {
-                  key => ident_string,
+                  key => string_complete,
               })
             | command_star!("known", Known, parse_params, {
                   nodes => hashlist,
               })
             | command!("pushkey", Pushkey, parse_params, {
                   namespace => ident_string,
-                  key => ident_string,
-                  old => nodehash,
-                  new => nodehash,
+                  key => string_complete,
+                  old => bytes_complete,
+                  new => bytes_complete,
               })
-            | command!("streamout", Streamout, parse_params, {})
+            | command!("stream_out", Streamout, parse_params, {})
             | command!("unbundle", Unbundle, parse_params, {
-                  heads => hashlist,
+                  heads => unbundle_heads,
               })
         );
 
@@ -448,6 +532,77 @@ fn parse_common(
     }))
 }
 
+/// A chunk of a payload following a command:
+/// <bytelen>\n
+/// <bytelen bytes>
+named!(chunk<&[u8]>,
+    do_parse!(
+        len: integer >> tag!(b"\n") >>
+        data: take!(len) >>
+        (data)
+    )
+);
+
+/// Parse a single chunk of a command payload. The end of the payload is marked by
+/// an empty chunk.
+pub fn parse_chunk(buf: &mut BytesMut) -> Result<Option<Bytes>> {
+    let res = {
+        let origlen = buf.len();
+        match chunk(&buf[..]) {
+            IResult::Done(rest, data) => Some((origlen - rest.len(), data.len())),
+            IResult::Incomplete(_) => None,
+            IResult::Error(err) => {
+                bail!(
+                Error::with_chain(
+                    err,
+                    errors::ErrorKind::CommandParse(buf.to_vec()),
+                ))
+            }
+        }
+    };
+
+    Ok(res.map(|(consume, len)| {
+        let mut chunk = buf.split_to(consume);
+        chunk.split_off(consume - len).freeze()
+    }))
+}
+
+/// A file requested after `getfiles`, as its node hash immediately followed by its path, or an
+/// empty line once the client has asked for everything it wants.
+named!(getfile<Option<(NodeHash, MPath)>>,
+    alt!(
+        map!(tag!(b"\n"), |_| None)
+      | do_parse!(
+            node: nodehash >>
+            path: map_res!(take_until_and_consume!("\n"), MPath::new) >>
+            (Some((node, path)))
+        )
+    )
+);
+
+/// Parse a single file request following `getfiles`. `Some(None)` is the end of the requests.
+pub fn parse_getfile(buf: &mut BytesMut) -> Result<Option<Option<(NodeHash, MPath)>>> {
+    let res = {
+        let origlen = buf.len();
+        match getfile(&buf[..]) {
+            IResult::Done(rest, file) => Some((origlen - rest.len(), file)),
+            IResult::Incomplete(_) => None,
+            IResult::Error(err) => {
+                bail!(
+                Error::with_chain(
+                    err,
+                    errors::ErrorKind::CommandParse(buf.to_vec()),
+                ))
+            }
+        }
+    };
+
+    Ok(res.map(|(consume, file)| {
+        let _ = buf.split_to(consume);
+        file
+    }))
+}
+
 /// Test individual combinators
 #[cfg(test)]
 mod test {
@@ -1058,12 +1213,13 @@ mod test_parse {
                 common: vec![],
                 bundlecaps: vec![],
                 listkeys: vec![],
+                phases: false,
             }),
         );
 
         // with arguments
         let inp = "getbundle\n\
-                   * 5\n\
+                   * 6\n\
                    heads 40\n\
                    1111111111111111111111111111111111111111\
                    common 81\n\
@@ -1072,6 +1228,8 @@ mod test_parse {
                    cap1,CAP2,cap3\
                    listkeys 9\n\
                    key1,key2\
+                   phases 1\n\
+                   1\
                    extra 5\n\
                    extra";
         test_parse(
@@ -1081,6 +1239,87 @@ mod test_parse {
                 common: vec![hash_twos(), hash_threes()],
                 bundlecaps: vec![b"cap1".to_vec(), b"CAP2".to_vec(), b"cap3".to_vec()],
                 listkeys: vec![b"key1".to_vec(), b"key2".to_vec()],
+                phases: true,
+            }),
+        );
+    }
+
+    #[test]
+    fn test_parse_getfiles() {
+        let inp = "getfiles\n";
+
+        test_parse(inp, Request::Getfiles {});
+    }
+
+    #[test]
+    fn test_parse_getflogheads() {
+        let inp = "getflogheads\n\
+                   path 11\n\
+                   foo/bar.txt";
+
+        test_parse(
+            inp,
+            Request::Getflogheads {
+                path: MPath::new("foo/bar.txt").unwrap(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_parse_getfile() {
+        let mut buf = BytesMut::from(
+            b"1111111111111111111111111111111111111111foo/bar.txt\n\nheads\n".to_vec(),
+        );
+        assert_eq!(
+            parse_getfile(&mut buf).unwrap(),
+            Some(Some((hash_ones(), MPath::new("foo/bar.txt").unwrap())))
+        );
+        assert_eq!(parse_getfile(&mut buf).unwrap(), Some(None));
+        assert_eq!(&*buf, &b"heads\n"[..]);
+
+        let mut buf = BytesMut::from(b"1111111111111111111111111111111111111111foo".to_vec());
+        assert_eq!(parse_getfile(&mut buf).unwrap(), None);
+
+        let mut buf = BytesMut::from(
+            b"x111111111111111111111111111111111111111foo/bar.txt\n".to_vec(),
+        );
+        assert!(parse_getfile(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_parse_gettreepack() {
+        let inp = "gettreepack\n\
+                   * 4\n\
+                   rootdir 0\n\
+                   mfnodes 81\n\
+                   1111111111111111111111111111111111111111 2222222222222222222222222222222222222222\
+                   basemfnodes 0\n\
+                   directories 14\n\
+                   foo:obar,a/baz";
+
+        test_parse(
+            inp,
+            Request::Gettreepack(GettreepackArgs {
+                rootdir: MPath::new("").unwrap(),
+                mfnodes: vec![hash_ones(), hash_twos()],
+                basemfnodes: vec![],
+                directories: vec![MPath::new("foo,bar").unwrap(), MPath::new("a/baz").unwrap()],
+            }),
+        );
+
+        let inp = "gettreepack\n\
+                   * 2\n\
+                   rootdir 3\n\
+                   dirmfnodes 40\n\
+                   1111111111111111111111111111111111111111";
+
+        test_parse(
+            inp,
+            Request::Gettreepack(GettreepackArgs {
+                rootdir: MPath::new("dir").unwrap(),
+                mfnodes: vec![hash_ones()],
+                basemfnodes: vec![],
+                directories: vec![],
             }),
         );
     }
@@ -1127,6 +1366,21 @@ mod test_parse {
         );
     }
 
+    #[test]
+    fn test_parse_lookup_prefix() {
+        // Hash prefixes and bookmark names aren't necessarily identifiers.
+        let inp = "lookup\n\
+                   key 7\n\
+                   1a2b-c3";
+
+        test_parse(
+            inp,
+            Request::Lookup {
+                key: "1a2b-c3".to_string(),
+            },
+        );
+    }
+
     #[test]
     fn test_parse_known() {
         let inp = "known\n\
@@ -1159,15 +1413,37 @@ mod test_parse {
             Request::Pushkey {
                 namespace: "bookmarks".to_string(),
                 key: "foobar".to_string(),
-                old: hash_ones(),
-                new: hash_twos(),
+                old: b"1111111111111111111111111111111111111111".to_vec(),
+                new: b"2222222222222222222222222222222222222222".to_vec(),
+            },
+        );
+    }
+
+    #[test]
+    fn test_parse_pushkey_create() {
+        let inp = "pushkey\n\
+                   namespace 9\n\
+                   bookmarks\
+                   key 10\n\
+                   foo/bar-12\
+                   old 0\n\
+                   new 40\n\
+                   2222222222222222222222222222222222222222";
+
+        test_parse(
+            inp,
+            Request::Pushkey {
+                namespace: "bookmarks".to_string(),
+                key: "foo/bar-12".to_string(),
+                old: vec![],
+                new: b"2222222222222222222222222222222222222222".to_vec(),
             },
         );
     }
 
     #[test]
     fn test_parse_streamout() {
-        let inp = "streamout\n";
+        let inp = "stream_out\n";
 
         test_parse(inp, Request::Streamout {});
     }
@@ -1184,6 +1460,27 @@ mod test_parse {
                 heads: vec! { hash_ones() },
             },
         );
+
+        let inp = "unbundle\n\
+                   heads 10\n\
+                   666f726365";
+
+        test_parse(inp, Request::Unbundle { heads: vec![] });
+    }
+
+    #[test]
+    fn test_parse_chunk() {
+        let mut buf = BytesMut::from(b"5\nhello0\n".to_vec());
+        assert_eq!(parse_chunk(&mut buf).unwrap(), Some(Bytes::from(&b"hello"[..])));
+        assert_eq!(parse_chunk(&mut buf).unwrap(), Some(Bytes::new()));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(b"5\nhel".to_vec());
+        assert_eq!(parse_chunk(&mut buf).unwrap(), None);
+        assert_eq!(&*buf, &b"5\nhel"[..]);
+
+        let mut buf = BytesMut::from(b"x\n".to_vec());
+        assert!(parse_chunk(&mut buf).is_err());
     }
 
     #[test]
@@ -8,9 +8,14 @@ use std::io::{self, Write};
 use std::fmt::Display;
 
 use bytes::{BufMut, Bytes, BytesMut};
+use futures::stream;
+
+use futures_ext::{BoxStream, StreamExt};
+use mercurial_types::{percent_encode, NULL_HASH};
 
 use batch;
-use Response;
+use errors::*;
+use {BytesStream, LookupRes, Response};
 
 fn separated<I, W>(write: &mut W, iter: I, sep: &str) -> io::Result<()>
 where
@@ -33,13 +38,24 @@ where
     Ok(())
 }
 
-pub fn encode(response: &Response, out: &mut BytesMut) {
-    let res = encode_cmd(response);
-    out.reserve(10 + res.len());
-    if !response.is_stream() {
-        out.put_slice(format!("{}\n", res.len()).as_bytes());
+/// Encode a response. Bodies that are generated as a stream, like `getbundle`'s, are passed on
+/// piece by piece, so they're only generated as fast as they can be sent.
+pub fn encode(response: Response) -> BoxStream<Bytes, Error> {
+    match response {
+        Response::Getbundle(BytesStream(stream)) => stream,
+        Response::Gettreepack(BytesStream(stream)) => stream,
+        Response::Streamout(BytesStream(stream)) => stream,
+        Response::Getfiles => stream::empty().boxify(),
+        response => {
+            let res = encode_cmd(&response);
+            let mut out = BytesMut::with_capacity(10 + res.len());
+            if !response.is_stream() {
+                out.put_slice(format!("{}\n", res.len()).as_bytes());
+            }
+            out.put(res);
+            stream::once(Ok(out.freeze())).boxify()
+        }
     }
-    out.put(res);
 }
 
 /// Encode the result of an individual command completion. This is used by both
@@ -73,8 +89,46 @@ pub fn encode_cmd(response: &Response) -> Bytes {
             Bytes::from(out)
         }
 
+        &Branchmap(ref map) => {
+            let mut out = Vec::new();
+
+            for (branch, heads) in map.iter() {
+                write!(out, "{} ", percent_encode(branch)).expect("write to vec failed");
+                separated(&mut out, heads, " ").expect("write to vec failed");
+            }
+
+            Bytes::from(out)
+        }
+
+        &Branches(ref branches) => {
+            let mut out = Vec::new();
+
+            for branch in branches {
+                let nodes = [
+                    branch.top,
+                    branch.node,
+                    branch.p0.unwrap_or(NULL_HASH),
+                    branch.p1.unwrap_or(NULL_HASH),
+                ];
+                separated(&mut out, &nodes, " ").expect("write to vec failed");
+            }
+
+            Bytes::from(out)
+        }
+
+        &Capabilities(ref caps) => Bytes::from(caps.join(" ")),
+
+        &Clonebundles(ref manifest) => Bytes::from(manifest.as_bytes()),
+
         &Debugwireargs(ref res) => res.clone(),
 
+        &Getfile(ref res) => res.clone(),
+
+        &Getflogheads(ref heads) => {
+            let heads: Vec<_> = heads.iter().map(|node| node.to_string()).collect();
+            Bytes::from(heads.join("\n"))
+        }
+
         &Heads(ref set) => {
             let mut out = Vec::new();
 
@@ -92,7 +146,28 @@ pub fn encode_cmd(response: &Response) -> Bytes {
             Bytes::from(out)
         }
 
-        &Getbundle(ref res) => res.clone(),
+        &Listkeys(ref keys) => {
+            let lines: Vec<_> = keys.iter()
+                .map(|(key, value)| {
+                    let mut line = key.clone();
+                    line.push(b'\t');
+                    line.extend_from_slice(value);
+                    line
+                })
+                .collect();
+
+            Bytes::from(lines.join(&b'\n'))
+        }
+
+        &Lookup(LookupRes::Found(ref node)) => Bytes::from(format!("1 {}\n", node)),
+
+        &Lookup(LookupRes::Failed(ref msg)) => Bytes::from(format!("0 {}\n", msg)),
+
+        &Pushkey(ok) => Bytes::from(format!("{}\n", ok as u8)),
+
+        &ReadyForStream => Bytes::new(),
+
+        &Unbundle(ref res) => res.clone(),
 
         r => panic!("Response for {:?} unimplemented", r),
     }
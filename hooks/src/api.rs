@@ -0,0 +1,245 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The functions hooks can call to look at the repo.
+//!
+//! Each of them takes the hex hash of a changeset, and returns a future that hooks wait for with
+//! `coroutine.yield`:
+//!
+//! - `get_author(hash)`: the changeset's author.
+//! - `get_comments(hash)`: the changeset's commit message.
+//! - `get_parents(hash)`: the hashes of the changeset's parents.
+//! - `get_files(hash)`: the paths of the files the changeset changed.
+//! - `get_file_content(hash, path)`: the content of a file as of the changeset, or nil if it
+//!   doesn't exist then or isn't text.
+//! - `get_file_size(hash, path)`: the size of a file as of the changeset, or nil if it doesn't
+//!   exist then.
+//! - `get_file_diff(hash, path)`: a unified diff of the changes the changeset made to a file,
+//!   compared to its first parent, or nil if either side isn't text.
+//!
+//! Authors, commit messages and paths are passed to hooks as Lua strings, with invalid UTF-8
+//! replaced. File contents and diffs that aren't valid UTF-8, such as those of binary files, are
+//! nil rather than corrupted, and `get_file_size` is the way to tell those files from missing ones.
+
+use std::fmt::Display;
+use std::sync::Arc;
+
+use ascii::IntoAsciiString;
+use futures::{future, Future};
+use futures::future::Either;
+use hlua::{self, AnyLuaValue, Lua, LuaError};
+
+use hlua_futures::AnyFuture;
+use mercurial_types::{Changeset, Entry, MPath, Manifest, NodeHash, Repo};
+use mercurial_types::manifest::Content;
+
+use diff::unified_diff;
+use errors::*;
+
+/// Make the functions available to the hook called `hook_name`.
+pub fn register<'lua, R: Repo>(lua: &mut Lua<'lua>, repo: Arc<R>, hook_name: &str) {
+    let get_author = {
+        let repo = repo.clone();
+        let hook_name = hook_name.to_string();
+        move |hash: String| -> Result<AnyFuture> {
+            let hash = parse_hash(&hook_name, hash)?;
+            let future = repo.get_changeset_by_nodeid(&hash)
+                .map_err(|err| repo_error("author", err))
+                .map(|cs| lua_string(cs.user()));
+            Ok(AnyFuture::new(future))
+        }
+    };
+    lua.set("get_author", hlua::function1(get_author));
+
+    let get_comments = {
+        let repo = repo.clone();
+        let hook_name = hook_name.to_string();
+        move |hash: String| -> Result<AnyFuture> {
+            let hash = parse_hash(&hook_name, hash)?;
+            let future = repo.get_changeset_by_nodeid(&hash)
+                .map_err(|err| repo_error("comments", err))
+                .map(|cs| lua_string(cs.comments()));
+            Ok(AnyFuture::new(future))
+        }
+    };
+    lua.set("get_comments", hlua::function1(get_comments));
+
+    let get_parents = {
+        let repo = repo.clone();
+        let hook_name = hook_name.to_string();
+        move |hash: String| -> Result<AnyFuture> {
+            let hash = parse_hash(&hook_name, hash)?;
+            let future = repo.get_changeset_by_nodeid(&hash)
+                .map_err(|err| repo_error("parents", err))
+                .map(|cs| {
+                    let parents = cs.parents().into_iter().map(|p| lua_string(p.to_string()));
+                    lua_array(parents)
+                });
+            Ok(AnyFuture::new(future))
+        }
+    };
+    lua.set("get_parents", hlua::function1(get_parents));
+
+    let get_files = {
+        let repo = repo.clone();
+        let hook_name = hook_name.to_string();
+        move |hash: String| -> Result<AnyFuture> {
+            let hash = parse_hash(&hook_name, hash)?;
+            let future = repo.get_changeset_by_nodeid(&hash)
+                .map_err(|err| repo_error("files", err))
+                .map(|cs| lua_array(cs.files().iter().map(|path| lua_string(path.to_vec()))));
+            Ok(AnyFuture::new(future))
+        }
+    };
+    lua.set("get_files", hlua::function1(get_files));
+
+    let get_file_content = {
+        let repo = repo.clone();
+        let hook_name = hook_name.to_string();
+        move |hash: String, path: String| -> Result<AnyFuture> {
+            let hash = parse_hash(&hook_name, hash)?;
+            let path = parse_path(&hook_name, path)?;
+            let future = file_entry(repo.clone(), &hash, path)
+                .and_then(|entry| match entry {
+                    Some(entry) => Either::A(entry_content(entry)),
+                    None => Either::B(future::ok(None)),
+                })
+                .map_err(|err| repo_error("file content", err))
+                .map(|content| match content {
+                    Some(content) => lua_content(content),
+                    None => AnyLuaValue::LuaNil,
+                });
+            Ok(AnyFuture::new(future))
+        }
+    };
+    lua.set("get_file_content", hlua::function2(get_file_content));
+
+    let get_file_size = {
+        let repo = repo.clone();
+        let hook_name = hook_name.to_string();
+        move |hash: String, path: String| -> Result<AnyFuture> {
+            let hash = parse_hash(&hook_name, hash)?;
+            let path = parse_path(&hook_name, path)?;
+            let future = file_entry(repo.clone(), &hash, path)
+                .and_then(|entry| match entry {
+                    Some(entry) => Either::A(entry.get_size()),
+                    None => Either::B(future::ok(None)),
+                })
+                .map_err(|err| repo_error("file size", err))
+                .map(|size| match size {
+                    Some(size) => AnyLuaValue::LuaNumber(size as f64),
+                    None => AnyLuaValue::LuaNil,
+                });
+            Ok(AnyFuture::new(future))
+        }
+    };
+    lua.set("get_file_size", hlua::function2(get_file_size));
+
+    let get_file_diff = {
+        let hook_name = hook_name.to_string();
+        move |hash: String, path: String| -> Result<AnyFuture> {
+            let hash = parse_hash(&hook_name, hash)?;
+            let path = parse_path(&hook_name, path)?;
+            let future = file_diff(repo.clone(), hash, path)
+                .map_err(|err| repo_error("file diff", err))
+                .map(lua_content);
+            Ok(AnyFuture::new(future))
+        }
+    };
+    lua.set("get_file_diff", hlua::function2(get_file_diff));
+}
+
+fn parse_hash(hook_name: &str, hash: String) -> Result<NodeHash> {
+    let hash = hash.into_ascii_string()
+        .map_err(|hash| ErrorKind::InvalidHash(hook_name.into(), hash.into_source()))?;
+    NodeHash::from_ascii_str(&hash)
+        .chain_err(|| ErrorKind::InvalidHash(hook_name.into(), hash.into()))
+}
+
+fn parse_path(hook_name: &str, path: String) -> Result<MPath> {
+    MPath::new(path.as_bytes()).chain_err(|| ErrorKind::InvalidPath(hook_name.into(), path))
+}
+
+fn repo_error<E: Display>(what: &str, err: E) -> LuaError {
+    LuaError::ExecutionError(format!("failed to get {}: {}", what, err))
+}
+
+fn lua_string<T: AsRef<[u8]>>(text: T) -> AnyLuaValue {
+    AnyLuaValue::LuaString(String::from_utf8_lossy(text.as_ref()).into_owned())
+}
+
+/// File content as a Lua string, or nil if it isn't valid UTF-8.
+fn lua_content(content: Vec<u8>) -> AnyLuaValue {
+    match String::from_utf8(content) {
+        Ok(content) => AnyLuaValue::LuaString(content),
+        Err(_) => AnyLuaValue::LuaNil,
+    }
+}
+
+/// A Lua sequence, indexed from 1.
+fn lua_array<I: IntoIterator<Item = AnyLuaValue>>(values: I) -> AnyLuaValue {
+    let values = values
+        .into_iter()
+        .enumerate()
+        .map(|(i, value)| (AnyLuaValue::LuaNumber((i + 1) as f64), value))
+        .collect();
+    AnyLuaValue::LuaArray(values)
+}
+
+/// The manifest entry for `path` as of the changeset `hash`, if there is one.
+pub fn file_entry<R: Repo>(
+    repo: Arc<R>,
+    hash: &NodeHash,
+    path: MPath,
+) -> Box<Future<Item = Option<Box<Entry<Error = R::Error> + Sync>>, Error = R::Error> + Send> {
+    Box::new(
+        repo.get_changeset_by_nodeid(hash)
+            .and_then(move |cs| repo.get_manifest_by_nodeid(cs.manifestid()))
+            .and_then(move |manifest| manifest.lookup(&path)),
+    )
+}
+
+/// The content of a file, or `None` if `entry` is a directory.
+pub fn entry_content<E>(
+    entry: Box<Entry<Error = E> + Sync>,
+) -> Box<Future<Item = Option<Vec<u8>>, Error = E> + Send>
+where
+    E: ::std::error::Error + Send + 'static,
+{
+    Box::new(entry.get_content().map(|content| match content {
+        Content::File(blob) | Content::Executable(blob) => blob.into_inner(),
+        Content::Symlink(path) => Some(path.to_vec()),
+        Content::Tree(_) => None,
+    }))
+}
+
+/// The changes the changeset `hash` made to `path`, compared to its first parent. A file that
+/// doesn't exist on one side is treated as empty.
+fn file_diff<R: Repo>(
+    repo: Arc<R>,
+    hash: NodeHash,
+    path: MPath,
+) -> Box<Future<Item = Vec<u8>, Error = R::Error> + Send> {
+    let content = |repo: Arc<R>, hash: Option<NodeHash>, path: MPath| match hash {
+        Some(hash) => Either::A(file_entry(repo, &hash, path).and_then(|entry| match entry {
+            Some(entry) => Either::A(entry_content(entry)),
+            None => Either::B(future::ok(None)),
+        })),
+        None => Either::B(future::ok(None)),
+    };
+
+    let parent = repo.get_changeset_by_nodeid(&hash).map(|cs| {
+        let (p1, _) = cs.parents().get_nodes();
+        p1.cloned()
+    });
+    Box::new(parent.and_then(move |parent| {
+        let old = content(repo.clone(), parent, path.clone());
+        let new = content(repo, Some(hash), path);
+        old.join(new).map(|(old, new)| {
+            unified_diff(&old.unwrap_or_default(), &new.unwrap_or_default())
+        })
+    }))
+}
@@ -0,0 +1,54 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Native hooks that repos can be configured to run by name.
+
+use futures::{future, Future};
+
+use errors::*;
+use native::{Hook, HookFile};
+use HookOutcome;
+
+/// Rejects text files with the lines that merge tools leave to mark conflicts they couldn't
+/// resolve.
+pub struct NoConflictMarkers;
+
+impl Hook<HookFile> for NoConflictMarkers {
+    fn run(&self, file: HookFile) -> Box<Future<Item = HookOutcome, Error = Error> + Send> {
+        let outcome = match file.content {
+            Some(ref content) if has_conflict_markers(content) => HookOutcome::Rejected(format!(
+                "{} has conflict markers in changeset {}",
+                file.path,
+                file.changeset
+            )),
+            _ => HookOutcome::Accepted,
+        };
+        Box::new(future::ok(outcome))
+    }
+}
+
+/// As in Mercurial, files with null bytes are taken to be binary, and aren't checked.
+fn has_conflict_markers(content: &[u8]) -> bool {
+    !content.contains(&0)
+        && content
+            .split(|b| *b == b'\n')
+            .any(|line| line.starts_with(b"<<<<<<< ") || line.starts_with(b">>>>>>> "))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_conflict_markers() {
+        assert!(has_conflict_markers(
+            b"a\n<<<<<<< local\nb\n=======\nc\n>>>>>>> other\n"
+        ));
+        assert!(!has_conflict_markers(b"a\n<<<<<<<\n"));
+        assert!(!has_conflict_markers(b"a\n <<<<<<< local\n"));
+        assert!(!has_conflict_markers(b"\0<<<<<<< local\n"));
+    }
+}
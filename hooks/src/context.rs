@@ -0,0 +1,168 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Lua states with a repo's hooks loaded into them, one for each hook.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures::Future;
+use futures::future::Either;
+use hlua::{self, AnyLuaValue, PushGuard};
+
+use hlua_futures::{LuaCoroutine, LuaCoroutineBuilder};
+use mercurial_types::Repo;
+
+use api;
+use errors::*;
+use sandbox::Sandbox;
+use timer::Timer;
+use {HookOutcome, LuaHook};
+
+/// A hook that's been loaded into a Lua state of its own, so that it can't see or change what
+/// other hooks leave in their globals, or use up the memory they can allocate.
+struct LoadedHook<'lua> {
+    sandbox: Sandbox<'lua>,
+    hook: LuaHook,
+}
+
+pub struct HookContext<'lua, R: Repo> {
+    repo: Arc<R>,
+    hooks: HashMap<String, LoadedHook<'lua>>,
+    max_memory: usize,
+    timer: Timer,
+}
+
+impl<'lua, R: Repo> HookContext<'lua, R> {
+    /// Load each of `hooks` into a Lua state that can allocate up to `max_memory` bytes, so that
+    /// their code only has to be run once. `timer` stops hooks that wait on the repo for too long.
+    pub fn new(
+        repo: Arc<R>,
+        hooks: &[LuaHook],
+        max_memory: usize,
+        timer: Timer,
+    ) -> Result<Self> {
+        let mut loaded = HashMap::new();
+        for hook in hooks {
+            let sandbox = load(&repo, hook, max_memory)?;
+            loaded.insert(
+                hook.name.clone(),
+                LoadedHook {
+                    sandbox,
+                    hook: hook.clone(),
+                },
+            );
+        }
+
+        Ok(HookContext {
+            repo,
+            hooks: loaded,
+            max_memory,
+            timer,
+        })
+    }
+
+    /// Run the hook called `name`, passing it `info`, and wait for it to finish.
+    pub fn run(
+        &mut self,
+        name: &str,
+        info: HashMap<&'static str, String>,
+    ) -> Result<HookOutcome> {
+        let loaded = match self.hooks.get_mut(name) {
+            Some(loaded) => loaded,
+            None => bail!(ErrorKind::UnknownHook(name.into())),
+        };
+        let limits = loaded.hook.limits;
+
+        loaded.sandbox.start(&limits);
+        let deadline = self.timer.at(loaded.sandbox.deadline());
+        let mut timed_out = false;
+        let result = start(&mut loaded.sandbox, name, info).and_then(|coroutine| {
+            match coroutine.select2(deadline).wait() {
+                Ok(Either::A((value, _))) => Ok(value),
+                Err(Either::A((err, _))) => Err(
+                    ErrorKind::HookRuntimeError(name.into(), format!("{:?}", err)).into(),
+                ),
+                // The sandbox only stops hooks while their Lua code is running, not while they
+                // wait for the repo.
+                Ok(Either::B(_)) => {
+                    timed_out = true;
+                    Ok(AnyLuaValue::LuaNil)
+                }
+                Err(Either::B(_)) => Err("hook timer has stopped".into()),
+            }
+        });
+        if timed_out {
+            loaded.sandbox.time_out();
+        }
+
+        // The hook fails once it's hit a limit, even if it caught the error and carried on. What
+        // it left in its state may be what used up the memory, so the state is thrown away and
+        // the hook loaded again.
+        if let Some(limit) = loaded.sandbox.exceeded() {
+            loaded.sandbox = load(&self.repo, &loaded.hook, self.max_memory)?;
+            return Err(limit.error(name, &limits));
+        }
+
+        match result {
+            Ok(AnyLuaValue::LuaBoolean(true)) => Ok(HookOutcome::Accepted),
+            Ok(AnyLuaValue::LuaBoolean(false)) => {
+                Ok(HookOutcome::Rejected("no reason given".into()))
+            }
+            Ok(AnyLuaValue::LuaString(reason)) => Ok(HookOutcome::Rejected(reason)),
+            Ok(value) => bail!(ErrorKind::HookRuntimeError(
+                name.into(),
+                format!("hook returned {:?} rather than a boolean or a string", value)
+            )),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Create a Lua state that can allocate up to `max_memory` bytes, with `hook` loaded into it.
+fn load<'lua, R: Repo>(repo: &Arc<R>, hook: &LuaHook, max_memory: usize) -> Result<Sandbox<'lua>> {
+    let mut sandbox = Sandbox::new(max_memory)?;
+    api::register(sandbox.lua(), repo.clone(), &hook.name);
+
+    let code = format!("{}\nreturn type(hook) == 'function'", hook.code);
+    sandbox.start(&hook.limits);
+    let defined: bool = match sandbox.lua().execute(&code) {
+        Ok(defined) => defined,
+        Err(err) => match sandbox.exceeded() {
+            Some(limit) => return Err(limit.error(&hook.name, &hook.limits)),
+            None => {
+                return Err(err).chain_err(|| {
+                    ErrorKind::HookDefinitionError(format!("failed to load hook '{}'", hook.name))
+                })
+            }
+        },
+    };
+    if !defined {
+        bail!(ErrorKind::HookDefinitionError(format!(
+            "function 'hook' not found in hook '{}'",
+            hook.name
+        )));
+    }
+
+    Ok(sandbox)
+}
+
+fn start<'a, 'lua>(
+    sandbox: &'a mut Sandbox<'lua>,
+    name: &str,
+    info: HashMap<&'static str, String>,
+) -> Result<LuaCoroutine<PushGuard<&'a mut hlua::Lua<'lua>>, AnyLuaValue>> {
+    let builder: LuaCoroutineBuilder<_> = match sandbox.lua().get("hook") {
+        Some(val) => val,
+        None => bail!(ErrorKind::HookDefinitionError(
+            "function 'hook' not found".into()
+        )),
+    };
+    // TODO: use chain_err once LuaFunctionCallError implements std::error::Error
+    builder.create(info).map_err(|err| {
+        ErrorKind::HookRuntimeError(name.into(), format!("{:?}", err)).into()
+    })
+}
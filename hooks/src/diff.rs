@@ -0,0 +1,313 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Line-based diffs of file contents, in the unified format.
+
+/// The number of unchanged lines shown around each change.
+const CONTEXT: usize = 3;
+
+/// The most lines deleted and inserted that the shortest edit script is searched for. The search
+/// needs memory in proportion to the square of their number, so files that differ by more are
+/// shown as entirely rewritten, apart from the lines they start and end with.
+const MAX_COST: isize = 1000;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    Equal,
+    Delete,
+    Insert,
+}
+
+/// A step in turning the old lines into the new ones, along with the positions in both that
+/// it's taken at.
+#[derive(Clone, Copy, Debug)]
+struct Edit {
+    kind: Kind,
+    old: usize,
+    new: usize,
+}
+
+/// A unified diff from `old` to `new`, without the file headers. Empty if they're the same.
+pub fn unified_diff(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    if old == new {
+        return out;
+    }
+
+    let old = lines(old);
+    let new = lines(new);
+    let edits = edits(&old, &new);
+
+    for (start, end) in hunks(&edits) {
+        let hunk = &edits[start..end];
+        let old_len = hunk.iter().filter(|edit| edit.kind != Kind::Insert).count();
+        let new_len = hunk.iter().filter(|edit| edit.kind != Kind::Delete).count();
+        // Empty ranges are given as the line before them.
+        let old_start = hunk[0].old + if old_len > 0 { 1 } else { 0 };
+        let new_start = hunk[0].new + if new_len > 0 { 1 } else { 0 };
+        out.extend_from_slice(
+            format!(
+                "@@ -{},{} +{},{} @@\n",
+                old_start,
+                old_len,
+                new_start,
+                new_len
+            ).as_bytes(),
+        );
+
+        for edit in hunk {
+            let (prefix, line) = match edit.kind {
+                Kind::Equal => (b' ', old[edit.old]),
+                Kind::Delete => (b'-', old[edit.old]),
+                Kind::Insert => (b'+', new[edit.new]),
+            };
+            out.push(prefix);
+            out.extend_from_slice(line);
+            if !line.ends_with(b"\n") {
+                out.extend_from_slice(b"\n\\ No newline at end of file\n");
+            }
+        }
+    }
+
+    out
+}
+
+/// Split `text` into lines, keeping their line endings.
+fn lines(text: &[u8]) -> Vec<&[u8]> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, b) in text.iter().enumerate() {
+        if *b == b'\n' {
+            lines.push(&text[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+/// The edit script from `old` to `new`. The lines they start and end with are left out of the
+/// search, and if what's between them differs too much to search for the shortest script, all of
+/// it is deleted and inserted instead.
+fn edits(old: &[&[u8]], new: &[&[u8]]) -> Vec<Edit> {
+    let prefix = old.iter()
+        .zip(new)
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    let equal = |old: usize, new: usize| Edit {
+        kind: Kind::Equal,
+        old,
+        new,
+    };
+    let mut edits: Vec<_> = (0..prefix).map(|i| equal(i, i)).collect();
+    match shortest_edits(old_middle, new_middle) {
+        Some(middle) => edits.extend(middle.into_iter().map(|edit| {
+            Edit {
+                old: edit.old + prefix,
+                new: edit.new + prefix,
+                ..edit
+            }
+        })),
+        None => {
+            edits.extend((0..old_middle.len()).map(|i| {
+                Edit {
+                    kind: Kind::Delete,
+                    old: prefix + i,
+                    new: prefix,
+                }
+            }));
+            edits.extend((0..new_middle.len()).map(|i| {
+                Edit {
+                    kind: Kind::Insert,
+                    old: prefix + old_middle.len(),
+                    new: prefix + i,
+                }
+            }));
+        }
+    }
+    edits.extend((0..suffix).map(|i| equal(old.len() - suffix + i, new.len() - suffix + i)));
+    edits
+}
+
+/// The shortest edit script from `old` to `new`, found with Myers' algorithm, or `None` if it
+/// needs more than `MAX_COST` lines deleted and inserted.
+fn shortest_edits(old: &[&[u8]], new: &[&[u8]]) -> Option<Vec<Edit>> {
+    let n = old.len() as isize;
+    let m = new.len() as isize;
+    let offset = n + m + 1;
+    let index = |k: isize| (k + offset) as usize;
+
+    // `v[index(k)]` is the furthest point along diagonal `k` reached so far. The diagonals that
+    // the next move can start from are kept for each number of non-diagonal moves, to find the
+    // path back afterwards.
+    let mut v = vec![0isize; 2 * offset as usize + 1];
+    let mut trace = Vec::new();
+    'search: for d in 0..(n + m + 1) {
+        if d > MAX_COST {
+            return None;
+        }
+        trace.push(v[index(-d - 1)..index(d + 1) + 1].to_vec());
+        let mut k = -d;
+        while k <= d {
+            let mut x = if k == -d || (k != d && v[index(k - 1)] < v[index(k + 1)]) {
+                v[index(k + 1)]
+            } else {
+                v[index(k - 1)] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[index(k)] = x;
+            if x >= n && y >= m {
+                break 'search;
+            }
+            k += 2;
+        }
+    }
+
+    let mut edits = Vec::new();
+    let (mut x, mut y) = (n, m);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        // `v` starts at diagonal `-d - 1`.
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            edits.push(Edit {
+                kind: Kind::Equal,
+                old: x as usize,
+                new: y as usize,
+            });
+        }
+        if d > 0 {
+            let kind = if x == prev_x {
+                Kind::Insert
+            } else {
+                Kind::Delete
+            };
+            edits.push(Edit {
+                kind,
+                old: prev_x as usize,
+                new: prev_y as usize,
+            });
+        }
+        x = prev_x;
+        y = prev_y;
+    }
+
+    edits.reverse();
+    Some(edits)
+}
+
+/// The ranges of `edits` to show as hunks: each change, with up to `CONTEXT` unchanged lines on
+/// either side. Changes that are close enough for their context to overlap share a hunk.
+fn hunks(edits: &[Edit]) -> Vec<(usize, usize)> {
+    let is_equal = |i: usize| edits[i].kind == Kind::Equal;
+    let mut hunks = Vec::new();
+
+    let mut i = 0;
+    while i < edits.len() {
+        if is_equal(i) {
+            i += 1;
+            continue;
+        }
+
+        let start = i.saturating_sub(CONTEXT);
+        let mut end = i;
+        loop {
+            while end < edits.len() && !is_equal(end) {
+                end += 1;
+            }
+            let mut unchanged = 0;
+            while end + unchanged < edits.len() && is_equal(end + unchanged) {
+                unchanged += 1;
+            }
+            if end + unchanged < edits.len() && unchanged <= 2 * CONTEXT {
+                end += unchanged;
+            } else {
+                end += unchanged.min(CONTEXT);
+                break;
+            }
+        }
+
+        hunks.push((start, end));
+        i = end;
+    }
+
+    hunks
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_same() {
+        assert_eq!(unified_diff(b"a\nb\n", b"a\nb\n"), b"");
+    }
+
+    #[test]
+    fn test_change() {
+        let old = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n";
+        let new = b"1\n2\n3\n4\nfive\n6\n7\n8\n9\n10\n11\n12\nthirteen\n";
+        let expected: &[u8] = b"@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+five\n 6\n 7\n 8\n\
+                                @@ -10,3 +10,4 @@\n 10\n 11\n 12\n+thirteen\n";
+        assert_eq!(unified_diff(old, new), expected);
+    }
+
+    #[test]
+    fn test_added_and_removed() {
+        let expected: &[u8] = b"@@ -0,0 +1,2 @@\n+a\n+b\n\\ No newline at end of file\n";
+        assert_eq!(unified_diff(b"", b"a\nb"), expected);
+        let expected: &[u8] = b"@@ -1,1 +0,0 @@\n-a\n";
+        assert_eq!(unified_diff(b"a\n", b""), expected);
+    }
+
+    #[test]
+    fn test_rewritten() {
+        let numbered = |prefix: &str| -> Vec<u8> {
+            let mut text = b"start\n".to_vec();
+            for i in 0..1000 {
+                text.extend(format!("{}{}\n", prefix, i).into_bytes());
+            }
+            text.extend_from_slice(b"end\n");
+            text
+        };
+
+        let mut expected = b"@@ -1,1002 +1,1002 @@\n start\n".to_vec();
+        for i in 0..1000 {
+            expected.extend(format!("-old{}\n", i).into_bytes());
+        }
+        for i in 0..1000 {
+            expected.extend(format!("+new{}\n", i).into_bytes());
+        }
+        expected.extend_from_slice(b" end\n");
+        assert_eq!(unified_diff(&numbered("old"), &numbered("new")), expected);
+    }
+}
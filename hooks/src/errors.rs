@@ -17,17 +17,34 @@ error_chain! {
             description("Hook definition error")
             display("Hook definition error: {}", err)
         }
+        UnknownHook(hook_name: String) {
+            description("Unknown hook")
+            display("Unknown hook '{}'", hook_name)
+        }
         HookRuntimeError(hook_name: String, err: String) {
             description("Error while running hook")
             display("Error while running hook '{}': {}", hook_name, err)
         }
+        HookTimeout(hook_name: String, timeout_ms: u64) {
+            description("Hook timed out")
+            display("Hook '{}' timed out after {}ms", hook_name, timeout_ms)
+        }
+        HookResourceExhausted(hook_name: String, resource: &'static str) {
+            description("Hook used too much of a resource")
+            display("Hook '{}' used too much {}", hook_name, resource)
+        }
         InvalidHash(hook_name: String, hash: String) {
             description("Error while running hook: invalid hash")
             display("Error while running hook '{}': invalid hash '{}'", hook_name, hash)
         }
+        InvalidPath(hook_name: String, path: String) {
+            description("Error while running hook: invalid path")
+            display("Error while running hook '{}': invalid path '{}'", hook_name, path)
+        }
     }
 
     foreign_links {
+        Io(::std::io::Error);
         Lua(hlua::LuaError);
     }
 }
@@ -12,7 +12,9 @@ extern crate ascii;
 extern crate error_chain;
 extern crate futures;
 extern crate hlua;
-#[cfg_attr(test, macro_use)]
+extern crate libc;
+extern crate lua52_sys as ffi;
+#[macro_use]
 extern crate maplit;
 #[cfg(test)]
 extern crate tempdir;
@@ -20,22 +22,50 @@ extern crate tempdir;
 extern crate hlua_futures;
 extern crate mercurial;
 extern crate mercurial_types;
+extern crate repoinfo;
+extern crate revset;
 
+mod api;
+mod builtin;
+mod context;
+mod diff;
 mod errors;
+mod native;
+mod registry;
+mod sandbox;
+mod timer;
 
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{mpsc as std_mpsc, Arc, Mutex};
+use std::thread;
 
-use ascii::IntoAsciiString;
-use futures::Future;
-use hlua::{AnyLuaValue, Lua, LuaError, PushGuard};
+use futures::{Future, Sink, Stream};
+use futures::sync::{mpsc, oneshot};
 
-use hlua_futures::{AnyFuture, LuaCoroutine, LuaCoroutineBuilder};
-use mercurial_types::{Changeset, NodeHash, Repo};
+use mercurial_types::{NodeHash, Repo};
+use repoinfo::RepoGenCache;
 
+use context::HookContext;
 pub use errors::*;
+pub use native::{Hook, HookChangeset, HookFile};
+use native::{Loader, NativeHook, RepoLoader};
+pub use registry::HookRegistry;
+pub use sandbox::HookLimits;
+use timer::Timer;
+
+/// A hook written in Lua, as configured by a repo's owner.
+#[derive(Clone, Debug)]
+pub struct LuaHook {
+    pub name: String,
+    /// Lua code that defines a `hook` function. The function is passed a table of the fields of
+    /// a `HookInfo`, with hashes in hex, and returns `true` to allow the bookmark move, or
+    /// `false` or the reason as a string to reject it. It can look at the changesets involved
+    /// with the functions in `api`.
+    pub code: String,
+    pub limits: HookLimits,
+}
 
-#[allow(dead_code)]
+/// What a hook is asked to allow.
 pub struct HookInfo {
     pub repo: String,
     pub bookmark: String,
@@ -43,88 +73,168 @@ pub struct HookInfo {
     pub new_hash: NodeHash,
 }
 
-pub struct HookManager<'lua> {
-    // TODO: multiple contexts
-    lua: Lua<'lua>,
+impl HookInfo {
+    fn into_table(self) -> HashMap<&'static str, String> {
+        hashmap! {
+            "repo" => self.repo,
+            "bookmark" => self.bookmark,
+            "old_hash" => self.old_hash.to_string(),
+            "new_hash" => self.new_hash.to_string(),
+        }
+    }
 }
 
-pub struct HookContext<'hook, R: Repo> {
-    name: &'hook str,
-    repo: Arc<R>,
+/// Whether a hook allows a bookmark move.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HookOutcome {
+    Accepted,
+    /// The move isn't allowed, for the reason given.
+    Rejected(String),
+}
+
+struct HookRequest {
+    name: String,
     info: HashMap<&'static str, String>,
-    code: &'hook str,
+    reply: oneshot::Sender<Result<HookOutcome>>,
 }
 
-impl<'hook, R: Repo> HookContext<'hook, R> {
-    fn run<'a, 'lua>(
-        &self,
-        lua: &'a mut Lua<'lua>,
-    ) -> Result<LuaCoroutine<PushGuard<&'a mut Lua<'lua>>, bool>> {
-        let repo = self.repo.clone();
-        let name = self.name.to_string();
-
-        let get_author = move |hash: String| -> Result<AnyFuture> {
-            let hash = hash.into_ascii_string().map_err(|hash| {
-                ErrorKind::InvalidHash(name.clone(), hash.into_source())
-            })?;
-            let hash = NodeHash::from_ascii_str(&hash)
-                .chain_err(|| ErrorKind::InvalidHash(name.clone(), hash.into()))?;
-
-            let future = repo.get_changeset_by_nodeid(&hash)
-                .map_err(|err| {
-                    LuaError::ExecutionError(format!("failed to get author: {}", err))
-                })
-                .map(|cs| {
-                    AnyLuaValue::LuaString(String::from_utf8_lossy(cs.user()).into_owned())
-                });
-            Ok(AnyFuture::new(future))
-        };
-        lua.set("get_author", hlua::function1(get_author));
+/// Runs a repo's hooks. Lua hooks are run in a pool of Lua contexts, each on a thread of its own,
+/// so that a slow hook only holds up the pushes that are waiting for it. They queue up while all
+/// the contexts are busy, and once the queue is full, callers wait for there to be room in it.
+/// Native hooks are run straight away, on each changeset the bookmark move adds.
+pub struct HookManager {
+    requests: mpsc::Sender<HookRequest>,
+    native: HashMap<String, NativeHook>,
+    loader: Arc<Loader>,
+}
 
-        lua.execute::<()>(self.code)?;
+impl HookManager {
+    /// Start `contexts` Lua contexts with the Lua hooks in `hooks` loaded into them. Each hook
+    /// gets a Lua state of its own in each context, which can allocate up to `max_memory` bytes.
+    /// Fails if any of the hooks can't be loaded.
+    pub fn new<R>(
+        repo: Arc<R>,
+        repo_generation: RepoGenCache<R>,
+        hooks: HookRegistry,
+        contexts: usize,
+        queue_size: usize,
+        max_memory: usize,
+    ) -> Result<Self>
+    where
+        R: Repo + Send + Sync + 'static,
+    {
+        if contexts == 0 {
+            bail!("hooks need at least one context to run in");
+        }
 
-        let builder: LuaCoroutineBuilder<_> = match lua.get("hook") {
-            Some(val) => val,
-            None => bail!(ErrorKind::HookDefinitionError(
-                "function 'hook' not found".into()
-            )),
-        };
-        // TODO: do we really need the clone?
-        // TODO: use chain_err once LuaFunctionCallError implements std::error::Error
-        let coroutine_fut = builder.create(self.info.clone()).map_err(|err| {
-            ErrorKind::HookRuntimeError(self.name.into(), format!("{:?}", err)).into()
+        let (sender, receiver) = mpsc::channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let HookRegistry { lua, native } = hooks;
+        let hooks = Arc::new(lua);
+        let loader = Arc::new(RepoLoader {
+            repo: repo.clone(),
+            repo_generation,
         });
-        coroutine_fut
+        let timer = Timer::new()?;
+        let (ready_sender, ready) = std_mpsc::channel();
+
+        for index in 0..contexts {
+            let repo = repo.clone();
+            let timer = timer.clone();
+            let hooks = hooks.clone();
+            let receiver = receiver.clone();
+            let ready_sender = ready_sender.clone();
+            thread::Builder::new()
+                .name(format!("hook_context_{}", index))
+                .spawn(move || match HookContext::new(repo, &hooks, max_memory, timer) {
+                    Ok(context) => {
+                        let _ = ready_sender.send(Ok(()));
+                        serve(context, receiver)
+                    }
+                    Err(err) => {
+                        let _ = ready_sender.send(Err(err));
+                    }
+                })?;
+        }
+        drop(ready_sender);
+
+        // Broken hooks are better found now than by the first push to run them.
+        for _ in 0..contexts {
+            match ready.recv() {
+                Ok(result) => result?,
+                Err(_) => bail!("hook context stopped while loading hooks"),
+            }
+        }
+
+        Ok(HookManager {
+            requests: sender,
+            native,
+            loader,
+        })
     }
-}
 
-impl<'lua> HookManager<'lua> {
-    pub fn new() -> Self {
-        let mut lua = Lua::new();
-        // TODO: don't open all libs
-        lua.openlibs();
+    /// Run the hook called `name`, resolving to whether it allows the bookmark move described by
+    /// `info`. Lua hooks are run in the first context that's free.
+    pub fn run_hook(
+        &self,
+        name: &str,
+        info: HookInfo,
+    ) -> Box<Future<Item = HookOutcome, Error = Error> + Send> {
+        if let Some(hook) = self.native.get(name) {
+            return hook.run(self.loader.clone(), info.old_hash, info.new_hash);
+        }
+
+        let (reply, result) = oneshot::channel();
+        let request = HookRequest {
+            name: name.to_string(),
+            info: info.into_table(),
+            reply,
+        };
 
-        HookManager { lua }
+        Box::new(
+            self.requests
+                .clone()
+                .send(request)
+                .map_err(|_| Error::from("hook contexts have stopped"))
+                .and_then(|_| {
+                    result.map_err(|_| Error::from("hook context stopped before replying"))
+                })
+                .and_then(|result| result),
+        )
     }
+}
 
-    pub fn run_hook<'hook, R: Repo>(
-        &mut self,
-        hook: HookContext<'hook, R>,
-    ) -> Result<LuaCoroutine<PushGuard<&mut Lua<'lua>>, bool>> {
-        // TODO: with multiple Lua contexts, choose a context to run in. Probably use a queue or
-        // something.
-        hook.run(&mut self.lua)
+/// Run the hooks that are requested in `context`, until the manager goes away.
+fn serve<R: Repo>(
+    mut context: HookContext<R>,
+    requests: Arc<Mutex<mpsc::Receiver<HookRequest>>>,
+) {
+    loop {
+        // Only one idle context waits on the queue at a time, while the rest wait for the lock.
+        let request = match requests.lock().expect("lock poisoned").by_ref().wait().next() {
+            Some(Ok(request)) => request,
+            _ => return,
+        };
+        let result = context.run(&request.name, request.info);
+        // Nobody is waiting for the result if the push has already failed for another reason.
+        let _ = request.reply.send(result);
     }
 }
 
 #[cfg(test)]
 mod test {
     use std::fs::File;
+    use std::io::Write;
     use std::path::Path;
     use std::process::Command;
+    use std::str::FromStr;
+    use std::time::Duration;
 
+    use futures::future;
     use tempdir::TempDir;
 
+    use mercurial_types::NULL_HASH;
+
     use super::*;
 
     #[test]
@@ -132,18 +242,9 @@ mod test {
         let (hash, dir) = create_repo();
         let dot_hg = dir.as_ref().join(".hg");
 
-        let hook_info = hashmap! {
-            "repo" => "fbsource".into(),
-            "bookmark" => "master".into(),
-            "old_hash" => "0000000000000000000000000000000000000000".into(),
-            "new_hash" => hash,
-        };
-        let mut hook_manager = HookManager::new();
         let repo = mercurial::RevlogRepo::open(dot_hg).unwrap();
-        let hook = HookContext {
-            name: "test",
-            repo: Arc::new(repo),
-            info: hook_info,
+        let hook = LuaHook {
+            name: "test".into(),
             code: "
                     function hook(info)
                         if info.repo ~= \"fbsource\" then
@@ -152,12 +253,402 @@ mod test {
                             author = coroutine.yield(get_author(info.new_hash))
                             return author == \"testuser\"
                         end
-                    end",
+                    end".into(),
+            limits: HookLimits::default(),
+        };
+        let hooks = lua_hooks(vec![hook]);
+        let hook_manager = start_hooks(repo, hooks, 1).unwrap();
+
+        let result = hook_manager.run_hook("test", hook_info(&hash)).wait();
+        assert_eq!(result.unwrap(), HookOutcome::Accepted);
+    }
+
+    #[test]
+    fn test_several_hooks() {
+        let (hash, dir) = create_repo();
+        let dot_hg = dir.as_ref().join(".hg");
+
+        let repo = mercurial::RevlogRepo::open(dot_hg).unwrap();
+        let hooks = vec![
+            LuaHook {
+                name: "allow".into(),
+                code: "function hook(info) return true end".into(),
+                limits: HookLimits::default(),
+            },
+            LuaHook {
+                name: "deny".into(),
+                code: "function hook(info) return false end".into(),
+                limits: HookLimits::default(),
+            },
+            LuaHook {
+                name: "set_global".into(),
+                code: "function hook(info) shared = true; return true end".into(),
+                limits: HookLimits::default(),
+            },
+            LuaHook {
+                name: "get_global".into(),
+                code: "function hook(info) return shared == nil end".into(),
+                limits: HookLimits::default(),
+            },
+        ];
+        let hooks = lua_hooks(hooks);
+        let hook_manager = start_hooks(repo, hooks, 2).unwrap();
+
+        // Each hook keeps its own function, although they're all loaded into the same contexts.
+        let allow = hook_manager.run_hook("allow", hook_info(&hash));
+        let deny = hook_manager.run_hook("deny", hook_info(&hash));
+        let results = allow.join(deny).wait().unwrap();
+        let rejected = HookOutcome::Rejected("no reason given".into());
+        assert_eq!(results, (HookOutcome::Accepted, rejected));
+
+        // Nor can hooks see each other's globals.
+        for name in &["set_global", "get_global"] {
+            let result = hook_manager.run_hook(name, hook_info(&hash)).wait();
+            assert_eq!(result.unwrap(), HookOutcome::Accepted);
+        }
+
+        let result = hook_manager.run_hook("missing", hook_info(&hash)).wait();
+        match result {
+            Err(Error(ErrorKind::UnknownHook(ref name), _)) if name == "missing" => (),
+            _ => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_broken_hook() {
+        let (_, dir) = create_repo();
+        let dot_hg = dir.as_ref().join(".hg");
+
+        let repo = mercurial::RevlogRepo::open(dot_hg).unwrap();
+        let hook = LuaHook {
+            name: "broken".into(),
+            code: "function not_a_hook(info) return true end".into(),
+            limits: HookLimits::default(),
+        };
+        let hooks = lua_hooks(vec![hook]);
+        assert!(start_hooks(repo, hooks, 2).is_err());
+    }
+
+    #[test]
+    fn test_repo_functions() {
+        let (hash, dir) = create_repo();
+        let dot_hg = dir.as_ref().join(".hg");
+
+        let repo = mercurial::RevlogRepo::open(dot_hg).unwrap();
+        let hook = LuaHook {
+            name: "files".into(),
+            code: "
+                    function hook(info)
+                        local hash = info.new_hash
+                        local files = coroutine.yield(get_files(hash))
+                        local parents = coroutine.yield(get_parents(hash))
+                        return #files == 1 and files[1] == \"foo.txt\"
+                            and #parents == 0
+                            and coroutine.yield(get_comments(hash)) == \"test\"
+                            and coroutine.yield(get_file_content(hash, \"foo.txt\")) == \"\"
+                            and coroutine.yield(get_file_size(hash, \"foo.txt\")) == 0
+                            and coroutine.yield(get_file_diff(hash, \"foo.txt\")) == \"\"
+                            and coroutine.yield(get_file_content(hash, \"bar.txt\")) == nil
+                    end".into(),
+            limits: HookLimits::default(),
+        };
+        let hooks = lua_hooks(vec![hook]);
+        let hook_manager = start_hooks(repo, hooks, 1).unwrap();
+
+        let result = hook_manager.run_hook("files", hook_info(&hash)).wait();
+        assert_eq!(result.unwrap(), HookOutcome::Accepted);
+    }
+
+    struct AuthorHook;
+
+    impl Hook<HookChangeset> for AuthorHook {
+        fn run(
+            &self,
+            changeset: HookChangeset,
+        ) -> Box<Future<Item = HookOutcome, Error = Error> + Send> {
+            let outcome = if changeset.author == b"testuser" {
+                HookOutcome::Accepted
+            } else {
+                HookOutcome::Rejected("unknown author".into())
+            };
+            Box::new(future::ok(outcome))
+        }
+    }
+
+    struct EmptyFileHook;
+
+    impl Hook<HookFile> for EmptyFileHook {
+        fn run(&self, file: HookFile) -> Box<Future<Item = HookOutcome, Error = Error> + Send> {
+            let outcome = match file.content {
+                Some(ref content) if content.is_empty() => {
+                    HookOutcome::Rejected(format!("{} is empty", file.path))
+                }
+                _ => HookOutcome::Accepted,
+            };
+            Box::new(future::ok(outcome))
+        }
+    }
+
+    #[test]
+    fn test_native_hooks() {
+        let (hash, dir) = create_repo();
+        let dot_hg = dir.as_ref().join(".hg");
+
+        let repo = mercurial::RevlogRepo::open(dot_hg).unwrap();
+        let mut hooks = lua_hooks(vec![
+            LuaHook {
+                name: "lua".into(),
+                code: "function hook(info) return \"not today\" end".into(),
+                limits: HookLimits::default(),
+            },
+        ]);
+        hooks.add_changeset_hook("author", AuthorHook).unwrap();
+        hooks.add_file_hook("empty_file", EmptyFileHook).unwrap();
+        assert!(hooks.add_file_hook("lua", EmptyFileHook).is_err());
+        let hook_manager = start_hooks(repo, hooks, 1).unwrap();
+
+        let result = hook_manager.run_hook("author", hook_info(&hash)).wait();
+        assert_eq!(result.unwrap(), HookOutcome::Accepted);
+
+        let result = hook_manager.run_hook("empty_file", hook_info(&hash)).wait();
+        let rejected = HookOutcome::Rejected("foo.txt is empty".into());
+        assert_eq!(result.unwrap(), rejected);
+
+        let result = hook_manager.run_hook("lua", hook_info(&hash)).wait();
+        assert_eq!(result.unwrap(), HookOutcome::Rejected("not today".into()));
+    }
+
+    #[test]
+    fn test_native_hooks_check_every_changeset() {
+        let (first, dir) = create_repo();
+        commit_file(&dir, "empty.txt", b"");
+        let last = commit_file(&dir, "full.txt", b"full\n");
+        let dot_hg = dir.as_ref().join(".hg");
+
+        let repo = mercurial::RevlogRepo::open(dot_hg).unwrap();
+        let mut hooks = HookRegistry::new();
+        hooks.add_file_hook("empty_file", EmptyFileHook).unwrap();
+        let hook_manager = start_hooks(repo, hooks, 1).unwrap();
+
+        // The empty file was added in a changeset between the old and new positions.
+        let info = HookInfo {
+            repo: "fbsource".into(),
+            bookmark: "master".into(),
+            old_hash: NodeHash::from_str(&first).unwrap(),
+            new_hash: NodeHash::from_str(&last).unwrap(),
+        };
+        let result = hook_manager.run_hook("empty_file", info).wait();
+        let rejected = HookOutcome::Rejected("empty.txt is empty".into());
+        assert_eq!(result.unwrap(), rejected);
+    }
+
+    #[test]
+    fn test_native_hooks_check_new_bookmark() {
+        let (first, dir) = create_repo();
+        let status = hg_cmd(&dir)
+            .arg("bookmark")
+            .arg("-r")
+            .arg(&first)
+            .arg("stable")
+            .status()
+            .expect("hg bookmark failed");
+        assert!(status.success());
+        commit_file(&dir, "empty.txt", b"");
+        let last = commit_file(&dir, "full.txt", b"full\n");
+        let dot_hg = dir.as_ref().join(".hg");
+
+        let repo = mercurial::RevlogRepo::open(dot_hg).unwrap();
+        let mut hooks = HookRegistry::new();
+        hooks.add_file_hook("empty_file", EmptyFileHook).unwrap();
+        let hook_manager = start_hooks(repo, hooks, 1).unwrap();
+
+        // Creating master at the last changeset adds both changesets on top of stable, but not
+        // the empty foo.txt that stable already has.
+        let result = hook_manager.run_hook("empty_file", hook_info(&last)).wait();
+        let rejected = HookOutcome::Rejected("empty.txt is empty".into());
+        assert_eq!(result.unwrap(), rejected);
+    }
+
+    #[test]
+    fn test_builtin_hooks() {
+        let (_, dir) = create_repo();
+        let hash = commit_file(&dir, "conflict.txt", b"<<<<<<< local\na\n=======\n");
+        let dot_hg = dir.as_ref().join(".hg");
+
+        let repo = mercurial::RevlogRepo::open(dot_hg).unwrap();
+        let mut hooks = HookRegistry::new();
+        hooks
+            .add_builtin_hook("conflicts", "no_conflict_markers")
+            .unwrap();
+        assert!(hooks.add_builtin_hook("other", "missing").is_err());
+        let hook_manager = start_hooks(repo, hooks, 1).unwrap();
+
+        let result = hook_manager.run_hook("conflicts", hook_info(&hash)).wait();
+        match result {
+            Ok(HookOutcome::Rejected(ref reason))
+                if reason.starts_with("conflict.txt has conflict markers") => (),
+            _ => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let (hash, dir) = create_repo();
+        let dot_hg = dir.as_ref().join(".hg");
+
+        let repo = mercurial::RevlogRepo::open(dot_hg).unwrap();
+        let limits = HookLimits {
+            max_instructions: 1_000_000,
+            timeout: Duration::from_secs(60),
         };
+        let hooks = vec![
+            LuaHook {
+                name: "allow".into(),
+                code: "function hook(info) return true end".into(),
+                limits,
+            },
+            LuaHook {
+                name: "os".into(),
+                code: "function hook(info) return os.execute('true') end".into(),
+                limits,
+            },
+            LuaHook {
+                name: "loop".into(),
+                code: "function hook(info) while true do end end".into(),
+                limits,
+            },
+            LuaHook {
+                name: "slow".into(),
+                code: "function hook(info) while true do end end".into(),
+                limits: HookLimits {
+                    max_instructions: u64::max_value(),
+                    timeout: Duration::from_millis(100),
+                },
+            },
+            LuaHook {
+                name: "memory".into(),
+                code: "
+                    function hook(info)
+                        local t = {}
+                        for i = 1, 1000000 do
+                            t[i] = string.rep('x', 1000) .. i
+                        end
+                        return true
+                    end"
+                    .into(),
+                limits,
+            },
+            LuaHook {
+                name: "caught".into(),
+                code: "
+                    function hook(info)
+                        coroutine.resume(coroutine.create(function()
+                            local t = {}
+                            for i = 1, 1000000 do
+                                t[i] = string.rep('x', 1000) .. i
+                            end
+                        end))
+                        return true
+                    end"
+                    .into(),
+                limits,
+            },
+            LuaHook {
+                name: "leak".into(),
+                code: "
+                    function hook(info)
+                        leaked = (leaked or '') .. string.rep('x', 1024 * 1024)
+                        return true
+                    end"
+                    .into(),
+                limits,
+            },
+        ];
+        let hooks = lua_hooks(hooks);
+        let hook_manager = start_hooks(repo, hooks, 1).unwrap();
+
+        let result = hook_manager.run_hook("os", hook_info(&hash)).wait();
+        match result {
+            Err(Error(ErrorKind::HookRuntimeError(ref name, _), _)) if name == "os" => (),
+            _ => panic!("unexpected result {:?}", result),
+        }
+
+        let result = hook_manager.run_hook("loop", hook_info(&hash)).wait();
+        match result {
+            Err(Error(ErrorKind::HookResourceExhausted(ref name, "instructions"), _))
+                if name == "loop" => (),
+            _ => panic!("unexpected result {:?}", result),
+        }
+
+        let result = hook_manager.run_hook("slow", hook_info(&hash)).wait();
+        match result {
+            Err(Error(ErrorKind::HookTimeout(ref name, 100), _)) if name == "slow" => (),
+            _ => panic!("unexpected result {:?}", result),
+        }
+
+        let result = hook_manager.run_hook("memory", hook_info(&hash)).wait();
+        match result {
+            Err(Error(ErrorKind::HookResourceExhausted(ref name, "memory"), _))
+                if name == "memory" => (),
+            _ => panic!("unexpected result {:?}", result),
+        }
 
-        let coroutine_fut = hook_manager.run_hook(hook).unwrap();
-        let result = coroutine_fut.wait();
-        assert!(result.unwrap());
+        // Hooks that catch the error are still stopped.
+        let result = hook_manager.run_hook("caught", hook_info(&hash)).wait();
+        match result {
+            Err(Error(ErrorKind::HookResourceExhausted(ref name, "memory"), _))
+                if name == "caught" => (),
+            _ => panic!("unexpected result {:?}", result),
+        }
+
+        // The context can still be used once hooks have been stopped.
+        let result = hook_manager.run_hook("allow", hook_info(&hash)).wait();
+        assert_eq!(result.unwrap(), HookOutcome::Accepted);
+
+        // A hook that keeps what it allocates in its globals runs out of memory, and then starts
+        // again from scratch.
+        let mut stopped = false;
+        for _ in 0..MAX_MEMORY / (1024 * 1024) {
+            match hook_manager.run_hook("leak", hook_info(&hash)).wait() {
+                Ok(HookOutcome::Accepted) => (),
+                Err(Error(ErrorKind::HookResourceExhausted(_, "memory"), _)) => {
+                    stopped = true;
+                    break;
+                }
+                result => panic!("unexpected result {:?}", result),
+            }
+        }
+        assert!(stopped);
+        let result = hook_manager.run_hook("leak", hook_info(&hash)).wait();
+        assert_eq!(result.unwrap(), HookOutcome::Accepted);
+    }
+
+    const MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+    fn start_hooks(
+        repo: mercurial::RevlogRepo,
+        hooks: HookRegistry,
+        contexts: usize,
+    ) -> Result<HookManager> {
+        let repo_generation = RepoGenCache::new(1000);
+        HookManager::new(Arc::new(repo), repo_generation, hooks, contexts, 1, MAX_MEMORY)
+    }
+
+    fn lua_hooks(hooks: Vec<LuaHook>) -> HookRegistry {
+        let mut registry = HookRegistry::new();
+        for hook in hooks {
+            registry.add_lua_hook(hook).unwrap();
+        }
+        registry
+    }
+
+    fn hook_info(hash: &str) -> HookInfo {
+        HookInfo {
+            repo: "fbsource".into(),
+            bookmark: "master".into(),
+            old_hash: NULL_HASH,
+            new_hash: NodeHash::from_str(hash).unwrap(),
+        }
     }
 
     fn create_repo() -> (String, TempDir) {
@@ -170,18 +661,24 @@ mod test {
             .expect("hg init failed");
         assert!(status.success());
 
+        let hash = commit_file(&dir, "foo.txt", b"");
+        (hash, dir)
+    }
+
+    /// Commit `content` to `file`, returning the hash of the new changeset.
+    fn commit_file(dir: &TempDir, file: &str, content: &[u8]) -> String {
         {
-            let new_file = dir.as_ref().join("foo.txt");
-            File::create(new_file).unwrap();
+            let mut new_file = File::create(dir.as_ref().join(file)).unwrap();
+            new_file.write_all(content).unwrap();
         }
-        let status = hg_cmd(&dir)
+        let status = hg_cmd(dir)
             .arg("add")
-            .arg("foo.txt")
+            .arg(file)
             .status()
             .expect("hg add failed");
         assert!(status.success());
 
-        let status = hg_cmd(&dir)
+        let status = hg_cmd(dir)
             .arg("commit")
             .arg("-utestuser")
             .arg("-mtest")
@@ -190,16 +687,15 @@ mod test {
         assert!(status.success());
 
         // Get the new hash and return it.
-        let output = hg_cmd(&dir)
+        let output = hg_cmd(dir)
             .arg("log")
             .arg("-r.")
             .arg("-T{node}")
             .output()
             .expect("hg log failed");
         assert!(output.status.success());
-        let stdout = output.stdout;
 
-        (String::from_utf8(stdout).unwrap(), dir)
+        String::from_utf8(output.stdout).unwrap()
     }
 
     fn hg_cmd<P: AsRef<Path>>(dir: P) -> Command {
@@ -0,0 +1,261 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Hooks written in Rust.
+//!
+//! Checks that every push goes through, or that look at every file, are much cheaper in Rust
+//! than in Lua. Native hooks don't get the repo: the manager loads what they check, from each
+//! changeset that the bookmark move adds to the bookmark, and runs them on the calling thread
+//! rather than in a Lua context.
+
+use std::sync::Arc;
+
+use futures::{future, Future, Stream};
+use futures::future::Either;
+
+use mercurial_types::{Changeset, MPath, NodeHash, Repo, NULL_HASH};
+use repoinfo::RepoGenCache;
+use revset::{AncestorsNodeStream, NodeStream, SetDifferenceNodeStream, UnionNodeStream};
+
+use api::{entry_content, file_entry};
+use errors::*;
+use HookOutcome;
+
+/// The number of changesets a native hook is run on at once.
+const CONCURRENT_CHANGESETS: usize = 10;
+
+/// A check written in Rust, run on a changeset (`Hook<HookChangeset>`) or on each of the files it
+/// changes (`Hook<HookFile>`).
+pub trait Hook<T>: Send + Sync + 'static {
+    fn run(&self, context: T) -> Box<Future<Item = HookOutcome, Error = Error> + Send>;
+}
+
+/// What a changeset hook is given.
+#[derive(Clone, Debug)]
+pub struct HookChangeset {
+    pub hash: NodeHash,
+    pub author: Vec<u8>,
+    pub comments: Vec<u8>,
+    pub parents: Vec<NodeHash>,
+    /// The paths of the files the changeset changed.
+    pub files: Vec<MPath>,
+}
+
+/// What a file hook is given, for each file a changeset changed.
+#[derive(Clone, Debug)]
+pub struct HookFile {
+    pub changeset: NodeHash,
+    pub path: MPath,
+    /// `None` if the changeset deleted the file.
+    pub content: Option<Vec<u8>>,
+}
+
+#[derive(Clone)]
+pub enum NativeHook {
+    Changeset(Arc<Hook<HookChangeset>>),
+    File(Arc<Hook<HookFile>>),
+}
+
+impl NativeHook {
+    /// Run the hook on each changeset that moving a bookmark from `old` to `new` adds to it, and
+    /// reject the move if it rejects any of them. Moves that delete a bookmark are always
+    /// allowed, as there's nothing to check.
+    pub fn run(
+        &self,
+        loader: Arc<Loader>,
+        old: NodeHash,
+        new: NodeHash,
+    ) -> Box<Future<Item = HookOutcome, Error = Error> + Send> {
+        if new == NULL_HASH {
+            return Box::new(future::ok(HookOutcome::Accepted));
+        }
+
+        let hook = self.clone();
+        let changesets = loader.changesets(old, new);
+        Box::new(
+            changesets
+                .map(move |hash| hook.run_changeset(loader.clone(), hash))
+                .buffer_unordered(CONCURRENT_CHANGESETS)
+                .collect()
+                .map(combine),
+        )
+    }
+
+    fn run_changeset(
+        &self,
+        loader: Arc<Loader>,
+        hash: NodeHash,
+    ) -> Box<Future<Item = HookOutcome, Error = Error> + Send> {
+        match *self {
+            NativeHook::Changeset(ref hook) => {
+                let hook = hook.clone();
+                Box::new(loader.changeset(hash).and_then(move |cs| hook.run(cs)))
+            }
+            NativeHook::File(ref hook) => {
+                let hook = hook.clone();
+                Box::new(loader.changeset(hash).and_then(move |cs| {
+                    let checks: Vec<_> = cs.files
+                        .into_iter()
+                        .map(|path| {
+                            let hook = hook.clone();
+                            loader.file(hash, path).and_then(move |file| hook.run(file))
+                        })
+                        .collect();
+                    future::join_all(checks).map(combine)
+                }))
+            }
+        }
+    }
+}
+
+/// Reject if any of `outcomes` do, for all the reasons given.
+fn combine(outcomes: Vec<HookOutcome>) -> HookOutcome {
+    let reasons: Vec<_> = outcomes
+        .into_iter()
+        .filter_map(|outcome| match outcome {
+            HookOutcome::Accepted => None,
+            HookOutcome::Rejected(reason) => Some(reason),
+        })
+        .collect();
+    if reasons.is_empty() {
+        HookOutcome::Accepted
+    } else {
+        HookOutcome::Rejected(reasons.join("\n"))
+    }
+}
+
+/// Loads what native hooks are given, so that the manager doesn't depend on the type of repo.
+pub trait Loader: Send + Sync + 'static {
+    /// The changesets that moving a bookmark from `old` to `new` adds to it: the ancestors of
+    /// `new` that aren't ancestors of `old`. When the bookmark is created, they're the ancestors
+    /// of `new` that aren't ancestors of any other bookmark.
+    fn changesets(
+        &self,
+        old: NodeHash,
+        new: NodeHash,
+    ) -> Box<Stream<Item = NodeHash, Error = Error> + Send>;
+    fn changeset(
+        &self,
+        hash: NodeHash,
+    ) -> Box<Future<Item = HookChangeset, Error = Error> + Send>;
+    fn file(
+        &self,
+        changeset: NodeHash,
+        path: MPath,
+    ) -> Box<Future<Item = HookFile, Error = Error> + Send>;
+}
+
+pub struct RepoLoader<R: Repo> {
+    pub repo: Arc<R>,
+    pub repo_generation: RepoGenCache<R>,
+}
+
+impl<R: Repo> RepoLoader<R> {
+    /// Where each of the repo's bookmarks points.
+    fn bookmarked(&self) -> Box<Future<Item = Vec<NodeHash>, Error = Error> + Send> {
+        let bookmarks = match self.repo.get_bookmarks() {
+            Ok(bookmarks) => bookmarks,
+            Err(err) => {
+                return Box::new(future::err(
+                    Error::with_chain(err, "failed to list bookmarks"),
+                ))
+            }
+        };
+        Box::new(
+            bookmarks
+                .keys()
+                .and_then(move |name| bookmarks.get(&name))
+                .filter_map(|bookmark| bookmark.map(|(node, _version)| node))
+                .collect()
+                .map_err(|err| Error::with_chain(err, "failed to list bookmarks")),
+        )
+    }
+}
+
+impl<R: Repo> Loader for RepoLoader<R> {
+    fn changesets(
+        &self,
+        old: NodeHash,
+        new: NodeHash,
+    ) -> Box<Stream<Item = NodeHash, Error = Error> + Send> {
+        // A new bookmark adds whatever isn't already on one of the others.
+        let known: Box<Future<Item = Vec<NodeHash>, Error = Error> + Send> = if old == NULL_HASH {
+            self.bookmarked()
+        } else {
+            Box::new(future::ok(vec![old]))
+        };
+
+        let repo = self.repo.clone();
+        let repo_generation = self.repo_generation.clone();
+        Box::new(
+            known
+                .map(move |known| {
+                    let ancestors = |node: NodeHash| {
+                        Box::new(AncestorsNodeStream::new(
+                            &repo,
+                            repo_generation.clone(),
+                            node,
+                        )) as Box<NodeStream>
+                    };
+                    let known: Vec<_> = known.into_iter().map(&ancestors).collect();
+                    let known = UnionNodeStream::new(&repo, repo_generation.clone(), known);
+                    SetDifferenceNodeStream::new(
+                        &repo,
+                        repo_generation.clone(),
+                        ancestors(new),
+                        Box::new(known),
+                    ).map_err(move |err| {
+                        Error::with_chain(
+                            err,
+                            format!("failed to find the changesets between {} and {}", old, new),
+                        )
+                    })
+                })
+                .flatten_stream(),
+        )
+    }
+
+    fn changeset(
+        &self,
+        hash: NodeHash,
+    ) -> Box<Future<Item = HookChangeset, Error = Error> + Send> {
+        Box::new(
+            self.repo
+                .get_changeset_by_nodeid(&hash)
+                .map(move |cs| HookChangeset {
+                    hash,
+                    author: cs.user().to_vec(),
+                    comments: cs.comments().to_vec(),
+                    parents: cs.parents().into_iter().collect(),
+                    files: cs.files().to_vec(),
+                })
+                .map_err(move |err| {
+                    Error::with_chain(err, format!("failed to load changeset {}", hash))
+                }),
+        )
+    }
+
+    fn file(
+        &self,
+        changeset: NodeHash,
+        path: MPath,
+    ) -> Box<Future<Item = HookFile, Error = Error> + Send> {
+        let error = format!("failed to load {} in changeset {}", path, changeset);
+        Box::new(
+            file_entry(self.repo.clone(), &changeset, path.clone())
+                .and_then(|entry| match entry {
+                    Some(entry) => Either::A(entry_content(entry)),
+                    None => Either::B(future::ok(None)),
+                })
+                .map(move |content| HookFile {
+                    changeset,
+                    path,
+                    content,
+                })
+                .map_err(move |err| Error::with_chain(err, error)),
+        )
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The hooks a `HookManager` is started with.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use builtin::NoConflictMarkers;
+use errors::*;
+use native::{Hook, HookChangeset, HookFile, NativeHook};
+use LuaHook;
+
+/// A repo's hooks, written in Lua or Rust. Each of them has a name of its own, which is what
+/// they're run by, whatever they're written in.
+#[derive(Clone, Default)]
+pub struct HookRegistry {
+    pub(crate) lua: Vec<LuaHook>,
+    pub(crate) native: HashMap<String, NativeHook>,
+}
+
+impl HookRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_lua_hook(&mut self, hook: LuaHook) -> Result<()> {
+        self.check_name(&hook.name)?;
+        self.lua.push(hook);
+        Ok(())
+    }
+
+    /// Add a hook that's run on each changeset a bookmark move adds to the bookmark.
+    pub fn add_changeset_hook<H>(&mut self, name: &str, hook: H) -> Result<()>
+    where
+        H: Hook<HookChangeset>,
+    {
+        self.check_name(name)?;
+        self.native
+            .insert(name.to_string(), NativeHook::Changeset(Arc::new(hook)));
+        Ok(())
+    }
+
+    /// Add a hook that's run on each file changed by each changeset a bookmark move adds to the
+    /// bookmark. The move is rejected if the hook rejects any of them.
+    pub fn add_file_hook<H>(&mut self, name: &str, hook: H) -> Result<()>
+    where
+        H: Hook<HookFile>,
+    {
+        self.check_name(name)?;
+        self.native
+            .insert(name.to_string(), NativeHook::File(Arc::new(hook)));
+        Ok(())
+    }
+
+    /// Add the native hook built into this crate called `builtin`, as the hook called `name`.
+    pub fn add_builtin_hook(&mut self, name: &str, builtin: &str) -> Result<()> {
+        match builtin {
+            "no_conflict_markers" => self.add_file_hook(name, NoConflictMarkers),
+            _ => bail!(ErrorKind::HookDefinitionError(format!(
+                "hook '{}' uses unknown native hook '{}'",
+                name,
+                builtin
+            ))),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lua.is_empty() && self.native.is_empty()
+    }
+
+    fn check_name(&self, name: &str) -> Result<()> {
+        if self.native.contains_key(name) || self.lua.iter().any(|hook| hook.name == name) {
+            bail!(ErrorKind::HookDefinitionError(format!(
+                "hook '{}' is defined more than once",
+                name
+            )));
+        }
+        Ok(())
+    }
+}
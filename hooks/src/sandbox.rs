@@ -0,0 +1,232 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Lua states that hooks can't use to harm the server.
+//!
+//! Hook code only gets the parts of the standard library that can't reach outside the Lua state.
+//! Each Lua state has a cap on the memory it can allocate, and each time a hook is run, it's
+//! stopped if it runs too many instructions or for too long. The limits are only checked while
+//! Lua code is running, so whoever waits for a hook that's waiting for the repo has to stop it
+//! at `Sandbox::deadline` themselves, with `Sandbox::time_out`.
+//!
+//! Hooks can still catch the errors that stop them, with `coroutine.resume`, so whoever runs a
+//! hook has to check `Sandbox::exceeded` whatever the hook returns.
+
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use ffi;
+use hlua::{AnyLuaValue, Lua};
+use libc::{self, c_int, c_void, size_t};
+
+use errors::*;
+
+/// How often the limits on a running hook are checked, in instructions.
+const CHECK_INTERVAL: c_int = 1000;
+
+/// The base library functions that hooks don't get: those that load code from files or
+/// bytecode, catch errors, write to the server's output or control garbage collection.
+const REMOVED_FUNCTIONS: &[&str] = &[
+    "collectgarbage",
+    "dofile",
+    "load",
+    "loadfile",
+    "pcall",
+    "print",
+    "xpcall",
+];
+
+/// Limits on what a hook can use each time it's run.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct HookLimits {
+    pub max_instructions: u64,
+    pub timeout: Duration,
+}
+
+impl Default for HookLimits {
+    fn default() -> Self {
+        HookLimits {
+            max_instructions: 100_000_000,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// A limit that stopped a hook.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Limit {
+    Memory,
+    Instructions,
+    Time,
+}
+
+impl Limit {
+    pub fn error(&self, hook_name: &str, limits: &HookLimits) -> Error {
+        match *self {
+            Limit::Memory => ErrorKind::HookResourceExhausted(hook_name.into(), "memory").into(),
+            Limit::Instructions => {
+                ErrorKind::HookResourceExhausted(hook_name.into(), "instructions").into()
+            }
+            Limit::Time => {
+                let timeout = limits.timeout;
+                let timeout_ms =
+                    timeout.as_secs() * 1000 + u64::from(timeout.subsec_nanos() / 1_000_000);
+                ErrorKind::HookTimeout(hook_name.into(), timeout_ms).into()
+            }
+        }
+    }
+}
+
+/// What a Lua state has used, and what it may use. The allocator and the hook that checks the
+/// limits get to it through the state's allocator data.
+struct Usage {
+    memory: usize,
+    max_memory: usize,
+    instructions: u64,
+    max_instructions: u64,
+    deadline: Instant,
+    /// The limit that stopped the code that's running, if one has. Running out of memory is
+    /// only recorded here, once Lua has collected garbage and the allocation still can't be made.
+    exceeded: Option<Limit>,
+    /// The address and new size of the last block the allocator refused to grow, until an
+    /// allocation succeeds. Lua asks for the same block again after collecting garbage.
+    refused: Option<(usize, size_t)>,
+}
+
+pub struct Sandbox<'lua> {
+    // Declared before `usage`, so that the state is closed before what it refers to is freed.
+    lua: Lua<'lua>,
+    usage: Box<Usage>,
+}
+
+impl<'lua> Sandbox<'lua> {
+    /// Create a Lua state that can allocate up to `max_memory` bytes.
+    pub fn new(max_memory: usize) -> Result<Self> {
+        let limits = HookLimits::default();
+        let mut usage = Box::new(Usage {
+            memory: 0,
+            max_memory,
+            instructions: 0,
+            max_instructions: limits.max_instructions,
+            deadline: Instant::now() + limits.timeout,
+            exceeded: None,
+            refused: None,
+        });
+
+        let mut lua = unsafe {
+            let state = ffi::lua_newstate(allocate, &mut *usage as *mut Usage as *mut c_void);
+            if state.is_null() {
+                bail!("failed to create a Lua state for hooks");
+            }
+            // Coroutines inherit the hook from the state they're created in.
+            ffi::lua_sethook(state, check_limits, ffi::LUA_MASKCOUNT, CHECK_INTERVAL);
+            Lua::from_existing_state(state, true)
+        };
+
+        lua.open_base();
+        lua.open_coroutine();
+        lua.open_math();
+        lua.open_string();
+        lua.open_table();
+        for name in REMOVED_FUNCTIONS {
+            lua.set(*name, AnyLuaValue::LuaNil);
+        }
+
+        Ok(Sandbox { lua, usage })
+    }
+
+    pub fn lua(&mut self) -> &mut Lua<'lua> {
+        &mut self.lua
+    }
+
+    /// Reset the limits before running a hook.
+    pub fn start(&mut self, limits: &HookLimits) {
+        self.usage.instructions = 0;
+        self.usage.max_instructions = limits.max_instructions;
+        self.usage.deadline = Instant::now() + limits.timeout;
+        self.usage.exceeded = None;
+        self.usage.refused = None;
+    }
+
+    /// When the hook that's running has to be stopped.
+    pub fn deadline(&self) -> Instant {
+        self.usage.deadline
+    }
+
+    /// Record that the hook that's running was stopped for taking too long while it wasn't
+    /// running Lua code.
+    pub fn time_out(&mut self) {
+        self.usage.exceeded = Some(Limit::Time);
+    }
+
+    /// The limit that stopped the hook that was last run, if one did.
+    pub fn exceeded(&self) -> Option<Limit> {
+        self.usage.exceeded
+    }
+}
+
+/// The Lua allocator, as in `lauxlib.c`, except that it refuses to grow the memory used beyond
+/// the cap. Lua then collects garbage and asks again, and fails with a memory error if it's
+/// refused a second time.
+extern "C" fn allocate(
+    ud: *mut c_void,
+    block: *mut c_void,
+    osize: size_t,
+    nsize: size_t,
+) -> *mut c_void {
+    let usage = unsafe { &mut *(ud as *mut Usage) };
+    // For new blocks, `osize` is the type of object being allocated rather than a size.
+    let old = if block.is_null() { 0 } else { osize };
+
+    if nsize == 0 {
+        unsafe { libc::free(block) };
+        usage.memory -= old;
+        return ptr::null_mut();
+    }
+    if nsize > old && usage.memory + (nsize - old) > usage.max_memory {
+        let request = (block as usize, nsize);
+        if usage.refused == Some(request) {
+            usage.exceeded = Some(Limit::Memory);
+        }
+        usage.refused = Some(request);
+        return ptr::null_mut();
+    }
+
+    let new = unsafe { libc::realloc(block, nsize) };
+    if !new.is_null() {
+        usage.memory = usage.memory + nsize - old;
+        usage.refused = None;
+    }
+    new
+}
+
+/// Called every `CHECK_INTERVAL` instructions, to stop hooks that have run for too long.
+extern "C" fn check_limits(state: *mut ffi::lua_State, _: *mut ffi::lua_Debug) {
+    let usage = unsafe {
+        let mut ud = ptr::null_mut();
+        ffi::lua_getallocf(state, &mut ud);
+        &mut *(ud as *mut Usage)
+    };
+
+    usage.instructions += CHECK_INTERVAL as u64;
+    let exceeded = if usage.instructions > usage.max_instructions {
+        Some(Limit::Instructions)
+    } else if Instant::now() > usage.deadline {
+        Some(Limit::Time)
+    } else {
+        None
+    };
+
+    if exceeded.is_some() {
+        usage.exceeded = exceeded;
+        // This doesn't return: the error unwinds the Lua stack with longjmp, so there must be
+        // nothing here that needs dropping.
+        let msg = b"hook stopped for exceeding its limits\0";
+        unsafe {
+            ffi::luaL_error(state, msg.as_ptr() as *const _);
+        }
+    }
+}
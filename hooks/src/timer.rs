@@ -0,0 +1,121 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Deadlines for hooks that are waiting on the repo.
+//!
+//! The sandbox only checks a hook's limits while its Lua code is running, so a hook waiting for
+//! a repo future is raced against one of these instead. A single thread keeps all the deadlines,
+//! rather than one sleeping thread for each hook that's run.
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Instant;
+
+use futures::sync::oneshot;
+
+use errors::*;
+
+/// A deadline waiting to be reached, and who to tell.
+struct Pending {
+    deadline: Instant,
+    sender: oneshot::Sender<()>,
+}
+
+// Ordered so that the earliest deadline is at the top of the heap.
+impl Ord for Pending {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.deadline.cmp(&self.deadline)
+    }
+}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Pending {}
+
+/// A handle on the timer thread, which stops once all the handles have gone.
+#[derive(Clone)]
+pub struct Timer {
+    requests: mpsc::Sender<Pending>,
+}
+
+impl Timer {
+    pub fn new() -> Result<Self> {
+        let (requests, receiver) = mpsc::channel();
+        thread::Builder::new()
+            .name("hook_timer".into())
+            .spawn(move || run(receiver))?;
+        Ok(Timer { requests })
+    }
+
+    /// A future that resolves at `deadline`. It's cancelled if the timer thread has stopped.
+    pub fn at(&self, deadline: Instant) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        // If the thread has gone, the sender is dropped, which cancels the receiver.
+        let _ = self.requests.send(Pending { deadline, sender });
+        receiver
+    }
+}
+
+fn run(requests: mpsc::Receiver<Pending>) {
+    let mut pending = BinaryHeap::new();
+    loop {
+        let now = Instant::now();
+        while pending
+            .peek()
+            .map_or(false, |next: &Pending| next.deadline <= now)
+        {
+            // Nobody is waiting any more if the hook has already finished.
+            let _ = pending.pop().unwrap().sender.send(());
+        }
+
+        let received = match pending.peek() {
+            Some(next) => requests.recv_timeout(next.deadline - now),
+            None => requests.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match received {
+            Ok(request) => pending.push(request),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use futures::Future;
+
+    use super::*;
+
+    #[test]
+    fn test_deadlines() {
+        let timer = Timer::new().unwrap();
+        let start = Instant::now();
+        let later = timer.at(start + Duration::from_millis(200));
+        let sooner = timer.at(start + Duration::from_millis(50));
+
+        sooner.wait().unwrap();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50));
+        assert!(elapsed < Duration::from_millis(200));
+
+        later.wait().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Parsing legacy HG10 bundles, as written by `hg bundle --type v1`.
+//!
+//! An HG10 bundle is the magic string "HG10", a two-byte compression type ("UN", "BZ" or "GZ"),
+//! and then a version 01 changegroup, compressed with that type.
+
+use std::mem;
+
+use bytes::BytesMut;
+use futures::{Async, Poll, Stream};
+use slog;
+use tokio_io::AsyncRead;
+use tokio_io::codec::Decoder;
+
+use async_compression::{Decompressor, DecompressorType};
+use futures_ext::{AsyncReadExt, FramedStream, ReadLeadingBuffer};
+
+use InnerPart;
+use changegroup::{CgVersion, Part};
+use changegroup::unpacker::CgUnpacker;
+use errors::*;
+
+#[derive(Debug)]
+pub struct Bundle1Stream<R>
+where
+    R: AsyncRead + 'static,
+{
+    logger: slog::Logger,
+    state: State<R>,
+}
+
+#[derive(Debug)]
+enum State<R>
+where
+    R: AsyncRead + 'static,
+{
+    Start(FramedStream<R, HeaderDecoder>),
+    Changegroup(FramedStream<Decompressor<ReadLeadingBuffer<R>>, CgUnpacker>),
+    Invalid,
+}
+
+impl<R> Bundle1Stream<R>
+where
+    R: AsyncRead,
+{
+    pub fn new(read: R, logger: slog::Logger) -> Bundle1Stream<R> {
+        Bundle1Stream {
+            logger: logger,
+            state: State::Start(read.framed_stream(HeaderDecoder)),
+        }
+    }
+}
+
+impl<R> Stream for Bundle1Stream<R>
+where
+    R: AsyncRead,
+{
+    type Item = Part;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Part>, Error> {
+        loop {
+            match mem::replace(&mut self.state, State::Invalid) {
+                State::Start(mut stream) => match stream.poll() {
+                    Err(e) => return Err(e),
+                    Ok(Async::NotReady) => {
+                        self.state = State::Start(stream);
+                        return Ok(Async::NotReady);
+                    }
+                    Ok(Async::Ready(None)) => {
+                        self.state = State::Start(stream);
+                        return Ok(Async::Ready(None));
+                    }
+                    Ok(Async::Ready(Some(decompressor_type))) => {
+                        let unpacker = CgUnpacker::new(
+                            self.logger.new(o!("stream" => "changegroup")),
+                            CgVersion::Cg1,
+                        );
+                        let read = stream.into_inner_leading();
+                        let read = Decompressor::new(read, decompressor_type);
+                        self.state = State::Changegroup(read.framed_stream(unpacker));
+                    }
+                },
+                State::Changegroup(mut stream) => {
+                    let ret = stream.poll();
+                    if ret.is_ok() {
+                        self.state = State::Changegroup(stream);
+                    }
+                    return Ok(Async::Ready(try_ready!(ret).map(InnerPart::cg2_part)));
+                }
+                State::Invalid => {
+                    return Err(ErrorKind::Bundle1Decode("byte stream corrupt".into()).into())
+                }
+            }
+        }
+    }
+}
+
+/// Decoder for the header of an HG10 bundle, which works out how the rest of it is compressed.
+#[derive(Debug)]
+struct HeaderDecoder;
+
+impl Decoder for HeaderDecoder {
+    type Item = DecompressorType;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<DecompressorType>> {
+        if buf.len() < 6 {
+            return Ok(None);
+        }
+
+        if &buf[..4] != b"HG10" {
+            bail!(ErrorKind::Bundle1Decode("invalid bundle magic string".into()));
+        }
+        let decompressor_type = match &buf[4..6] {
+            b"UN" => DecompressorType::Uncompressed,
+            b"BZ" => DecompressorType::Bzip2,
+            b"GZ" => DecompressorType::Gzip,
+            other => bail!(ErrorKind::Bundle1Decode(format!(
+                "unknown compression '{}'",
+                String::from_utf8_lossy(other)
+            ))),
+        };
+
+        // The "BZ" doubles as the magic string of the bzip2 stream, so leave it for the
+        // decompressor.
+        let header_len = match decompressor_type {
+            DecompressorType::Bzip2 => 4,
+            _ => 6,
+        };
+        let _ = buf.split_to(header_len);
+        Ok(Some(decompressor_type))
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Writing legacy HG10 bundles. See `bundle1` for the format.
+
+use std::cmp;
+use std::io::{self, Write};
+use std::mem;
+
+use bytes::BytesMut;
+use futures::{Async, Future, Poll, Stream};
+use futures::stream::Forward;
+use tokio_io::AsyncWrite;
+use tokio_io::codec::{Encoder, FramedWrite};
+use tokio_io::io::{write_all, WriteAll};
+
+use async_compression::{Compressor, CompressorType};
+
+use changegroup::{CgVersion, Part};
+use changegroup::packer::CgPacker;
+use chunk::Chunk;
+use errors::*;
+
+/// A sink that changegroup chunks go into, after the header has been written.
+type CgSink<W> = FramedWrite<Compressor<SkipPrefix<W>>, CgChunkEncoder>;
+
+enum EncodeState<W, S>
+where
+    W: AsyncWrite + 'static,
+{
+    Header(WriteAll<W, &'static [u8]>, CompressorType, CgPacker<S>),
+    Changegroup(Forward<CgPacker<S>, CgSink<W>>),
+    Finish(Compressor<SkipPrefix<W>>),
+    Done,
+    Invalid,
+}
+
+/// A future that writes an HG10 bundle containing the changegroup made up of `parts`, and
+/// returns the writer once it's done.
+pub struct Bundle1Encode<W, S>
+where
+    W: AsyncWrite + 'static,
+{
+    state: EncodeState<W, S>,
+}
+
+impl<W, S> Bundle1Encode<W, S>
+where
+    W: AsyncWrite + Send,
+    S: Stream<Item = Part, Error = Error>,
+{
+    pub fn new(writer: W, compressor_type: CompressorType, parts: S) -> Result<Self> {
+        let header: &'static [u8] = match compressor_type {
+            CompressorType::Uncompressed => b"HG10UN",
+            CompressorType::Bzip2(_) => b"HG10BZ",
+            CompressorType::Gzip => b"HG10GZ",
+            CompressorType::Zstd { .. } => bail!(ErrorKind::Bundle1Encode(
+                "HG10 bundles can't be compressed with zstd".into()
+            )),
+        };
+        Ok(Bundle1Encode {
+            state: EncodeState::Header(
+                write_all(writer, header),
+                compressor_type,
+                CgPacker::new(CgVersion::Cg1, parts),
+            ),
+        })
+    }
+}
+
+impl<W, S> Future for Bundle1Encode<W, S>
+where
+    W: AsyncWrite + Send,
+    S: Stream<Item = Part, Error = Error>,
+{
+    type Item = W;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<W, Error> {
+        loop {
+            match mem::replace(&mut self.state, EncodeState::Invalid) {
+                EncodeState::Header(mut write, compressor_type, packer) => match write.poll()? {
+                    Async::NotReady => {
+                        self.state = EncodeState::Header(write, compressor_type, packer);
+                        return Ok(Async::NotReady);
+                    }
+                    Async::Ready((writer, _)) => {
+                        // The "BZ" in the header is also the start of the bzip2 stream, so don't
+                        // write it twice.
+                        let skip = match compressor_type {
+                            CompressorType::Bzip2(_) => 2,
+                            _ => 0,
+                        };
+                        let compressor =
+                            Compressor::new(SkipPrefix::new(writer, skip), compressor_type);
+                        let sink = FramedWrite::new(compressor, CgChunkEncoder);
+                        self.state = EncodeState::Changegroup(packer.forward(sink));
+                    }
+                },
+                EncodeState::Changegroup(mut forward) => match forward.poll()? {
+                    Async::NotReady => {
+                        self.state = EncodeState::Changegroup(forward);
+                        return Ok(Async::NotReady);
+                    }
+                    Async::Ready((_packer, sink)) => {
+                        self.state = EncodeState::Finish(sink.into_inner());
+                    }
+                },
+                EncodeState::Finish(compressor) => match compressor.try_finish() {
+                    Ok(skip_prefix) => {
+                        self.state = EncodeState::Done;
+                        return Ok(Async::Ready(skip_prefix.into_inner()));
+                    }
+                    Err((compressor, err)) => if err.kind() == io::ErrorKind::WouldBlock {
+                        self.state = EncodeState::Finish(compressor);
+                        return Ok(Async::NotReady);
+                    } else {
+                        return Err(err).chain_err(|| {
+                            ErrorKind::Bundle1Encode("error while completing write".into())
+                        });
+                    },
+                },
+                EncodeState::Done => panic!("polled Bundle1Encode future after it is complete"),
+                EncodeState::Invalid => {
+                    panic!("polled Bundle1Encode future after it returned an error")
+                }
+            }
+        }
+    }
+}
+
+/// Encode changegroup chunks as they are. Unlike in bundle2, they aren't framed any further.
+#[derive(Debug)]
+struct CgChunkEncoder;
+
+impl Encoder for CgChunkEncoder {
+    type Item = Chunk;
+    type Error = Error;
+
+    fn encode(&mut self, item: Chunk, dst: &mut BytesMut) -> Result<()> {
+        dst.extend_from_slice(&item.into_bytes()?);
+        Ok(())
+    }
+}
+
+/// A writer that drops the first few bytes written to it.
+#[derive(Debug)]
+struct SkipPrefix<W> {
+    inner: W,
+    remaining: usize,
+}
+
+impl<W> SkipPrefix<W> {
+    fn new(inner: W, len: usize) -> Self {
+        SkipPrefix {
+            inner: inner,
+            remaining: len,
+        }
+    }
+
+    fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for SkipPrefix<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.remaining > 0 {
+            // Report the skipped bytes on their own, so that an error writing the rest can't
+            // make them get skipped twice.
+            let skipped = cmp::min(self.remaining, buf.len());
+            self.remaining -= skipped;
+            return Ok(skipped);
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for SkipPrefix<W> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// Ensure that Bundle1Encode is Send.
+fn _assert_send() {
+    use std::io::Cursor;
+
+    use futures::stream;
+
+    fn _assert<T: Send>(_val: &T) {}
+
+    let parts = stream::iter_ok::<_, Error>(vec![Part::End]);
+    _assert(&Bundle1Encode::new(Cursor::new(Vec::new()), CompressorType::Uncompressed, parts));
+}
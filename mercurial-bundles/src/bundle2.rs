@@ -173,12 +173,14 @@ impl Bundle2StreamInner {
                         (Ok(Async::Ready(None)), CurrentStream::Outer(stream))
                     }
                     Ok(Async::Ready(Some(OuterFrame::Header(header)))) => {
-                        let inner_stream =
-                            CurrentStream::Inner(inner_stream(&header, stream, &self.logger));
-                        (
-                            Ok(Async::Ready(Some(Bundle2Item::Header(header)))),
-                            inner_stream,
-                        )
+                        match inner_stream(&header, stream, &self.logger) {
+                            Ok(inner_stream) => (
+                                Ok(Async::Ready(Some(Bundle2Item::Header(header)))),
+                                CurrentStream::Inner(inner_stream),
+                            ),
+                            // The rest of the stream went away with the part's payload.
+                            Err(e) => (Err(e), CurrentStream::Invalid),
+                        }
                     }
                     Ok(Async::Ready(Some(OuterFrame::Discard))) => {
                         self.poll_next(CurrentStream::Outer(stream))
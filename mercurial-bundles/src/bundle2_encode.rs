@@ -4,13 +4,15 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::cmp;
 use std::collections::HashMap;
-use std::io::{self, Cursor};
+use std::io::{self, Cursor, Write};
 use std::mem;
+use std::sync::{Arc, Mutex};
 use std::vec::IntoIter;
 
 use byteorder::ByteOrder;
-use bytes::{BigEndian, Buf, BufMut, Bytes, IntoBuf};
+use bytes::{BigEndian, Buf, BufMut, Bytes, BytesMut, IntoBuf};
 use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
 use futures::stream::Forward;
 use tokio_io::AsyncWrite;
@@ -128,6 +130,22 @@ where
     }
 }
 
+impl Bundle2EncodeBuilder<StreamWriter> {
+    /// Start a bundle2 that is produced as a stream of chunks by `build_stream`, rather than
+    /// written out to a writer.
+    pub fn new_stream() -> Self {
+        Self::new(StreamWriter::new())
+    }
+
+    pub fn build_stream(self) -> Bundle2EncodeStream {
+        let writer = self.writer.clone();
+        Bundle2EncodeStream {
+            encode: Some(self.build()),
+            writer: writer,
+        }
+    }
+}
+
 /// A sink that chunks generated by PartEncodes goes into.
 type PartSink<W> = FramedWrite<Compressor<W>, ChunkEncoder>;
 
@@ -314,6 +332,83 @@ where
     }
 }
 
+/// How many bytes of a streamed bundle can be waiting to be consumed before encoding stops until
+/// they are.
+const STREAM_BUFFER_LIMIT: usize = 64 * 1024;
+
+/// The writer for a bundle that's produced as a stream. Writes would block once
+/// `STREAM_BUFFER_LIMIT` bytes are buffered, until `Bundle2EncodeStream` hands them out.
+#[derive(Clone, Debug)]
+pub struct StreamWriter {
+    buf: Arc<Mutex<BytesMut>>,
+}
+
+impl StreamWriter {
+    fn new() -> Self {
+        StreamWriter {
+            buf: Arc::new(Mutex::new(BytesMut::new())),
+        }
+    }
+
+    fn take(&self) -> BytesMut {
+        self.buf.lock().expect("lock poisoned").take()
+    }
+}
+
+impl Write for StreamWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let mut buf = self.buf.lock().expect("lock poisoned");
+        let len = cmp::min(data.len(), STREAM_BUFFER_LIMIT.saturating_sub(buf.len()));
+        if len == 0 && !data.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        buf.extend_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for StreamWriter {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+/// A bundle2 as a stream of chunks of its bytes. Encoding only moves ahead as the chunks are
+/// consumed, so that the whole bundle doesn't have to be held in memory.
+pub struct Bundle2EncodeStream {
+    // Set to None once encoding is finished, or has failed.
+    encode: Option<Bundle2Encode<StreamWriter>>,
+    writer: StreamWriter,
+}
+
+impl Stream for Bundle2EncodeStream {
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        if let Some(mut encode) = self.encode.take() {
+            if let Async::NotReady = encode.poll()? {
+                self.encode = Some(encode);
+            }
+        }
+
+        // If encoding is stuck, it's either waiting for parts to be generated or for the buffer
+        // to be emptied, in which case there's something to return here.
+        let buf = self.writer.take();
+        if !buf.is_empty() {
+            Ok(Async::Ready(Some(buf.freeze())))
+        } else if self.encode.is_none() {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
 /// Ensure that Bundle2Encode is Send.
 fn _assert_send() {
     use std::io::Cursor;
@@ -323,4 +418,5 @@ fn _assert_send() {
     let builder = Bundle2EncodeBuilder::new(Cursor::new(Vec::new()));
     _assert(&builder);
     _assert(&builder.build());
+    _assert(&Bundle2EncodeBuilder::new_stream().build_stream());
 }
@@ -4,15 +4,67 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
 use mercurial_types::{Delta, MPath, NodeHash};
 
+use errors::*;
+
 pub mod packer;
 pub mod unpacker;
 
+/// The changegroup versions that can be packed and unpacked.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CgVersion {
+    /// Version 01, where each delta is against the previous revision in its section, or the
+    /// first parent for the first one.
+    Cg1,
+    /// Version 02, which made the delta base explicit.
+    Cg2,
+    /// Version 03, which added revlog flags to deltas, and tree manifests.
+    Cg3,
+}
+
+impl CgVersion {
+    /// The version as it appears in the `version` param of changegroup parts and in bundle2
+    /// capabilities.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            CgVersion::Cg1 => "01",
+            CgVersion::Cg2 => "02",
+            CgVersion::Cg3 => "03",
+        }
+    }
+}
+
+impl FromStr for CgVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "01" => Ok(CgVersion::Cg1),
+            "02" => Ok(CgVersion::Cg2),
+            "03" => Ok(CgVersion::Cg3),
+            _ => bail!(ErrorKind::Cg2Decode(
+                format!("unsupported changegroup version '{}'", s)
+            )),
+        }
+    }
+}
+
+impl Display for CgVersion {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}", self.as_str())
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Section {
     Changeset,
     Manifest,
+    /// The manifests of a directory, sent after the root manifests in changegroup version 03.
+    Treemanifest(MPath),
     Filelog(MPath),
 }
 
@@ -39,14 +91,18 @@ pub struct CgDeltaChunk {
     pub p2: NodeHash,
     pub base: NodeHash,
     pub linknode: NodeHash,
+    /// Revlog flags for this revision. These can only be sent in changegroup version 03, and are
+    /// always 0 in earlier versions.
+    pub flags: u16,
     pub delta: Delta,
 }
 
 #[cfg(test)]
 mod test {
     use std::io::{self, Cursor};
+    use std::str::FromStr;
 
-    use futures::Stream;
+    use futures::{stream, Stream};
     use quickcheck::{QuickCheck, StdGen, TestResult};
     use rand;
     use slog::{Drain, Logger};
@@ -58,9 +114,12 @@ mod test {
     use partial_io::{GenWouldBlock, PartialAsyncRead, PartialAsyncWrite, PartialWithErrors};
 
     use chunk::{ChunkDecoder, ChunkEncoder};
+    use mercurial_types::NULL_HASH;
     use quickcheck_types::Cg2PartSequence;
 
     use super::*;
+    use super::packer::CgPacker;
+    use super::unpacker::CgUnpacker;
 
     #[test]
     fn test_roundtrip() {
@@ -74,7 +133,15 @@ mod test {
         // - WouldBlock would require parking and unparking the task, which
         //   isn't yet supported in partial-io.
         quickcheck.quickcheck(
-            roundtrip as
+            roundtrip_cg2 as
+                fn(
+                    Cg2PartSequence,
+                    PartialWithErrors<GenWouldBlock>,
+                    PartialWithErrors<GenWouldBlock>,
+                ) -> TestResult,
+        );
+        quickcheck.quickcheck(
+            roundtrip_cg3 as
                 fn(
                     Cg2PartSequence,
                     PartialWithErrors<GenWouldBlock>,
@@ -89,7 +156,7 @@ mod test {
         let rng = StdGen::new(rand::thread_rng(), 200);
         let mut quickcheck = QuickCheck::new().gen(rng).tests(1);
         quickcheck.quickcheck(
-            roundtrip as
+            roundtrip_cg2 as
                 fn(
                     Cg2PartSequence,
                     PartialWithErrors<GenWouldBlock>,
@@ -98,7 +165,115 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_roundtrip_cg3() {
+        let chunk = |node: &str, flags| CgDeltaChunk {
+            node: NodeHash::from_str(node).unwrap(),
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            base: NULL_HASH,
+            linknode: NodeHash::from_str(CHANGESET_HASH_STR).unwrap(),
+            flags: flags,
+            delta: Delta::new_fulltext(&b"text"[..]),
+        };
+        let dir = MPath::new("dir").unwrap();
+        let file = MPath::new("dir/file").unwrap();
+        let parts = vec![
+            Part::CgChunk(Section::Changeset, chunk(CHANGESET_HASH_STR, 0)),
+            Part::SectionEnd(Section::Changeset),
+            Part::CgChunk(Section::Manifest, chunk(MANIFEST_HASH_STR, 0)),
+            Part::SectionEnd(Section::Manifest),
+            Part::CgChunk(Section::Treemanifest(dir.clone()), chunk(TREE_HASH_STR, 0)),
+            Part::SectionEnd(Section::Treemanifest(dir)),
+            Part::CgChunk(Section::Filelog(file.clone()), chunk(FILE_HASH_STR, 1 << 15)),
+            Part::SectionEnd(Section::Filelog(file)),
+            Part::End,
+        ];
+
+        let mut core = Core::new().unwrap();
+        assert_eq!(
+            roundtrip_parts(&mut core, CgVersion::Cg3, parts.clone()).unwrap(),
+            parts
+        );
+
+        // Without any directories or files, the end of the (empty) list of
+        // directories still needs to be sent.
+        let parts = vec![
+            Part::SectionEnd(Section::Changeset),
+            Part::SectionEnd(Section::Manifest),
+            Part::End,
+        ];
+        assert_eq!(
+            roundtrip_parts(&mut core, CgVersion::Cg3, parts.clone()).unwrap(),
+            parts
+        );
+    }
+
+    #[test]
+    fn test_cg2_rejects_cg3_features() {
+        let mut core = Core::new().unwrap();
+        let dir = MPath::new("dir").unwrap();
+        let mut chunk = CgDeltaChunk {
+            node: NodeHash::from_str(TREE_HASH_STR).unwrap(),
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            base: NULL_HASH,
+            linknode: NodeHash::from_str(CHANGESET_HASH_STR).unwrap(),
+            flags: 0,
+            delta: Delta::new_fulltext(&b"text"[..]),
+        };
+
+        let parts = vec![Part::CgChunk(Section::Treemanifest(dir), chunk.clone())];
+        assert_matches!(
+            roundtrip_parts(&mut core, CgVersion::Cg2, parts),
+            Err(Error(ErrorKind::Cg2Encode(_), _))
+        );
+
+        chunk.flags = 1 << 15;
+        let parts = vec![Part::CgChunk(Section::Filelog(MPath::new("file").unwrap()), chunk)];
+        assert_matches!(
+            roundtrip_parts(&mut core, CgVersion::Cg2, parts),
+            Err(Error(ErrorKind::Cg2Encode(_), _))
+        );
+    }
+
+    const CHANGESET_HASH_STR: &str = "b2040b24fd5cdfaf36e3164ddc357e834167b14a";
+    const MANIFEST_HASH_STR: &str = "afcff2144f55cfa5d9b04ac4ed6598f26035aa77";
+    const TREE_HASH_STR: &str = "aa93dc3435cbfecd0c4c245b80b2a0b9ed35a015";
+    const FILE_HASH_STR: &str = "b80de5d138758541c5f05265ad144ab9fa86d1db";
+
+    /// Pack and then unpack `parts`, without any partial reads or writes.
+    fn roundtrip_parts(core: &mut Core, version: CgVersion, parts: Vec<Part>) -> Result<Vec<Part>> {
+        let packer = CgPacker::new(version, stream::iter_ok::<_, Error>(parts));
+        let sink = FramedWrite::new(Cursor::new(Vec::new()), ChunkEncoder);
+        let (_, sink) = core.run(packer.forward(sink))?;
+        let mut cursor = sink.into_inner();
+        cursor.set_position(0);
+
+        let chunks = FramedRead::new(cursor, ChunkDecoder)
+            .map(|chunk| chunk.into_bytes().expect("expected normal chunk"));
+        let unpacker = CgUnpacker::new(make_root_logger(), version);
+        core.run(chunks.decode(unpacker).map(|x| x.cg2_part()).collect())
+    }
+
+    fn roundtrip_cg2(
+        seq: Cg2PartSequence,
+        write_ops: PartialWithErrors<GenWouldBlock>,
+        read_ops: PartialWithErrors<GenWouldBlock>,
+    ) -> TestResult {
+        roundtrip(CgVersion::Cg2, seq, write_ops, read_ops)
+    }
+
+    fn roundtrip_cg3(
+        seq: Cg2PartSequence,
+        write_ops: PartialWithErrors<GenWouldBlock>,
+        read_ops: PartialWithErrors<GenWouldBlock>,
+    ) -> TestResult {
+        roundtrip(CgVersion::Cg3, seq, write_ops, read_ops)
+    }
+
     fn roundtrip(
+        version: CgVersion,
         seq: Cg2PartSequence,
         write_ops: PartialWithErrors<GenWouldBlock>,
         read_ops: PartialWithErrors<GenWouldBlock>,
@@ -106,7 +281,7 @@ mod test {
         // Encode this sequence.
         let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
         let partial_write = PartialAsyncWrite::new(cursor, write_ops);
-        let packer = packer::Cg2Packer::new(seq.to_stream().and_then(|x| x));
+        let packer = CgPacker::new(version, seq.to_stream().and_then(|x| x));
         let sink = FramedWrite::new(partial_write, ChunkEncoder);
         let encode_fut = packer.forward(sink);
 
@@ -122,7 +297,7 @@ mod test {
             .map(|chunk| chunk.into_bytes().expect("expected normal chunk"));
 
         let logger = make_root_logger();
-        let unpacker = unpacker::Cg2Unpacker::new(logger);
+        let unpacker = CgUnpacker::new(logger, version);
         let part_stream = chunks.decode(unpacker);
 
         let parts = Vec::new();
@@ -13,23 +13,44 @@ use bytes::{BigEndian, BufMut};
 use chunk::Chunk;
 use errors::*;
 
-use super::{CgDeltaChunk, Part, Section};
+use mercurial_types::NodeHash;
 
-pub struct Cg2Packer<S> {
+use super::{CgDeltaChunk, CgVersion, Part, Section};
+
+pub struct CgPacker<S> {
+    version: CgVersion,
     delta_stream: S,
     last_seen: Section,
+    // In changegroup version 03, the tree manifest directories that follow the root manifests
+    // are terminated by an empty chunk. This is set until that's sent.
+    directories_open: bool,
+    // The last node sent in the current section, which deltas are against in version 01.
+    prev_node: Option<NodeHash>,
 }
 
-impl<S> Cg2Packer<S> {
-    pub fn new(delta_stream: S) -> Self {
-        Cg2Packer {
+impl<S> CgPacker<S> {
+    pub fn new(version: CgVersion, delta_stream: S) -> Self {
+        CgPacker {
+            version: version,
             delta_stream: delta_stream,
             last_seen: Section::Changeset,
+            directories_open: false,
+            prev_node: None,
+        }
+    }
+
+    /// The bytes to send before a new section or the end of the changegroup.
+    fn close_directories(&mut self) -> &'static [u8] {
+        if self.directories_open {
+            self.directories_open = false;
+            &EMPTY_CG_CHUNK
+        } else {
+            &[]
         }
     }
 }
 
-impl<S> Stream for Cg2Packer<S>
+impl<S> Stream for CgPacker<S>
 where
     S: Stream<Item = Part>,
     Error: From<S::Error>,
@@ -43,26 +64,61 @@ where
         match try_ready!(self.delta_stream.poll()) {
             None => Ok(Async::Ready(None)),
             Some(CgChunk(section, delta_chunk)) => {
-                let mut builder = ChunkBuilder::new();
+                let prefix = match section {
+                    Section::Treemanifest(_) if self.version != CgVersion::Cg3 => {
+                        bail!(ErrorKind::Cg2Encode(
+                            "tree manifests require changegroup version 03".into()
+                        ))
+                    }
+                    Section::Filelog(_) => self.close_directories(),
+                    _ => &[],
+                };
+                if self.version == CgVersion::Cg1 {
+                    let base = self.prev_node.unwrap_or(delta_chunk.p1);
+                    if delta_chunk.base != base {
+                        bail!(ErrorKind::Cg2Encode(format!(
+                            "changegroup version 01 can't send {} as a delta against {}, \
+                             only against {}",
+                            delta_chunk.node,
+                            delta_chunk.base,
+                            base
+                        )));
+                    }
+                    self.prev_node = Some(delta_chunk.node);
+                }
+                let mut builder = ChunkBuilder::with_prefix(prefix);
                 if self.last_seen != section {
                     builder.encode_section(&section)?;
                     self.last_seen = section;
                 }
-                builder.encode_delta_chunk(delta_chunk);
+                builder.encode_delta_chunk(self.version, delta_chunk)?;
                 Ok(Async::Ready(Some(builder.build()?)))
             }
-            Some(SectionEnd(_section)) => Ok(Async::Ready(Some(empty_cg_chunk()))),
-            Some(End) => Ok(Async::Ready(Some(empty_cg_chunk()))),
+            Some(SectionEnd(section)) => {
+                self.prev_node = None;
+                if self.version == CgVersion::Cg3 && section == Section::Manifest {
+                    self.directories_open = true;
+                }
+                Ok(Async::Ready(Some(empty_cg_chunk(&[]))))
+            }
+            Some(End) => {
+                let prefix = self.close_directories();
+                Ok(Async::Ready(Some(empty_cg_chunk(prefix))))
+            }
         }
     }
 }
 
-/// Produce an empty changegroup chunk.
+const EMPTY_CG_CHUNK: [u8; 4] = [0, 0, 0, 0];
+
+/// Produce an empty changegroup chunk, after `prefix`.
 ///
 /// Note that this is distinct from Chunk::empty() -- this is an actual chunk
 /// with a 4-byte payload.
-fn empty_cg_chunk() -> Chunk {
-    Chunk::new(vec![0, 0, 0, 0]).expect("Chunk::new should not fail for a 4-byte chunk")
+fn empty_cg_chunk(prefix: &[u8]) -> Chunk {
+    let mut inner = prefix.to_vec();
+    inner.extend_from_slice(&EMPTY_CG_CHUNK);
+    Chunk::new(inner).expect("Chunk::new should not fail for a short chunk")
 }
 
 #[derive(Debug)]
@@ -72,11 +128,14 @@ struct ChunkBuilder {
 }
 
 impl ChunkBuilder {
-    pub fn new() -> Self {
+    /// Start a chunk after `prefix`, which must be a sequence of complete changegroup chunks.
+    pub fn with_prefix(prefix: &[u8]) -> Self {
+        let mut inner = prefix.to_vec();
+        // Reserve four bytes in the beginning for the length.
+        inner.put_slice(&[0, 0, 0, 0]);
         ChunkBuilder {
-            // Reserve four bytes in the beginning for the length.
-            inner: vec![0, 0, 0, 0],
-            len_offset: 0,
+            inner: inner,
+            len_offset: prefix.len(),
         }
     }
 
@@ -85,35 +144,58 @@ impl ChunkBuilder {
     pub fn encode_section(&mut self, section: &Section) -> Result<&mut Self> {
         assert_eq!(
             self.inner.len(),
-            4,
+            self.len_offset + 4,
             "encode_section must only be called once at the start"
         );
         // Changeset and manifest sections are implicitly encoded, so we don't
-        // need to do anything there.
-        // TODO: will need to encode tree manifests here as well
-        if let &Section::Filelog(ref f) = section {
-            let f_vec = f.to_vec();
-            if f_vec.len() == 0 {
-                bail!(ErrorKind::Cg2Encode(
-                    "attempted to encode a zero-length path".into()
-                ));
-            }
-            // Note that the filename length must include the four bytes for itself.
-            BigEndian::write_i32(&mut self.inner[0..], (f_vec.len() + 4) as i32);
-            self.inner.put_slice(f_vec.as_slice());
-            // Add four more bytes for the start of the section.
-            self.len_offset = self.inner.len();
-            self.inner.put_slice(&[0, 0, 0, 0]);
+        // need to do anything there. Directories are encoded like filelogs, but
+        // with a trailing '/'.
+        let mut f_vec = match section {
+            &Section::Changeset | &Section::Manifest => return Ok(self),
+            &Section::Treemanifest(ref d) => d.to_vec(),
+            &Section::Filelog(ref f) => f.to_vec(),
+        };
+        if f_vec.len() == 0 {
+            bail!(ErrorKind::Cg2Encode(
+                "attempted to encode a zero-length path".into()
+            ));
+        }
+        if let &Section::Treemanifest(_) = section {
+            f_vec.push(b'/');
         }
+        // Note that the filename length must include the four bytes for itself.
+        let len_offset = self.len_offset;
+        BigEndian::write_i32(&mut self.inner[len_offset..], (f_vec.len() + 4) as i32);
+        self.inner.put_slice(f_vec.as_slice());
+        // Add four more bytes for the start of the section.
+        self.len_offset = self.inner.len();
+        self.inner.put_slice(&[0, 0, 0, 0]);
         Ok(self)
     }
 
-    pub fn encode_delta_chunk(&mut self, chunk: CgDeltaChunk) -> &mut Self {
+    pub fn encode_delta_chunk(
+        &mut self,
+        version: CgVersion,
+        chunk: CgDeltaChunk,
+    ) -> Result<&mut Self> {
         self.inner.put_slice(chunk.node.as_ref());
         self.inner.put_slice(chunk.p1.as_ref());
         self.inner.put_slice(chunk.p2.as_ref());
-        self.inner.put_slice(chunk.base.as_ref());
+        // Version 01 doesn't send the base, since it's implied.
+        if version != CgVersion::Cg1 {
+            self.inner.put_slice(chunk.base.as_ref());
+        }
         self.inner.put_slice(chunk.linknode.as_ref());
+        match version {
+            CgVersion::Cg1 | CgVersion::Cg2 => if chunk.flags != 0 {
+                bail!(ErrorKind::Cg2Encode(format!(
+                    "revlog flags {:#x} for {} require changegroup version 03",
+                    chunk.flags,
+                    chunk.node
+                )));
+            },
+            CgVersion::Cg3 => self.inner.put_u16::<BigEndian>(chunk.flags),
+        }
 
         for fragment in chunk.delta.fragments() {
             self.inner.put_i32::<BigEndian>(fragment.start as i32);
@@ -123,7 +205,7 @@ impl ChunkBuilder {
             self.inner.put_slice(&fragment.content[..]);
         }
 
-        self
+        Ok(self)
     }
 
     pub fn build(self) -> Result<Chunk> {
@@ -141,7 +223,7 @@ mod test {
 
     #[test]
     fn test_empty_filelog_path() {
-        let mut builder = ChunkBuilder::new();
+        let mut builder = ChunkBuilder::with_prefix(&[]);
         let section = Section::Filelog(MPath::new("").unwrap());
         assert_matches!(
             builder.encode_section(&section),
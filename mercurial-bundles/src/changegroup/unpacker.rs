@@ -14,19 +14,22 @@ use bytes::BytesMut;
 use slog;
 use tokio_io::codec::Decoder;
 
-use mercurial_types::{Delta, MPath};
+use mercurial_types::{Delta, MPath, NodeHash};
 use mercurial_types::delta::Fragment;
 
 use InnerPart;
 use errors::*;
 use utils::BytesExt;
 
-use super::{CgDeltaChunk, Part, Section};
+use super::{CgDeltaChunk, CgVersion, Part, Section};
 
 #[derive(Debug)]
-pub struct Cg2Unpacker {
+pub struct CgUnpacker {
     logger: slog::Logger,
+    version: CgVersion,
     state: State,
+    // The last node in the current section, which deltas are against in version 01.
+    prev_node: Option<NodeHash>,
 }
 
 impl Part {
@@ -59,13 +62,18 @@ impl Part {
 // See the chunk header definition below for the first 100 bytes. The last 4 is
 // for the length field itself.
 const CHUNK_HEADER_LEN: usize = 20 + 20 + 20 + 20 + 20 + 4;
+// Changegroup version 01 has no base node, and version 03 adds 2 bytes of
+// flags.
+const CG1_CHUNK_HEADER_LEN: usize = CHUNK_HEADER_LEN - 20;
+const CG3_CHUNK_HEADER_LEN: usize = CHUNK_HEADER_LEN + 2;
 
-impl Decoder for Cg2Unpacker {
+impl Decoder for CgUnpacker {
     type Item = InnerPart;
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<InnerPart>> {
-        match Self::decode_next(buf, self.state.take()) {
+        let state = self.state.take();
+        match self.decode_next(buf, state) {
             Err(e) => {
                 self.state = State::Invalid;
                 Err(e)
@@ -109,17 +117,19 @@ impl Decoder for Cg2Unpacker {
     }
 }
 
-impl Cg2Unpacker {
-    pub fn new(logger: slog::Logger) -> Self {
-        Cg2Unpacker {
+impl CgUnpacker {
+    pub fn new(logger: slog::Logger, version: CgVersion) -> Self {
+        CgUnpacker {
             logger: logger,
+            version: version,
             state: State::Changeset,
+            prev_node: None,
         }
     }
 
-    fn decode_next(buf: &mut BytesMut, state: State) -> Result<(Option<Part>, State)> {
+    fn decode_next(&mut self, buf: &mut BytesMut, state: State) -> Result<(Option<Part>, State)> {
         match state {
-            State::Changeset => match Self::decode_chunk(buf)? {
+            State::Changeset => match self.decode_chunk(buf)? {
                 None => Ok((None, State::Changeset)),
                 Some(CgChunk::Empty) => Ok((
                     Some(Part::SectionEnd(Section::Changeset)),
@@ -130,45 +140,70 @@ impl Cg2Unpacker {
                     State::Changeset,
                 )),
             },
-            State::Manifest => match Self::decode_chunk(buf)? {
+            State::Manifest => match self.decode_chunk(buf)? {
                 None => Ok((None, State::Manifest)),
                 Some(CgChunk::Empty) => {
-                    Ok((Some(Part::SectionEnd(Section::Manifest)), State::Filename))
+                    // In version 03, the root manifests are followed by directories.
+                    let next_state = match self.version {
+                        CgVersion::Cg1 | CgVersion::Cg2 => State::Filename,
+                        CgVersion::Cg3 => State::Dirname,
+                    };
+                    Ok((Some(Part::SectionEnd(Section::Manifest)), next_state))
                 }
                 Some(CgChunk::Delta(chunk)) => Ok((
                     Some(Part::CgChunk(Section::Manifest, chunk)),
                     State::Manifest,
                 )),
             },
+            State::Dirname => match Self::decode_filename(buf)? {
+                DecodeRes::None => Ok((None, State::Dirname)),
+                DecodeRes::Some(d) => self.decode_section_chunk(buf, Section::Treemanifest(d)),
+                // The end of the directories isn't reported, so go straight on to the files.
+                DecodeRes::End => self.decode_next(buf, State::Filename),
+            },
             State::Filename => {
                 let filename = Self::decode_filename(buf)?;
                 match filename {
                     DecodeRes::None => Ok((None, State::Filename)),
-                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f),
+                    DecodeRes::Some(f) => self.decode_section_chunk(buf, Section::Filelog(f)),
                     DecodeRes::End => Ok((Some(Part::End), State::End)),
                 }
             }
-            State::Filelog(filename) => Self::decode_filelog_chunk(buf, filename),
+            State::Section(section) => self.decode_section_chunk(buf, section),
             State::End => Ok((None, State::End)),
             State::Invalid => Err(ErrorKind::Cg2Decode("byte stream corrupt".into()).into()),
         }
     }
 
-    fn decode_filelog_chunk(buf: &mut BytesMut, f: MPath) -> Result<(Option<Part>, State)> {
-        match Self::decode_chunk(buf)? {
-            None => Ok((None, State::Filelog(f))),
-            Some(CgChunk::Empty) => Ok((
-                Some(Part::SectionEnd(Section::Filelog(f))),
-                State::Filename,
-            )),
+    /// Decode the next chunk of a directory or filelog section.
+    fn decode_section_chunk(
+        &mut self,
+        buf: &mut BytesMut,
+        section: Section,
+    ) -> Result<(Option<Part>, State)> {
+        match self.decode_chunk(buf)? {
+            None => Ok((None, State::Section(section))),
+            Some(CgChunk::Empty) => {
+                let next_state = match section {
+                    Section::Treemanifest(_) => State::Dirname,
+                    _ => State::Filename,
+                };
+                Ok((Some(Part::SectionEnd(section)), next_state))
+            }
             Some(CgChunk::Delta(chunk)) => Ok((
-                Some(Part::CgChunk(Section::Filelog(f.clone()), chunk)),
-                State::Filelog(f),
+                Some(Part::CgChunk(section.clone(), chunk)),
+                State::Section(section),
             )),
         }
     }
 
-    fn decode_chunk(buf: &mut BytesMut) -> Result<Option<CgChunk>> {
+    fn decode_chunk(&mut self, buf: &mut BytesMut) -> Result<Option<CgChunk>> {
+        let header_len = match self.version {
+            CgVersion::Cg1 => CG1_CHUNK_HEADER_LEN,
+            CgVersion::Cg2 => CHUNK_HEADER_LEN,
+            CgVersion::Cg3 => CG3_CHUNK_HEADER_LEN,
+        };
+
         if buf.len() < 4 {
             return Ok(None);
         }
@@ -179,12 +214,13 @@ impl Cg2Unpacker {
         let chunk_len = chunk_len as usize;
         if chunk_len == 0 {
             let _ = buf.drain_i32();
+            self.prev_node = None;
             return Ok(Some(CgChunk::Empty));
         }
-        if chunk_len < CHUNK_HEADER_LEN {
+        if chunk_len < header_len {
             let msg = format!(
                 "invalid chunk: length >= {} required, found {}",
-                CHUNK_HEADER_LEN,
+                header_len,
                 chunk_len
             );
             bail!(ErrorKind::Cg2Decode(msg));
@@ -202,21 +238,32 @@ impl Cg2Unpacker {
         // p2: NodeHash (20 bytes) -- NULL_HASH if only 1 parent
         // base node: NodeHash (20 bytes) (new in changegroup2)
         // link node: NodeHash (20 bytes)
+        // flags: u16 (new in changegroup3)
         // ---
 
         let node = buf.drain_node();
         let p1 = buf.drain_node();
         let p2 = buf.drain_node();
-        let base = buf.drain_node();
+        let base = match self.version {
+            // In version 01, the base is implied.
+            CgVersion::Cg1 => self.prev_node.unwrap_or(p1),
+            CgVersion::Cg2 | CgVersion::Cg3 => buf.drain_node(),
+        };
+        self.prev_node = Some(node);
         let linknode = buf.drain_node();
+        let flags = match self.version {
+            CgVersion::Cg1 | CgVersion::Cg2 => 0,
+            CgVersion::Cg3 => buf.drain_u16(),
+        };
 
-        let delta = Self::decode_delta(buf, chunk_len - CHUNK_HEADER_LEN)?;
+        let delta = Self::decode_delta(buf, chunk_len - header_len)?;
         return Ok(Some(CgChunk::Delta(CgDeltaChunk {
             node: node,
             p1: p1,
             p2: p2,
             base: base,
             linknode: linknode,
+            flags: flags,
             delta: delta,
         })));
     }
@@ -298,8 +345,9 @@ enum CgChunk {
 enum State {
     Changeset,
     Manifest,
+    Dirname,
     Filename,
-    Filelog(MPath),
+    Section(Section),
     End,
     Invalid,
 }
@@ -29,6 +29,14 @@ error_chain! {
             description("bundle2 encode error")
             display("{}", msg)
         }
+        Bundle1Decode(msg: String) {
+            description("bundle1 decode error")
+            display("bundle1 decode error: {}", msg)
+        }
+        Bundle1Encode(msg: String) {
+            description("bundle1 encode error")
+            display("bundle1 encode error: {}", msg)
+        }
         Bundle2Chunk(msg: String) {
             description("bundle2 chunk error")
             display("bundle2 chunk error: {}", msg)
@@ -45,6 +53,14 @@ error_chain! {
             description("error while generating listkey part")
             display("error while generating listkey part")
         }
+        ChangegroupGeneration {
+            description("error while generating changegroup part")
+            display("error while generating changegroup part")
+        }
+        PhaseHeadsGeneration {
+            description("error while generating phase-heads part")
+            display("error while generating phase-heads part")
+        }
     }
 
     foreign_links {
@@ -12,6 +12,8 @@ extern crate ascii;
 extern crate assert_matches;
 extern crate byteorder;
 extern crate bytes;
+#[cfg(test)]
+extern crate bzip2;
 #[macro_use]
 extern crate error_chain;
 #[macro_use]
@@ -38,11 +40,14 @@ extern crate mercurial_types;
 #[cfg(test)]
 extern crate partial_io;
 
+pub mod bundle1;
+pub mod bundle1_encode;
 pub mod bundle2;
 pub mod bundle2_encode;
 pub mod changegroup;
 mod chunk;
 pub mod parts;
+mod part_decode;
 pub mod part_encode;
 mod part_header;
 mod part_inner;
@@ -59,7 +64,9 @@ mod errors;
 pub use errors::*;
 mod utils;
 
-pub use bundle2_encode::Bundle2EncodeBuilder;
+pub use bundle1_encode::Bundle1Encode;
+pub use bundle2_encode::{Bundle2EncodeBuilder, Bundle2EncodeStream, StreamWriter};
+pub use part_decode::decode_caps;
 pub use part_header::PartHeader;
 pub use part_inner::InnerPart;
 pub use types::StreamHeader;
@@ -0,0 +1,349 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Decoders for the payloads of bundle2 parts other than changegroups.
+//!
+//! Parts whose contents are all in their params, like `pushkey` and `reply:*`, have no payload,
+//! and are decoded by `EmptyDecoder` to check that that's the case.
+
+use std::collections::HashMap;
+
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Bytes, BytesMut};
+use tokio_io::codec::Decoder;
+use url::percent_encoding::percent_decode;
+
+use errors::*;
+use part_inner::InnerPart;
+use utils::BytesExt;
+
+/// Split the next line off `buf`. At the end of the payload, whatever is left is the last line,
+/// since Mercurial doesn't terminate it with a newline.
+fn next_line(buf: &mut BytesMut, eof: bool) -> Option<Bytes> {
+    match buf.iter().position(|b| *b == b'\n') {
+        Some(idx) => {
+            let line = buf.split_to(idx).freeze();
+            let _ = buf.split_to(1);
+            Some(line)
+        }
+        None if eof && !buf.is_empty() => Some(buf.take().freeze()),
+        None => None,
+    }
+}
+
+/// Decoder for `listkeys` parts: a `<key>\t<value>` line for each key.
+#[derive(Debug)]
+pub struct ListkeysDecoder;
+
+impl ListkeysDecoder {
+    fn decode_line(buf: &mut BytesMut, eof: bool) -> Result<Option<InnerPart>> {
+        let mut key = match next_line(buf, eof) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        match key.iter().position(|b| *b == b'\t') {
+            Some(idx) => {
+                let value = key.split_off(idx + 1);
+                key.truncate(idx);
+                Ok(Some(InnerPart::Listkey(key, value)))
+            }
+            None => bail!(ErrorKind::Bundle2Decode(format!(
+                "listkeys entry {:?} has no value",
+                key
+            ))),
+        }
+    }
+}
+
+impl Decoder for ListkeysDecoder {
+    type Item = InnerPart;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<InnerPart>> {
+        Self::decode_line(buf, false)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<InnerPart>> {
+        Self::decode_line(buf, true)
+    }
+}
+
+/// Decoder for `replycaps` parts: a line for each capability, with its name and its
+/// comma-separated values (if any) all urlquoted, as in `<name>=<value>,<value>`.
+#[derive(Debug)]
+pub struct ReplycapsDecoder;
+
+impl ReplycapsDecoder {
+    fn decode_line(buf: &mut BytesMut, eof: bool) -> Result<Option<InnerPart>> {
+        loop {
+            match next_line(buf, eof) {
+                // Mercurial skips empty lines.
+                Some(ref line) if line.is_empty() => continue,
+                Some(line) => {
+                    let (name, vals) = decode_cap(&line)?;
+                    return Ok(Some(InnerPart::Replycap(name, vals)));
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Decoder for ReplycapsDecoder {
+    type Item = InnerPart;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<InnerPart>> {
+        Self::decode_line(buf, false)
+    }
+
+    fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<InnerPart>> {
+        Self::decode_line(buf, true)
+    }
+}
+
+/// Decode a single capability line.
+fn decode_cap(line: &[u8]) -> Result<(String, Vec<String>)> {
+    fn unquote(val: &[u8]) -> Result<String> {
+        Ok(percent_decode(val)
+            .decode_utf8()
+            .chain_err(|| ErrorKind::Bundle2Decode("capability is invalid UTF-8".into()))?
+            .into_owned())
+    }
+
+    let mut name_vals = line.splitn(2, |b| *b == b'=');
+    // splitn always returns at least one item.
+    let name = unquote(name_vals.next().unwrap())?;
+    let vals = match name_vals.next() {
+        Some(vals) => vals.split(|b| *b == b',')
+            .map(unquote)
+            .collect::<Result<Vec<_>>>()?,
+        None => Vec::new(),
+    };
+    Ok((name, vals))
+}
+
+/// Decode a set of bundle2 capabilities, in the format of a `replycaps` payload. This is also
+/// how clients advertise them to the server in `bundlecaps`, once unquoted.
+pub fn decode_caps(blob: &[u8]) -> Result<HashMap<String, Vec<String>>> {
+    blob.split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(decode_cap)
+        .collect()
+}
+
+// node (20 bytes) + name length (2 bytes)
+const BOOKMARK_HEADER_LEN: usize = 20 + 2;
+
+/// Decoder for `bookmarks` parts: each entry is the node the bookmark points to, or the null
+/// hash if it's being deleted, followed by the bookmark name and its length.
+#[derive(Debug)]
+pub struct BookmarksDecoder;
+
+impl Decoder for BookmarksDecoder {
+    type Item = InnerPart;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<InnerPart>> {
+        if buf.len() < BOOKMARK_HEADER_LEN {
+            return Ok(None);
+        }
+        let name_len = BigEndian::read_u16(&buf[20..BOOKMARK_HEADER_LEN]) as usize;
+        if buf.len() < BOOKMARK_HEADER_LEN + name_len {
+            return Ok(None);
+        }
+
+        let node = buf.drain_node();
+        let _ = buf.drain_u16();
+        let name = buf.split_to(name_len).freeze();
+        Ok(Some(InnerPart::Bookmark(name, node)))
+    }
+}
+
+// phase (4 bytes) + node (20 bytes)
+const PHASE_HEAD_LEN: usize = 4 + 20;
+
+/// Decoder for `phase-heads` parts: each entry is a phase followed by one of its heads.
+#[derive(Debug)]
+pub struct PhaseHeadsDecoder;
+
+impl Decoder for PhaseHeadsDecoder {
+    type Item = InnerPart;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<InnerPart>> {
+        if buf.len() < PHASE_HEAD_LEN {
+            return Ok(None);
+        }
+
+        let phase = buf.drain_u32();
+        let node = buf.drain_node();
+        Ok(Some(InnerPart::PhaseHead(phase, node)))
+    }
+}
+
+/// Decoder for `check:heads` parts: the payload is the heads, one after the other.
+#[derive(Debug)]
+pub struct CheckHeadsDecoder;
+
+impl Decoder for CheckHeadsDecoder {
+    type Item = InnerPart;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<InnerPart>> {
+        if buf.len() < 20 {
+            return Ok(None);
+        }
+
+        Ok(Some(InnerPart::CheckHead(buf.drain_node())))
+    }
+}
+
+/// Decoder for parts that don't have a payload.
+#[derive(Debug)]
+pub struct EmptyDecoder {
+    part_type: String,
+}
+
+impl EmptyDecoder {
+    pub fn new<S: Into<String>>(part_type: S) -> Self {
+        EmptyDecoder {
+            part_type: part_type.into(),
+        }
+    }
+}
+
+impl Decoder for EmptyDecoder {
+    type Item = InnerPart;
+    type Error = Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<InnerPart>> {
+        if !buf.is_empty() {
+            bail!(ErrorKind::Bundle2Decode(format!(
+                "part '{}' has an unexpected payload of {} bytes",
+                self.part_type,
+                buf.len()
+            )));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::str::FromStr;
+
+    use mercurial_types::{NodeHash, NULL_HASH};
+
+    use super::*;
+
+    const NODE_STR: &str = "b2040b24fd5cdfaf36e3164ddc357e834167b14a";
+
+    fn decode_all<D>(mut decoder: D, input: &[u8]) -> Result<Vec<InnerPart>>
+    where
+        D: Decoder<Item = InnerPart, Error = Error>,
+    {
+        let mut buf = BytesMut::from(input);
+        let mut parts = Vec::new();
+        while let Some(part) = decoder.decode(&mut buf)? {
+            parts.push(part);
+        }
+        while !buf.is_empty() {
+            match decoder.decode_eof(&mut buf)? {
+                Some(part) => parts.push(part),
+                None => break,
+            }
+        }
+        Ok(parts)
+    }
+
+    #[test]
+    fn test_listkeys() {
+        let parts = decode_all(ListkeysDecoder, b"foo\tbar\nbaz\tquux\tx").unwrap();
+        assert_eq!(
+            parts,
+            vec![
+                InnerPart::Listkey(Bytes::from("foo"), Bytes::from("bar")),
+                InnerPart::Listkey(Bytes::from("baz"), Bytes::from("quux\tx")),
+            ]
+        );
+
+        assert!(decode_all(ListkeysDecoder, b"foo\n").is_err());
+    }
+
+    #[test]
+    fn test_replycaps() {
+        let input = b"HG20\n\nchangegroup=01,02\nab%3Dc=d%2Ce";
+        let parts = decode_all(ReplycapsDecoder, input).unwrap();
+        assert_eq!(
+            parts,
+            vec![
+                InnerPart::Replycap("HG20".into(), vec![]),
+                InnerPart::Replycap("changegroup".into(), vec!["01".into(), "02".into()]),
+                InnerPart::Replycap("ab=c".into(), vec!["d,e".into()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_decode_caps() {
+        let caps = decode_caps(b"HG20\nchangegroup=02,03\n").unwrap();
+        assert_eq!(caps.len(), 2);
+        assert_eq!(caps["HG20"], Vec::<String>::new());
+        assert_eq!(caps["changegroup"], vec!["02".to_string(), "03".to_string()]);
+    }
+
+    #[test]
+    fn test_bookmarks() {
+        let node = NodeHash::from_str(NODE_STR).unwrap();
+        let mut input = Vec::new();
+        input.extend_from_slice(node.as_ref());
+        input.extend_from_slice(b"\x00\x06master");
+        input.extend_from_slice(NULL_HASH.as_ref());
+        input.extend_from_slice(b"\x00\x03old");
+
+        let parts = decode_all(BookmarksDecoder, &input).unwrap();
+        assert_eq!(
+            parts,
+            vec![
+                InnerPart::Bookmark(Bytes::from("master"), node),
+                InnerPart::Bookmark(Bytes::from("old"), NULL_HASH),
+            ]
+        );
+
+        assert!(decode_all(BookmarksDecoder, &input[..30]).is_err());
+    }
+
+    #[test]
+    fn test_phase_heads() {
+        let node = NodeHash::from_str(NODE_STR).unwrap();
+        let mut input = b"\x00\x00\x00\x01".to_vec();
+        input.extend_from_slice(node.as_ref());
+
+        let parts = decode_all(PhaseHeadsDecoder, &input).unwrap();
+        assert_eq!(parts, vec![InnerPart::PhaseHead(1, node)]);
+    }
+
+    #[test]
+    fn test_check_heads() {
+        let node = NodeHash::from_str(NODE_STR).unwrap();
+        let mut input = node.as_ref().to_vec();
+        input.extend_from_slice(NULL_HASH.as_ref());
+
+        let parts = decode_all(CheckHeadsDecoder, &input).unwrap();
+        assert_eq!(
+            parts,
+            vec![InnerPart::CheckHead(node), InnerPart::CheckHead(NULL_HASH)]
+        );
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(decode_all(EmptyDecoder::new("pushkey"), b"").unwrap(), vec![]);
+        assert!(decode_all(EmptyDecoder::new("pushkey"), b"x").is_err());
+    }
+}
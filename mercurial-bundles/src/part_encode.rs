@@ -8,6 +8,7 @@
 
 use std::fmt::{self, Debug, Formatter};
 use std::mem;
+use std::vec::IntoIter;
 
 use bytes::Bytes;
 use futures::{Async, Future, Poll, Stream};
@@ -15,6 +16,7 @@ use futures::{Async, Future, Poll, Stream};
 use chunk::Chunk;
 use errors::*;
 use part_header::{PartHeader, PartHeaderBuilder};
+use parts;
 
 /// Represents a stream of chunks produced by the individual part handler.
 pub struct ChunkStream(Box<Stream<Item = Chunk, Error = Error> + Send>);
@@ -47,6 +49,7 @@ enum GenerationState {
     NotStarted(PartHeader, PartEncodeData),
     Fixed(Chunk),
     Generating(ChunkStream),
+    Interrupted(IntoIter<Chunk>),
     EmptyChunk,
     Done,
     Invalid,
@@ -151,6 +154,7 @@ impl PartEncode {
         // NotStarted = header not output yet
         // Generating = payload currently being generated by inner stream
         // Fixed = fixed-length payload (no generation, just one chunk)
+        // Interrupted = payload generation failed, sending an error part instead
         // EmptyChunk = end of payload (or no payload)
         // Done = chunk completed
         // Invalid = some sort of error occured
@@ -176,14 +180,40 @@ impl PartEncode {
                     }
                     Ok(Async::Ready(None)) => (Ok(Async::Ready(Some(Chunk::empty()))), Done),
                     Ok(Async::NotReady) => (Ok(Async::NotReady), Generating(ChunkStream(stream))),
-                    // TODO: produce an error part for (some kinds of?) errors
-                    Err(e) => (Err(e), Generating(ChunkStream(stream))),
+                    Err(e) => Self::interrupt(e),
                 }
             }
+            Interrupted(mut chunks) => match chunks.next() {
+                Some(chunk) => (Ok(Async::Ready(Some(chunk))), Interrupted(chunks)),
+                None => (Ok(Async::Ready(None)), Done),
+            },
             Fixed(chunk) => (Ok(Async::Ready(Some(chunk))), EmptyChunk),
             EmptyChunk => (Ok(Async::Ready(Some(Chunk::empty()))), Done),
             Done => (Ok(Async::Ready(None)), Done),
             Invalid => panic!("invalid state"),
         }
     }
+    /// Replace the rest of the payload with an error part describing `err`, so that the client
+    /// aborts with a meaningful message instead of waiting for data that never arrives.
+    ///
+    /// The error part is sent as an interruption: an error chunk followed by the part, which
+    /// the client handles straight away. The interrupted part is then closed with an empty
+    /// chunk.
+    fn interrupt(err: Error) -> (Poll<Option<Chunk>, Error>, GenerationState) {
+        let error_part = match parts::error_part(&err) {
+            Ok(error_part) => error_part,
+            // Nothing better to do than to fail the stream.
+            Err(_) => return (Err(err), GenerationState::Invalid),
+        };
+
+        let chunks = vec![
+            error_part.headerb.build(0).encode(),
+            Chunk::empty(),
+            Chunk::empty(),
+        ].into_iter();
+        (
+            Ok(Async::Ready(Some(Chunk::error()))),
+            GenerationState::Interrupted(chunks),
+        )
+    }
 }
@@ -18,9 +18,13 @@ use bytes::Bytes;
 use futures::stream::Map;
 use tokio_io::AsyncRead;
 
+use mercurial_types::NodeHash;
+
 use changegroup;
 use errors::*;
 use futures_ext::{BoxStreamWrapper, StreamExt, StreamLayeredExt, TakeWhile};
+use part_decode::{BookmarksDecoder, CheckHeadsDecoder, EmptyDecoder, ListkeysDecoder,
+                  PhaseHeadsDecoder, ReplycapsDecoder};
 use part_header::PartHeader;
 use part_outer::{OuterFrame, OuterStream};
 
@@ -28,8 +32,7 @@ use part_outer::{OuterFrame, OuterStream};
 
 macro_rules! add_part {
     ( $m:expr, $part_type:expr, [$( $params:expr ),*] ) => {{
-        let mut h = HashSet::new();
-        $(h.insert($params);)*
+        let h: HashSet<&'static str> = [$( $params ),*].iter().cloned().collect();
         $m.insert(AsciiStr::from_ascii($part_type).unwrap(), h);
     }}
 }
@@ -38,6 +41,16 @@ lazy_static! {
     static ref KNOWN_PARAMS: HashMap<&'static AsciiStr, HashSet<&'static str>> = {
         let mut m: HashMap<&'static AsciiStr, HashSet<&'static str>> = HashMap::new();
         add_part!(m, "changegroup", ["version", "nbchanges", "treemanifest"]);
+        add_part!(m, "b2x:rebase", ["onto", "newhead", "obsmarkerversions", "cgversion"]);
+        add_part!(m, "listkeys", ["namespace"]);
+        add_part!(m, "pushkey", ["namespace", "key", "old", "new"]);
+        add_part!(m, "bookmarks", []);
+        add_part!(m, "phase-heads", []);
+        add_part!(m, "reply:changegroup", ["return", "in-reply-to"]);
+        add_part!(m, "reply:pushkey", ["return", "in-reply-to"]);
+        add_part!(m, "reply:obsmarkers", ["new", "in-reply-to"]);
+        add_part!(m, "check:heads", []);
+        add_part!(m, "replycaps", []);
         m
     };
 }
@@ -68,18 +81,30 @@ pub type BoxInnerStream<T> = Box<InnerStream<T, Item = InnerPart, Error = Error>
 #[derive(Debug, Eq, PartialEq)]
 pub enum InnerPart {
     Cg2(changegroup::Part),
+    /// A key and its value from a `listkeys` part.
+    Listkey(Bytes, Bytes),
+    /// A bookmark and the node it's being moved to, or `NULL_HASH` if it's being deleted.
+    Bookmark(Bytes, NodeHash),
+    /// A phase, and one of the heads of the changesets in it.
+    PhaseHead(u32, NodeHash),
+    /// One of the heads the client expects the repo to have.
+    CheckHead(NodeHash),
+    /// A capability the client supports in replies, and its values.
+    Replycap(String, Vec<String>),
 }
 
 impl InnerPart {
     pub fn is_cg2(&self) -> bool {
         match self {
             &InnerPart::Cg2(_) => true,
+            _ => false,
         }
     }
 
     pub fn cg2_part(self) -> changegroup::Part {
         match self {
             InnerPart::Cg2(part) => part,
+            _ => panic!("cg2_part called on an InnerPart that isn't Cg2!"),
         }
     }
 }
@@ -112,25 +137,53 @@ pub fn validate_header(header: PartHeader) -> Result<Option<PartHeader>> {
 }
 
 /// Convert an OuterStream into an InnerStream using the part header.
+///
+/// Only parts that made it through `validate_header` should get here, so failing to find a
+/// decoder means the two are out of sync.
 pub fn inner_stream<R: AsyncRead>(
     header: &PartHeader,
     stream: OuterStream<R>,
     logger: &slog::Logger,
-) -> BoxInnerStream<R> {
+) -> Result<BoxInnerStream<R>> {
     // The casts are required for Rust to not complain about "expected fn
     // pointer, found fn item". See http://stackoverflow.com/q/34787928.
     let wrapped_stream: WrappedStream<R> = stream
         .take_while_wrapper(is_payload_fut as fn(&OuterFrame) -> BoolFuture)
         .map(OuterFrame::get_payload as fn(OuterFrame) -> Bytes);
-    match header.part_type_lower().as_str() {
-        "changegroup" => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
+    let part_type = header.part_type_lower().as_str();
+    let inner: BoxInnerStream<R> = match part_type {
+        // Pushrebase parts are changegroups that the server rebases before applying.
+        "changegroup" | "b2x:rebase" => {
+            let version_param = if part_type == "changegroup" {
+                "version"
+            } else {
+                "cgversion"
+            };
+            let version = match header.mparams().get(version_param) {
+                Some(version) => str::from_utf8(version)
+                    .chain_err(|| ErrorKind::Bundle2Decode("invalid changegroup version".into()))?
+                    .parse::<changegroup::CgVersion>()?,
+                None => bail!(ErrorKind::Bundle2Decode(
+                    "changegroup part has no version".into()
+                )),
+            };
+            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::CgUnpacker::new(
                 logger.new(o!("stream" => "cg2")),
+                version,
             ));
             Box::new(cg2_stream)
         }
-        _ => panic!("TODO: make this an error"),
-    }
+        "listkeys" => Box::new(wrapped_stream.decode(ListkeysDecoder)),
+        "bookmarks" => Box::new(wrapped_stream.decode(BookmarksDecoder)),
+        "phase-heads" => Box::new(wrapped_stream.decode(PhaseHeadsDecoder)),
+        "check:heads" => Box::new(wrapped_stream.decode(CheckHeadsDecoder)),
+        "replycaps" => Box::new(wrapped_stream.decode(ReplycapsDecoder)),
+        "pushkey" | "reply:changegroup" | "reply:pushkey" | "reply:obsmarkers" => {
+            Box::new(wrapped_stream.decode(EmptyDecoder::new(part_type)))
+        }
+        _ => bail!(ErrorKind::BundleUnknownPart(header.clone())),
+    };
+    Ok(inner)
 }
 
 fn is_payload_fut(item: &OuterFrame) -> BoolFuture {
@@ -4,9 +4,13 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use bytes::Bytes;
+use bytes::{BigEndian, BufMut, Bytes};
 use futures::{Future, Stream};
 
+use mercurial_types::NodeHash;
+
+use changegroup::{CgVersion, Part};
+use changegroup::packer::CgPacker;
 use errors::*;
 use part_encode::PartEncodeBuilder;
 
@@ -36,3 +40,149 @@ where
 
     Ok(builder)
 }
+
+pub fn changegroup_part<S>(changelogentries: S, version: CgVersion) -> Result<PartEncodeBuilder>
+where
+    S: Stream<Item = Part> + Send + 'static,
+    S::Error: ::std::error::Error + Send,
+{
+    let mut builder = PartEncodeBuilder::mandatory("changegroup")?;
+    builder.add_mparam("version", version.as_str())?;
+
+    let changelogentries =
+        changelogentries.map_err(|err| Error::with_chain(err, ErrorKind::ChangegroupGeneration));
+    builder.set_data_generated(CgPacker::new(version, changelogentries));
+
+    Ok(builder)
+}
+
+/// A `phase-heads` part, which gives the phases of the changesets in a bundle as the heads of
+/// the changesets in each phase. Each item is a phase, as the number Mercurial uses for it, and
+/// one of its heads.
+pub fn phase_heads_part<S>(heads: S) -> Result<PartEncodeBuilder>
+where
+    S: Stream<Item = (u32, NodeHash)> + Send + 'static,
+    S::Error: ::std::error::Error + Send,
+{
+    let mut builder = PartEncodeBuilder::mandatory("phase-heads")?;
+    let fut = heads
+        .fold(Vec::new(), |mut payload, (phase, node)| {
+            payload.put_u32::<BigEndian>(phase);
+            payload.extend_from_slice(node.as_ref());
+            Ok(payload)
+        })
+        .or_else(|err| Err(err).chain_err(|| ErrorKind::PhaseHeadsGeneration));
+
+    builder.set_data_future(fut);
+
+    Ok(builder)
+}
+
+/// Size of a version 1 obsolescence marker with one successor, no parents and no metadata.
+const OBSMARKER_SIZE: u32 = 4 + 8 + 2 + 2 + 1 + 1 + 1 + 20 + 20;
+
+/// An `obsmarkers` part, with a version 1 marker for each pair of a changeset and the one that
+/// replaced it. `time` is when they were replaced, in seconds since the epoch (UTC).
+pub fn obsmarkers_part(
+    replacements: &[(NodeHash, NodeHash)],
+    time: f64,
+) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::advisory("obsmarkers")?;
+
+    let mut payload = Vec::with_capacity(1 + replacements.len() * OBSMARKER_SIZE as usize);
+    payload.put_u8(1);
+    for &(ref old, ref new) in replacements {
+        payload.put_u32::<BigEndian>(OBSMARKER_SIZE);
+        payload.put_f64::<BigEndian>(time);
+        // Timezone offset in minutes, and flags
+        payload.put_i16::<BigEndian>(0);
+        payload.put_u16::<BigEndian>(0);
+        // One successor, parents not recorded, and no metadata
+        payload.put_u8(1);
+        payload.put_u8(3);
+        payload.put_u8(0);
+        payload.extend_from_slice(old.as_ref());
+        payload.extend_from_slice(new.as_ref());
+    }
+    builder.set_data_bytes(payload)?;
+
+    Ok(builder)
+}
+
+/// The reply to a changegroup part received as part of an unbundle. `ret` has the same meaning
+/// as the return value of Mercurial's `addchangegroup` (0 for failure, 1 + the number of added
+/// heads, or -1 - the number of removed heads), and `in_reply_to` is the id of the changegroup
+/// part being replied to.
+pub fn replychangegroup_part(ret: i64, in_reply_to: u32) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::advisory("reply:changegroup")?;
+    builder.add_aparam("return", format!("{}", ret))?;
+    builder.add_aparam("in-reply-to", format!("{}", in_reply_to))?;
+
+    Ok(builder)
+}
+
+/// Part parameter values are at most this many bytes long.
+const MAX_PARAM_LEN: usize = 255;
+
+/// Truncate `val` to fit in a part parameter, without splitting a character.
+fn param_value(val: &str) -> String {
+    let mut end = ::std::cmp::min(val.len(), MAX_PARAM_LEN);
+    while !val.is_char_boundary(end) {
+        end -= 1;
+    }
+    val[..end].to_string()
+}
+
+/// An `error:abort` part, which makes the client abort with `message`, followed by `hint` if
+/// there is one. Both are truncated to the maximum length of a parameter.
+pub fn error_abort_part(message: &str, hint: Option<&str>) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory("error:abort")?;
+    let message = param_value(message);
+    builder.add_mparam(
+        "message",
+        if message.is_empty() {
+            "unknown error".to_string()
+        } else {
+            message
+        },
+    )?;
+    if let Some(hint) = hint.map(param_value) {
+        if !hint.is_empty() {
+            builder.add_aparam("hint", hint)?;
+        }
+    }
+
+    Ok(builder)
+}
+
+/// An `error:unsupportedcontent` part, telling the client that a part of type `part_type`, or
+/// some of its `params`, aren't supported.
+pub fn error_unsupportedcontent_part<S>(part_type: &str, params: &[S]) -> Result<PartEncodeBuilder>
+where
+    S: AsRef<str>,
+{
+    let mut builder = PartEncodeBuilder::mandatory("error:unsupportedcontent")?;
+    builder.add_mparam("parttype", param_value(part_type))?;
+    let params: Vec<_> = params.iter().map(|param| param.as_ref()).collect();
+    let params = param_value(&params.join("\0"));
+    if !params.is_empty() {
+        builder.add_mparam("params", params)?;
+    }
+
+    Ok(builder)
+}
+
+/// The part reporting `err` to the client: `error:unsupportedcontent` for unknown parts and
+/// params, and `error:abort` for everything else. Only the top-level message is sent, as the
+/// causes of the error are internal details of the server.
+pub fn error_part(err: &Error) -> Result<PartEncodeBuilder> {
+    match err.kind() {
+        &ErrorKind::BundleUnknownPart(ref header) => {
+            error_unsupportedcontent_part::<&str>(header.part_type().as_str(), &[])
+        }
+        &ErrorKind::BundleUnknownPartParams(ref part_type, ref params) => {
+            error_unsupportedcontent_part(part_type.as_str(), params)
+        }
+        _ => error_abort_part(&err.to_string(), None),
+    }
+}
@@ -198,6 +198,8 @@ impl Arbitrary for changegroup::CgDeltaChunk {
             p2: NodeHash::arbitrary(g),
             base: NodeHash::arbitrary(g),
             linknode: NodeHash::arbitrary(g),
+            // Flags can only be sent in some changegroup versions.
+            flags: 0,
             delta: Delta::arbitrary(g),
         }
     }
@@ -212,6 +214,7 @@ impl Arbitrary for changegroup::CgDeltaChunk {
                 p2: clone.p2.clone(),
                 base: clone.base.clone(),
                 linknode: clone.linknode.clone(),
+                flags: clone.flags,
                 delta: delta,
             }
         }))
@@ -10,7 +10,9 @@ use std::fmt::Debug;
 use std::io::{self, Cursor};
 use std::str::FromStr;
 
-use futures::stream::Stream;
+use ascii::AsciiString;
+use bzip2;
+use futures::stream::{self, Stream};
 use slog::{Drain, Logger};
 use slog_term;
 use tokio_core::reactor::Core;
@@ -18,18 +20,22 @@ use tokio_io::AsyncRead;
 
 use async_compression::{CompressorType, ZSTD_DEFAULT_LEVEL};
 use async_compression::membuf::MemBuf;
-use mercurial_types::{MPath, NodeHash, NULL_HASH};
+use mercurial_types::{Delta, MPath, NodeHash, NULL_HASH};
 use partial_io::{GenWouldBlock, PartialAsyncRead, PartialWithErrors};
 use quickcheck::{QuickCheck, StdGen};
 use rand;
 
-use Bundle2Item;
+use {Bundle1Encode, Bundle2Item, InnerPart};
+use bundle1::Bundle1Stream;
 use bundle2::Bundle2Stream;
 use bundle2_encode::Bundle2EncodeBuilder;
-use changegroup;
+use changegroup::{self, CgVersion};
+use changegroup::packer::CgPacker;
+use chunk::Chunk;
 use errors::*;
 use part_encode::PartEncodeBuilder;
-use part_header::PartHeaderBuilder;
+use part_header::{self, PartHeaderBuilder};
+use parts;
 use types::StreamHeader;
 use utils::get_compression_param;
 
@@ -87,6 +93,11 @@ fn test_empty_bundle_roundtrip_zstd() {
     });
 }
 
+#[test]
+fn test_empty_bundle_roundtrip_gzip() {
+    empty_bundle_roundtrip(CompressorType::Gzip);
+}
+
 #[test]
 fn test_empty_bundle_roundtrip_uncompressed() {
     empty_bundle_roundtrip(CompressorType::Uncompressed);
@@ -184,6 +195,411 @@ fn unknown_part(ct: CompressorType) {
                     if header.part_type() == "UNKNOWN:UNKNOWN");
 }
 
+#[test]
+fn test_changegroup_part_roundtrip() {
+    let changeset1_hash = NodeHash::from_str(CHANGESET1_HASH_STR).unwrap();
+    let manifest1_hash = NodeHash::from_str(MANIFEST1_HASH_STR).unwrap();
+    let abch = NodeHash::from_str(ABC_HASH_STR).unwrap();
+    let chunk = |node, text: &[u8]| changegroup::CgDeltaChunk {
+        node: node,
+        p1: NULL_HASH,
+        p2: NULL_HASH,
+        base: NULL_HASH,
+        linknode: changeset1_hash,
+        flags: 0,
+        delta: Delta::new_fulltext(text),
+    };
+
+    let abc = changegroup::Section::Filelog(path(b"abc"));
+    let input = vec![
+        changegroup::Part::CgChunk(
+            changegroup::Section::Changeset,
+            chunk(changeset1_hash, b"changeset"),
+        ),
+        changegroup::Part::SectionEnd(changegroup::Section::Changeset),
+        changegroup::Part::CgChunk(
+            changegroup::Section::Manifest,
+            chunk(manifest1_hash, b"manifest"),
+        ),
+        changegroup::Part::SectionEnd(changegroup::Section::Manifest),
+        changegroup::Part::CgChunk(abc.clone(), chunk(abch, b"file")),
+        changegroup::Part::SectionEnd(abc),
+        changegroup::Part::End,
+    ];
+
+    let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
+    let mut builder = Bundle2EncodeBuilder::new(cursor);
+    builder.set_compressor_type(CompressorType::Uncompressed);
+    let cg_stream = stream::iter_ok::<_, Error>(input.clone());
+    let part = parts::changegroup_part(cg_stream, CgVersion::Cg2).unwrap();
+    builder.add_part(part);
+    let encode_fut = builder.build();
+
+    let mut core = Core::new().unwrap();
+    let mut buf = core.run(encode_fut).unwrap();
+    buf.set_position(0);
+
+    let logger = make_root_logger();
+    let stream = Bundle2Stream::new(buf, logger);
+    let decode_fut = stream
+        .map_err(|e| -> () { panic!("unexpected error: {}", e) })
+        .forward(Vec::new());
+    let (_stream, items) = core.run(decode_fut).unwrap();
+
+    let mut header = PartHeaderBuilder::new("CHANGEGROUP").unwrap();
+    header.add_mparam("version", "02").unwrap();
+    let header = header.build(0);
+    assert_eq!(items[1], Bundle2Item::Header(header));
+
+    let output: Vec<_> = items
+        .into_iter()
+        .skip(2)
+        .map(|item| item.inner_part().cg2_part())
+        .collect();
+    assert_eq!(output, input);
+}
+
+#[test]
+fn test_stream_bundle_roundtrip() {
+    let changeset1_hash = NodeHash::from_str(CHANGESET1_HASH_STR).unwrap();
+    let chunk = |text: &[u8]| changegroup::CgDeltaChunk {
+        node: changeset1_hash,
+        p1: NULL_HASH,
+        p2: NULL_HASH,
+        base: NULL_HASH,
+        linknode: changeset1_hash,
+        flags: 0,
+        delta: Delta::new_fulltext(text),
+    };
+
+    // Big enough that the bundle has to be streamed in several pieces.
+    let content = vec![b'x'; 256 * 1024];
+    let input = vec![
+        changegroup::Part::CgChunk(changegroup::Section::Changeset, chunk(&content)),
+        changegroup::Part::SectionEnd(changegroup::Section::Changeset),
+        changegroup::Part::SectionEnd(changegroup::Section::Manifest),
+        changegroup::Part::End,
+    ];
+
+    let mut builder = Bundle2EncodeBuilder::new_stream();
+    let cg_stream = stream::iter_ok::<_, Error>(input.clone());
+    let part = parts::changegroup_part(cg_stream, CgVersion::Cg2).unwrap();
+    builder.add_part(part);
+
+    let mut core = Core::new().unwrap();
+    let chunks = core.run(builder.build_stream().collect()).unwrap();
+    assert!(chunks.len() > 1, "expected several chunks, got {}", chunks.len());
+
+    let buf: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.iter().cloned()).collect();
+    let stream = Bundle2Stream::new(Cursor::new(buf), make_root_logger());
+    let items = core.run(stream.collect()).unwrap();
+
+    let output: Vec<_> = items
+        .into_iter()
+        .skip(2)
+        .map(|item| item.inner_part().cg2_part())
+        .collect();
+    assert_eq!(output, input);
+}
+
+#[test]
+fn test_bundle1_roundtrip_uncompressed() {
+    bundle1_roundtrip(CompressorType::Uncompressed);
+}
+
+#[test]
+fn test_bundle1_roundtrip_bzip2() {
+    bundle1_roundtrip(CompressorType::Bzip2(bzip2::Compression::Default));
+}
+
+#[test]
+fn test_bundle1_roundtrip_gzip() {
+    bundle1_roundtrip(CompressorType::Gzip);
+}
+
+fn bundle1_roundtrip(ct: CompressorType) {
+    let input = bundle1_parts();
+
+    let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
+    let cg_stream = stream::iter_ok::<_, Error>(input.clone());
+    let encode_fut = Bundle1Encode::new(cursor, ct, cg_stream).unwrap();
+
+    let mut core = Core::new().unwrap();
+    let mut buf = core.run(encode_fut).unwrap();
+    assert_eq!(&buf.get_ref()[..4], b"HG10");
+    assert_eq!(&buf.get_ref()[4..6], get_compression_param(&ct).as_bytes());
+    buf.set_position(0);
+
+    let stream = Bundle1Stream::new(buf, make_root_logger());
+    let output = core.run(stream.collect()).unwrap();
+    assert_eq!(output, input);
+}
+
+#[test]
+fn test_bundle1_rejects_explicit_base() {
+    let mut input = bundle1_parts();
+    // The second changeset can only be sent as a delta against the first one.
+    if let changegroup::Part::CgChunk(_, ref mut chunk) = input[1] {
+        chunk.base = NULL_HASH;
+    }
+
+    let cursor = Cursor::new(Vec::new());
+    let cg_stream = stream::iter_ok::<_, Error>(input);
+    let encode_fut = Bundle1Encode::new(cursor, CompressorType::Uncompressed, cg_stream).unwrap();
+
+    let mut core = Core::new().unwrap();
+    let err = core.run(encode_fut).unwrap_err();
+    assert_matches!(err.kind(), &ErrorKind::Cg2Encode(_));
+}
+
+#[test]
+fn test_bundle1_rejects_zstd() {
+    let cg_stream = stream::iter_ok::<_, Error>(bundle1_parts());
+    let ct = CompressorType::Zstd {
+        level: ZSTD_DEFAULT_LEVEL,
+    };
+    let err = Bundle1Encode::new(Cursor::new(Vec::new()), ct, cg_stream)
+        .err()
+        .expect("zstd should be rejected for HG10 bundles");
+    assert_matches!(err.kind(), &ErrorKind::Bundle1Encode(_));
+}
+
+#[test]
+fn test_bundle1_bad_magic() {
+    let mut core = Core::new().unwrap();
+    let stream = Bundle1Stream::new(Cursor::new(b"HG20UN".to_vec()), make_root_logger());
+    let err = core.run(stream.collect()).unwrap_err();
+    assert_matches!(err.kind(),
+                    &ErrorKind::Bundle1Decode(ref msg) if msg == "invalid bundle magic string");
+}
+
+/// A changegroup that can be sent as version 01: each delta is against the previous revision in
+/// its section, or the first parent of the first one.
+fn bundle1_parts() -> Vec<changegroup::Part> {
+    let changeset1_hash = NodeHash::from_str(CHANGESET1_HASH_STR).unwrap();
+    let changeset2_hash = NodeHash::from_str(CHANGESET2_HASH_STR).unwrap();
+    let manifest1_hash = NodeHash::from_str(MANIFEST1_HASH_STR).unwrap();
+    let abch = NodeHash::from_str(ABC_HASH_STR).unwrap();
+    let chunk = |node, p1, base, text: &[u8]| changegroup::CgDeltaChunk {
+        node: node,
+        p1: p1,
+        p2: NULL_HASH,
+        base: base,
+        linknode: changeset1_hash,
+        flags: 0,
+        delta: Delta::new_fulltext(text),
+    };
+
+    let abc = changegroup::Section::Filelog(path(b"abc"));
+    vec![
+        changegroup::Part::CgChunk(
+            changegroup::Section::Changeset,
+            chunk(changeset1_hash, NULL_HASH, NULL_HASH, b"changeset1"),
+        ),
+        changegroup::Part::CgChunk(
+            changegroup::Section::Changeset,
+            chunk(
+                changeset2_hash,
+                changeset1_hash,
+                changeset1_hash,
+                b"changeset2",
+            ),
+        ),
+        changegroup::Part::SectionEnd(changegroup::Section::Changeset),
+        changegroup::Part::CgChunk(
+            changegroup::Section::Manifest,
+            chunk(manifest1_hash, NULL_HASH, NULL_HASH, b"manifest"),
+        ),
+        changegroup::Part::SectionEnd(changegroup::Section::Manifest),
+        changegroup::Part::CgChunk(abc.clone(), chunk(abch, NULL_HASH, NULL_HASH, b"file")),
+        changegroup::Part::SectionEnd(abc),
+        changegroup::Part::End,
+    ]
+}
+
+#[test]
+fn test_listkeys_part_roundtrip() {
+    let input = vec![
+        (b"bookmark1".to_vec(), b"value1".to_vec()),
+        (b"bookmark2".to_vec(), b"value2".to_vec()),
+    ];
+
+    let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
+    let mut builder = Bundle2EncodeBuilder::new(cursor);
+    builder.set_compressor_type(CompressorType::Uncompressed);
+    let part = parts::listkey_part("bookmarks", stream::iter_ok::<_, Error>(input.clone()));
+    builder.add_part(part.unwrap());
+    let encode_fut = builder.build();
+
+    let mut core = Core::new().unwrap();
+    let mut buf = core.run(encode_fut).unwrap();
+    buf.set_position(0);
+
+    let logger = make_root_logger();
+    let stream = Bundle2Stream::new(buf, logger);
+    let decode_fut = stream
+        .map_err(|e| -> () { panic!("unexpected error: {}", e) })
+        .forward(Vec::new());
+    let (_stream, items) = core.run(decode_fut).unwrap();
+
+    let mut header = PartHeaderBuilder::new("LISTKEYS").unwrap();
+    header.add_mparam("namespace", "bookmarks").unwrap();
+    let header = header.build(0);
+    assert_eq!(items[1], Bundle2Item::Header(header));
+
+    let output: Vec<_> = items
+        .into_iter()
+        .skip(2)
+        .map(|item| match item.inner_part() {
+            InnerPart::Listkey(key, value) => (key.to_vec(), value.to_vec()),
+            other => panic!("unexpected part: {:?}", other),
+        })
+        .collect();
+    assert_eq!(output, input);
+}
+
+#[test]
+fn test_phase_heads_part_roundtrip() {
+    let input = vec![
+        (0, NodeHash::from_str(CHANGESET1_HASH_STR).unwrap()),
+        (1, NodeHash::from_str(CHANGESET2_HASH_STR).unwrap()),
+    ];
+
+    let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
+    let mut builder = Bundle2EncodeBuilder::new(cursor);
+    builder.set_compressor_type(CompressorType::Uncompressed);
+    let part = parts::phase_heads_part(stream::iter_ok::<_, Error>(input.clone()));
+    builder.add_part(part.unwrap());
+    let encode_fut = builder.build();
+
+    let mut core = Core::new().unwrap();
+    let mut buf = core.run(encode_fut).unwrap();
+    buf.set_position(0);
+
+    let logger = make_root_logger();
+    let stream = Bundle2Stream::new(buf, logger);
+    let decode_fut = stream
+        .map_err(|e| -> () { panic!("unexpected error: {}", e) })
+        .forward(Vec::new());
+    let (_stream, items) = core.run(decode_fut).unwrap();
+
+    let header = PartHeaderBuilder::new("PHASE-HEADS").unwrap().build(0);
+    assert_eq!(items[1], Bundle2Item::Header(header));
+
+    let output: Vec<_> = items
+        .into_iter()
+        .skip(2)
+        .map(|item| match item.inner_part() {
+            InnerPart::PhaseHead(phase, node) => (phase, node),
+            other => panic!("unexpected part: {:?}", other),
+        })
+        .collect();
+    assert_eq!(output, input);
+}
+
+#[test]
+fn test_rebase_part_decode() {
+    let input = bundle1_parts();
+
+    let mut part = PartEncodeBuilder::mandatory("b2x:rebase").unwrap();
+    part.add_mparam("onto", "master").unwrap();
+    part.add_mparam("cgversion", "02").unwrap();
+    part.set_data_generated(CgPacker::new(
+        CgVersion::Cg2,
+        stream::iter_ok::<_, Error>(input.clone()),
+    ));
+
+    let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
+    let mut builder = Bundle2EncodeBuilder::new(cursor);
+    builder.set_compressor_type(CompressorType::Uncompressed);
+    builder.add_part(part);
+    let encode_fut = builder.build();
+
+    let mut core = Core::new().unwrap();
+    let mut buf = core.run(encode_fut).unwrap();
+    buf.set_position(0);
+
+    let stream = Bundle2Stream::new(buf, make_root_logger());
+    let items = core.run(stream.collect()).unwrap();
+
+    let mut header = PartHeaderBuilder::new("B2X:REBASE").unwrap();
+    header.add_mparam("onto", "master").unwrap();
+    header.add_mparam("cgversion", "02").unwrap();
+    assert_eq!(items[1], Bundle2Item::Header(header.build(0)));
+
+    let output: Vec<_> = items
+        .into_iter()
+        .skip(2)
+        .map(|item| item.inner_part().cg2_part())
+        .collect();
+    assert_eq!(output, input);
+}
+
+#[test]
+fn test_obsmarkers_part() {
+    let changeset1_hash = NodeHash::from_str(CHANGESET1_HASH_STR).unwrap();
+    let changeset2_hash = NodeHash::from_str(CHANGESET2_HASH_STR).unwrap();
+    let part = parts::obsmarkers_part(&[(changeset1_hash, changeset2_hash)], 1.5).unwrap();
+
+    let mut core = Core::new().unwrap();
+    let chunks = core.run(part.build(0).collect()).unwrap();
+    let header = part_header::decode(chunks[0].clone().into_bytes().unwrap()).unwrap();
+    assert_eq!(header.part_type(), "obsmarkers");
+    assert!(!header.is_mandatory());
+
+    let mut expected = vec![1, 0, 0, 0, 59];
+    expected.extend_from_slice(&[0x3f, 0xf8, 0, 0, 0, 0, 0, 0]);
+    expected.extend_from_slice(&[0, 0, 0, 0, 1, 3, 0]);
+    expected.extend_from_slice(changeset1_hash.as_ref());
+    expected.extend_from_slice(changeset2_hash.as_ref());
+    assert_eq!(chunks[1], Chunk::new(expected).unwrap());
+    assert!(chunks[2].is_empty());
+}
+
+#[test]
+fn test_part_generation_error() {
+    let data = stream::iter_ok::<_, Error>(vec![Chunk::new("abc").unwrap()]).chain(stream::once(
+        Err(Error::with_chain(
+            io::Error::new(io::ErrorKind::Other, "disk on fire"),
+            ErrorKind::ChangegroupGeneration,
+        )),
+    ));
+    let mut part = PartEncodeBuilder::mandatory("changegroup").unwrap();
+    part.set_data_generated(data);
+
+    let mut core = Core::new().unwrap();
+    let chunks = core.run(part.build(0).collect()).unwrap();
+    assert_eq!(chunks.len(), 6);
+    assert_eq!(chunks[1], Chunk::new("abc").unwrap());
+
+    // The payload is interrupted by an error part, and then closed.
+    assert!(chunks[2].is_error());
+    let header = part_header::decode(chunks[3].clone().into_bytes().unwrap()).unwrap();
+    assert_eq!(header.part_type(), "ERROR:ABORT");
+    assert_eq!(
+        header.mparams()["message"],
+        "error while generating changegroup part"
+    );
+    assert!(!header.aparams().contains_key("hint"));
+    assert!(chunks[4].is_empty());
+    assert!(chunks[5].is_empty());
+}
+
+#[test]
+fn test_unsupportedcontent_error_part() {
+    let part_type = AsciiString::from_ascii("b2x:foo").unwrap();
+    let err: Error =
+        ErrorKind::BundleUnknownPartParams(part_type, vec!["a".into(), "b".into()]).into();
+    let part = parts::error_part(&err).unwrap().build(0);
+
+    let mut core = Core::new().unwrap();
+    let chunks = core.run(part.collect()).unwrap();
+    let header = part_header::decode(chunks[0].clone().into_bytes().unwrap()).unwrap();
+    assert_eq!(header.part_type(), "ERROR:UNSUPPORTEDCONTENT");
+    assert_eq!(header.mparams()["parttype"], "b2x:foo");
+    assert_eq!(header.mparams()["params"], "a\0b");
+}
+
 fn parse_bundle(
     input: &[u8],
     compression: Option<&str>,
@@ -23,6 +23,20 @@ impl Delta {
         Ok(Delta { frags: frags })
     }
 
+    /// Construct a new Delta object that replaces an empty base with `text`. This is how
+    /// full texts are sent in changegroups, with a null base.
+    pub fn new_fulltext<T: Into<Vec<u8>>>(text: T) -> Self {
+        Delta {
+            frags: vec![
+                Fragment {
+                    start: 0,
+                    end: 0,
+                    content: text.into(),
+                },
+            ],
+        }
+    }
+
     pub fn fragments(&self) -> &[Fragment] {
         self.frags.as_slice()
     }
@@ -413,4 +427,12 @@ mod tests {
         let res = apply(text, delta);
         assert_eq!(&res[..], b"aaaa\ncccc\n");
     }
+
+    #[test]
+    fn test_apply_fulltext() {
+        let delta = Delta::new_fulltext(&b"aaaa\nbbbb\n"[..]);
+
+        let res = apply(b"", delta);
+        assert_eq!(&res[..], b"aaaa\nbbbb\n");
+    }
 }
@@ -65,7 +65,7 @@ pub use node::Node;
 pub use nodehash::{NodeHash, NULL_HASH};
 pub use path::{fsencode, MPath, MPathElement, RepoPath};
 pub use repo::{BoxRepo, Repo};
-pub use utils::percent_encode;
+pub use utils::{percent_decode, percent_encode};
 
 pub use errors::{Error, ErrorKind};
 
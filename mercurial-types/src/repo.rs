@@ -28,7 +28,7 @@ pub type BoxedBookmarks<E> = Box<
     >,
 >;
 
-pub trait Repo: 'static {
+pub trait Repo: Send + Sync + 'static {
     type Error: error::Error + Send + 'static;
 
     /// Return a stream of all changeset ids
@@ -50,6 +50,19 @@ pub trait Repo: 'static {
         nodeid: &NodeHash,
     ) -> BoxFuture<Box<Manifest<Error = Self::Error> + Sync>, Self::Error>;
 
+    /// Return all the changesets whose hex hash starts with `prefix`
+    ///
+    /// The default implementation walks every changeset, so implementations should override it
+    /// if they have an index.
+    fn get_changesets_by_prefix(&self, prefix: &str) -> BoxFuture<Vec<NodeHash>, Self::Error> {
+        let prefix = prefix.to_lowercase();
+
+        self.get_changesets()
+            .filter(move |node| node.to_hex().as_str().starts_with(&prefix))
+            .collect()
+            .boxify()
+    }
+
     fn boxed(self) -> Box<Repo<Error = Self::Error> + Sync>
     where
         Self: Sync + Sized,
@@ -148,6 +161,15 @@ where
             .map_err(cvterr)
             .boxify()
     }
+
+    fn get_changesets_by_prefix(&self, prefix: &str) -> BoxFuture<Vec<NodeHash>, Self::Error> {
+        let cvterr = self.cvterr;
+
+        self.repo
+            .get_changesets_by_prefix(prefix)
+            .map_err(cvterr)
+            .boxify()
+    }
 }
 
 
@@ -183,6 +205,48 @@ where
     ) -> BoxFuture<Box<Manifest<Error = Self::Error> + Sync>, Self::Error> {
         (**self).get_manifest_by_nodeid(nodeid)
     }
+
+    fn get_changesets_by_prefix(&self, prefix: &str) -> BoxFuture<Vec<NodeHash>, Self::Error> {
+        (**self).get_changesets_by_prefix(prefix)
+    }
+}
+
+impl<RE> Repo for Box<Repo<Error = RE> + Sync + Send>
+where
+    RE: error::Error + Send + 'static,
+{
+    type Error = RE;
+
+    fn get_changesets(&self) -> BoxStream<NodeHash, Self::Error> {
+        (**self).get_changesets()
+    }
+
+    fn get_heads(&self) -> BoxStream<NodeHash, Self::Error> {
+        (**self).get_heads()
+    }
+
+    fn get_bookmarks(&self) -> Result<BoxedBookmarks<Self::Error>, Self::Error> {
+        (**self).get_bookmarks()
+    }
+
+    fn changeset_exists(&self, nodeid: &NodeHash) -> BoxFuture<bool, Self::Error> {
+        (**self).changeset_exists(nodeid)
+    }
+
+    fn get_changeset_by_nodeid(&self, nodeid: &NodeHash) -> BoxFuture<Box<Changeset>, Self::Error> {
+        (**self).get_changeset_by_nodeid(nodeid)
+    }
+
+    fn get_manifest_by_nodeid(
+        &self,
+        nodeid: &NodeHash,
+    ) -> BoxFuture<Box<Manifest<Error = Self::Error> + Sync>, Self::Error> {
+        (**self).get_manifest_by_nodeid(nodeid)
+    }
+
+    fn get_changesets_by_prefix(&self, prefix: &str) -> BoxFuture<Vec<NodeHash>, Self::Error> {
+        (**self).get_changesets_by_prefix(prefix)
+    }
 }
 
 impl<R> Repo for Box<R>
@@ -217,6 +281,10 @@ where
     ) -> BoxFuture<Box<Manifest<Error = Self::Error> + Sync>, Self::Error> {
         (**self).get_manifest_by_nodeid(nodeid)
     }
+
+    fn get_changesets_by_prefix(&self, prefix: &str) -> BoxFuture<Vec<NodeHash>, Self::Error> {
+        (**self).get_changesets_by_prefix(prefix)
+    }
 }
 
 impl<RE> Repo for Arc<Repo<Error = RE>>
@@ -251,6 +319,10 @@ where
     ) -> BoxFuture<Box<Manifest<Error = Self::Error> + Sync>, Self::Error> {
         (**self).get_manifest_by_nodeid(nodeid)
     }
+
+    fn get_changesets_by_prefix(&self, prefix: &str) -> BoxFuture<Vec<NodeHash>, Self::Error> {
+        (**self).get_changesets_by_prefix(prefix)
+    }
 }
 
 impl<R> Repo for Arc<R>
@@ -285,6 +357,10 @@ where
     ) -> BoxFuture<Box<Manifest<Error = Self::Error> + Sync>, Self::Error> {
         (**self).get_manifest_by_nodeid(nodeid)
     }
+
+    fn get_changesets_by_prefix(&self, prefix: &str) -> BoxFuture<Vec<NodeHash>, Self::Error> {
+        (**self).get_changesets_by_prefix(prefix)
+    }
 }
 
 #[cfg(test)]
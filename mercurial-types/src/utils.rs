@@ -17,3 +17,7 @@ pub fn percent_encode(input: &str) -> String {
     // one.
     percent_encoding::utf8_percent_encode(input, HG_ENCODE_SET).collect::<String>()
 }
+
+pub fn percent_decode(input: &[u8]) -> Vec<u8> {
+    percent_encoding::percent_decode(input).collect()
+}
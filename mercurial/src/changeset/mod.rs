@@ -173,6 +173,26 @@ impl RevlogChangeset {
         Self::parse(node)
     }
 
+    pub fn new_from_parts(
+        parents: Parents,
+        manifestid: NodeHash,
+        user: Vec<u8>,
+        time: Time,
+        extra: BTreeMap<Vec<u8>, Vec<u8>>,
+        files: Vec<MPath>,
+        comments: Vec<u8>,
+    ) -> Self {
+        Self {
+            parents,
+            manifestid,
+            user,
+            time,
+            extra: Extra(extra),
+            files,
+            comments,
+        }
+    }
+
     // format used:
     // nodeid\n        : manifest node in ascii
     // user\n          : user, no \n or \r allowed
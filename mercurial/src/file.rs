@@ -80,27 +80,28 @@ impl File {
             return Ok(None);
         }
 
-        let meta = self.node.as_blob().as_slice().map(Self::parse_meta);
-        let ret = meta.and_then(|meta| {
-            let path = meta.get(b"copy".as_ref()).cloned().map(MPath::new);
-            let nodeid = meta.get(b"copyrev".as_ref())
-                .and_then(|rev| str::from_utf8(rev).ok())
-                .and_then(|rev| rev.parse().ok());
-
-            if let (Some(path), Some(nodeid)) = (path, nodeid) {
-                Some((path, nodeid))
-            } else {
-                None
-            }
-        });
-
-        match ret {
-            Some((Ok(path), nodeid)) => Ok(Some((path, nodeid))),
-            Some((Err(e), _nodeid)) => Err(e).chain_err(|| "invalid path in copy metadata"),
+        match self.node.as_blob().as_slice() {
+            Some(file) => Self::copied_from_meta(file),
             None => Ok(None),
         }
     }
 
+    /// Get the copy source recorded in the metadata of a file's revlog text. Unlike
+    /// `copied_from`, this doesn't need to know the file's parents.
+    pub fn copied_from_meta(file: &[u8]) -> Result<Option<(MPath, NodeHash)>> {
+        let meta = Self::parse_meta(file);
+        let path = meta.get(b"copy".as_ref()).cloned().map(MPath::new);
+        let nodeid = meta.get(b"copyrev".as_ref())
+            .and_then(|rev| str::from_utf8(rev).ok())
+            .and_then(|rev| rev.parse().ok());
+
+        match (path, nodeid) {
+            (Some(Ok(path)), Some(nodeid)) => Ok(Some((path, nodeid))),
+            (Some(Err(e)), Some(_)) => Err(e).chain_err(|| "invalid path in copy metadata"),
+            _ => Ok(None),
+        }
+    }
+
     pub fn content(&self) -> Option<&[u8]> {
         self.node.as_blob().as_slice().map(|s| {
             let (_, off) = Self::extract_meta(s);
@@ -122,6 +123,7 @@ impl File {
 #[cfg(test)]
 mod test {
     use super::{File, META_MARKER, META_SZ};
+    use mercurial_types::{MPath, NodeHash};
 
     #[test]
     fn extract_meta_sz() {
@@ -208,4 +210,18 @@ mod test {
             ]
         )
     }
+
+    #[test]
+    fn copied_from_meta() {
+        const DATA: &[u8] = b"\x01\ncopy: foo/bar\n\
+                              copyrev: 1111111111111111111111111111111111111111\n\
+                              \x01\nfile contents";
+
+        let (path, node) = File::copied_from_meta(DATA).unwrap().unwrap();
+        assert_eq!(path, MPath::new("foo/bar").unwrap());
+        let expected: NodeHash = "1111111111111111111111111111111111111111".parse().unwrap();
+        assert_eq!(node, expected);
+
+        assert_eq!(File::copied_from_meta(b"foo - no meta").unwrap(), None);
+    }
 }
@@ -231,6 +231,22 @@ impl RevlogRepo {
         &self.requirements
     }
 
+    /// Whether the repo's manifests are tree manifests, either because it requires them or
+    /// because it keeps them alongside flat ones, as the treemanifest extension can.
+    pub fn has_tree_manifests(&self) -> bool {
+        self.requirements.contains(&Required::Treemanifest)
+            || self.basepath.join("store").join("00manifesttree.i").exists()
+    }
+
+    pub fn get_changelog_revlog(&self) -> &Revlog {
+        &self.changelog
+    }
+
+    /// Get the revlog of the root manifests, which are tree manifests in a treemanifest repo.
+    pub fn get_manifest_revlog(&self) -> &Revlog {
+        &self.manifest
+    }
+
     pub fn get_tree_revlog(&self, path: &MPath) -> Result<Revlog> {
         {
             let inner = self.inner.read().expect("poisoned lock");
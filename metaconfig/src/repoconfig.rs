@@ -29,6 +29,51 @@ use errors::*;
 pub struct RepoConfig {
     /// Defines the type of repository
     pub repotype: RepoType,
+    /// Pre-built bundles that clients can clone from, listed in the `clonebundles` manifest
+    pub clonebundles: Vec<CloneBundle>,
+    /// Hooks that are run before a bookmark is moved
+    pub hooks: Vec<HookConfig>,
+    /// Whether to serve tree manifests to clients that use the treemanifest extension. Only set
+    /// this for repos that store tree manifests; revlog repos must also have them on disk
+    pub treemanifest: bool,
+}
+
+/// An entry in a repo's clonebundles manifest
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CloneBundle {
+    /// Where clients can fetch the bundle from
+    pub url: String,
+    /// Mercurial bundle specification of the bundle, e.g. `none-v2`, so that clients can skip
+    /// bundles they can't apply
+    pub bundlespec: Option<String>,
+}
+
+/// A hook that can refuse to move some of a repo's bookmarks
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HookConfig {
+    /// Name of the hook, used in logs and in the message sent to clients it rejects
+    pub name: String,
+    /// Bookmarks the hook is run for
+    pub bookmarks: Vec<String>,
+    /// What the hook runs
+    pub hook_type: HookType,
+}
+
+/// Types of hooks supported
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum HookType {
+    /// A hook written in Lua
+    Lua {
+        /// Lua code that defines the `hook` function
+        code: String,
+        /// Number of Lua instructions the hook can run before it's stopped, if not the default
+        max_instructions: Option<u64>,
+        /// Number of milliseconds the hook can run for before it's stopped, if not the default
+        timeout_ms: Option<u64>,
+    },
+    /// One of the hooks written in Rust that are built into the server, by name. They're run on
+    /// every changeset the bookmark move adds to the bookmark
+    Native(String),
 }
 
 /// Types of repositories supported
@@ -176,6 +221,25 @@ impl RepoConfigs {
 struct RawRepoConfig {
     path: PathBuf,
     repotype: RawRepoType,
+    #[serde(default)] clonebundles: Vec<RawCloneBundle>,
+    #[serde(default)] hooks: Vec<RawHookConfig>,
+    #[serde(default)] treemanifest: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawCloneBundle {
+    url: String,
+    bundlespec: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RawHookConfig {
+    name: String,
+    bookmarks: Vec<String>,
+    code: Option<String>,
+    native: Option<String>,
+    max_instructions: Option<u64>,
+    timeout_ms: Option<u64>,
 }
 
 /// Types of repositories supported
@@ -198,7 +262,47 @@ impl TryFrom<RawRepoConfig> for RepoConfig {
             BlobRocks => RepoType::BlobRocks(this.path),
         };
 
-        Ok(RepoConfig { repotype })
+        let clonebundles = this.clonebundles
+            .into_iter()
+            .map(|bundle| {
+                CloneBundle {
+                    url: bundle.url,
+                    bundlespec: bundle.bundlespec,
+                }
+            })
+            .collect();
+
+        let hooks = this.hooks
+            .into_iter()
+            .map(|hook| {
+                let hook_type = match (hook.code, hook.native) {
+                    (Some(code), None) => HookType::Lua {
+                        code,
+                        max_instructions: hook.max_instructions,
+                        timeout_ms: hook.timeout_ms,
+                    },
+                    (None, Some(native)) => {
+                        if hook.max_instructions.is_some() || hook.timeout_ms.is_some() {
+                            bail!("native hook {} can't have Lua limits", hook.name);
+                        }
+                        HookType::Native(native)
+                    }
+                    _ => bail!("hook {} needs either code or native, but not both", hook.name),
+                };
+                Ok(HookConfig {
+                    name: hook.name,
+                    bookmarks: hook.bookmarks,
+                    hook_type,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(RepoConfig {
+            repotype,
+            clonebundles,
+            hooks,
+            treemanifest: this.treemanifest,
+        })
     }
 }
 
@@ -215,10 +319,24 @@ mod test {
         let fbsource_content = r#"
             path="/tmp/fbsource"
             repotype="blob:files"
+
+            [[clonebundles]]
+            url="http://localhost:3000/fbsource/clonebundle"
+            bundlespec="none-v2"
+            [[hooks]]
+            name="no_noop_moves"
+            bookmarks=["master"]
+            code="function hook(info) return info.new_hash ~= info.old_hash end"
+            timeout_ms=1000
+            [[hooks]]
+            name="conflicts"
+            bookmarks=["master"]
+            native="no_conflict_markers"
         "#;
         let www_content = r#"
             path="/tmp/www"
             repotype="revlog"
+            treemanifest=true
         "#;
 
         let repoconfig = RepoConfigs::read_manifest(&MockManifest::<Error>::with_content(vec![
@@ -233,12 +351,39 @@ mod test {
             "fbsource".to_string(),
             RepoConfig {
                 repotype: RepoType::BlobFiles("/tmp/fbsource".into()),
+                clonebundles: vec![
+                    CloneBundle {
+                        url: "http://localhost:3000/fbsource/clonebundle".into(),
+                        bundlespec: Some("none-v2".into()),
+                    },
+                ],
+                hooks: vec![
+                    HookConfig {
+                        name: "no_noop_moves".into(),
+                        bookmarks: vec!["master".into()],
+                        hook_type: HookType::Lua {
+                            code: "function hook(info) return info.new_hash ~= info.old_hash end"
+                                .into(),
+                            max_instructions: None,
+                            timeout_ms: Some(1000),
+                        },
+                    },
+                    HookConfig {
+                        name: "conflicts".into(),
+                        bookmarks: vec!["master".into()],
+                        hook_type: HookType::Native("no_conflict_markers".into()),
+                    },
+                ],
+                treemanifest: false,
             },
         );
         repos.insert(
             "www".to_string(),
             RepoConfig {
                 repotype: RepoType::Revlog("/tmp/www".into()),
+                clonebundles: vec![],
+                hooks: vec![],
+                treemanifest: true,
             },
         );
         assert_eq!(
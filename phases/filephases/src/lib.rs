@@ -0,0 +1,136 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate futures;
+extern crate futures_cpupool;
+
+extern crate filekv;
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate phases;
+extern crate storage_types;
+
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use futures::{Future, IntoFuture, Stream};
+use futures::future::{self, Either};
+use futures_cpupool::CpuPool;
+
+use filekv::FileKV;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use mercurial_types::NodeHash;
+use phases::{Error as PhasesError, ErrorKind as PhasesErrorKind, Phase, Phases, ResultExt};
+use storage_types::Version;
+
+static PREFIX: &str = "phase:";
+
+/// A basic file-based persistent phases store.
+///
+/// Phases are stored as files in the specified base directory, one per changeset.
+pub struct FilePhases {
+    kv: Arc<FileKV<Phase>>,
+}
+
+impl FilePhases {
+    #[inline]
+    pub fn open<P: AsRef<Path>>(path: P) -> filekv::Result<Self> {
+        Ok(FilePhases {
+            kv: Arc::new(FileKV::open(path, PREFIX)?),
+        })
+    }
+
+    #[inline]
+    pub fn open_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> filekv::Result<Self> {
+        Ok(FilePhases {
+            kv: Arc::new(FileKV::open_with_pool(path, PREFIX, pool)?),
+        })
+    }
+
+    #[inline]
+    pub fn create<P: AsRef<Path>>(path: P) -> filekv::Result<Self> {
+        Ok(FilePhases {
+            kv: Arc::new(FileKV::create(path, PREFIX)?),
+        })
+    }
+
+    #[inline]
+    pub fn create_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> filekv::Result<Self> {
+        Ok(FilePhases {
+            kv: Arc::new(FileKV::create_with_pool(path, PREFIX, pool)?),
+        })
+    }
+}
+
+impl Phases for FilePhases {
+    type Get = BoxFuture<Option<Phase>, PhasesError>;
+    type Effect = BoxFuture<(), PhasesError>;
+    type Drafts = BoxStream<NodeHash, PhasesError>;
+
+    fn get(&self, node: &NodeHash) -> Self::Get {
+        self.kv
+            .get(node.to_hex())
+            .map(|phase| phase.map(|(phase, _version)| phase))
+            .then(|res| res.chain_err(|| PhasesErrorKind::StorageError))
+            .boxify()
+    }
+
+    /// Overwrite the phase of a changeset. If another write gets in between reading the
+    /// current version and writing, that write wins, as it would have if it came just after.
+    fn set(&self, node: &NodeHash, phase: Phase) -> Self::Effect {
+        let kv = self.kv.clone();
+        let key = String::from(node.to_hex());
+
+        self.kv
+            .get(key.clone())
+            .and_then(move |current| {
+                let version = match current {
+                    Some((_, version)) => version,
+                    None => Version::absent(),
+                };
+                kv.set(key, &phase, &version)
+            })
+            .map(|_| ())
+            .then(|res| res.chain_err(|| PhasesErrorKind::StorageError))
+            .boxify()
+    }
+
+    /// Likewise, a concurrent `set` of the same changeset wins over removing it.
+    fn remove(&self, node: &NodeHash) -> Self::Effect {
+        let kv = self.kv.clone();
+        let key = String::from(node.to_hex());
+
+        self.kv
+            .get(key.clone())
+            .and_then(move |current| match current {
+                Some((_, version)) => Either::A(kv.delete(key, &version).map(|_| ())),
+                None => Either::B(future::ok(())),
+            })
+            .then(|res| res.chain_err(|| PhasesErrorKind::StorageError))
+            .boxify()
+    }
+
+    fn drafts(&self) -> Self::Drafts {
+        let kv = self.kv.clone();
+        self.kv
+            .keys()
+            .then(|res| res.chain_err(|| PhasesErrorKind::StorageError))
+            .and_then(move |key| {
+                let node = NodeHash::from_str(&key).chain_err(|| PhasesErrorKind::StorageError);
+                let phase = kv.get(key)
+                    .then(|res| res.chain_err(|| PhasesErrorKind::StorageError));
+                node.into_future().join(phase)
+            })
+            .filter_map(|(node, phase)| match phase {
+                Some((Phase::Draft, _version)) => Some(node),
+                _ => None,
+            })
+            .boxify()
+    }
+}
@@ -0,0 +1,67 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate futures;
+
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate phases;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use futures::future::{ok, FutureResult};
+use futures::stream::iter_ok;
+use futures_ext::{BoxStream, StreamExt};
+
+use mercurial_types::NodeHash;
+use phases::{Error as PhasesError, Phase, Phases};
+
+/// In-memory phases store backed by a HashMap, intended to be used in tests.
+pub struct MemPhases {
+    phases: Mutex<HashMap<NodeHash, Phase>>,
+}
+
+impl MemPhases {
+    pub fn new() -> Self {
+        MemPhases {
+            phases: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Phases for MemPhases {
+    type Get = FutureResult<Option<Phase>, PhasesError>;
+    type Effect = FutureResult<(), PhasesError>;
+    type Drafts = BoxStream<NodeHash, PhasesError>;
+
+    fn get(&self, node: &NodeHash) -> Self::Get {
+        ok(self.phases.lock().unwrap().get(node).cloned())
+    }
+
+    fn set(&self, node: &NodeHash, phase: Phase) -> Self::Effect {
+        self.phases.lock().unwrap().insert(*node, phase);
+        ok(())
+    }
+
+    fn remove(&self, node: &NodeHash) -> Self::Effect {
+        self.phases.lock().unwrap().remove(node);
+        ok(())
+    }
+
+    fn drafts(&self) -> Self::Drafts {
+        let drafts: Vec<_> = self.phases
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|&(_, phase)| *phase == Phase::Draft)
+            .map(|(node, _)| *node)
+            .collect();
+        iter_ok(drafts).boxify()
+    }
+}
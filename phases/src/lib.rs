@@ -0,0 +1,110 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate error_chain;
+extern crate futures;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+
+extern crate mercurial_types;
+
+use std::fmt;
+use std::sync::Arc;
+
+use futures::{Future, Stream};
+
+use mercurial_types::NodeHash;
+
+mod errors {
+    error_chain! {
+        errors {
+            StorageError {
+                description("phase storage error")
+                display("phase storage error")
+            }
+        }
+    }
+}
+
+pub use errors::*;
+
+/// The phase of a changeset. Public changesets are part of the permanent history of the repo,
+/// while draft ones can still be rewritten.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum Phase {
+    Public,
+    Draft,
+}
+
+impl Phase {
+    /// The number Mercurial uses for this phase on the wire, in `phases` listkeys values and
+    /// `phase-heads` parts.
+    pub fn as_num(&self) -> u32 {
+        match *self {
+            Phase::Public => 0,
+            Phase::Draft => 1,
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Phase::Public => write!(fmt, "public"),
+            Phase::Draft => write!(fmt, "draft"),
+        }
+    }
+}
+
+/// Trait representing the interface to a phases store, which records the phases changesets
+/// were given when they were added or later moved to. Whether a changeset is public can also
+/// depend on other state, such as bookmarks, so this is only part of the picture.
+pub trait Phases: Send + 'static {
+    type Get: Future<Item = Option<Phase>, Error = Error> + Send + 'static;
+    type Effect: Future<Item = (), Error = Error> + Send + 'static;
+    type Drafts: Stream<Item = NodeHash, Error = Error> + Send + 'static;
+
+    /// Get the phase recorded for a changeset, or `None` if there isn't one.
+    fn get(&self, node: &NodeHash) -> Self::Get;
+    fn set(&self, node: &NodeHash, phase: Phase) -> Self::Effect;
+    /// Forget the phase recorded for a changeset. Removing a phase that isn't recorded succeeds.
+    fn remove(&self, node: &NodeHash) -> Self::Effect;
+    /// All the changesets whose recorded phase is draft.
+    fn drafts(&self) -> Self::Drafts;
+}
+
+impl<P> Phases for Arc<P>
+where
+    P: Phases + Sync,
+{
+    type Get = P::Get;
+    type Effect = P::Effect;
+    type Drafts = P::Drafts;
+
+    #[inline]
+    fn get(&self, node: &NodeHash) -> Self::Get {
+        (**self).get(node)
+    }
+
+    #[inline]
+    fn set(&self, node: &NodeHash, phase: Phase) -> Self::Effect {
+        (**self).set(node, phase)
+    }
+
+    #[inline]
+    fn remove(&self, node: &NodeHash) -> Self::Effect {
+        (**self).remove(node)
+    }
+
+    #[inline]
+    fn drafts(&self) -> Self::Drafts {
+        (**self).drafts()
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright (c) 2017-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests run against all phases implementations.
+
+#![deny(warnings)]
+
+extern crate futures;
+extern crate tempdir;
+
+extern crate filephases;
+extern crate memphases;
+extern crate mercurial_types_mocks;
+extern crate phases;
+
+use std::collections::HashSet;
+
+use futures::{Future, Stream};
+use tempdir::TempDir;
+
+use filephases::FilePhases;
+use memphases::MemPhases;
+use mercurial_types_mocks::nodehash::*;
+use phases::{Phase, Phases};
+
+fn set_and_get<P: Phases>(phases: P) {
+    assert_eq!(phases.get(&ONES_HASH).wait().unwrap(), None);
+
+    phases.set(&ONES_HASH, Phase::Draft).wait().unwrap();
+    phases.set(&TWOS_HASH, Phase::Public).wait().unwrap();
+    assert_eq!(phases.get(&ONES_HASH).wait().unwrap(), Some(Phase::Draft));
+    assert_eq!(phases.get(&TWOS_HASH).wait().unwrap(), Some(Phase::Public));
+
+    // Phases can be changed after they're first set.
+    phases.set(&ONES_HASH, Phase::Public).wait().unwrap();
+    assert_eq!(phases.get(&ONES_HASH).wait().unwrap(), Some(Phase::Public));
+}
+
+fn drafts<P: Phases>(phases: P) {
+    phases.set(&ONES_HASH, Phase::Draft).wait().unwrap();
+    phases.set(&TWOS_HASH, Phase::Draft).wait().unwrap();
+    phases.set(&THREES_HASH, Phase::Public).wait().unwrap();
+    phases.set(&TWOS_HASH, Phase::Public).wait().unwrap();
+
+    let drafts: HashSet<_> = phases.drafts().collect().wait().unwrap().into_iter().collect();
+    assert_eq!(drafts, vec![ONES_HASH].into_iter().collect());
+}
+
+fn remove<P: Phases>(phases: P) {
+    phases.set(&ONES_HASH, Phase::Draft).wait().unwrap();
+    phases.set(&TWOS_HASH, Phase::Draft).wait().unwrap();
+
+    phases.remove(&ONES_HASH).wait().unwrap();
+    assert_eq!(phases.get(&ONES_HASH).wait().unwrap(), None);
+    assert_eq!(phases.get(&TWOS_HASH).wait().unwrap(), Some(Phase::Draft));
+    let drafts = phases.drafts().collect().wait().unwrap();
+    assert_eq!(drafts, vec![TWOS_HASH]);
+
+    // Removing a phase that isn't recorded does nothing.
+    phases.remove(&ONES_HASH).wait().unwrap();
+    phases.remove(&THREES_HASH).wait().unwrap();
+    assert_eq!(phases.get(&THREES_HASH).wait().unwrap(), None);
+}
+
+fn persistence<F, P>(mut new_phases: F)
+where
+    F: FnMut() -> P,
+    P: Phases,
+{
+    {
+        let phases = new_phases();
+        phases.set(&ONES_HASH, Phase::Draft).wait().unwrap();
+    }
+
+    let phases = new_phases();
+    assert_eq!(phases.get(&ONES_HASH).wait().unwrap(), Some(Phase::Draft));
+}
+
+macro_rules! phases_test_impl {
+    ($mod_name: ident => {
+        state: $state: expr,
+        new: $new_cb: expr,
+        persistent: $persistent: expr,
+    }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_set_and_get() {
+                let state = $state;
+                set_and_get($new_cb(&state));
+            }
+
+            #[test]
+            fn test_drafts() {
+                let state = $state;
+                drafts($new_cb(&state));
+            }
+
+            #[test]
+            fn test_remove() {
+                let state = $state;
+                remove($new_cb(&state));
+            }
+
+            #[test]
+            fn test_persistence() {
+                // Not all phases implementations support persistence.
+                if $persistent {
+                    let state = $state;
+                    persistence(|| $new_cb(&state));
+                }
+            }
+        }
+    }
+}
+
+phases_test_impl! {
+    memphases_test => {
+        state: (),
+        new: |_| MemPhases::new(),
+        persistent: false,
+    }
+}
+
+phases_test_impl! {
+    filephases_test => {
+        state: TempDir::new("filephases_test").unwrap(),
+        new: |dir| FilePhases::open(&dir).unwrap(),
+        persistent: true,
+    }
+}
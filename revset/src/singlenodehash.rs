@@ -14,7 +14,7 @@ use std::boxed::Box;
 use errors::*;
 
 pub struct SingleNodeHash {
-    node: Box<Stream<Item = NodeHash, Error = Error>>,
+    node: Box<Stream<Item = NodeHash, Error = Error> + Send>,
 }
 
 impl SingleNodeHash {
@@ -0,0 +1,111 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Named branches
+//!
+//! A changeset's named branch is the `branch` key of its extras, or `default` if there is none.
+//! The branch heads reported by `branchmap` are the repo heads grouped by branch. Working them
+//! out means loading every head, so the result is cached per set of heads.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use futures::{future, stream, Future, Stream};
+use futures::future::Loop;
+use futures_ext::{BoxFuture, FutureExt};
+
+use asyncmemo::{Asyncmemo, Filler};
+use hgproto::{self, BranchRes};
+use mercurial_types::{Changeset, NodeHash, Parents, Repo};
+
+use repo::BoxedHgRepo;
+
+pub type Branchmap = HashMap<String, HashSet<NodeHash>>;
+
+const DEFAULT_BRANCH: &str = "default";
+
+/// The named branch `cs` is on.
+fn branch_name(cs: &Changeset) -> String {
+    match cs.extra().get(&b"branch"[..]) {
+        Some(branch) => String::from_utf8_lossy(branch).into_owned(),
+        None => DEFAULT_BRANCH.to_string(),
+    }
+}
+
+/// Cache of branch heads, keyed by the sorted repo heads they were computed from.
+pub struct BranchmapCache {
+    repo: Arc<BoxedHgRepo>,
+    cache: Asyncmemo<BranchmapFiller>,
+}
+
+impl BranchmapCache {
+    /// Construct a new `BranchmapCache`, bounded to `sizelimit` bytes.
+    pub fn new(repo: Arc<BoxedHgRepo>, sizelimit: usize) -> Self {
+        let filler = BranchmapFiller { repo: repo.clone() };
+
+        BranchmapCache {
+            repo,
+            cache: Asyncmemo::with_limits(filler, usize::max_value(), sizelimit),
+        }
+    }
+
+    /// Get the heads of each named branch for the current repo heads.
+    pub fn get(&self) -> BoxFuture<Branchmap, hgproto::Error> {
+        let cache = self.cache.clone();
+
+        self.repo
+            .get_heads()
+            .collect()
+            .and_then(move |mut heads| {
+                heads.sort();
+                heads.dedup();
+                cache.get(heads)
+            })
+            .boxify()
+    }
+}
+
+struct BranchmapFiller {
+    repo: Arc<BoxedHgRepo>,
+}
+
+impl Filler for BranchmapFiller {
+    type Key = Vec<NodeHash>;
+    type Value = BoxFuture<Branchmap, hgproto::Error>;
+
+    fn fill(&self, _cache: &Asyncmemo<Self>, heads: &Self::Key) -> Self::Value {
+        let repo = self.repo.clone();
+
+        stream::iter_ok(heads.clone())
+            .and_then(move |head| {
+                repo.get_changeset_by_nodeid(&head)
+                    .map(move |cs| (branch_name(&*cs), head))
+            })
+            .fold(Branchmap::new(), |mut branchmap, (branch, head)| {
+                branchmap
+                    .entry(branch)
+                    .or_insert_with(HashSet::new)
+                    .insert(head);
+                Ok::<_, hgproto::Error>(branchmap)
+            })
+            .boxify()
+    }
+}
+
+/// Follow the first parents of `top` down to the first merge or root, as the legacy `branches`
+/// discovery command expects.
+pub fn branch_base(
+    repo: Arc<BoxedHgRepo>,
+    top: NodeHash,
+) -> BoxFuture<BranchRes, hgproto::Error> {
+    future::loop_fn(top, move |node| {
+        repo.get_changeset_by_nodeid(&node).map(move |cs| match *cs.parents() {
+            Parents::One(p) => Loop::Continue(p),
+            Parents::None => Loop::Break(BranchRes::new(top, node, None, None)),
+            Parents::Two(p0, p1) => Loop::Break(BranchRes::new(top, node, Some(p0), Some(p1))),
+        })
+    }).boxify()
+}
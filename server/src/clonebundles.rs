@@ -0,0 +1,27 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Clone bundles
+//!
+//! Clients that support clonebundles first fetch a manifest of pre-built bundles, bootstrap the
+//! clone from one of them, and then pull whatever the bundle is missing from the server. The
+//! manifest comes from the repo config. The bundles themselves are generated with
+//! `--generate-clonebundle`, which stores a bundle of the whole repo in its blobstore for
+//! `eden_server` to serve.
+
+use mercurial_types::percent_encode;
+use metaconfig::repoconfig::CloneBundle;
+
+/// The clonebundles manifest: a line per bundle, with its URL followed by its attributes.
+pub fn manifest(bundles: &[CloneBundle]) -> String {
+    bundles
+        .iter()
+        .map(|bundle| match bundle.bundlespec {
+            Some(ref spec) => format!("{} BUNDLESPEC={}\n", bundle.url, percent_encode(spec)),
+            None => format!("{}\n", bundle.url),
+        })
+        .collect()
+}
@@ -0,0 +1,146 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Pushkey namespaces
+//!
+//! Mercurial exposes some of a repo's state as namespaces of keys and values, which clients read
+//! either with the `listkeys` command or from `listkeys` parts in a bundle2 reply to `getbundle`.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use futures::{stream, Future, Stream};
+use futures_ext::{BoxStream, StreamExt};
+
+use blobrepo::Phase;
+use hgproto;
+use mercurial_types::Repo;
+
+use phases::RepoPhases;
+use repo::BoxedHgRepo;
+
+pub type ListkeysStream = BoxStream<(Vec<u8>, Vec<u8>), hgproto::Error>;
+
+/// A namespace whose keys and values can be listed.
+pub trait NamespaceProvider: Send + Sync + 'static {
+    fn list(&self) -> ListkeysStream;
+}
+
+/// Bookmark names, mapped to the hex hashes of the changesets they point to.
+pub struct BookmarksNamespace {
+    repo: Arc<BoxedHgRepo>,
+}
+
+impl BookmarksNamespace {
+    pub fn new(repo: Arc<BoxedHgRepo>) -> Self {
+        BookmarksNamespace { repo }
+    }
+}
+
+impl NamespaceProvider for BookmarksNamespace {
+    fn list(&self) -> ListkeysStream {
+        let bookmarks = match self.repo.get_bookmarks() {
+            Ok(bookmarks) => bookmarks,
+            Err(err) => return stream::once(Err(err)).boxify(),
+        };
+
+        bookmarks
+            .keys()
+            .and_then(move |name| {
+                bookmarks.get(&name).map(move |value| {
+                    // A bookmark can be deleted between listing the names and getting its value,
+                    // in which case it's skipped.
+                    value.map(|(hash, _version)| {
+                        let hash: Vec<u8> = hash.to_hex().into();
+                        (name, hash)
+                    })
+                })
+            })
+            .filter_map(|item| item)
+            .boxify()
+    }
+}
+
+/// Phase information: the roots of the draft changesets, mapped to the draft phase. Publishing
+/// repos don't have any, and say that they're publishing instead.
+pub struct PhasesNamespace {
+    phases: RepoPhases,
+}
+
+impl PhasesNamespace {
+    pub fn new(phases: RepoPhases) -> Self {
+        PhasesNamespace { phases }
+    }
+}
+
+impl NamespaceProvider for PhasesNamespace {
+    fn list(&self) -> ListkeysStream {
+        if self.phases.is_publishing() {
+            return stream::once(Ok((b"publishing".to_vec(), b"True".to_vec()))).boxify();
+        }
+
+        let draft = format!("{}", Phase::Draft.as_num()).into_bytes();
+        self.phases
+            .draft_roots()
+            .map(move |roots| {
+                let items: Vec<_> = roots
+                    .into_iter()
+                    .map(|root| {
+                        let root: Vec<u8> = root.to_hex().into();
+                        (root, draft.clone())
+                    })
+                    .collect();
+                stream::iter_ok(items)
+            })
+            .flatten_stream()
+            .boxify()
+    }
+}
+
+/// The names of all the available namespaces, with empty values.
+struct NamespacesNamespace {
+    names: Vec<&'static str>,
+}
+
+impl NamespaceProvider for NamespacesNamespace {
+    fn list(&self) -> ListkeysStream {
+        let items: Vec<_> = self.names
+            .iter()
+            .map(|name| (name.as_bytes().to_vec(), Vec::new()))
+            .collect();
+        stream::iter_ok(items).boxify()
+    }
+}
+
+/// All the namespaces a repo serves.
+pub struct Namespaces {
+    providers: BTreeMap<&'static str, Box<NamespaceProvider>>,
+}
+
+impl Namespaces {
+    pub fn new(repo: Arc<BoxedHgRepo>, phases: RepoPhases) -> Self {
+        let mut providers = BTreeMap::new();
+        providers.insert(
+            "bookmarks",
+            Box::new(BookmarksNamespace::new(repo)) as Box<NamespaceProvider>,
+        );
+        providers.insert("phases", Box::new(PhasesNamespace::new(phases)));
+
+        let mut names: Vec<_> = providers.keys().cloned().collect();
+        names.push("namespaces");
+        providers.insert("namespaces", Box::new(NamespacesNamespace { names }));
+
+        Namespaces { providers }
+    }
+
+    /// List the keys and values in `namespace`. As in Mercurial, unknown namespaces are empty.
+    pub fn list(&self, namespace: &str) -> ListkeysStream {
+        match self.providers.get(namespace) {
+            Some(provider) => provider.list(),
+            None => stream::empty().boxify(),
+        }
+    }
+}
@@ -32,20 +32,53 @@ extern crate slog_term;
 extern crate maplit;
 
 extern crate async_compression;
+extern crate asyncmemo;
 extern crate blobrepo;
+extern crate blobstore;
+extern crate bzip2;
 extern crate bytes;
 extern crate hgproto;
+extern crate hooks;
+extern crate lz4;
 extern crate mercurial;
 extern crate mercurial_bundles;
 extern crate mercurial_types;
 extern crate metaconfig;
+extern crate repoinfo;
+extern crate revset;
 extern crate services;
 extern crate sshrelay;
 extern crate stats;
+extern crate storage_types;
+
+#[cfg(test)]
+extern crate mercurial_types_mocks;
+#[cfg(test)]
+extern crate memblob;
+#[cfg(test)]
+extern crate membookmarks;
+#[cfg(test)]
+extern crate memcsindex;
+#[cfg(test)]
+extern crate memheads;
+#[cfg(test)]
+extern crate memlinknodes;
+#[cfg(test)]
+extern crate memphases;
 
 mod errors;
 mod repo;
 mod listener;
+mod branchmap;
+mod clonebundles;
+mod listkeys;
+mod phases;
+mod pushrebase;
+mod remotefilelog;
+mod repohooks;
+mod streamclone;
+mod treemanifest;
+mod unbundle;
 
 use std::io;
 use std::panic;
@@ -57,9 +90,9 @@ use std::thread::{self, JoinHandle};
 use futures::{Future, Sink, Stream};
 use futures::sink::Wait;
 use futures::sync::mpsc;
-use futures_ext::{encode, StreamLayeredExt};
+use futures_ext::{FutureExt, StreamLayeredExt};
 
-use clap::{App, ArgGroup, ArgMatches};
+use clap::{App, Arg, ArgGroup, ArgMatches};
 
 use slog::{Drain, Level, Logger};
 use slog_glog_fmt::{kv_categorizer, kv_defaults, GlogFormat};
@@ -67,10 +100,10 @@ use slog_kvfilter::KVFilter;
 use slog_logview::LogViewDrain;
 
 use bytes::Bytes;
-use hgproto::HgService;
-use hgproto::sshproto::{HgSshCommandDecode, HgSshCommandEncode};
+use hgproto::{HgCommandRes, HgService, DEFAULT_BATCH_CONCURRENCY};
+use hgproto::sshproto::{response, HgSshCommandDecode, DEFAULT_MAX_UNBUNDLE_SIZE};
 use metaconfig::RepoConfigs;
-use metaconfig::repoconfig::RepoType;
+use metaconfig::repoconfig::{RepoConfig, RepoType};
 
 use errors::*;
 
@@ -118,10 +151,24 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
             [crhash]      -C, --configrepo_hash [HASH]           'config repo commit hash'
 
             -p, --thrift_port [PORT] 'if provided the thrift server will start on this port'
+            --max-push-size [BYTES] 'largest push to accept, in bytes'
+
+            [clonebundle] --generate-clonebundle [REPO] 'store a bundle of REPO for clonebundles and exit'
+            [streamclone] --generate-streamclone [REPO] 'store the files of REPO for stream clones and exit'
+            [csindex] --index-changesets [REPO] 'index the existing changesets of REPO and exit'
+            [linknodes] --backfill-linknodes [REPO] 'record the missing linknodes of REPO and exit'
 
             -d, --debug                                          'print debug level output'
         "#,
         )
+        .arg(
+            Arg::from_usage(
+                "--batch-concurrency [N] 'max number of commands in a batch to run at once'",
+            ).validator(|n| match n.parse::<usize>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err("must be a positive number".into()),
+            }),
+        )
         .group(
             ArgGroup::default()
                 .args(&["crbookmark", "crhash"])
@@ -189,7 +236,9 @@ fn start_thrift_service<'a>(
 }
 
 fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<RepoConfigs> {
-    let config_repo = RepoType::Revlog(matches.value_of("crpath").unwrap().into()).open()?;
+    let config_repo = RepoType::Revlog(matches.value_of("crpath").unwrap().into())
+        .open()?
+        .hgrepo;
 
     let node_hash = if let Some(bookmark) = matches.value_of("crbookmark") {
         config_repo
@@ -213,9 +262,14 @@ fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<RepoConfi
         .wait()
 }
 
-fn start_repo_listeners<I>(repos: I, root_log: &Logger) -> Result<Vec<JoinHandle<!>>>
+fn start_repo_listeners<I>(
+    repos: I,
+    batch_concurrency: usize,
+    max_push_size: usize,
+    root_log: &Logger,
+) -> Result<Vec<JoinHandle<!>>>
 where
-    I: IntoIterator<Item = RepoType>,
+    I: IntoIterator<Item = (String, RepoConfig)>,
 {
     // Given the list of paths to repos:
     // - initialize the repo
@@ -223,7 +277,7 @@ where
     // - wait for connections in that thread
     let repos: Vec<_> = repos
         .into_iter()
-        .map(|repotype| repo::init_repo(root_log, &repotype))
+        .map(|(name, config)| repo::init_repo(root_log, &name, &config))
         .collect();
 
     if repos.iter().any(Result::is_err) {
@@ -244,7 +298,9 @@ where
             // connections and detach it
             thread::Builder::new()
                 .name(format!("listener_{}", repo.path()))
-                .spawn(move || repo_listen(sockname, repo, listen_log))
+                .spawn(move || {
+                    repo_listen(sockname, repo, batch_concurrency, max_push_size, listen_log)
+                })
                 .map_err(Error::from)
         })
         .collect();
@@ -261,8 +317,74 @@ where
     Ok(handles.into_iter().filter_map(Result::ok).collect())
 }
 
+/// A command run on a repo instead of serving it, which resolves to what to log once it's done.
+type RepoCommand = fn(&repo::RepoClient) -> HgCommandRes<String>;
+
+/// The flags that name a repo to run a command on and then exit, with what's logged while the
+/// command runs.
+const REPO_COMMANDS: &[(&str, &str, RepoCommand)] = &[
+    ("clonebundle", "Generating clonebundle", generate_clonebundle),
+    ("streamclone", "Generating stream clone data", generate_streamclone),
+    ("csindex", "Indexing changesets", index_changesets),
+    ("linknodes", "Backfilling linknodes", backfill_linknodes),
+];
+
+fn run_repo_command(
+    root_log: &Logger,
+    config: RepoConfigs,
+    reponame: &str,
+    running: &str,
+    command: RepoCommand,
+) -> Result<()> {
+    let config = config
+        .repos
+        .get(reponame)
+        .ok_or_else(|| Error::from(format!("unknown repo {}", reponame)))?;
+
+    info!(root_log, "{} for {}", running, reponame);
+    let repo = Arc::new(repo::HgRepo::new(root_log, reponame, config)?);
+    let done = command(&repo::RepoClient::new(repo, root_log)).wait()?;
+    info!(root_log, "{} for {}", done, reponame);
+
+    Ok(())
+}
+
+fn generate_clonebundle(client: &repo::RepoClient) -> HgCommandRes<String> {
+    client
+        .generate_clonebundle()
+        .map(|()| "Stored clonebundle".to_string())
+        .boxify()
+}
+
+fn generate_streamclone(client: &repo::RepoClient) -> HgCommandRes<String> {
+    client
+        .generate_streamclone()
+        .map(|()| "Stored stream clone data".to_string())
+        .boxify()
+}
+
+fn index_changesets(client: &repo::RepoClient) -> HgCommandRes<String> {
+    client
+        .index_changesets()
+        .map(|count| format!("Indexed {} changesets", count))
+        .boxify()
+}
+
+fn backfill_linknodes(client: &repo::RepoClient) -> HgCommandRes<String> {
+    client
+        .backfill_linknodes()
+        .map(|count| format!("Backfilled linknodes of {} changesets", count))
+        .boxify()
+}
+
 // Listener thread for a specific repo
-fn repo_listen<P>(sockname: P, repo: repo::HgRepo, listen_log: Logger) -> !
+fn repo_listen<P>(
+    sockname: P,
+    repo: repo::HgRepo,
+    batch_concurrency: usize,
+    max_push_size: usize,
+    listen_log: Logger,
+) -> !
 where
     P: AsRef<Path>,
 {
@@ -303,16 +425,22 @@ where
 
             // Construct a repo
             let client = repo::RepoClient::new(repo.clone(), &conn_log);
-            let service = Arc::new(HgService::new_with_logger(client, &conn_log));
+            let service = HgService::new_with_logger(client, &conn_log)
+                .with_batch_concurrency(batch_concurrency);
+            let service = Arc::new(service);
 
             // Map stdin into mercurial requests
-            let reqs = stdin.decode(HgSshCommandDecode);
+            let decoder = HgSshCommandDecode::new().with_max_unbundle_size(max_push_size);
+            let reqs = stdin.decode(decoder);
 
             // process requests
             let resps = reqs.and_then(move |req| service.clone().command(req));
 
-            // send responses back
-            let endres = encode::encode(resps, HgSshCommandEncode)
+            // send responses back, with streaming responses sent as they're generated so that
+            // they don't have to be held in memory
+            let endres = resps
+                .map(response::encode)
+                .flatten()
                 .map_err(Error::from)
                 .forward(stdout)
                 .map(|_| ());
@@ -347,6 +475,14 @@ fn main() {
     let root_log = setup_logger(&matches);
 
     fn run_server<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<!> {
+        for &(flag, running, command) in REPO_COMMANDS {
+            if let Some(reponame) = matches.value_of(flag) {
+                let config = get_config(root_log, &matches)?;
+                run_repo_command(root_log, config, reponame, running, command)?;
+                std::process::exit(0);
+            }
+        }
+
         info!(root_log, "Starting up");
 
         let stats_aggregation = start_stats()?;
@@ -355,9 +491,25 @@ fn main() {
             Some(handle) => Some(handle?),
         };
 
+        let batch_concurrency = match matches.value_of("batch-concurrency") {
+            Some(n) => n.parse()
+                .chain_err(|| "Failed to parse batch-concurrency as number")?,
+            None => DEFAULT_BATCH_CONCURRENCY,
+        };
+
+        let max_push_size = match matches.value_of("max-push-size") {
+            Some(n) => n.parse()
+                .chain_err(|| "Failed to parse max-push-size as number")?,
+            None => DEFAULT_MAX_UNBUNDLE_SIZE,
+        };
+
         let config = get_config(root_log, &matches)?;
-        let repo_listeners =
-            start_repo_listeners(config.repos.into_iter().map(|(_, c)| c.repotype), root_log)?;
+        let repo_listeners = start_repo_listeners(
+            config.repos,
+            batch_concurrency,
+            max_push_size,
+            root_log,
+        )?;
 
         for handle in vec![stats_aggregation]
             .into_iter()
@@ -0,0 +1,255 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Phases
+//!
+//! Changesets that are pushed start out draft, so that those pushed for review can still be
+//! rewritten. They become public once they're ancestors of a bookmark, as all bookmarks are
+//! publishing, or once a client publishes them. Changesets that were added in any other way,
+//! such as by blobimport, are public, as are all the changesets of repos that can't record
+//! phases.
+//!
+//! Only draft changesets have phase records, and they're removed once the changesets are
+//! published, so that reading them stays cheap however many changesets were ever pushed. Each
+//! bookmark move publishes where the bookmark was and where it goes, so that the records are
+//! all there is to read: changesets stay public when a bookmark is moved back or deleted.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobrepo::{BlobRepo, BlobState, Phase};
+use hgproto;
+use mercurial_types::{NodeHash, Repo};
+
+use repo::BoxedHgRepo;
+use unbundle::repo_err;
+
+/// The operations on a repo's underlying storage that are needed to keep track of phases.
+pub trait PhaseStore: Send + Sync + 'static {
+    /// Get the changesets whose recorded phase is draft.
+    fn get_drafts(&self) -> BoxStream<NodeHash, hgproto::Error>;
+    fn set_phase(&self, node: &NodeHash, phase: Phase) -> BoxFuture<(), hgproto::Error>;
+    /// Forget the phase recorded for a changeset, which makes it public.
+    fn remove_phase(&self, node: &NodeHash) -> BoxFuture<(), hgproto::Error>;
+}
+
+impl<State> PhaseStore for BlobRepo<State>
+where
+    State: BlobState,
+{
+    fn get_drafts(&self) -> BoxStream<NodeHash, hgproto::Error> {
+        BlobRepo::get_drafts(self).map_err(repo_err).boxify()
+    }
+
+    fn set_phase(&self, node: &NodeHash, phase: Phase) -> BoxFuture<(), hgproto::Error> {
+        BlobRepo::set_phase(self, node, phase)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn remove_phase(&self, node: &NodeHash) -> BoxFuture<(), hgproto::Error> {
+        BlobRepo::remove_phase(self, node)
+            .map_err(repo_err)
+            .boxify()
+    }
+}
+
+/// Draft changesets, mapped to their parents.
+type Drafts = HashMap<NodeHash, Vec<NodeHash>>;
+
+/// The phases of the changesets in a repo.
+#[derive(Clone)]
+pub struct RepoPhases {
+    hgrepo: Arc<BoxedHgRepo>,
+    store: Option<Arc<PhaseStore>>,
+}
+
+impl RepoPhases {
+    pub fn new(hgrepo: Arc<BoxedHgRepo>, store: Option<Arc<PhaseStore>>) -> Self {
+        RepoPhases { hgrepo, store }
+    }
+
+    /// Whether all the changesets in the repo are public.
+    pub fn is_publishing(&self) -> bool {
+        self.store.is_none()
+    }
+
+    /// The draft changesets in the repo.
+    pub fn drafts(&self) -> BoxFuture<Drafts, hgproto::Error> {
+        let store = match self.store {
+            Some(ref store) => store.clone(),
+            None => return future::ok(HashMap::new()).boxify(),
+        };
+        let hgrepo = self.hgrepo.clone();
+
+        store
+            .get_drafts()
+            .and_then(move |node| {
+                hgrepo
+                    .get_changeset_by_nodeid(&node)
+                    .map(move |cs| (node, cs.parents().into_iter().collect::<Vec<_>>()))
+            })
+            .collect()
+            .map(|drafts| drafts.into_iter().collect())
+            .boxify()
+    }
+
+    /// The draft changesets that don't have draft parents, which is how the `phases` listkeys
+    /// namespace describes the draft changesets.
+    pub fn draft_roots(&self) -> BoxFuture<Vec<NodeHash>, hgproto::Error> {
+        self.drafts()
+            .map(|drafts| {
+                drafts
+                    .iter()
+                    .filter(|&(_, parents)| !parents.iter().any(|p| drafts.contains_key(p)))
+                    .map(|(node, _)| *node)
+                    .collect()
+            })
+            .boxify()
+    }
+
+    /// The heads of each phase among `heads` and their ancestors, for a `phase-heads` part. The
+    /// draft heads are those of `heads` that are draft, and the public heads are the rest of
+    /// `heads` plus the public parents of the draft changesets.
+    pub fn phase_heads(
+        &self,
+        heads: Vec<NodeHash>,
+    ) -> BoxStream<(Phase, NodeHash), hgproto::Error> {
+        self.drafts()
+            .map(move |drafts| {
+                let (mut draft_heads, mut public_heads): (Vec<_>, Vec<_>) = heads
+                    .into_iter()
+                    .partition(|head| drafts.contains_key(head));
+
+                let mut pending = draft_heads.clone();
+                let mut seen = HashSet::new();
+                while let Some(node) = pending.pop() {
+                    if !seen.insert(node) {
+                        continue;
+                    }
+                    for parent in &drafts[&node] {
+                        if drafts.contains_key(parent) {
+                            pending.push(*parent);
+                        } else {
+                            public_heads.push(*parent);
+                        }
+                    }
+                }
+
+                public_heads.sort();
+                public_heads.dedup();
+                draft_heads.sort();
+                let public_heads = public_heads.into_iter().map(|node| (Phase::Public, node));
+                let draft_heads = draft_heads.into_iter().map(|node| (Phase::Draft, node));
+                stream::iter_ok(public_heads.chain(draft_heads).collect::<Vec<_>>())
+            })
+            .flatten_stream()
+            .boxify()
+    }
+
+    /// Record changesets that are being added as draft, apart from any the repo already has.
+    pub fn add_drafts(&self, nodes: Vec<NodeHash>) -> BoxFuture<(), hgproto::Error> {
+        let store = match self.store {
+            Some(ref store) => store.clone(),
+            None => return future::ok(()).boxify(),
+        };
+        let hgrepo = self.hgrepo.clone();
+
+        let adds: Vec<_> = nodes
+            .into_iter()
+            .map(move |node| {
+                let store = store.clone();
+                hgrepo
+                    .changeset_exists(&node)
+                    .and_then(move |exists| if exists {
+                        future::ok(()).boxify()
+                    } else {
+                        store.set_phase(&node, Phase::Draft)
+                    })
+            })
+            .collect();
+        future::join_all(adds).map(|_| ()).boxify()
+    }
+
+    /// Make `heads` and all their ancestors public.
+    pub fn publish(&self, heads: Vec<NodeHash>) -> BoxFuture<(), hgproto::Error> {
+        let store = match self.store {
+            Some(ref store) => store.clone(),
+            None => return future::ok(()).boxify(),
+        };
+
+        self.drafts()
+            .and_then(move |drafts| {
+                let mut pending: Vec<_> = heads
+                    .into_iter()
+                    .filter(|head| drafts.contains_key(head))
+                    .collect();
+                let mut published = HashSet::new();
+                while let Some(node) = pending.pop() {
+                    if published.insert(node) {
+                        pending.extend(
+                            drafts[&node]
+                                .iter()
+                                .filter(|parent| drafts.contains_key(*parent)),
+                        );
+                    }
+                }
+
+                let removes: Vec<_> = published
+                    .iter()
+                    .map(|node| store.remove_phase(node))
+                    .collect();
+                future::join_all(removes)
+            })
+            .map(|_| ())
+            .boxify()
+    }
+
+    /// Publish what a bookmark pointed to before and after it was moved. Publishing where it
+    /// was as well keeps changesets public that were bookmarked before publishing was recorded.
+    pub fn bookmark_moved(
+        &self,
+        old: Option<NodeHash>,
+        new: Option<NodeHash>,
+    ) -> BoxFuture<(), hgproto::Error> {
+        self.publish(old.into_iter().chain(new).collect())
+    }
+
+    /// Move changeset `node` from phase `old` to phase `new`, as a client asked with `pushkey`.
+    /// As in Mercurial, phases can only move towards public, and moving a changeset that's
+    /// already in phase `new` succeeds.
+    pub fn pushkey(
+        &self,
+        node: NodeHash,
+        old: Phase,
+        new: Phase,
+    ) -> BoxFuture<bool, hgproto::Error> {
+        let phases = self.clone();
+        self.hgrepo
+            .changeset_exists(&node)
+            .join(self.drafts())
+            .and_then(move |(exists, drafts)| {
+                if !exists {
+                    return future::ok(false).boxify();
+                }
+                let current = if drafts.contains_key(&node) {
+                    Phase::Draft
+                } else {
+                    Phase::Public
+                };
+
+                if current == old && old == Phase::Draft && new == Phase::Public {
+                    phases.publish(vec![node]).map(|_| true).boxify()
+                } else {
+                    future::ok(current == new).boxify()
+                }
+            })
+            .boxify()
+    }
+}
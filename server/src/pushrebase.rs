@@ -0,0 +1,1076 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Pushrebase
+//!
+//! When many people push to the same bookmark, ordinary pushes race: each one is based on where
+//! the bookmark was when its author last pulled, and fails if anything else has landed since.
+//! Pushrebase clients send their changesets in a `b2x:rebase` part instead, naming the bookmark
+//! they're meant for, and the server rebases them onto wherever the bookmark is now. The push
+//! is only refused if one of the pushed changesets touches a file that has changed on the
+//! bookmark since the changeset the push is based on.
+//!
+//! The rebased changesets are written to the repo and, if the bookmark's hooks allow it, the
+//! bookmark is moved to them, provided nothing else moved it in the meantime. If something did,
+//! the push is rebased again onto the bookmark's new position, a limited number of times. Once
+//! the bookmark has moved, the rebased changesets are published, and the client is told which
+//! changeset replaced each one it pushed.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{future, Future, Stream};
+use futures::future::Loop;
+use futures_ext::{BoxFuture, FutureExt};
+
+use hgproto;
+use mercurial::changeset::RevlogChangeset;
+use mercurial::manifest::revlog as revlog_manifest;
+use mercurial_bundles::PartHeader;
+use mercurial_types::{Blob, BlobNode, Changeset, MPath, NodeHash, Parents, Repo, RepoPath,
+                      NULL_HASH};
+use repoinfo::RepoGenCache;
+use revset::{AncestorsNodeStream, IntersectNodeStream, NodeStream, SetDifferenceNodeStream,
+             SingleNodeHash};
+
+use phases::RepoPhases;
+use repo::{manifest_lines, manifest_text, BoxedHgRepo, ManifestLines};
+use repohooks::RepoHooks;
+use unbundle::{repo_err, store_changesets, store_nodes, store_unlinked_files, update_heads,
+               PushStore, Revision, Revisions};
+
+/// What a client asked for in a `b2x:rebase` part.
+pub struct RebaseRequest {
+    /// The bookmark to rebase onto.
+    pub onto: Vec<u8>,
+    /// Whether the client can read version 1 obsolescence markers.
+    pub obsmarkers: bool,
+}
+
+impl RebaseRequest {
+    pub fn from_header(header: &PartHeader) -> hgproto::Result<Self> {
+        let onto = match param(header, "onto") {
+            Some(onto) => onto.to_vec(),
+            None => bail!("b2x:rebase part has no onto parameter"),
+        };
+        // Clients list the marker formats they support, separated by nulls. Those that don't
+        // say are sent version 1, which all of them can read.
+        let obsmarkers = match param(header, "obsmarkerversions") {
+            Some(versions) => versions.split(|b| *b == b'\0').any(|v| v == b"1"),
+            None => true,
+        };
+
+        Ok(RebaseRequest { onto, obsmarkers })
+    }
+}
+
+fn param<'a>(header: &'a PartHeader, key: &str) -> Option<&'a Bytes> {
+    header
+        .mparams()
+        .get(key)
+        .or_else(|| header.aparams().get(key))
+}
+
+/// How many times a push is rebased onto a bookmark that keeps being moved by other pushes,
+/// before giving up.
+const MAX_ATTEMPTS: usize = 10;
+
+/// A pushed changeset.
+struct Pushed {
+    node: NodeHash,
+    cs: RevlogChangeset,
+}
+
+/// Changesets that have been rebased, and aren't in the repo yet.
+struct Rebased {
+    changesets: Vec<Revision>,
+    manifests: Vec<Revision>,
+    /// Each pushed changeset, and the changeset that replaces it.
+    replacements: Vec<(NodeHash, NodeHash)>,
+}
+
+/// Rebased changesets that are in the repo, with the bookmark moved to them.
+struct Landed {
+    /// Where the bookmark was moved from.
+    onto: NodeHash,
+    changesets: Vec<Revision>,
+    /// Each new manifest, and the changeset that introduced it.
+    manifest_links: Vec<(NodeHash, NodeHash)>,
+    replacements: Vec<(NodeHash, NodeHash)>,
+}
+
+/// What each attempt at rebasing a push needs.
+#[derive(Clone)]
+struct Push {
+    hgrepo: Arc<BoxedHgRepo>,
+    store: Arc<PushStore>,
+    hooks: RepoHooks,
+    repo_generation: RepoGenCache<BoxedHgRepo>,
+    bookmark: Vec<u8>,
+    /// The changeset the pushed ones are based on.
+    base: NodeHash,
+    stack: Arc<Vec<Pushed>>,
+    /// The manifest of each pushed changeset.
+    manifests: Arc<Vec<ManifestLines>>,
+}
+
+/// Rebase the pushed changesets in `revs` onto the bookmark the client asked for, write them to
+/// the repo and move the bookmark to them. If another push moves the bookmark first, this is
+/// tried again on top of it. Resolves to the value for `reply:changegroup`, and each pushed
+/// changeset paired with the changeset that replaced it.
+pub fn pushrebase(
+    hgrepo: Arc<BoxedHgRepo>,
+    store: Arc<PushStore>,
+    phases: RepoPhases,
+    hooks: RepoHooks,
+    repo_generation: RepoGenCache<BoxedHgRepo>,
+    request: RebaseRequest,
+    revs: Revisions,
+) -> BoxFuture<(i64, Vec<(NodeHash, NodeHash)>), hgproto::Error> {
+    let Revisions {
+        changesets,
+        manifests,
+        filelogs,
+    } = revs;
+    let (base, stack) = match linear_stack(changesets) {
+        Ok(stack) => stack,
+        Err(err) => return future::err(err).boxify(),
+    };
+
+    // The pushed manifests are needed to find the new versions of the files each changeset
+    // touches. A changeset that doesn't change its parent's manifest doesn't send it.
+    let manifests: HashMap<_, _> = manifests
+        .into_iter()
+        .map(|rev| (rev.node, rev.text))
+        .collect();
+    let pushed_manifests: Vec<_> = stack
+        .iter()
+        .map(|pushed| {
+            let manifestid = pushed.cs.manifestid();
+            let text = match manifests.get(manifestid) {
+                Some(text) => future::ok(text.clone()).boxify(),
+                None => store.get_raw_content(manifestid),
+            };
+            text.and_then(|text| parse_manifest(&text))
+        })
+        .collect();
+
+    // File revisions are the same whichever changesets they end up in, so they're only stored
+    // once. Which changesets introduced them is only known once the bookmark has moved.
+    let file_links: Vec<_> = filelogs
+        .iter()
+        .flat_map(|&(ref path, ref revs)| {
+            revs.iter()
+                .map(move |rev| (path.clone(), rev.node, rev.linknode))
+        })
+        .collect();
+    let files = store_unlinked_files(store.clone(), filelogs);
+
+    let bookmark = request.onto;
+    let landed = future::join_all(pushed_manifests)
+        .join(files)
+        .and_then({
+            let hgrepo = hgrepo.clone();
+            let store = store.clone();
+            move |(pushed_manifests, ())| {
+                let push = Push {
+                    hgrepo,
+                    store,
+                    hooks,
+                    repo_generation,
+                    bookmark: bookmark.clone(),
+                    base,
+                    stack: Arc::new(stack),
+                    manifests: Arc::new(pushed_manifests),
+                };
+                future::loop_fn(1, move |attempt| {
+                    let bookmark = bookmark.clone();
+                    rebase_and_move(push.clone()).and_then(move |landed| match landed {
+                        Some(landed) => Ok(Loop::Break(landed)),
+                        None if attempt < MAX_ATTEMPTS => Ok(Loop::Continue(attempt + 1)),
+                        None => Err(hgproto::Error::from(format!(
+                            "bookmark {} kept moving during the push - please try again",
+                            String::from_utf8_lossy(&bookmark)
+                        ))),
+                    })
+                })
+            }
+        });
+
+    landed
+        .and_then(move |landed| {
+            let Landed {
+                onto,
+                changesets,
+                manifest_links,
+                replacements,
+            } = landed;
+            let head = replacements[replacements.len() - 1].1;
+
+            let linknodes =
+                add_rebased_linknodes(&store, manifest_links, file_links, &replacements);
+            let published = phases.bookmark_moved(Some(onto), Some(head));
+            // Other pushes may have added heads since this one started.
+            let heads = hgrepo
+                .get_heads()
+                .collect()
+                .map(|heads| heads.into_iter().collect::<HashSet<_>>());
+            linknodes
+                .join3(published, heads)
+                .and_then(move |((), (), heads)| update_heads(store, heads, changesets))
+                .map(move |ret| (ret, replacements))
+        })
+        .boxify()
+}
+
+/// Rebase the pushed changesets onto wherever the bookmark is now, write them to the repo and
+/// move the bookmark to them. Resolves to `None` if something else moved the bookmark first.
+fn rebase_and_move(push: Push) -> BoxFuture<Option<Landed>, hgproto::Error> {
+    let bookmarks = match push.hgrepo.get_bookmarks() {
+        Ok(bookmarks) => bookmarks,
+        Err(err) => return future::err(err).boxify(),
+    };
+
+    let onto = bookmarks.get(&push.bookmark).and_then({
+        let name = push.bookmark.clone();
+        move |bookmark| {
+            bookmark.ok_or_else(|| {
+                hgproto::Error::from(format!(
+                    "bookmark {} doesn't exist",
+                    String::from_utf8_lossy(&name)
+                ))
+            })
+        }
+    });
+
+    let rebased = onto.and_then(move |(onto, version)| {
+        let hgrepo = push.hgrepo.clone();
+        let changed = changed_since(hgrepo.clone(), push.repo_generation.clone(), push.base, onto);
+        let onto_manifest = hgrepo.get_changeset_by_nodeid(&onto).and_then(move |cs| {
+            let manifestid = *cs.manifestid();
+            manifest_lines(&hgrepo, &manifestid).map(move |lines| (manifestid, lines))
+        });
+
+        changed.join(onto_manifest).and_then(
+            move |(changed, (manifestid, lines))| -> hgproto::Result<_> {
+                check_conflicts(&push.bookmark, &changed, &push.stack)?;
+                let rebased = rebase(onto, manifestid, lines, &push.stack, &push.manifests)?;
+                Ok((push, onto, version, rebased))
+            },
+        )
+    });
+
+    rebased
+        .and_then(|(push, onto, version, rebased)| {
+            let Rebased {
+                changesets,
+                manifests,
+                replacements,
+            } = rebased;
+            let head = replacements[replacements.len() - 1].1;
+            let manifest_links: Vec<_> = manifests
+                .iter()
+                .map(|rev| (rev.node, rev.linknode))
+                .collect();
+            let Push {
+                store,
+                hooks,
+                bookmark,
+                ..
+            } = push;
+
+            // The hooks are run once the rebased changesets are in the repo, so that they can
+            // look at them. Changesets left behind by an attempt that lost the race are never
+            // made heads.
+            store_nodes(store.clone(), manifests)
+                .and_then({
+                    let store = store.clone();
+                    move |()| store_changesets(store, changesets)
+                })
+                .and_then({
+                    let bookmark = bookmark.clone();
+                    move |changesets| {
+                        hooks
+                            .check(&bookmark, Some(onto), Some(head))
+                            .map(move |_| changesets)
+                    }
+                })
+                .and_then(move |changesets| {
+                    store
+                        .set_bookmark(&bookmark, &head, &version)
+                        .map(move |moved| if moved {
+                            Some(Landed {
+                                onto,
+                                changesets,
+                                manifest_links,
+                                replacements,
+                            })
+                        } else {
+                            None
+                        })
+                })
+        })
+        .boxify()
+}
+
+/// Record the rebased changesets as the ones that introduced the pushed file revisions and the
+/// new manifests.
+fn add_rebased_linknodes(
+    store: &Arc<PushStore>,
+    manifest_links: Vec<(NodeHash, NodeHash)>,
+    file_links: Vec<(MPath, NodeHash, NodeHash)>,
+    replacements: &[(NodeHash, NodeHash)],
+) -> BoxFuture<(), hgproto::Error> {
+    let replacements: HashMap<_, _> = replacements.iter().cloned().collect();
+    let mut adds: Vec<_> = manifest_links
+        .iter()
+        .map(|&(node, linknode)| store.add_linknode(RepoPath::root(), &node, &linknode))
+        .collect();
+    for (path, node, linknode) in file_links {
+        let path = match RepoPath::file(path) {
+            Ok(path) => path,
+            Err(err) => return future::err(repo_err(err)).boxify(),
+        };
+        let linknode = replacements.get(&linknode).unwrap_or(&linknode);
+        adds.push(store.add_linknode(path, &node, linknode));
+    }
+    future::join_all(adds).map(|_| ()).boxify()
+}
+
+/// Check that the pushed changesets form a linear stack, parents first, and return the
+/// changeset it's based on along with them.
+fn linear_stack(changesets: Vec<Revision>) -> hgproto::Result<(NodeHash, Vec<Pushed>)> {
+    let mut base = None;
+    let mut stack: Vec<Pushed> = Vec::new();
+
+    for rev in changesets {
+        let cs = {
+            let (p1, p2) = rev.parents.get_nodes();
+            if p2.is_some() {
+                bail!("can't pushrebase merge {}", rev.node);
+            }
+            let parent = *p1.unwrap_or(&NULL_HASH);
+            match stack.last() {
+                Some(prev) if prev.node != parent => {
+                    bail!("can't pushrebase changesets that aren't a linear stack")
+                }
+                Some(_) => (),
+                None => base = Some(parent),
+            }
+            RevlogChangeset::new(BlobNode::new(Blob::Dirty(rev.text.as_slice()), p1, p2))?
+        };
+        stack.push(Pushed { node: rev.node, cs });
+    }
+
+    match base {
+        Some(base) if base == NULL_HASH => bail!("can't pushrebase changesets without parents"),
+        Some(base) => Ok((base, stack)),
+        None => bail!("pushrebase bundle has no changesets"),
+    }
+}
+
+/// The files changed by the ancestors of `onto` that aren't ancestors of `base`. Fails if
+/// `base` isn't an ancestor of `onto`, as there's nothing to rebase onto then.
+fn changed_since(
+    hgrepo: Arc<BoxedHgRepo>,
+    repo_generation: RepoGenCache<BoxedHgRepo>,
+    base: NodeHash,
+    onto: NodeHash,
+) -> BoxFuture<HashSet<MPath>, hgproto::Error> {
+    let ancestors = |node: NodeHash| {
+        Box::new(AncestorsNodeStream::new(
+            &hgrepo,
+            repo_generation.clone(),
+            node,
+        )) as Box<NodeStream>
+    };
+
+    let inputs = vec![
+        ancestors(onto),
+        Box::new(SingleNodeHash::new(base, &*hgrepo)) as Box<NodeStream>,
+    ];
+    let is_ancestor = IntersectNodeStream::new(&hgrepo, repo_generation.clone(), inputs)
+        .map_err(revset_err)
+        .take(1)
+        .collect()
+        .map(|nodes| !nodes.is_empty());
+
+    let changed = {
+        let repo = hgrepo.clone();
+        SetDifferenceNodeStream::new(
+            &hgrepo,
+            repo_generation.clone(),
+            ancestors(onto),
+            ancestors(base),
+        ).map_err(revset_err)
+            .and_then(move |node| repo.get_changeset_by_nodeid(&node))
+            .fold(HashSet::new(), |mut files, cs| {
+                files.extend(cs.files().iter().cloned());
+                Ok::<_, hgproto::Error>(files)
+            })
+    };
+
+    is_ancestor
+        .and_then(move |is_ancestor| if is_ancestor {
+            changed.boxify()
+        } else {
+            let msg = format!("{} is not an ancestor of {}", base, onto);
+            future::err(msg.into()).boxify()
+        })
+        .boxify()
+}
+
+fn revset_err(err: ::revset::errors::Error) -> hgproto::Error {
+    hgproto::Error::with_chain(err, hgproto::ErrorKind::Repo)
+}
+
+/// Fail if any of the pushed changesets touch a file that has changed on the bookmark since the
+/// push's base.
+fn check_conflicts(
+    bookmark: &[u8],
+    changed: &HashSet<MPath>,
+    stack: &[Pushed],
+) -> hgproto::Result<()> {
+    let mut conflicts: Vec<_> = stack
+        .iter()
+        .flat_map(|pushed| pushed.cs.files())
+        .filter(|path| changed.contains(*path))
+        .map(|path| format!("{}", path))
+        .collect();
+    conflicts.sort();
+    conflicts.dedup();
+
+    if !conflicts.is_empty() {
+        bail!(
+            "can't pushrebase onto {}, as these files have changed there: {}",
+            String::from_utf8_lossy(bookmark),
+            conflicts.join(", ")
+        );
+    }
+    Ok(())
+}
+
+fn parse_manifest(text: &[u8]) -> hgproto::Result<ManifestLines> {
+    let files = revlog_manifest::parse(text)?;
+    Ok(files
+        .into_iter()
+        .map(|(path, details)| {
+            (
+                path.to_vec(),
+                format!("{}{}", details.nodeid(), details.flag()),
+            )
+        })
+        .collect())
+}
+
+fn hash(text: &[u8], parent: &NodeHash) -> NodeHash {
+    BlobNode::new(Blob::Dirty(text), Some(parent), None)
+        .nodeid()
+        .expect("dirty blobs always have a hash")
+}
+
+/// Rebase each pushed changeset onto the previous one, starting with `onto`, whose manifest is
+/// `lines`. The files that a changeset touches are taken from its pushed manifest in
+/// `pushed_manifests`, and the rest from its new parent.
+fn rebase(
+    onto: NodeHash,
+    onto_manifest: NodeHash,
+    mut lines: ManifestLines,
+    stack: &[Pushed],
+    pushed_manifests: &[ManifestLines],
+) -> hgproto::Result<Rebased> {
+    let mut rebased = Rebased {
+        changesets: Vec::new(),
+        manifests: Vec::new(),
+        replacements: Vec::new(),
+    };
+    let mut parent = onto;
+    let mut parent_manifest = onto_manifest;
+
+    for (pushed, pushed_lines) in stack.iter().zip(pushed_manifests) {
+        let cs = &pushed.cs;
+        let mut changed = false;
+        for path in cs.files() {
+            let path = path.to_vec();
+            let details = pushed_lines.get(&path).cloned();
+            if lines.get(&path) != details.as_ref() {
+                changed = true;
+                match details {
+                    Some(details) => lines.insert(path, details),
+                    None => lines.remove(&path),
+                };
+            }
+        }
+
+        // As in Mercurial, a changeset that leaves the manifest as it is shares its parent's.
+        let manifest = if changed {
+            let text = manifest_text(&lines);
+            Some((hash(&text, &parent_manifest), text))
+        } else {
+            None
+        };
+        let manifestid = match manifest {
+            Some((manifestid, _)) => manifestid,
+            None => parent_manifest,
+        };
+
+        let mut text = Vec::new();
+        RevlogChangeset::new_from_parts(
+            Parents::new(Some(&parent), None),
+            manifestid,
+            cs.user().to_vec(),
+            *cs.time(),
+            cs.extra().clone(),
+            cs.files().to_vec(),
+            cs.comments().to_vec(),
+        ).generate(&mut text)?;
+        let node = hash(&text, &parent);
+
+        if let Some((_, mf_text)) = manifest {
+            rebased.manifests.push(Revision {
+                node: manifestid,
+                parents: Parents::new(Some(&parent_manifest), None),
+                linknode: node,
+                text: mf_text,
+            });
+        }
+        rebased.changesets.push(Revision {
+            node,
+            parents: Parents::new(Some(&parent), None),
+            linknode: node,
+            text,
+        });
+        rebased.replacements.push((pushed.node, node));
+        parent = node;
+        parent_manifest = manifestid;
+    }
+
+    Ok(rebased)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::sync::Mutex;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use blobrepo::{BlobRepo, MemBlobState};
+    use memblob::Memblob;
+    use membookmarks::MemBookmarks;
+    use memcsindex::MemChangesetIndex;
+    use memheads::MemHeads;
+    use memlinknodes::MemLinknodes;
+    use memphases::MemPhases;
+    use mercurial_types::{BoxRepo, Time};
+    use mercurial_types_mocks::nodehash::*;
+    use metaconfig::repoconfig::{HookConfig, HookType};
+    use slog::{Discard, Logger};
+    use storage_types::Version;
+
+    use phases::PhaseStore;
+    use repo::ChangesetStore;
+
+    use super::*;
+
+    fn path(path: &str) -> MPath {
+        MPath::new(path).unwrap()
+    }
+
+    /// A changeset with parent `parent` that changes `files`.
+    fn changeset(parent: NodeHash, manifestid: NodeHash, files: &[&str]) -> Revision {
+        let mut text = Vec::new();
+        RevlogChangeset::new_from_parts(
+            Parents::new(Some(&parent), None),
+            manifestid,
+            b"test".to_vec(),
+            Time { time: 0, tz: 0 },
+            BTreeMap::new(),
+            files.iter().map(|file| path(file)).collect(),
+            b"message".to_vec(),
+        ).generate(&mut text)
+            .unwrap();
+        Revision {
+            node: hash(&text, &parent),
+            parents: Parents::new(Some(&parent), None),
+            linknode: NULL_HASH,
+            text,
+        }
+    }
+
+    fn lines(entries: &[(&str, NodeHash)]) -> ManifestLines {
+        entries
+            .iter()
+            .map(|&(path, node)| (path.as_bytes().to_vec(), format!("{}", node)))
+            .collect()
+    }
+
+    #[test]
+    fn linear() {
+        let first = changeset(ONES_HASH, AS_HASH, &["a"]);
+        let second = changeset(first.node, BS_HASH, &["b"]);
+        let nodes = vec![first.node, second.node];
+
+        let (base, stack) = linear_stack(vec![first, second]).unwrap();
+        assert_eq!(base, ONES_HASH);
+        assert_eq!(
+            stack.iter().map(|pushed| pushed.node).collect::<Vec<_>>(),
+            nodes
+        );
+    }
+
+    #[test]
+    fn not_linear() {
+        let first = changeset(ONES_HASH, AS_HASH, &["a"]);
+        let second = changeset(TWOS_HASH, BS_HASH, &["b"]);
+        assert!(linear_stack(vec![first, second]).is_err());
+
+        assert!(linear_stack(vec![]).is_err());
+        assert!(linear_stack(vec![changeset(NULL_HASH, AS_HASH, &["a"])]).is_err());
+    }
+
+    #[test]
+    fn merge() {
+        let mut rev = changeset(ONES_HASH, AS_HASH, &["a"]);
+        rev.parents = Parents::new(Some(&ONES_HASH), Some(&TWOS_HASH));
+        assert!(linear_stack(vec![rev]).is_err());
+    }
+
+    #[test]
+    fn conflicts() {
+        let first = changeset(ONES_HASH, AS_HASH, &["a", "b"]);
+        let second = changeset(first.node, BS_HASH, &["b", "c"]);
+        let (_, stack) = linear_stack(vec![first, second]).unwrap();
+
+        let changed = vec![path("d")].into_iter().collect();
+        assert!(check_conflicts(b"master", &changed, &stack).is_ok());
+
+        let changed = vec![path("b"), path("c"), path("d")].into_iter().collect();
+        let err = check_conflicts(b"master", &changed, &stack).unwrap_err();
+        assert!(format!("{}", err).ends_with("have changed there: b, c"));
+    }
+
+    #[test]
+    fn rebase_carries_files_over() {
+        // The push changes a, and deletes b. c changed on the bookmark since the push's base.
+        let pushed = changeset(ONES_HASH, AS_HASH, &["a", "b"]);
+        let pushed_node = pushed.node;
+        let (_, stack) = linear_stack(vec![pushed]).unwrap();
+        let pushed_lines = lines(&[("a", THREES_HASH), ("c", FOURS_HASH)]);
+        let onto_lines = lines(&[("a", FOURS_HASH), ("b", FIVES_HASH), ("c", SIXES_HASH)]);
+
+        let rebased = rebase(TWOS_HASH, CS_HASH, onto_lines, &stack, &[pushed_lines]).unwrap();
+        assert_eq!(rebased.manifests.len(), 1);
+        let manifest = &rebased.manifests[0];
+        assert_eq!(
+            manifest.text,
+            manifest_text(&lines(&[("a", THREES_HASH), ("c", SIXES_HASH)]))
+        );
+        assert_eq!(manifest.parents, Parents::new(Some(&CS_HASH), None));
+
+        assert_eq!(rebased.changesets.len(), 1);
+        let cs = &rebased.changesets[0];
+        assert_eq!(cs.parents, Parents::new(Some(&TWOS_HASH), None));
+        assert_eq!(manifest.linknode, cs.node);
+        let parsed = RevlogChangeset::new(BlobNode::new(
+            Blob::Dirty(cs.text.as_slice()),
+            Some(&TWOS_HASH),
+            None,
+        )).unwrap();
+        assert_eq!(parsed.manifestid(), &manifest.node);
+        assert_eq!(rebased.replacements, vec![(pushed_node, cs.node)]);
+    }
+
+    #[test]
+    fn rebase_unchanged_manifest() {
+        // The pushed changeset only touches a file, leaving it as it is on the bookmark.
+        let pushed = changeset(ONES_HASH, AS_HASH, &["a"]);
+        let (_, stack) = linear_stack(vec![pushed]).unwrap();
+        let onto_lines = lines(&[("a", THREES_HASH), ("b", FOURS_HASH)]);
+        let pushed_lines = lines(&[("a", THREES_HASH)]);
+
+        let rebased = rebase(TWOS_HASH, CS_HASH, onto_lines, &stack, &[pushed_lines]).unwrap();
+        assert!(rebased.manifests.is_empty());
+        let cs = &rebased.changesets[0];
+        let parsed = RevlogChangeset::new(BlobNode::new(
+            Blob::Dirty(cs.text.as_slice()),
+            Some(&TWOS_HASH),
+            None,
+        )).unwrap();
+        assert_eq!(parsed.manifestid(), &CS_HASH);
+    }
+
+    #[test]
+    fn rebase_stack() {
+        let first = changeset(ONES_HASH, AS_HASH, &["a"]);
+        let second = changeset(first.node, BS_HASH, &["b"]);
+        let (_, stack) = linear_stack(vec![first, second]).unwrap();
+        let pushed_lines = vec![
+            lines(&[("a", THREES_HASH)]),
+            lines(&[("a", THREES_HASH), ("b", FOURS_HASH)]),
+        ];
+
+        let rebased = rebase(TWOS_HASH, CS_HASH, lines(&[]), &stack, &pushed_lines).unwrap();
+        assert_eq!(rebased.changesets.len(), 2);
+        assert_eq!(rebased.manifests.len(), 2);
+        let first = &rebased.changesets[0];
+        let second = &rebased.changesets[1];
+        assert_eq!(second.parents, Parents::new(Some(&first.node), None));
+        assert_eq!(
+            rebased.manifests[1].parents,
+            Parents::new(Some(&rebased.manifests[0].node), None)
+        );
+        assert_eq!(
+            rebased.manifests[1].text,
+            manifest_text(&lines(&[("a", THREES_HASH), ("b", FOURS_HASH)]))
+        );
+    }
+
+    /// A store in which another push moves the bookmark to `race` just before this one first
+    /// tries to.
+    struct RacingStore {
+        inner: Arc<PushStore>,
+        race: Mutex<Option<NodeHash>>,
+        attempts: AtomicUsize,
+    }
+
+    impl ChangesetStore for RacingStore {
+        fn get_changeset_text(
+            &self,
+            node: &NodeHash,
+        ) -> BoxFuture<(Parents, Vec<u8>), hgproto::Error> {
+            self.inner.get_changeset_text(node)
+        }
+
+        fn get_manifest_parents(&self, node: &NodeHash) -> BoxFuture<Parents, hgproto::Error> {
+            self.inner.get_manifest_parents(node)
+        }
+    }
+
+    impl PushStore for RacingStore {
+        fn get_raw_content(&self, node: &NodeHash) -> BoxFuture<Vec<u8>, hgproto::Error> {
+            self.inner.get_raw_content(node)
+        }
+
+        fn put_changeset(
+            &self,
+            node: &NodeHash,
+            cs: RevlogChangeset,
+            text: Vec<u8>,
+        ) -> BoxFuture<(), hgproto::Error> {
+            self.inner.put_changeset(node, cs, text)
+        }
+
+        fn put_node(
+            &self,
+            node: &NodeHash,
+            parents: Parents,
+            content: Vec<u8>,
+        ) -> BoxFuture<(), hgproto::Error> {
+            self.inner.put_node(node, parents, content)
+        }
+
+        fn put_file_node(
+            &self,
+            node: &NodeHash,
+            parents: Parents,
+            content: Vec<u8>,
+        ) -> BoxFuture<(), hgproto::Error> {
+            self.inner.put_file_node(node, parents, content)
+        }
+
+        fn add_linknode(
+            &self,
+            path: RepoPath,
+            node: &NodeHash,
+            linknode: &NodeHash,
+        ) -> BoxFuture<(), hgproto::Error> {
+            self.inner.add_linknode(path, node, linknode)
+        }
+
+        fn add_head(&self, node: &NodeHash) -> BoxFuture<(), hgproto::Error> {
+            self.inner.add_head(node)
+        }
+
+        fn remove_head(&self, node: &NodeHash) -> BoxFuture<(), hgproto::Error> {
+            self.inner.remove_head(node)
+        }
+
+        fn update_bookmark(
+            &self,
+            key: &[u8],
+            old: Option<NodeHash>,
+            new: Option<NodeHash>,
+        ) -> BoxFuture<bool, hgproto::Error> {
+            self.inner.update_bookmark(key, old, new)
+        }
+
+        fn set_bookmark(
+            &self,
+            key: &[u8],
+            value: &NodeHash,
+            version: &Version,
+        ) -> BoxFuture<bool, hgproto::Error> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            let race = self.race.lock().unwrap().take();
+            let raced = match race {
+                Some(racing) => self.inner.set_bookmark(key, &racing, version),
+                None => future::ok(true).boxify(),
+            };
+            let inner = self.inner.clone();
+            let key = key.to_vec();
+            let value = *value;
+            let version = *version;
+            raced
+                .and_then(move |_| inner.set_bookmark(&key, &value, &version))
+                .boxify()
+        }
+
+        fn put_clonebundle(&self, bundle: Vec<u8>) -> BoxFuture<(), hgproto::Error> {
+            self.inner.put_clonebundle(bundle)
+        }
+
+        fn index_changesets(&self) -> BoxFuture<usize, hgproto::Error> {
+            self.inner.index_changesets()
+        }
+
+        fn backfill_linknodes(&self) -> BoxFuture<usize, hgproto::Error> {
+            self.inner.backfill_linknodes()
+        }
+    }
+
+    struct TestRepo {
+        hgrepo: Arc<BoxedHgRepo>,
+        store: Arc<PushStore>,
+        phases: RepoPhases,
+        repo_generation: RepoGenCache<BoxedHgRepo>,
+        /// The changeset `master` points at.
+        base: NodeHash,
+        /// Its manifest.
+        lines: ManifestLines,
+    }
+
+    /// An in-memory repo with a single changeset, which adds the file `a` and is bookmarked as
+    /// `master`.
+    fn test_repo() -> TestRepo {
+        let repo = BlobRepo::new(MemBlobState::new(
+            MemHeads::new(),
+            MemBookmarks::new(),
+            Memblob::new(),
+            MemLinknodes::new(),
+            MemPhases::new(),
+            MemChangesetIndex::new(),
+        ));
+        let store = Arc::new(repo.clone()) as Arc<PushStore>;
+        let phasestore = Arc::new(repo.clone()) as Arc<PhaseStore>;
+        let hgrepo = Arc::new(BoxRepo::new_with_cvterr(repo, repo_err));
+
+        let mut lines = ManifestLines::new();
+        let base = commit(&store, None, &mut lines, &[("a", &b"a\n"[..])]);
+        store.add_head(&base).wait().unwrap();
+        assert!(
+            store
+                .update_bookmark(b"master", None, Some(base))
+                .wait()
+                .unwrap()
+        );
+
+        TestRepo {
+            hgrepo: hgrepo.clone(),
+            store,
+            phases: RepoPhases::new(hgrepo, Some(phasestore)),
+            repo_generation: RepoGenCache::new(1000),
+            base,
+            lines,
+        }
+    }
+
+    fn node(text: &[u8], parent: Option<&NodeHash>) -> NodeHash {
+        BlobNode::new(Blob::Dirty(text), parent, None)
+            .nodeid()
+            .unwrap()
+    }
+
+    /// Write a changeset with parent `parent` to `store`, which sets each of `files` to its
+    /// content in the manifest `lines`.
+    fn commit(
+        store: &Arc<PushStore>,
+        parent: Option<NodeHash>,
+        lines: &mut ManifestLines,
+        files: &[(&str, &[u8])],
+    ) -> NodeHash {
+        let mut puts = Vec::new();
+        for &(file, content) in files {
+            let filenode = node(content, None);
+            puts.push(store.put_file_node(&filenode, Parents::new(None, None), content.to_vec()));
+            lines.insert(file.as_bytes().to_vec(), format!("{}", filenode));
+        }
+        let manifest = manifest_text(lines);
+        let manifestid = node(&manifest, None);
+        puts.push(store.put_node(&manifestid, Parents::new(None, None), manifest));
+        future::join_all(puts).wait().unwrap();
+
+        let cs = RevlogChangeset::new_from_parts(
+            Parents::new(parent.as_ref(), None),
+            manifestid,
+            b"test".to_vec(),
+            Time { time: 0, tz: 0 },
+            BTreeMap::new(),
+            files.iter().map(|&(file, _)| path(file)).collect(),
+            b"message".to_vec(),
+        );
+        let mut text = Vec::new();
+        cs.generate(&mut text).unwrap();
+        let csnode = node(&text, parent.as_ref());
+        store.put_changeset(&csnode, cs, text).wait().unwrap();
+        csnode
+    }
+
+    /// What a client pushes to add the file `b` on top of `base`, whose manifest is `lines`.
+    fn push_revisions(base: NodeHash, mut lines: ManifestLines) -> Revisions {
+        let content = b"b\n".to_vec();
+        let filenode = node(&content, None);
+        lines.insert(b"b".to_vec(), format!("{}", filenode));
+        let manifest = manifest_text(&lines);
+        let manifestid = node(&manifest, None);
+        let cs = changeset(base, manifestid, &["b"]);
+
+        Revisions {
+            manifests: vec![
+                Revision {
+                    node: manifestid,
+                    parents: Parents::new(None, None),
+                    linknode: cs.node,
+                    text: manifest,
+                },
+            ],
+            filelogs: vec![
+                (
+                    path("b"),
+                    vec![
+                        Revision {
+                            node: filenode,
+                            parents: Parents::new(None, None),
+                            linknode: cs.node,
+                            text: content,
+                        },
+                    ],
+                ),
+            ],
+            changesets: vec![cs],
+        }
+    }
+
+    fn request() -> RebaseRequest {
+        RebaseRequest {
+            onto: b"master".to_vec(),
+            obsmarkers: true,
+        }
+    }
+
+    fn hooks(repo: &TestRepo, hooks: Vec<HookConfig>) -> RepoHooks {
+        RepoHooks::new(
+            "repo",
+            repo.hgrepo.clone(),
+            repo.repo_generation.clone(),
+            hooks,
+            &Logger::root(Discard, o!()),
+        ).unwrap()
+    }
+
+    fn master(hgrepo: &Arc<BoxedHgRepo>) -> NodeHash {
+        let bookmarks = hgrepo.get_bookmarks().unwrap();
+        let (node, _) = bookmarks.get(&b"master".to_vec()).wait().unwrap().unwrap();
+        node
+    }
+
+    #[test]
+    fn retry_when_bookmark_moves() {
+        let repo = test_repo();
+        let hooks = hooks(&repo, vec![]);
+        // Another push lands a changeset that adds c, just before this one moves the bookmark.
+        let mut racing_lines = repo.lines.clone();
+        let racing = commit(
+            &repo.store,
+            Some(repo.base),
+            &mut racing_lines,
+            &[("c", &b"c\n"[..])],
+        );
+        repo.phases.add_drafts(vec![racing]).wait().unwrap();
+        let store = Arc::new(RacingStore {
+            inner: repo.store.clone(),
+            race: Mutex::new(Some(racing)),
+            attempts: AtomicUsize::new(0),
+        });
+
+        let (_, replacements) = pushrebase(
+            repo.hgrepo.clone(),
+            store.clone(),
+            repo.phases.clone(),
+            hooks,
+            repo.repo_generation.clone(),
+            request(),
+            push_revisions(repo.base, repo.lines.clone()),
+        ).wait()
+            .unwrap();
+        assert_eq!(store.attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(replacements.len(), 1);
+
+        let head = replacements[0].1;
+        assert_eq!(master(&repo.hgrepo), head);
+        let cs = repo.hgrepo.get_changeset_by_nodeid(&head).wait().unwrap();
+        assert_eq!(cs.parents(), &Parents::new(Some(&racing), None));
+        let lines = manifest_lines(&repo.hgrepo, cs.manifestid())
+            .wait()
+            .unwrap();
+        assert_eq!(
+            lines.keys().cloned().collect::<Vec<_>>(),
+            vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]
+        );
+
+        let heads = repo.hgrepo.get_heads().collect().wait().unwrap();
+        assert_eq!(heads, vec![head]);
+        // Moving the bookmark published both the rebased changeset and the one it was rebased
+        // onto.
+        assert!(repo.phases.drafts().wait().unwrap().is_empty());
+    }
+
+    #[test]
+    fn hook_rejects() {
+        let repo = test_repo();
+        let hooks = hooks(
+            &repo,
+            vec![
+                HookConfig {
+                    name: "deny".into(),
+                    bookmarks: vec!["master".into()],
+                    hook_type: HookType::Lua {
+                        code: "function hook(info) return false end".into(),
+                        max_instructions: None,
+                        timeout_ms: None,
+                    },
+                },
+            ],
+        );
+
+        let result = pushrebase(
+            repo.hgrepo.clone(),
+            repo.store.clone(),
+            repo.phases.clone(),
+            hooks,
+            repo.repo_generation.clone(),
+            request(),
+            push_revisions(repo.base, repo.lines.clone()),
+        ).wait();
+        let err = result.err().expect("the hook should have rejected the push");
+        assert!(format!("{}", err).starts_with("hook deny rejected moving bookmark master"));
+
+        // The rebased changeset was written for the hook to look at, but nothing points at it.
+        assert_eq!(master(&repo.hgrepo), repo.base);
+        let heads = repo.hgrepo.get_heads().collect().wait().unwrap();
+        assert_eq!(heads, vec![repo.base]);
+    }
+}
@@ -0,0 +1,494 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Serving shallow clients that use the remotefilelog extension
+//!
+//! Shallow clients don't get file revisions in the changegroups they pull. Instead they fetch
+//! each file they need with `getfiles`, which sends it as a blob holding its text followed by
+//! the history of the file up to that revision:
+//! ```
+//! blob := <text length> '\0' <text> history-entry*
+//! history-entry := <node> <p1> <p2> <linknode> <copied from path> '\0'
+//! ```
+//! The first history entry is for the revision itself. Nodes are binary, and a file that was
+//! copied has the revision it was copied from as its first parent. The whole blob is then
+//! compressed with lz4.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{future, stream, Future, Stream};
+use futures::future::Loop;
+use futures_ext::{BoxFuture, FutureExt};
+use lz4::liblz4::{LZ4_compressBound, LZ4_compress_default};
+
+use asyncmemo::{Asyncmemo, Filler};
+use blobrepo::{BlobRepo, BlobState};
+use hgproto;
+use mercurial::file::File;
+use mercurial_types::{MPath, NodeHash, Parents, RepoPath, Type, NULL_HASH};
+
+use repo::BoxedHgRepo;
+use unbundle::repo_err;
+
+/// The operations on a repo's underlying storage that are needed to serve file contents and
+/// history to shallow clients.
+pub trait FileStore: Send + Sync + 'static {
+    /// Get the revlog text of a file node, including any copy metadata.
+    fn get_raw_content(&self, node: &NodeHash) -> BoxFuture<Vec<u8>, hgproto::Error>;
+    fn get_parents(&self, node: &NodeHash) -> BoxFuture<Parents, hgproto::Error>;
+    /// Get the path and node a file node was copied from, without reading the whole file.
+    fn get_copy_info(
+        &self,
+        node: &NodeHash,
+    ) -> BoxFuture<Option<(MPath, NodeHash)>, hgproto::Error>;
+    /// Get the changeset that introduced a file node.
+    fn get_linknode(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<NodeHash, hgproto::Error>;
+}
+
+impl<State> FileStore for BlobRepo<State>
+where
+    State: BlobState,
+{
+    fn get_raw_content(&self, node: &NodeHash) -> BoxFuture<Vec<u8>, hgproto::Error> {
+        BlobRepo::get_raw_content(self, node)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn get_parents(&self, node: &NodeHash) -> BoxFuture<Parents, hgproto::Error> {
+        BlobRepo::get_parents(self, node).map_err(repo_err).boxify()
+    }
+
+    fn get_copy_info(
+        &self,
+        node: &NodeHash,
+    ) -> BoxFuture<Option<(MPath, NodeHash)>, hgproto::Error> {
+        BlobRepo::get_copy_info(self, node)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn get_linknode(
+        &self,
+        path: RepoPath,
+        node: &NodeHash,
+    ) -> BoxFuture<NodeHash, hgproto::Error> {
+        BlobRepo::get_linknode(self, path, node)
+            .map_err(repo_err)
+            .boxify()
+    }
+}
+
+/// A revision in the history of a file, as remotefilelog sees it.
+struct HistoryEntry {
+    node: NodeHash,
+    p1: NodeHash,
+    p2: NodeHash,
+    linknode: NodeHash,
+    copied_from: Option<MPath>,
+}
+
+impl HistoryEntry {
+    /// The revisions this one is based on, along with the paths they're at.
+    fn parents(&self, path: &MPath) -> Vec<(MPath, NodeHash)> {
+        let p1_path = self.copied_from.as_ref().unwrap_or(path);
+        let mut parents = Vec::new();
+        if self.p1 != NULL_HASH {
+            parents.push((p1_path.clone(), self.p1));
+        }
+        if self.p2 != NULL_HASH {
+            parents.push((path.clone(), self.p2));
+        }
+        parents
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.node.as_ref());
+        out.extend_from_slice(self.p1.as_ref());
+        out.extend_from_slice(self.p2.as_ref());
+        out.extend_from_slice(self.linknode.as_ref());
+        if let Some(ref path) = self.copied_from {
+            out.extend_from_slice(&path.to_vec());
+        }
+        out.push(b'\0');
+    }
+}
+
+/// Fetch the history entry of a revision of a file.
+fn history_entry(
+    store: &Arc<FileStore>,
+    path: MPath,
+    node: NodeHash,
+) -> BoxFuture<HistoryEntry, hgproto::Error> {
+    let repopath = match RepoPath::file(path.clone()) {
+        Ok(repopath) => repopath,
+        Err(err) => return future::err(repo_err(err)).boxify(),
+    };
+
+    let linknode = store.get_linknode(repopath, &node).map_err(move |err| {
+        let msg = format!(
+            "failed to get the linknode of {} node {} (repos that were imported before \
+             linknodes were recorded need them filled in with --backfill-linknodes)",
+            path,
+            node
+        );
+        hgproto::Error::with_chain(err, msg)
+    });
+
+    store
+        .get_parents(&node)
+        .join3(store.get_copy_info(&node), linknode)
+        .map(move |(parents, copy, linknode)| {
+            // The stored parents don't say whether the file was copied, as that's recorded
+            // with a null first parent, so go by the metadata instead.
+            let (p1, p2) = parents.get_nodes();
+            let (p1, p2, copied_from) = match copy {
+                Some((path, copynode)) => {
+                    (copynode, p1.cloned().unwrap_or(NULL_HASH), Some(path))
+                }
+                None => (
+                    p1.cloned().unwrap_or(NULL_HASH),
+                    p2.cloned().unwrap_or(NULL_HASH),
+                    None,
+                ),
+            };
+            HistoryEntry {
+                node,
+                p1,
+                p2,
+                linknode,
+                copied_from,
+            }
+        })
+        .boxify()
+}
+
+/// Fetch the text of a revision of a file, without its metadata.
+fn file_text(store: &Arc<FileStore>, node: NodeHash) -> BoxFuture<Vec<u8>, hgproto::Error> {
+    store
+        .get_raw_content(&node)
+        .map(|raw| {
+            let (_, metasize) = File::extract_meta(&raw);
+            raw[metasize..].to_vec()
+        })
+        .boxify()
+}
+
+/// Build the compressed blob sent to a client that asked for revision `node` of `path`.
+pub fn getfile(
+    store: Arc<FileStore>,
+    path: MPath,
+    node: NodeHash,
+) -> BoxFuture<Bytes, hgproto::Error> {
+    // Clients ask for the null revision of files that don't exist yet.
+    if node == NULL_HASH {
+        return future::ok(Bytes::new()).boxify();
+    }
+
+    history_entry(&store, path.clone(), node)
+        .join(file_text(&store, node))
+        .and_then(move |(entry, text)| {
+            let queue: VecDeque<_> = entry.parents(&path).into_iter().collect();
+            let mut seen = HashSet::new();
+            seen.insert((path, node));
+
+            future::loop_fn(
+                (queue, seen, vec![entry]),
+                move |(mut queue, mut seen, mut history)| {
+                    let next = loop {
+                        match queue.pop_front() {
+                            Some(rev) => if seen.insert(rev.clone()) {
+                                break Some(rev);
+                            },
+                            None => break None,
+                        }
+                    };
+
+                    match next {
+                        None => future::ok(Loop::Break(history)).boxify(),
+                        Some((path, node)) => history_entry(&store, path.clone(), node)
+                            .map(move |entry| {
+                                queue.extend(entry.parents(&path));
+                                history.push(entry);
+                                Loop::Continue((queue, seen, history))
+                            })
+                            .boxify(),
+                    }
+                },
+            ).map(move |history| (text, history))
+        })
+        .and_then(|(text, history)| {
+            let mut blob = format!("{}\0", text.len()).into_bytes();
+            blob.extend_from_slice(&text);
+            for entry in &history {
+                entry.write(&mut blob);
+            }
+            lz4_compress(&blob)
+        })
+        .boxify()
+}
+
+/// Compress data the way remotefilelog expects: its length as a little-endian u32, followed by
+/// a single lz4 block.
+fn lz4_compress(data: &[u8]) -> hgproto::Result<Bytes> {
+    let len = data.len();
+    if len > i32::max_value() as usize {
+        bail!("file too large to compress: {} bytes", len);
+    }
+
+    let bound = unsafe { LZ4_compressBound(len as i32) };
+    let mut out = Vec::with_capacity(4 + bound as usize);
+    out.extend_from_slice(&[
+        len as u8,
+        (len >> 8) as u8,
+        (len >> 16) as u8,
+        (len >> 24) as u8,
+    ]);
+
+    unsafe {
+        let compressed = LZ4_compress_default(
+            data.as_ptr() as *const _,
+            out.as_mut_ptr().offset(4) as *mut _,
+            len as i32,
+            bound,
+        );
+        if compressed <= 0 {
+            bail!("lz4 compression failed");
+        }
+        out.set_len(4 + compressed as usize);
+    }
+
+    Ok(Bytes::from(out))
+}
+
+/// Cache of the heads of the histories of files, keyed by the sorted repo heads they were
+/// computed from and the path, as working them out walks the whole history of the file.
+pub struct FlogheadsCache {
+    repo: Arc<BoxedHgRepo>,
+    cache: Asyncmemo<FlogheadsFiller>,
+}
+
+impl FlogheadsCache {
+    /// Construct a new `FlogheadsCache`, bounded to `sizelimit` bytes.
+    pub fn new(repo: Arc<BoxedHgRepo>, store: Arc<FileStore>, sizelimit: usize) -> Self {
+        let filler = FlogheadsFiller {
+            repo: repo.clone(),
+            store,
+        };
+
+        FlogheadsCache {
+            repo,
+            cache: Asyncmemo::with_limits(filler, usize::max_value(), sizelimit),
+        }
+    }
+
+    /// Get the heads of the history of `path` for the current repo heads.
+    pub fn get(&self, path: MPath) -> BoxFuture<Vec<NodeHash>, hgproto::Error> {
+        let cache = self.cache.clone();
+
+        self.repo
+            .get_heads()
+            .collect()
+            .and_then(move |mut heads| {
+                heads.sort();
+                heads.dedup();
+                cache.get((heads, path))
+            })
+            .boxify()
+    }
+}
+
+struct FlogheadsFiller {
+    repo: Arc<BoxedHgRepo>,
+    store: Arc<FileStore>,
+}
+
+impl Filler for FlogheadsFiller {
+    type Key = (Vec<NodeHash>, MPath);
+    type Value = BoxFuture<Vec<NodeHash>, hgproto::Error>;
+
+    fn fill(&self, _cache: &Asyncmemo<Self>, key: &Self::Key) -> Self::Value {
+        let (ref heads, ref path) = *key;
+        flogheads(
+            self.repo.clone(),
+            self.store.clone(),
+            heads.clone(),
+            path.clone(),
+        )
+    }
+}
+
+/// The heads of the history of `path`: the revisions of it in the manifests of `heads`, other
+/// than those that are ancestors of another one.
+fn flogheads(
+    hgrepo: Arc<BoxedHgRepo>,
+    store: Arc<FileStore>,
+    heads: Vec<NodeHash>,
+    path: MPath,
+) -> BoxFuture<Vec<NodeHash>, hgproto::Error> {
+    let revisions = stream::iter_ok(heads)
+        .and_then({
+            let hgrepo = hgrepo.clone();
+            move |head| hgrepo.get_changeset_by_nodeid(&head)
+        })
+        .and_then({
+            let hgrepo = hgrepo.clone();
+            move |cs| hgrepo.get_manifest_by_nodeid(cs.manifestid())
+        })
+        .and_then(move |manifest| manifest.lookup(&path))
+        .filter_map(|entry| entry)
+        .filter(|entry| entry.get_type() != Type::Tree)
+        .map(|entry| *entry.get_hash())
+        .collect()
+        .map(|nodes| nodes.into_iter().collect::<HashSet<_>>());
+
+    revisions
+        .and_then(move |revisions| {
+            if revisions.len() < 2 {
+                return future::ok(revisions.into_iter().collect()).boxify();
+            }
+
+            let ancestors: Vec<_> = revisions
+                .iter()
+                .map(|node| ancestors(store.clone(), *node))
+                .collect();
+            stream::futures_unordered(ancestors)
+                .fold(HashSet::new(), |mut all, ancestors| {
+                    all.extend(ancestors);
+                    Ok::<_, hgproto::Error>(all)
+                })
+                .map(move |ancestors| revisions.difference(&ancestors).cloned().collect())
+                .boxify()
+        })
+        .boxify()
+}
+
+/// All the ancestors of a file revision, not counting the revisions it was copied from.
+fn ancestors(
+    store: Arc<FileStore>,
+    node: NodeHash,
+) -> BoxFuture<HashSet<NodeHash>, hgproto::Error> {
+    future::loop_fn(
+        (vec![node], HashSet::new()),
+        move |(mut pending, mut ancestors)| match pending.pop() {
+            None => future::ok(Loop::Break(ancestors)).boxify(),
+            Some(node) => store
+                .get_parents(&node)
+                .map(move |parents| {
+                    for parent in &parents {
+                        if ancestors.insert(parent) {
+                            pending.push(parent);
+                        }
+                    }
+                    Loop::Continue((pending, ancestors))
+                })
+                .boxify(),
+        },
+    ).boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use lz4::liblz4::LZ4_decompress_safe;
+    use mercurial_types_mocks::nodehash::*;
+
+    use super::*;
+
+    fn lz4_decompress(data: &[u8]) -> Vec<u8> {
+        let len = data[0] as usize | (data[1] as usize) << 8 | (data[2] as usize) << 16
+            | (data[3] as usize) << 24;
+        let mut out = Vec::with_capacity(len);
+        unsafe {
+            let got = LZ4_decompress_safe(
+                data[4..].as_ptr() as *const _,
+                out.as_mut_ptr() as *mut _,
+                (data.len() - 4) as i32,
+                len as i32,
+            );
+            assert_eq!(got, len as i32);
+            out.set_len(len);
+        }
+        out
+    }
+
+    #[test]
+    fn lz4_framing() {
+        let data: Vec<u8> = (0..30).flat_map(|_| b"remotefile".iter().cloned()).collect();
+        let compressed = lz4_compress(&data).unwrap();
+        assert_eq!(&compressed[..4], &[0x2c, 0x01, 0, 0]);
+        assert!(compressed.len() < data.len());
+        assert_eq!(lz4_decompress(&compressed), data);
+
+        let compressed = lz4_compress(b"").unwrap();
+        assert_eq!(&compressed[..4], &[0, 0, 0, 0]);
+        assert_eq!(lz4_decompress(&compressed), b"");
+    }
+
+    #[test]
+    fn history_entry_format() {
+        let entry = HistoryEntry {
+            node: ONES_HASH,
+            p1: TWOS_HASH,
+            p2: NULL_HASH,
+            linknode: THREES_HASH,
+            copied_from: None,
+        };
+        let mut out = Vec::new();
+        entry.write(&mut out);
+        let mut expected = Vec::new();
+        expected.extend_from_slice(ONES_HASH.as_ref());
+        expected.extend_from_slice(TWOS_HASH.as_ref());
+        expected.extend_from_slice(NULL_HASH.as_ref());
+        expected.extend_from_slice(THREES_HASH.as_ref());
+        expected.push(b'\0');
+        assert_eq!(out, expected);
+
+        let copied = HistoryEntry {
+            copied_from: Some(MPath::new("dir/old").unwrap()),
+            ..entry
+        };
+        let mut out = Vec::new();
+        copied.write(&mut out);
+        assert_eq!(out.len(), 4 * 20 + "dir/old".len() + 1);
+        assert_eq!(&out[80..], b"dir/old\0");
+    }
+
+    #[test]
+    fn history_entry_parents() {
+        let path = MPath::new("dir/file").unwrap();
+        let old = MPath::new("dir/old").unwrap();
+
+        let entry = HistoryEntry {
+            node: ONES_HASH,
+            p1: TWOS_HASH,
+            p2: THREES_HASH,
+            linknode: FOURS_HASH,
+            copied_from: None,
+        };
+        assert_eq!(
+            entry.parents(&path),
+            vec![(path.clone(), TWOS_HASH), (path.clone(), THREES_HASH)]
+        );
+
+        // The first parent of a copy is the revision it was copied from.
+        let copied = HistoryEntry {
+            p2: NULL_HASH,
+            copied_from: Some(old.clone()),
+            ..entry
+        };
+        assert_eq!(copied.parents(&path), vec![(old, TWOS_HASH)]);
+
+        let root = HistoryEntry {
+            p1: NULL_HASH,
+            p2: NULL_HASH,
+            copied_from: None,
+            ..copied
+        };
+        assert_eq!(root.parents(&path), vec![]);
+    }
+}
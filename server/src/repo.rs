@@ -6,38 +6,57 @@
 
 //! State for a single source control Repo
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error;
 use std::fmt::{self, Debug};
-use std::io::Cursor;
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::str::{self, FromStr};
 use std::sync::Arc;
 
 use bytes::Bytes;
 use futures::{future, stream, Async, Future, IntoFuture, Poll, Stream};
-use futures_ext::{BoxFuture, FutureExt, StreamExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use slog::Logger;
 
-use async_compression::CompressorType;
-use mercurial;
-use mercurial_bundles::{parts, Bundle2EncodeBuilder};
-use mercurial_types::{percent_encode, BoxRepo, Changeset, NodeHash, Parents, Repo, NULL_HASH};
-use metaconfig::repoconfig::RepoType;
+use async_compression::{CompressorType, ZSTD_DEFAULT_LEVEL};
+use bzip2;
+use mercurial::{self, RevlogRepo};
+use mercurial_bundles::{decode_caps, parts, Bundle2EncodeBuilder};
+use mercurial_bundles::changegroup::{CgDeltaChunk, CgVersion, Part, Section};
+use mercurial_types::{percent_decode, percent_encode, BlobNode, BoxRepo, Changeset, Delta, Entry,
+                      MPath, Manifest, NodeHash, Parents, Repo, Type, NULL_HASH};
+use metaconfig::repoconfig::{CloneBundle, RepoConfig, RepoType};
+use repoinfo::RepoGenCache;
+use revset::{AncestorsNodeStream, NodeStream, SetDifferenceNodeStream, UnionNodeStream};
 
-use hgproto::{self, GetbundleArgs, HgCommandRes, HgCommands};
+use hgproto::{self, BranchRes, GetbundleArgs, GettreepackArgs, HgCommandRes, HgCommands,
+              LookupRes};
 
-use blobrepo::{BlobRepo, FilesBlobState, RocksBlobState};
+use blobrepo::{BlobRepo, BlobState, FilesBlobState, Phase, RocksBlobState};
 
+use branchmap::{self, BranchmapCache};
+use clonebundles;
 use errors::*;
+use listkeys::Namespaces;
+use phases::{PhaseStore, RepoPhases};
+use remotefilelog::{self, FileStore, FlogheadsCache};
+use repohooks::RepoHooks;
+use streamclone::{self, StreamStore};
+use treemanifest::{self, TreeStore};
+use unbundle::{self, repo_err, PushStore};
 
-pub fn init_repo(parent_logger: &Logger, repotype: &RepoType) -> Result<(PathBuf, HgRepo)> {
-    let repopath = repotype.path();
+pub fn init_repo(
+    parent_logger: &Logger,
+    name: &str,
+    config: &RepoConfig,
+) -> Result<(PathBuf, HgRepo)> {
+    let repopath = config.repotype.path();
 
     let mut sock = repopath.join(".hg");
 
-    let repo = HgRepo::new(parent_logger, repotype)
+    let repo = HgRepo::new(parent_logger, name, config)
         .chain_err(|| format!("Failed to initialize repo {:?}", repopath))?;
 
     sock.push("mononoke.sock");
@@ -46,13 +65,94 @@ pub fn init_repo(parent_logger: &Logger, repotype: &RepoType) -> Result<(PathBuf
 }
 
 
+// Limit on the memory used to cache generation numbers, in bytes.
+const GENCACHE_SIZE: usize = 1_000_000;
+// Limit on the memory used to cache branch heads, in bytes.
+const BRANCHMAP_CACHE_SIZE: usize = 1_000_000;
+// Limit on the memory used to cache the heads of file histories, in bytes.
+const FLOGHEADS_CACHE_SIZE: usize = 10_000_000;
+// Limit on the number of revisions fetched at once while sending a changegroup.
+const CHANGEGROUP_FETCHES: usize = 100;
+
+/// The operations on a repo's underlying storage that are needed to send changesets and
+/// manifests exactly as they were stored.
+pub trait ChangesetStore: Send + Sync + 'static {
+    /// Get the revlog text of a changeset, along with its parents.
+    fn get_changeset_text(
+        &self,
+        node: &NodeHash,
+    ) -> BoxFuture<(Parents, Vec<u8>), hgproto::Error>;
+    /// Get the parents a manifest node was stored with.
+    fn get_manifest_parents(&self, node: &NodeHash) -> BoxFuture<Parents, hgproto::Error>;
+}
+
+fn changeset_blob_text(node: BlobNode) -> hgproto::Result<(Parents, Vec<u8>)> {
+    let parents = *node.parents();
+    let text = node.as_blob()
+        .as_slice()
+        .ok_or("missing changeset content")?
+        .to_vec();
+    Ok((parents, text))
+}
+
+impl ChangesetStore for RevlogRepo {
+    fn get_changeset_text(
+        &self,
+        node: &NodeHash,
+    ) -> BoxFuture<(Parents, Vec<u8>), hgproto::Error> {
+        self.get_changeset_blob_by_nodeid(node)
+            .from_err()
+            .and_then(changeset_blob_text)
+            .boxify()
+    }
+
+    fn get_manifest_parents(&self, node: &NodeHash) -> BoxFuture<Parents, hgproto::Error> {
+        self.get_manifest_blob_by_nodeid(node)
+            .map(|node| *node.parents())
+            .from_err()
+            .boxify()
+    }
+}
+
+impl<State> ChangesetStore for BlobRepo<State>
+where
+    State: BlobState,
+{
+    fn get_changeset_text(
+        &self,
+        node: &NodeHash,
+    ) -> BoxFuture<(Parents, Vec<u8>), hgproto::Error> {
+        BlobRepo::get_changeset_blob(self, node)
+            .map_err(repo_err)
+            .and_then(changeset_blob_text)
+            .boxify()
+    }
+
+    fn get_manifest_parents(&self, node: &NodeHash) -> BoxFuture<Parents, hgproto::Error> {
+        BlobRepo::get_parents(self, node).map_err(repo_err).boxify()
+    }
+}
+
+/// A repo, along with the stores for the operations that `Repo` doesn't cover. Only some kinds
+/// of repo can accept pushes, serve shallow clients, record phases or serve stream clones, and
+/// only revlog repos with tree manifests or blob repos can serve tree manifests.
+pub struct OpenedRepo {
+    pub hgrepo: BoxedHgRepo,
+    pub csstore: Arc<ChangesetStore>,
+    pub pushstore: Option<Arc<PushStore>>,
+    pub filestore: Option<Arc<FileStore>>,
+    pub treestore: Option<Arc<TreeStore>>,
+    pub phasestore: Option<Arc<PhaseStore>>,
+    pub streamstore: Option<Arc<StreamStore>>,
+}
+
 pub trait OpenableRepoType {
-    fn open(&self) -> Result<Box<Repo<Error = hgproto::Error> + Sync + Send>>;
+    fn open(&self) -> Result<OpenedRepo>;
     fn path(&self) -> &Path;
 }
 
 impl OpenableRepoType for RepoType {
-    fn open(&self) -> Result<Box<Repo<Error = hgproto::Error> + Sync + Send>> {
+    fn open(&self) -> Result<OpenedRepo> {
         use metaconfig::repoconfig::RepoType::*;
         use hgproto::{Error, ErrorKind};
 
@@ -62,15 +162,47 @@ impl OpenableRepoType for RepoType {
 
         let ret = match *self {
             Revlog(ref path) => {
-                BoxRepo::new_with_cvterr(mercurial::RevlogRepo::open(path.join(".hg"))?, repo_chain)
+                let repo = mercurial::RevlogRepo::open(path.join(".hg"))?;
+                let treestore = if repo.has_tree_manifests() {
+                    Some(Arc::new(repo.clone()) as Arc<TreeStore>)
+                } else {
+                    None
+                };
+                OpenedRepo {
+                    csstore: Arc::new(repo.clone()) as Arc<ChangesetStore>,
+                    treestore,
+                    hgrepo: BoxRepo::new_with_cvterr(repo, repo_chain),
+                    pushstore: None,
+                    filestore: None,
+                    phasestore: None,
+                    streamstore: None,
+                }
             }
 
             BlobFiles(ref path) => {
-                BoxRepo::new_with_cvterr(BlobRepo::new(FilesBlobState::new(&path)?), repo_chain)
+                let repo = BlobRepo::new(FilesBlobState::new(&path)?);
+                OpenedRepo {
+                    csstore: Arc::new(repo.clone()) as Arc<ChangesetStore>,
+                    pushstore: Some(Arc::new(repo.clone()) as Arc<PushStore>),
+                    filestore: Some(Arc::new(repo.clone()) as Arc<FileStore>),
+                    treestore: Some(Arc::new(repo.clone()) as Arc<TreeStore>),
+                    phasestore: Some(Arc::new(repo.clone()) as Arc<PhaseStore>),
+                    streamstore: Some(Arc::new(repo.clone()) as Arc<StreamStore>),
+                    hgrepo: BoxRepo::new_with_cvterr(repo, repo_chain),
+                }
             }
 
             BlobRocks(ref path) => {
-                BoxRepo::new_with_cvterr(BlobRepo::new(RocksBlobState::new(&path)?), repo_chain)
+                let repo = BlobRepo::new(RocksBlobState::new(&path)?);
+                OpenedRepo {
+                    csstore: Arc::new(repo.clone()) as Arc<ChangesetStore>,
+                    pushstore: Some(Arc::new(repo.clone()) as Arc<PushStore>),
+                    filestore: Some(Arc::new(repo.clone()) as Arc<FileStore>),
+                    treestore: Some(Arc::new(repo.clone()) as Arc<TreeStore>),
+                    phasestore: Some(Arc::new(repo.clone()) as Arc<PhaseStore>),
+                    streamstore: Some(Arc::new(repo.clone()) as Arc<StreamStore>),
+                    hgrepo: BoxRepo::new_with_cvterr(repo, repo_chain),
+                }
             }
         };
 
@@ -86,9 +218,23 @@ impl OpenableRepoType for RepoType {
     }
 }
 
+pub type BoxedHgRepo = Box<Repo<Error = hgproto::Error> + Send + Sync>;
+
 pub struct HgRepo {
     path: String,
-    hgrepo: Arc<Box<Repo<Error = hgproto::Error> + Send + Sync>>,
+    hgrepo: Arc<BoxedHgRepo>,
+    csstore: Arc<ChangesetStore>,
+    pushstore: Option<Arc<PushStore>>,
+    filestore: Option<Arc<FileStore>>,
+    flogheads: Option<FlogheadsCache>,
+    treestore: Option<Arc<TreeStore>>,
+    streamstore: Option<Arc<StreamStore>>,
+    phases: RepoPhases,
+    hooks: RepoHooks,
+    namespaces: Namespaces,
+    branchmap: BranchmapCache,
+    clonebundles: Vec<CloneBundle>,
+    repo_generation: RepoGenCache<BoxedHgRepo>,
     _logger: Logger,
 }
 
@@ -97,14 +243,70 @@ fn wireprotocaps() -> Vec<String> {
         "lookup".to_string(),
         "known".to_string(),
         "getbundle".to_string(),
+        "pushkey".to_string(),
+        "branchmap".to_string(),
+        "clonebundles".to_string(),
+        // Only bundle2 pushes can be decoded.
+        "unbundle=HG20".to_string(),
+        format!("compression={}", COMPRESSION_ENGINES.join(",")),
     ]
 }
 
+/// The compression engines that bundles sent to clients can use, in order of preference. Clients
+/// list the ones they accept in a `compression=` entry in their `bundlecaps`.
+const COMPRESSION_ENGINES: &[&str] = &["zstd", "bzip2", "zlib", "none"];
+
+/// The compression to use for a bundle sent to a client: the most preferred engine it accepts,
+/// going by its `bundlecaps`. Old clients don't say, and some of them hang while reading
+/// compressed bundles over the wire (https://bz.mercurial-scm.org/show_bug.cgi?id=5646), so
+/// bundles are sent to them uncompressed.
+fn compressor_type(bundlecaps: &[Vec<u8>]) -> CompressorType {
+    let prefix = b"compression=";
+    let accepted: Vec<&[u8]> = bundlecaps
+        .iter()
+        .filter(|cap| cap.starts_with(prefix))
+        .flat_map(|cap| cap[prefix.len()..].split(|b| *b == b','))
+        .collect();
+
+    let engine = COMPRESSION_ENGINES
+        .iter()
+        .find(|engine| accepted.contains(&engine.as_bytes()));
+    match engine {
+        Some(&"zstd") => CompressorType::Zstd {
+            level: ZSTD_DEFAULT_LEVEL,
+        },
+        Some(&"bzip2") => CompressorType::Bzip2(bzip2::Compression::Default),
+        Some(&"zlib") => CompressorType::Gzip,
+        _ => CompressorType::Uncompressed,
+    }
+}
+
+/// Everything the server advertises in `hello` and `capabilities`. Shallow clients can only use
+/// repos that can serve them file history, treemanifest clients can only use repos that store
+/// tree manifests, and only some repos can store stream clone data.
+fn capabilities(repo: &HgRepo) -> Vec<String> {
+    let mut caps = wireprotocaps();
+    if repo.streamstore.is_some() {
+        caps.push("stream".to_string());
+    }
+    if repo.filestore.is_some() {
+        caps.push("remotefilelog".to_string());
+        caps.push("getflogheads".to_string());
+    }
+    if repo.treestore.is_some() {
+        caps.push("gettreepack".to_string());
+    }
+    caps.push(format!("bundle2={}", bundle2caps()));
+    caps
+}
+
 fn bundle2caps() -> String {
     let caps = hashmap! {
         "HG20" => vec![],
         "listkeys" => vec![],
-        "changegroup" => vec!["02"],
+        "changegroup" => vec!["02", "03"],
+        "phases" => vec!["heads"],
+        "b2x:rebase" => vec![],
     };
 
     let mut encodedcaps = vec![];
@@ -122,14 +324,82 @@ fn bundle2caps() -> String {
     percent_encode(&encodedcaps.join("\n"))
 }
 
+/// Log the whole cause chain of the errors in the data of a bundle part. The client is only sent
+/// the top-level message.
+fn log_errors<S>(logger: &Logger, what: &'static str, stream: S) -> BoxStream<S::Item, S::Error>
+where
+    S: Stream<Error = hgproto::Error> + Send + 'static,
+    S::Item: Send + 'static,
+{
+    let logger = logger.clone();
+    stream
+        .map_err(move |err| {
+            let causes: Vec<_> = err.iter().map(|cause| cause.to_string()).collect();
+            error!(logger, "Failed to generate {}: {}", what, causes.join(": "));
+            err
+        })
+        .boxify()
+}
+
+/// The changegroup version to send to a client: 03 if the bundle2 capabilities among its
+/// `bundlecaps` say it supports it, or 02 otherwise.
+fn changegroup_version(bundlecaps: &[Vec<u8>]) -> CgVersion {
+    let prefix = b"bundle2=";
+    let supported = bundlecaps
+        .iter()
+        .filter(|cap| cap.starts_with(prefix))
+        .filter_map(|cap| decode_caps(&percent_decode(&cap[prefix.len()..])).ok())
+        .filter_map(|mut caps| caps.remove("changegroup"))
+        .any(|versions| versions.iter().any(|v| v == CgVersion::Cg3.as_str()));
+
+    if supported {
+        CgVersion::Cg3
+    } else {
+        CgVersion::Cg2
+    }
+}
+
 impl HgRepo {
-    pub fn new(parent_logger: &Logger, repo: &RepoType) -> Result<Self> {
-        let path = repo.path().to_owned();
+    pub fn new(parent_logger: &Logger, name: &str, config: &RepoConfig) -> Result<Self> {
+        let path = config.repotype.path().to_owned();
+        let logger = parent_logger.new(o!("repo" => format!("{}", path.display())));
+        let opened = config.repotype.open()?;
+        let hgrepo = Arc::new(opened.hgrepo);
+        let repo_generation = RepoGenCache::new(GENCACHE_SIZE);
+        let phases = RepoPhases::new(hgrepo.clone(), opened.phasestore);
+        let hooks = RepoHooks::new(
+            name,
+            hgrepo.clone(),
+            repo_generation.clone(),
+            config.hooks.clone(),
+            &logger,
+        )?;
+        let flogheads = opened
+            .filestore
+            .clone()
+            .map(|store| FlogheadsCache::new(hgrepo.clone(), store, FLOGHEADS_CACHE_SIZE));
 
         Ok(HgRepo {
             path: format!("{}", path.display()),
-            hgrepo: Arc::new(repo.open()?),
-            _logger: parent_logger.new(o!("repo" => format!("{}", path.display()))),
+            hgrepo: hgrepo.clone(),
+            csstore: opened.csstore,
+            pushstore: opened.pushstore,
+            filestore: opened.filestore,
+            flogheads,
+            // Blob repos can store either kind of manifest, so only the config can say.
+            treestore: if config.treemanifest {
+                opened.treestore
+            } else {
+                None
+            },
+            streamstore: opened.streamstore,
+            phases: phases.clone(),
+            hooks,
+            namespaces: Namespaces::new(hgrepo.clone(), phases),
+            branchmap: BranchmapCache::new(hgrepo, BRANCHMAP_CACHE_SIZE),
+            clonebundles: config.clonebundles.clone(),
+            repo_generation,
+            _logger: logger,
         })
     }
 
@@ -144,6 +414,7 @@ impl Debug for HgRepo {
     }
 }
 
+#[derive(Clone)]
 pub struct RepoClient {
     repo: Arc<HgRepo>,
     logger: Logger,
@@ -162,50 +433,448 @@ impl RepoClient {
         &self.logger
     }
 
-    fn create_bundle(&self, args: GetbundleArgs) -> hgproto::Result<HgCommandRes<Bytes>> {
-        let writer = Cursor::new(Vec::new());
-        let mut bundle = Bundle2EncodeBuilder::new(writer);
-        // Mercurial currently hangs while trying to read compressed bundles over the wire:
-        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        bundle.set_compressor_type(CompressorType::Uncompressed);
-
-        // TODO: generalize this to other listkey types
-        // (note: just calling &b"bookmarks"[..] doesn't work because https://fburl.com/0p0sq6kp)
-        if args.listkeys.contains(&b"bookmarks".to_vec()) {
-            let bookmarks = self.repo.hgrepo.get_bookmarks()?;
-            let bookmark_names = bookmarks.keys();
-            let items = bookmark_names.and_then(move |name| {
-                // For each bookmark name, grab the corresponding value.
-                bookmarks.get(&name).and_then(|result| {
-                    // If the name somehow wasn't found, it's possible a race happened. where the
-                    // bookmark was deleted from underneath. Skip it.
-                    // Boxing is necessary here to make the match arms return the same types.
-                    match result {
-                        Some((hash, _version)) => {
-                            // AsciiString doesn't currently implement AsRef<[u8]>, so switch to
-                            // Vec which does
-                            let hash: Vec<u8> = hash.to_hex().into();
-                            Ok((name, hash)).into_future().boxify()
-                        }
-                        None => future::empty().boxify(),
-                    }
+    fn pushstore(&self) -> hgproto::Result<Arc<PushStore>> {
+        self.repo
+            .pushstore
+            .clone()
+            .ok_or("this repo doesn't accept pushes".into())
+    }
+
+    fn filestore(&self) -> hgproto::Result<Arc<FileStore>> {
+        self.repo
+            .filestore
+            .clone()
+            .ok_or("this repo doesn't support shallow clients".into())
+    }
+
+    fn treestore(&self) -> hgproto::Result<Arc<TreeStore>> {
+        self.repo
+            .treestore
+            .clone()
+            .ok_or("this repo doesn't serve tree manifests".into())
+    }
+
+    /// Generate a bundle of the whole repo, and store it for clients to clone from.
+    pub fn generate_clonebundle(&self) -> HgCommandRes<()> {
+        let pushstore = match self.pushstore() {
+            Ok(pushstore) => pushstore,
+            Err(err) => return future::err(err).boxify(),
+        };
+
+        let client = self.clone();
+        self.repo
+            .hgrepo
+            .get_heads()
+            .collect()
+            .and_then(move |heads| {
+                let args = GetbundleArgs {
+                    heads,
+                    common: vec![],
+                    bundlecaps: vec![],
+                    listkeys: vec![],
+                    phases: false,
+                };
+                client.create_bundle(args).into_future().and_then(|bundle| {
+                    bundle.fold(Vec::new(), |mut buf, chunk| {
+                        buf.extend_from_slice(&chunk);
+                        Ok::<_, hgproto::Error>(buf)
+                    })
                 })
-            });
-            bundle.add_part(parts::listkey_part("bookmarks", items)?);
+            })
+            .and_then(move |bundle| pushstore.put_clonebundle(bundle))
+            .boxify()
+    }
+
+    /// Add the changesets the repo already has to its changeset index, so that they can be
+    /// found by prefix. Resolves to the number of changesets.
+    pub fn index_changesets(&self) -> HgCommandRes<usize> {
+        match self.pushstore() {
+            Ok(pushstore) => pushstore.index_changesets(),
+            Err(err) => future::err(err).boxify(),
+        }
+    }
+
+    /// Record the linknodes of the changesets the repo had before linknodes were recorded, which
+    /// shallow clients need. Resolves to the number of changesets.
+    pub fn backfill_linknodes(&self) -> HgCommandRes<usize> {
+        match self.pushstore() {
+            Ok(pushstore) => pushstore.backfill_linknodes(),
+            Err(err) => future::err(err).boxify(),
+        }
+    }
+
+    /// Generate the store files of the whole repo, and store them for stream clones.
+    pub fn generate_streamclone(&self) -> HgCommandRes<()> {
+        let streamstore = match self.repo.streamstore.clone() {
+            Some(streamstore) => streamstore,
+            None => return future::err("this repo doesn't support stream clones".into()).boxify(),
+        };
+
+        let client = self.clone();
+        self.repo
+            .hgrepo
+            .get_heads()
+            .collect()
+            .and_then(move |heads| {
+                streamclone::generate(streamstore, client.changegroup_entries(&heads, &[]))
+            })
+            .boxify()
+    }
+
+    fn create_bundle(
+        &self,
+        args: GetbundleArgs,
+    ) -> hgproto::Result<BoxStream<Bytes, hgproto::Error>> {
+        let mut bundle = Bundle2EncodeBuilder::new_stream();
+        bundle.set_compressor_type(compressor_type(&args.bundlecaps));
+
+        let version = changegroup_version(&args.bundlecaps);
+        let changegroup = self.changegroup_entries(&args.heads, &args.common);
+        let changegroup = log_errors(&self.logger, "changegroup", changegroup);
+        bundle.add_part(parts::changegroup_part(changegroup, version)?);
+
+        for namespace in &args.listkeys {
+            let items = self.repo
+                .namespaces
+                .list(&String::from_utf8_lossy(namespace));
+            let items = log_errors(&self.logger, "listkeys", items);
+            bundle.add_part(parts::listkey_part(namespace.clone(), items)?);
         }
 
-        let encode_fut = bundle.build();
+        if args.phases {
+            let heads = self.repo
+                .phases
+                .phase_heads(args.heads.clone())
+                .map(|(phase, node)| (phase.as_num(), node));
+            let heads = log_errors(&self.logger, "phase heads", heads);
+            bundle.add_part(parts::phase_heads_part(heads)?);
+        }
+
+        Ok(bundle.build_stream().from_err().boxify())
+    }
+
+    /// Stream the changegroup entries for all changesets that are ancestors of `heads` but not
+    /// of `common`. Changesets are sent parents first, followed by their manifests and then the
+    /// file revisions they introduce, grouped per file. Each manifest and file revision is only
+    /// sent once.
+    fn changegroup_entries(
+        &self,
+        heads: &[NodeHash],
+        common: &[NodeHash],
+    ) -> BoxStream<Part, hgproto::Error> {
+        let hgrepo = &self.repo.hgrepo;
+        let repo_generation = &self.repo.repo_generation;
+
+        let ancestors_stream = |nodes: &[NodeHash]| -> Box<NodeStream> {
+            let ancestors = nodes.iter().map(|node| {
+                Box::new(AncestorsNodeStream::new(
+                    hgrepo,
+                    repo_generation.clone(),
+                    *node,
+                )) as Box<NodeStream>
+            });
+            Box::new(UnionNodeStream::new(
+                hgrepo,
+                repo_generation.clone(),
+                ancestors,
+            ))
+        };
+
+        let missing = SetDifferenceNodeStream::new(
+            hgrepo,
+            repo_generation.clone(),
+            ancestors_stream(heads),
+            ancestors_stream(common),
+        );
+
+        let repo = hgrepo.clone();
+        let csstore = self.repo.csstore.clone();
+        missing
+            .map_err(|err| hgproto::Error::with_chain(err, hgproto::ErrorKind::Repo))
+            .collect()
+            .map(move |mut nodes| {
+                // Revsets produce the newest changesets first, but the receiving side needs
+                // parents before children.
+                nodes.reverse();
+                let nodes = Arc::new(nodes);
 
-        Ok(
-            encode_fut
-                .map(|cursor| Bytes::from(cursor.into_inner()))
-                .from_err()
-                .boxify(),
-        )
+                changelog_entries(csstore.clone(), nodes.clone())
+                    .chain(manifest_entries(repo.clone(), csstore, nodes.clone()))
+                    .chain(filelog_entries(repo, nodes))
+                    .chain(stream::once(Ok(Part::End)))
+            })
+            .flatten_stream()
+            .boxify()
     }
 }
 
+fn iter_nodes(nodes: Arc<Vec<NodeHash>>) -> BoxStream<NodeHash, hgproto::Error> {
+    stream::iter_ok((0..nodes.len()).map(move |i| nodes[i])).boxify()
+}
+
+/// The changelog section of a changegroup. Changesets are sent with the text they were stored
+/// with, so that they're guaranteed to hash to the same node on the receiving side.
+fn changelog_entries(
+    store: Arc<ChangesetStore>,
+    nodes: Arc<Vec<NodeHash>>,
+) -> BoxStream<Part, hgproto::Error> {
+    iter_nodes(nodes)
+        .map(move |node| {
+            store
+                .get_changeset_text(&node)
+                .map(move |(parents, text)| fulltext_chunk(node, &parents, node, text))
+        })
+        .buffered(CHANGEGROUP_FETCHES)
+        .map(|chunk| Part::CgChunk(Section::Changeset, chunk))
+        .chain(stream::once(Ok(Part::SectionEnd(Section::Changeset))))
+        .boxify()
+}
+
+/// The manifest section of a changegroup. A manifest shared by several changesets is only sent
+/// once, linked to the first of them.
+fn manifest_entries(
+    repo: Arc<BoxedHgRepo>,
+    store: Arc<ChangesetStore>,
+    nodes: Arc<Vec<NodeHash>>,
+) -> BoxStream<Part, hgproto::Error> {
+    let mut seen = HashSet::new();
+
+    iter_nodes(nodes)
+        .map({
+            let repo = repo.clone();
+            move |node| repo.get_changeset_by_nodeid(&node).map(move |cs| (node, cs))
+        })
+        .buffered(CHANGEGROUP_FETCHES)
+        .filter(move |&(_, ref cs)| seen.insert(*cs.manifestid()))
+        .map(move |(node, cs)| manifest_chunk(repo.clone(), store.clone(), node, cs))
+        .buffered(CHANGEGROUP_FETCHES)
+        .map(|chunk| Part::CgChunk(Section::Manifest, chunk))
+        .chain(stream::once(Ok(Part::SectionEnd(Section::Manifest))))
+        .boxify()
+}
+
+/// The file sections of a changegroup. Finding out which file revisions to send means going
+/// through all the changesets first, so only their nodes are kept until then, and their
+/// contents are fetched as they're sent.
+fn filelog_entries(
+    repo: Arc<BoxedHgRepo>,
+    nodes: Arc<Vec<NodeHash>>,
+) -> BoxStream<Part, hgproto::Error> {
+    iter_nodes(nodes)
+        .map({
+            let repo = repo.clone();
+            move |node| {
+                let repo = repo.clone();
+                repo.get_changeset_by_nodeid(&node)
+                    .and_then(move |cs| file_revisions(repo, node, cs))
+            }
+        })
+        .buffered(CHANGEGROUP_FETCHES)
+        .fold(BTreeMap::new(), |mut filelogs, revs| {
+            for (path, rev) in revs {
+                let filelog = filelogs.entry(path).or_insert_with(Filelog::default);
+                // Several changesets can list the same file revision, for instance when the
+                // same change is made on two branches. It's only sent once, linked to the
+                // first of them.
+                if filelog.seen.insert(rev.node) {
+                    filelog.revs.push(rev);
+                }
+            }
+            Ok::<_, hgproto::Error>(filelogs)
+        })
+        .map(move |filelogs: BTreeMap<MPath, Filelog>| {
+            stream::iter_ok(filelogs)
+                .map(move |(path, filelog)| {
+                    let section = Section::Filelog(path.clone());
+                    let end = Part::SectionEnd(section.clone());
+                    let repo = repo.clone();
+                    stream::iter_ok(filelog.revs)
+                        .map(move |rev| filelog_chunk(repo.clone(), path.clone(), rev))
+                        .buffered(CHANGEGROUP_FETCHES)
+                        .map(move |chunk| Part::CgChunk(section.clone(), chunk))
+                        .chain(stream::once(Ok(end)))
+                })
+                .flatten()
+        })
+        .flatten_stream()
+        .boxify()
+}
+
+/// The revisions of a file to send in a changegroup, in the order they were introduced.
+#[derive(Default)]
+struct Filelog {
+    revs: Vec<FileRevision>,
+    seen: HashSet<NodeHash>,
+}
+
+/// A file revision to send in a changegroup, along with where to find it.
+struct FileRevision {
+    node: NodeHash,
+    /// The changeset that introduced the revision.
+    linknode: NodeHash,
+    /// The manifest of `linknode`.
+    manifestid: NodeHash,
+}
+
+fn parent_nodes(parents: &Parents) -> (NodeHash, NodeHash) {
+    let (p1, p2) = parents.get_nodes();
+    (
+        p1.cloned().unwrap_or(NULL_HASH),
+        p2.cloned().unwrap_or(NULL_HASH),
+    )
+}
+
+pub fn fulltext_chunk(
+    node: NodeHash,
+    parents: &Parents,
+    linknode: NodeHash,
+    text: Vec<u8>,
+) -> CgDeltaChunk {
+    let (p1, p2) = parent_nodes(parents);
+    CgDeltaChunk {
+        node,
+        p1,
+        p2,
+        base: NULL_HASH,
+        linknode,
+        flags: 0,
+        delta: Delta::new_fulltext(text),
+    }
+}
+
+fn manifest_chunk(
+    repo: Arc<BoxedHgRepo>,
+    store: Arc<ChangesetStore>,
+    linknode: NodeHash,
+    cs: Box<Changeset>,
+) -> BoxFuture<CgDeltaChunk, hgproto::Error> {
+    let manifestid = *cs.manifestid();
+
+    // The manifest is sent with the parents it was stored with, which needn't be the manifests
+    // of the changeset's parents, or the receiving side would hash it differently.
+    let parents = store.get_manifest_parents(&manifestid);
+    let text = manifest_lines(&repo, &manifestid).map(|lines| manifest_text(&lines));
+
+    parents
+        .join(text)
+        .map(move |(parents, text)| fulltext_chunk(manifestid, &parents, linknode, text))
+        .boxify()
+}
+
+/// The entries of a flat manifest, mapping each path to its hex hash followed by its flag.
+pub type ManifestLines = BTreeMap<Vec<u8>, String>;
+
+pub fn manifest_lines(
+    repo: &BoxedHgRepo,
+    manifestid: &NodeHash,
+) -> BoxFuture<ManifestLines, hgproto::Error> {
+    repo.get_manifest_by_nodeid(manifestid)
+        .and_then(|manifest| manifest.list().collect())
+        .map(|entries| {
+            entries
+                .into_iter()
+                .map(|entry| {
+                    (
+                        entry.get_path().to_vec(),
+                        format!("{}{}", entry.get_hash(), entry.get_type()),
+                    )
+                })
+                .collect()
+        })
+        .boxify()
+}
+
+/// The revlog text of a flat manifest, which is a sorted list of
+/// `<path>\0<hex hash>[<flag>]\n` lines.
+pub fn manifest_text(lines: &ManifestLines) -> Vec<u8> {
+    let mut text = Vec::new();
+    for (path, details) in lines {
+        text.extend_from_slice(path);
+        text.push(b'\0');
+        text.extend_from_slice(details.as_bytes());
+        text.push(b'\n');
+    }
+    text
+}
+
+/// The file revisions introduced by the changeset `linknode`.
+fn file_revisions(
+    repo: Arc<BoxedHgRepo>,
+    linknode: NodeHash,
+    cs: Box<Changeset>,
+) -> BoxFuture<Vec<(MPath, FileRevision)>, hgproto::Error> {
+    let manifestid = *cs.manifestid();
+    let files = cs.files().to_vec();
+
+    repo.get_manifest_by_nodeid(&manifestid)
+        .and_then(move |manifest| {
+            // Files that were removed by this changeset don't appear in its manifest.
+            stream::iter_ok(files)
+                .and_then(move |path| manifest.lookup(&path))
+                .filter_map(|entry| entry)
+                .filter(|entry| entry.get_type() != Type::Tree)
+                .map(move |entry| {
+                    let rev = FileRevision {
+                        node: *entry.get_hash(),
+                        linknode,
+                        manifestid,
+                    };
+                    (entry.get_path().clone(), rev)
+                })
+                .collect()
+        })
+        .boxify()
+}
+
+fn filelog_chunk(
+    repo: Arc<BoxedHgRepo>,
+    path: MPath,
+    rev: FileRevision,
+) -> BoxFuture<CgDeltaChunk, hgproto::Error> {
+    let FileRevision {
+        node,
+        linknode,
+        manifestid,
+    } = rev;
+
+    repo.get_manifest_by_nodeid(&manifestid)
+        .and_then(move |manifest| manifest.lookup(&path))
+        .and_then(|entry| {
+            entry.ok_or(hgproto::Error::from(
+                "file revision missing from the manifest that introduced it",
+            ))
+        })
+        .and_then(|entry| entry.get_parents().join(entry.get_raw_content()))
+        .and_then(move |(parents, content)| -> hgproto::Result<_> {
+            let text = content.into_inner().ok_or("missing file content")?;
+            Ok(fulltext_chunk(node, &parents, linknode, text))
+        })
+        .boxify()
+}
+
+/// Parse a phase sent by a client, which is the number Mercurial uses for it. Secret changesets
+/// are never pushed, so that phase isn't supported.
+fn phase_value(value: &[u8]) -> hgproto::Result<Phase> {
+    match value {
+        b"0" => Ok(Phase::Public),
+        b"1" => Ok(Phase::Draft),
+        _ => bail!("invalid phase {:?}", String::from_utf8_lossy(value)),
+    }
+}
+
+/// Parse a bookmark value sent by a client: either a hex node hash, or empty if the bookmark
+/// doesn't exist.
+fn bookmark_value(value: &[u8]) -> hgproto::Result<Option<NodeHash>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    let hex = str::from_utf8(value)?;
+    hex.parse().map(Some).map_err(|err| {
+        hgproto::Error::with_chain(err, format!("invalid bookmark value {:?}", hex))
+    })
+}
+
 impl HgCommands for RepoClient {
     // @wireprotocommand('between', 'pairs')
     fn between(&self, pairs: Vec<(NodeHash, NodeHash)>) -> HgCommandRes<Vec<Vec<NodeHash>>> {
@@ -277,6 +946,31 @@ impl HgCommands for RepoClient {
             .boxify()
     }
 
+    // @wireprotocommand('branchmap')
+    fn branchmap(&self) -> HgCommandRes<HashMap<String, HashSet<NodeHash>>> {
+        info!(self.logger, "branchmap");
+
+        self.repo.branchmap.get()
+    }
+
+    // @wireprotocommand('branches', 'nodes')
+    fn branches(&self, nodes: Vec<NodeHash>) -> HgCommandRes<Vec<BranchRes>> {
+        info!(self.logger, "branches: {:?}", nodes);
+
+        let branches: Vec<_> = nodes
+            .into_iter()
+            .map(|node| branchmap::branch_base(self.repo.hgrepo.clone(), node))
+            .collect();
+        future::join_all(branches).boxify()
+    }
+
+    // @wireprotocommand('clonebundles', '')
+    fn clonebundles(&self) -> HgCommandRes<String> {
+        info!(self.logger, "clonebundles");
+
+        future::ok(clonebundles::manifest(&self.repo.clonebundles)).boxify()
+    }
+
     // @wireprotocommand('changegroup', 'roots')
     fn changegroup(&self, roots: Vec<NodeHash>) -> HgCommandRes<()> {
         // TODO: streaming something
@@ -311,33 +1005,222 @@ impl HgCommands for RepoClient {
     }
 
     // @wireprotocommand('getbundle', '*')
-    fn getbundle(&self, args: GetbundleArgs) -> HgCommandRes<Bytes> {
+    fn getbundle(&self, args: GetbundleArgs) -> HgCommandRes<BoxStream<Bytes, hgproto::Error>> {
         info!(self.logger, "Getbundle: {:?}", args);
 
-        match self.create_bundle(args) {
-            Ok(res) => res,
-            Err(err) => Err(err).into_future().boxify(),
+        self.create_bundle(args).into_future().boxify()
+    }
+
+    // @wireprotocommand('getfiles', '') from remotefilelog
+    fn getfile(&self, node: NodeHash, path: MPath) -> HgCommandRes<Bytes> {
+        info!(self.logger, "getfile {} {}", path, node);
+
+        match self.filestore() {
+            Ok(filestore) => remotefilelog::getfile(filestore, path, node),
+            Err(err) => future::err(err).boxify(),
         }
     }
 
+    // @wireprotocommand('getflogheads', 'path') from remotefilelog
+    fn getflogheads(&self, path: MPath) -> HgCommandRes<Vec<NodeHash>> {
+        info!(self.logger, "getflogheads {}", path);
+
+        match self.repo.flogheads {
+            Some(ref flogheads) => flogheads.get(path),
+            None => future::err("this repo doesn't support shallow clients".into()).boxify(),
+        }
+    }
+
+    // @wireprotocommand('gettreepack', '*') from treemanifest
+    fn gettreepack(
+        &self,
+        args: GettreepackArgs,
+    ) -> HgCommandRes<BoxStream<Bytes, hgproto::Error>> {
+        info!(self.logger, "gettreepack: {:?}", args);
+
+        self.treestore()
+            .and_then(|treestore| treemanifest::gettreepack(treestore, args))
+            .into_future()
+            .boxify()
+    }
+
     // @wireprotocommand('hello')
     fn hello(&self) -> HgCommandRes<HashMap<String, Vec<String>>> {
         info!(self.logger, "Hello -> capabilities");
 
         let mut res = HashMap::new();
-        let mut caps = wireprotocaps();
-        caps.push(format!("bundle2={}", bundle2caps()));
-        res.insert("capabilities".to_string(), caps);
+        res.insert("capabilities".to_string(), capabilities(&self.repo));
 
         future::ok(res).boxify()
     }
 
+    // @wireprotocommand('capabilities')
+    fn capabilities(&self) -> HgCommandRes<Vec<String>> {
+        info!(self.logger, "capabilities");
+
+        future::ok(capabilities(&self.repo)).boxify()
+    }
+
+    // @wireprotocommand('listkeys', 'namespace')
+    fn listkeys(&self, namespace: String) -> HgCommandRes<HashMap<Vec<u8>, Vec<u8>>> {
+        info!(self.logger, "listkeys {}", namespace);
+
+        self.repo
+            .namespaces
+            .list(&namespace)
+            .collect()
+            .map(|items| items.into_iter().collect())
+            .boxify()
+    }
+
+    // @wireprotocommand('lookup', 'key')
+    fn lookup(&self, key: String) -> HgCommandRes<LookupRes> {
+        info!(self.logger, "lookup {}", key);
+
+        let bookmarks = match self.repo.hgrepo.get_bookmarks() {
+            Ok(bookmarks) => bookmarks,
+            Err(err) => return future::err(err).boxify(),
+        };
+        let hgrepo = self.repo.hgrepo.clone();
+
+        // As in Mercurial, bookmarks take precedence over full hashes, which take precedence
+        // over hash prefixes.
+        bookmarks
+            .get(&key)
+            .and_then(move |bookmark| {
+                if let Some((node, _)) = bookmark {
+                    return future::ok(LookupRes::Found(node)).boxify();
+                }
+
+                let unknown = LookupRes::Failed(format!("unknown revision '{}'", key));
+                if key.is_empty() || !key.chars().all(|c| c.is_digit(16)) {
+                    return future::ok(unknown).boxify();
+                }
+
+                if key.len() == 40 {
+                    let node = match NodeHash::from_str(&key) {
+                        Ok(node) => node,
+                        Err(_) => return future::ok(unknown).boxify(),
+                    };
+                    return hgrepo
+                        .changeset_exists(&node)
+                        .map(move |exists| if exists {
+                            LookupRes::Found(node)
+                        } else {
+                            unknown
+                        })
+                        .boxify();
+                }
+
+                hgrepo
+                    .get_changesets_by_prefix(&key)
+                    .map(move |nodes| match nodes.len() {
+                        0 => unknown,
+                        1 => LookupRes::Found(nodes[0]),
+                        _ => LookupRes::Failed(format!("{}: ambiguous identifier", key)),
+                    })
+                    .boxify()
+            })
+            .boxify()
+    }
+
+    // @wireprotocommand('stream_out')
+    fn stream_out(&self) -> HgCommandRes<BoxStream<Bytes, hgproto::Error>> {
+        info!(self.logger, "stream_out");
+
+        let stream = match self.repo.streamstore {
+            Some(ref streamstore) => streamclone::stream_out(streamstore.clone()),
+            None => streamclone::unavailable(),
+        };
+        future::ok(stream).boxify()
+    }
+
     // @wireprotocommand('unbundle', 'heads')
     fn unbundle(
         &self,
-        heads: Vec<NodeHash>, /* , _stream: BoxStream<Vec<u8>, Error> */
-    ) -> HgCommandRes<()> {
+        heads: Vec<NodeHash>,
+        stream: BoxStream<Bytes, hgproto::Error>,
+    ) -> HgCommandRes<Bytes> {
         info!(self.logger, "unbundle heads {:?}", heads);
-        future::ok(()).boxify()
+
+        match self.pushstore() {
+            Ok(pushstore) => unbundle::unbundle(
+                self.repo.hgrepo.clone(),
+                pushstore,
+                self.repo.phases.clone(),
+                self.repo.hooks.clone(),
+                self.repo.repo_generation.clone(),
+                heads,
+                stream,
+                self.logger.clone(),
+            ),
+            Err(err) => future::err(err).boxify(),
+        }
+    }
+
+    // @wireprotocommand('pushkey', 'namespace key old new')
+    fn pushkey(
+        &self,
+        namespace: String,
+        key: String,
+        old: Vec<u8>,
+        new: Vec<u8>,
+    ) -> HgCommandRes<bool> {
+        info!(
+            self.logger,
+            "pushkey {} {}: {} -> {}",
+            namespace,
+            key,
+            String::from_utf8_lossy(&old),
+            String::from_utf8_lossy(&new)
+        );
+
+        match namespace.as_str() {
+            "bookmarks" => {
+                let args = self.pushstore().and_then(|pushstore| {
+                    Ok((pushstore, bookmark_value(&old)?, bookmark_value(&new)?))
+                });
+                let (pushstore, old, new) = match args {
+                    Ok(args) => args,
+                    Err(err) => return future::err(err).boxify(),
+                };
+
+                // Bookmarks can only point to changesets the repo has.
+                let exists = match new {
+                    Some(ref node) => self.repo.hgrepo.changeset_exists(node),
+                    None => future::ok(true).boxify(),
+                };
+                let hooks = self.repo.hooks.clone();
+                let phases = self.repo.phases.clone();
+                exists
+                    .and_then(move |exists| if exists {
+                        hooks
+                            .check(key.as_bytes(), old, new)
+                            .and_then(move |_| pushstore.update_bookmark(key.as_bytes(), old, new))
+                            .boxify()
+                    } else {
+                        future::ok(false).boxify()
+                    })
+                    .and_then(move |updated| if updated {
+                        phases.bookmark_moved(old, new).map(|_| true).boxify()
+                    } else {
+                        future::ok(false).boxify()
+                    })
+                    .boxify()
+            }
+            "phases" => {
+                let args = NodeHash::from_str(&key)
+                    .map_err(|err| {
+                        hgproto::Error::with_chain(err, format!("invalid node {:?}", key))
+                    })
+                    .and_then(|node| Ok((node, phase_value(&old)?, phase_value(&new)?)));
+                match args {
+                    Ok((node, old, new)) => self.repo.phases.pushkey(node, old, new),
+                    Err(err) => future::err(err).boxify(),
+                }
+            }
+            // As in Mercurial, pushing to an unknown namespace just fails.
+            _ => future::ok(false).boxify(),
+        }
     }
 }
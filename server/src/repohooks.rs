@@ -0,0 +1,186 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Hooks
+//!
+//! Repo owners can configure hooks for some of a repo's bookmarks, either written in Lua or
+//! picked by name from the native hooks built into the server. They're run before one of those
+//! bookmarks is moved, whether by `pushkey` or by a pushrebase, and the move only goes ahead if
+//! all of them allow it. Each repo has its own pool of Lua contexts to run Lua hooks in.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use slog::Logger;
+
+use hgproto;
+use hooks::{HookInfo, HookLimits, HookManager, HookOutcome, HookRegistry, LuaHook};
+use mercurial_types::{NodeHash, NULL_HASH};
+use metaconfig::repoconfig::{HookConfig, HookType};
+use repoinfo::RepoGenCache;
+
+use errors::*;
+use repo::BoxedHgRepo;
+
+// The number of hooks that can run at once for each repo.
+const HOOK_CONTEXTS: usize = 4;
+// The number of hooks that can wait for a context before pushes have to wait to queue them.
+const HOOK_QUEUE_SIZE: usize = 100;
+// Limit on the memory each hook can use in each of the Lua contexts that run a repo's hooks, in
+// bytes.
+const HOOK_MAX_MEMORY: usize = 64 * 1024 * 1024;
+
+/// The hooks configured for a repo.
+#[derive(Clone)]
+pub struct RepoHooks {
+    reponame: String,
+    /// The names of the hooks for each bookmark that has any.
+    bookmarks: Arc<HashMap<String, Vec<String>>>,
+    /// Where the hooks are run, if there are any.
+    manager: Option<Arc<HookManager>>,
+    logger: Logger,
+}
+
+impl RepoHooks {
+    pub fn new(
+        reponame: &str,
+        hgrepo: Arc<BoxedHgRepo>,
+        repo_generation: RepoGenCache<BoxedHgRepo>,
+        hooks: Vec<HookConfig>,
+        logger: &Logger,
+    ) -> Result<Self> {
+        let mut bookmarks = HashMap::new();
+        for hook in &hooks {
+            for bookmark in &hook.bookmarks {
+                bookmarks
+                    .entry(bookmark.clone())
+                    .or_insert_with(Vec::new)
+                    .push(hook.name.clone());
+            }
+        }
+
+        let manager = if hooks.is_empty() {
+            None
+        } else {
+            let mut registry = HookRegistry::new();
+            for hook in hooks {
+                let added = match hook.hook_type {
+                    HookType::Lua {
+                        code,
+                        max_instructions,
+                        timeout_ms,
+                    } => {
+                        let defaults = HookLimits::default();
+                        let limits = HookLimits {
+                            max_instructions: max_instructions
+                                .unwrap_or(defaults.max_instructions),
+                            timeout: timeout_ms
+                                .map(Duration::from_millis)
+                                .unwrap_or(defaults.timeout),
+                        };
+                        registry.add_lua_hook(LuaHook {
+                            name: hook.name,
+                            code,
+                            limits,
+                        })
+                    }
+                    HookType::Native(native) => registry.add_builtin_hook(&hook.name, &native),
+                };
+                added.map_err(|err| Error::with_chain(err, "failed to load hooks"))?;
+            }
+            let manager = HookManager::new(
+                hgrepo,
+                repo_generation,
+                registry,
+                HOOK_CONTEXTS,
+                HOOK_QUEUE_SIZE,
+                HOOK_MAX_MEMORY,
+            ).map_err(|err| Error::with_chain(err, "failed to load hooks"))?;
+            Some(Arc::new(manager))
+        };
+
+        Ok(RepoHooks {
+            reponame: reponame.to_string(),
+            bookmarks: Arc::new(bookmarks),
+            manager,
+            logger: logger.clone(),
+        })
+    }
+
+    /// Run the hooks for `bookmark`, failing with the reason if any of them don't allow it to be
+    /// moved from `old` to `new`. `None` means the bookmark doesn't exist before or after the
+    /// move.
+    pub fn check(
+        &self,
+        bookmark: &[u8],
+        old: Option<NodeHash>,
+        new: Option<NodeHash>,
+    ) -> BoxFuture<(), hgproto::Error> {
+        let bookmark = String::from_utf8_lossy(bookmark).into_owned();
+        let (manager, names) = match (self.manager.as_ref(), self.bookmarks.get(&bookmark)) {
+            (Some(manager), Some(names)) => (manager, names),
+            _ => return future::ok(()).boxify(),
+        };
+
+        let checks: Vec<_> = names
+            .iter()
+            .map(|name| {
+                let info = HookInfo {
+                    repo: self.reponame.clone(),
+                    bookmark: bookmark.clone(),
+                    old_hash: old.unwrap_or(NULL_HASH),
+                    new_hash: new.unwrap_or(NULL_HASH),
+                };
+                let name = name.clone();
+                let bookmark = bookmark.clone();
+                let logger = self.logger.clone();
+                let start = Instant::now();
+
+                manager.run_hook(&name, info).then(move |outcome| {
+                    let elapsed = start.elapsed();
+                    let elapsed_ms =
+                        elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos() / 1_000_000);
+                    let result = match outcome {
+                        Ok(HookOutcome::Accepted) => "allowed",
+                        Ok(HookOutcome::Rejected(_)) => "rejected",
+                        Err(_) => "failed",
+                    };
+                    info!(
+                        logger,
+                        "hook {} {} moving bookmark {} in {}ms",
+                        name,
+                        result,
+                        bookmark,
+                        elapsed_ms
+                    );
+
+                    match outcome {
+                        Ok(HookOutcome::Accepted) => Ok(()),
+                        Ok(HookOutcome::Rejected(reason)) => {
+                            let msg = format!(
+                                "hook {} rejected moving bookmark {}: {}",
+                                name,
+                                bookmark,
+                                reason
+                            );
+                            Err(msg.into())
+                        }
+                        Err(err) => Err(hgproto::Error::with_chain(
+                            err,
+                            format!("hook {} failed", name),
+                        )),
+                    }
+                })
+            })
+            .collect();
+
+        future::join_all(checks).map(|_| ()).boxify()
+    }
+}
@@ -0,0 +1,462 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Streaming clones
+//!
+//! `stream_out` sends the files of the repo's store as they would be on disk, and the client
+//! copies them straight into its own store. Blob repos don't have any revlogs, so
+//! `--generate-streamclone` generates them ahead of time, from the same changegroup entries
+//! `getbundle` sends for a full clone, and stores them in the blobstore in pieces. Serving a
+//! stream clone then only reads those pieces back, as fast as the client takes them. Like a
+//! clonebundle, the stored data is a snapshot, and clients pull anything newer afterwards.
+//!
+//! Every revision is stored as an uncompressed fulltext in an inline revlog, which is valid if
+//! not compact. Only one revlog is built in memory at a time.
+//!
+//! The stream is a `0\n` status line, then `<file count> <total bytes>\n`, followed by each
+//! file as `<name>\0<bytes>\n` and its contents. If there's nothing to send, it's just `1\n`,
+//! which tells the client that stream clones aren't available.
+
+use std::collections::HashMap;
+use std::mem;
+use std::str;
+use std::sync::Arc;
+
+use bytes::{BigEndian, BufMut, Bytes};
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobrepo::{BlobRepo, BlobState};
+use blobstore::Blobstore;
+use hgproto;
+use mercurial_bundles::changegroup::{CgDeltaChunk, Part, Section};
+use mercurial_types::{delta, NodeHash, NULL_HASH};
+
+use unbundle::repo_err;
+
+const REVLOGV1: u32 = 1;
+const FLAG_INLINE_DATA: u32 = 1 << 16;
+const NULL_REV: i32 = -1;
+
+/// The stored data is split into pieces of this size.
+const CHUNK_SIZE: usize = 1 << 20;
+/// How many pieces to read or write at once.
+const CHUNK_FETCHES: usize = 10;
+/// Name of the list of files that make up the stream clone data.
+const INDEX: &str = "files";
+
+/// The storage for a repo's pre-generated stream clone data.
+pub trait StreamStore: Send + Sync + 'static {
+    fn get_blob(&self, name: &str) -> BoxFuture<Option<Bytes>, hgproto::Error>;
+    fn put_blob(&self, name: &str, data: Vec<u8>) -> BoxFuture<(), hgproto::Error>;
+}
+
+impl<State> StreamStore for BlobRepo<State>
+where
+    State: BlobState,
+    <State::Blobstore as Blobstore>::ValueIn: From<Vec<u8>>,
+{
+    fn get_blob(&self, name: &str) -> BoxFuture<Option<Bytes>, hgproto::Error> {
+        BlobRepo::get_streamclone_blob(self, name)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn put_blob(&self, name: &str, data: Vec<u8>) -> BoxFuture<(), hgproto::Error> {
+        BlobRepo::put_streamclone_blob(self, name, data)
+            .map_err(repo_err)
+            .boxify()
+    }
+}
+
+/// A store file, stored as `chunks` consecutive pieces starting at `first`.
+struct StoredFile {
+    name: Vec<u8>,
+    size: u64,
+    first: u64,
+    chunks: u64,
+}
+
+/// The files that make up a generation of stream clone data. Each generation's pieces are stored
+/// separately, so that clones already in progress aren't affected when the data is regenerated.
+///
+/// It's stored as a line with the generation, followed by a `<first> <chunks> <size> <name>`
+/// line for each file.
+struct Index {
+    generation: u64,
+    files: Vec<StoredFile>,
+}
+
+impl Index {
+    fn parse(data: &[u8]) -> hgproto::Result<Self> {
+        fn number(field: Option<&[u8]>) -> hgproto::Result<u64> {
+            let field = field.ok_or("stream clone index line is incomplete")?;
+            str::from_utf8(field)
+                .ok()
+                .and_then(|field| field.parse().ok())
+                .ok_or_else(|| "stream clone index has an invalid number".into())
+        }
+
+        let mut lines = data.split(|b| *b == b'\n').filter(|line| !line.is_empty());
+        let generation = number(lines.next())?;
+        let files = lines
+            .map(|line| -> hgproto::Result<StoredFile> {
+                let mut fields = line.splitn(4, |b| *b == b' ');
+                Ok(StoredFile {
+                    first: number(fields.next())?,
+                    chunks: number(fields.next())?,
+                    size: number(fields.next())?,
+                    name: fields
+                        .next()
+                        .ok_or("stream clone index line is incomplete")?
+                        .to_vec(),
+                })
+            })
+            .collect::<hgproto::Result<_>>()?;
+
+        Ok(Index { generation, files })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{}\n", self.generation).into_bytes();
+        for file in &self.files {
+            out.extend_from_slice(format!("{} {} {} ", file.first, file.chunks, file.size)
+                .as_bytes());
+            out.extend_from_slice(&file.name);
+            out.push(b'\n');
+        }
+        out
+    }
+}
+
+fn chunk_name(generation: u64, chunk: u64) -> String {
+    format!("{}.{}", generation, chunk)
+}
+
+/// An inline revlog, generated in memory.
+struct RevlogBuilder {
+    revs: HashMap<NodeHash, i32>,
+    /// Revisions that can't be added until one of their parents is, keyed by that parent.
+    waiting: HashMap<NodeHash, Vec<(CgDeltaChunk, Option<i32>)>>,
+    // Length of the revision data, not counting the index entries interleaved with it.
+    datalen: u64,
+    content: Vec<u8>,
+}
+
+impl RevlogBuilder {
+    fn new() -> Self {
+        RevlogBuilder {
+            revs: HashMap::new(),
+            waiting: HashMap::new(),
+            datalen: 0,
+            content: Vec::new(),
+        }
+    }
+
+    fn rev(&self, node: &NodeHash) -> Option<i32> {
+        if *node == NULL_HASH {
+            Some(NULL_REV)
+        } else {
+            self.revs.get(node).cloned()
+        }
+    }
+
+    /// Add a revision, linked to the changelog revision `linkrev`, or to itself if this is the
+    /// changelog. Revisions that are already present are skipped, and ones whose parents aren't
+    /// there yet are added once they are.
+    fn add(&mut self, chunk: CgDeltaChunk, linkrev: Option<i32>) {
+        let mut ready = vec![(chunk, linkrev)];
+        while let Some((chunk, linkrev)) = ready.pop() {
+            if self.revs.contains_key(&chunk.node) {
+                continue;
+            }
+
+            let missing = [chunk.p1, chunk.p2]
+                .iter()
+                .find(|parent| self.rev(parent).is_none())
+                .cloned();
+            match missing {
+                Some(parent) => self.waiting
+                    .entry(parent)
+                    .or_insert_with(Vec::new)
+                    .push((chunk, linkrev)),
+                None => {
+                    let node = chunk.node;
+                    self.append(chunk, linkrev);
+                    if let Some(children) = self.waiting.remove(&node) {
+                        ready.extend(children);
+                    }
+                }
+            }
+        }
+    }
+
+    fn append(&mut self, chunk: CgDeltaChunk, linkrev: Option<i32>) {
+        let rev = self.revs.len() as i32;
+        let linkrev = linkrev.unwrap_or(rev);
+        let p1 = self.rev(&chunk.p1).unwrap_or(NULL_REV);
+        let p2 = self.rev(&chunk.p2).unwrap_or(NULL_REV);
+        // Changegroup entries are all fulltexts.
+        let text = delta::apply(b"", chunk.delta);
+
+        // Data that starts with a NUL byte is stored as is, anything else is marked as
+        // uncompressed with a `u`.
+        let mut data = Vec::with_capacity(text.len() + 1);
+        if !text.is_empty() && text[0] != b'\0' {
+            data.push(b'u');
+        }
+        data.extend_from_slice(&text);
+
+        // The first entry's offset is always 0, and its top half holds the revlog version.
+        let offset_flags = self.datalen << 16;
+        if rev == 0 {
+            self.content.put_u32::<BigEndian>(REVLOGV1 | FLAG_INLINE_DATA);
+        } else {
+            self.content.put_u32::<BigEndian>((offset_flags >> 32) as u32);
+        }
+        self.content.put_u32::<BigEndian>(offset_flags as u32);
+        self.content.put_i32::<BigEndian>(data.len() as i32);
+        self.content.put_i32::<BigEndian>(text.len() as i32);
+        // Every revision is a fulltext, so it's its own delta base.
+        self.content.put_i32::<BigEndian>(rev);
+        self.content.put_i32::<BigEndian>(linkrev);
+        self.content.put_i32::<BigEndian>(p1);
+        self.content.put_i32::<BigEndian>(p2);
+        self.content.put_slice(chunk.node.as_ref());
+        self.content.put_slice(&[0; 12]);
+        self.content.extend_from_slice(&data);
+
+        self.datalen += data.len() as u64;
+        self.revs.insert(chunk.node, rev);
+    }
+
+    /// The revlog's contents, once every revision has been added.
+    fn finish(&mut self) -> hgproto::Result<Vec<u8>> {
+        if let Some(parent) = self.waiting.keys().next() {
+            bail!("revision {} is missing", parent);
+        }
+        Ok(mem::replace(&mut self.content, Vec::new()))
+    }
+}
+
+/// Stores the generated files in pieces, and keeps track of where they went.
+struct Writer {
+    store: Arc<StreamStore>,
+    generation: u64,
+    files: Vec<StoredFile>,
+    next_chunk: u64,
+}
+
+impl Writer {
+    fn write(mut self, name: Vec<u8>, content: Vec<u8>) -> BoxFuture<Self, hgproto::Error> {
+        let pieces: Vec<_> = content.chunks(CHUNK_SIZE).map(|piece| piece.to_vec()).collect();
+        let first = self.next_chunk;
+        self.next_chunk += pieces.len() as u64;
+        self.files.push(StoredFile {
+            name,
+            size: content.len() as u64,
+            first,
+            chunks: pieces.len() as u64,
+        });
+
+        let store = self.store.clone();
+        let generation = self.generation;
+        stream::iter_ok(pieces.into_iter().enumerate())
+            .map(move |(i, piece)| {
+                store.put_blob(&chunk_name(generation, first + i as u64), piece)
+            })
+            .buffer_unordered(CHUNK_FETCHES)
+            .for_each(|()| Ok(()))
+            .map(move |()| self)
+            .boxify()
+    }
+
+    /// Store the index, which makes the new generation the one that's served. The changelog
+    /// goes last, after the manifest, so that everything it refers to is already there by the
+    /// time a client sees it.
+    fn finish(self) -> BoxFuture<(), hgproto::Error> {
+        let mut files = self.files;
+        files.sort_by_key(|file| match file.name.as_slice() {
+            b"00changelog.i" => 2,
+            b"00manifest.i" => 1,
+            _ => 0,
+        });
+
+        let index = Index {
+            generation: self.generation,
+            files,
+        };
+        self.store.put_blob(INDEX, index.to_bytes())
+    }
+}
+
+/// Builds each revlog from the changegroup entries, and stores it once it's complete.
+struct Generator {
+    writer: Writer,
+    /// The revision number of each changeset, once the changelog is complete.
+    linkrevs: HashMap<NodeHash, i32>,
+    revlog: RevlogBuilder,
+}
+
+impl Generator {
+    fn add(mut self, part: Part) -> BoxFuture<Self, hgproto::Error> {
+        match part {
+            Part::CgChunk(Section::Changeset, chunk) => {
+                self.revlog.add(chunk, None);
+                future::ok(self).boxify()
+            }
+            Part::CgChunk(Section::Manifest, chunk) | Part::CgChunk(Section::Filelog(_), chunk) => {
+                let linkrev = match self.linkrevs.get(&chunk.linknode) {
+                    Some(linkrev) => *linkrev,
+                    None => {
+                        let err = format!("linknode {} is missing", chunk.linknode);
+                        return future::err(err.into()).boxify();
+                    }
+                };
+                self.revlog.add(chunk, Some(linkrev));
+                future::ok(self).boxify()
+            }
+            Part::CgChunk(Section::Treemanifest(_), _) |
+            Part::SectionEnd(Section::Treemanifest(_)) => {
+                future::err("tree manifests are not supported".into()).boxify()
+            }
+            Part::SectionEnd(section) => {
+                let content = match self.revlog.finish() {
+                    Ok(content) => content,
+                    Err(err) => return future::err(err).boxify(),
+                };
+                let revlog = mem::replace(&mut self.revlog, RevlogBuilder::new());
+                let name = match section {
+                    Section::Changeset => {
+                        self.linkrevs = revlog.revs;
+                        b"00changelog.i".to_vec()
+                    }
+                    Section::Manifest => b"00manifest.i".to_vec(),
+                    Section::Filelog(path) => {
+                        let mut name = b"data/".to_vec();
+                        name.extend_from_slice(&path.to_vec());
+                        name.extend_from_slice(b".i");
+                        encode_dir(&name)
+                    }
+                    Section::Treemanifest(_) => unreachable!(),
+                };
+                if content.is_empty() {
+                    return future::ok(self).boxify();
+                }
+
+                let Generator {
+                    writer, linkrevs, ..
+                } = self;
+                writer
+                    .write(name, content)
+                    .map(move |writer| Generator {
+                        writer,
+                        linkrevs,
+                        revlog: RevlogBuilder::new(),
+                    })
+                    .boxify()
+            }
+            Part::End => future::ok(self).boxify(),
+        }
+    }
+}
+
+/// Escape directory names that would clash with revlog files or the `.hg` directory, as
+/// Mercurial's `store.encodedir` does.
+fn encode_dir(name: &[u8]) -> Vec<u8> {
+    fn replace(input: Vec<u8>, from: &[u8], to: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(input.len());
+        let mut idx = 0;
+        while idx < input.len() {
+            if input[idx..].starts_with(from) {
+                out.extend_from_slice(to);
+                idx += from.len();
+            } else {
+                out.push(input[idx]);
+                idx += 1;
+            }
+        }
+        out
+    }
+
+    let name = replace(name.to_vec(), b".hg/", b".hg.hg/");
+    let name = replace(name, b".i/", b".i.hg/");
+    replace(name, b".d/", b".d.hg/")
+}
+
+/// Generate the stream clone data for a repo from the changegroup entries for all of its
+/// changesets, and store it as a new generation.
+pub fn generate(
+    store: Arc<StreamStore>,
+    parts: BoxStream<Part, hgproto::Error>,
+) -> BoxFuture<(), hgproto::Error> {
+    store
+        .get_blob(INDEX)
+        .and_then(|index| match index {
+            Some(index) => Ok(Index::parse(&index)?.generation + 1),
+            None => Ok(0),
+        })
+        .and_then(move |generation| {
+            let generator = Generator {
+                writer: Writer {
+                    store,
+                    generation,
+                    files: Vec::new(),
+                    next_chunk: 0,
+                },
+                linkrevs: HashMap::new(),
+                revlog: RevlogBuilder::new(),
+            };
+            parts.fold(generator, Generator::add)
+        })
+        .and_then(|generator| generator.writer.finish())
+        .boxify()
+}
+
+/// Send the stored stream clone data, reading it as it's sent.
+pub fn stream_out(store: Arc<StreamStore>) -> BoxStream<Bytes, hgproto::Error> {
+    store
+        .get_blob(INDEX)
+        .and_then(move |index| match index {
+            Some(index) => Ok(send(store, Index::parse(&index)?)),
+            None => Ok(unavailable()),
+        })
+        .flatten_stream()
+        .boxify()
+}
+
+/// The reply for repos that can't be stream cloned.
+pub fn unavailable() -> BoxStream<Bytes, hgproto::Error> {
+    stream::once(Ok(Bytes::from_static(b"1\n"))).boxify()
+}
+
+fn send(store: Arc<StreamStore>, index: Index) -> BoxStream<Bytes, hgproto::Error> {
+    let total: u64 = index.files.iter().map(|file| file.size).sum();
+    let header = format!("0\n{} {}\n", index.files.len(), total);
+
+    let generation = index.generation;
+    let files = stream::iter_ok(index.files)
+        .map(move |file| {
+            let mut header = file.name;
+            header.push(b'\0');
+            header.extend_from_slice(format!("{}\n", file.size).as_bytes());
+
+            let store = store.clone();
+            let content = stream::iter_ok(file.first..file.first + file.chunks)
+                .map(move |chunk| {
+                    let name = chunk_name(generation, chunk);
+                    store.get_blob(&name).and_then(move |piece| {
+                        let missing = || format!("stream clone data {} is missing", name).into();
+                        piece.ok_or_else(missing)
+                    })
+                })
+                .buffered(CHUNK_FETCHES);
+            stream::once(Ok(Bytes::from(header))).chain(content)
+        })
+        .flatten();
+
+    stream::once(Ok(Bytes::from(header))).chain(files).boxify()
+}
@@ -0,0 +1,459 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Serving tree manifests to clients that use the treemanifest extension
+//!
+//! Such clients fetch the manifests they need with `gettreepack`, which names the tree manifests
+//! of a directory that the client wants and those it already has. The reply is a bundle with a
+//! version 03 changegroup holding every tree manifest reachable from the wanted ones, except for
+//! subtrees that are the same in one of the manifests the client has. The root manifests go in
+//! the manifest section and those of other directories in the directory sections, while the
+//! changeset and file sections are left empty.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures::future::Loop;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobrepo::{BlobRepo, BlobState};
+use hgproto::{self, GettreepackArgs};
+use mercurial::{self, RevlogRepo};
+use mercurial::manifest::revlog;
+use mercurial_bundles::{parts, Bundle2EncodeBuilder};
+use mercurial_bundles::changegroup::{CgDeltaChunk, CgVersion, Part, Section};
+use mercurial_types::{MPath, NodeHash, Parents, RepoPath};
+
+use repo::fulltext_chunk;
+use unbundle::repo_err;
+
+/// A revision of the tree manifest of a directory.
+pub struct TreeRevision {
+    pub parents: Parents,
+    /// The changeset that introduced this revision.
+    pub linknode: NodeHash,
+    pub text: Vec<u8>,
+}
+
+/// The operations on a repo's underlying storage that are needed to serve tree manifests.
+pub trait TreeStore: Send + Sync + 'static {
+    /// Get a revision of the tree manifest of directory `path`, which is empty for the root.
+    fn get_tree(&self, path: &MPath, node: &NodeHash) -> BoxFuture<TreeRevision, hgproto::Error>;
+}
+
+impl TreeStore for RevlogRepo {
+    fn get_tree(&self, path: &MPath, node: &NodeHash) -> BoxFuture<TreeRevision, hgproto::Error> {
+        let get_tree = || -> mercurial::Result<_> {
+            let revlog = if path.is_empty() {
+                self.get_manifest_revlog().clone()
+            } else {
+                self.get_tree_revlog(path)?
+            };
+            let entry = revlog.get_entry_by_nodeid(node)?;
+            let rev = revlog.get_rev_by_nodeid(node)?;
+            let linknode = *self.get_changelog_revlog()
+                .get_entry(entry.linkrev)?
+                .nodeid();
+            let text = rev.as_blob()
+                .as_slice()
+                .ok_or("missing tree manifest content")?
+                .to_vec();
+
+            Ok(TreeRevision {
+                parents: *rev.parents(),
+                linknode,
+                text,
+            })
+        };
+
+        get_tree().into_future().from_err().boxify()
+    }
+}
+
+impl<State> TreeStore for BlobRepo<State>
+where
+    State: BlobState,
+{
+    fn get_tree(&self, path: &MPath, node: &NodeHash) -> BoxFuture<TreeRevision, hgproto::Error> {
+        let repopath = if path.is_empty() {
+            RepoPath::root()
+        } else {
+            match RepoPath::dir(path.clone()) {
+                Ok(repopath) => repopath,
+                Err(err) => return future::err(repo_err(err)).boxify(),
+            }
+        };
+
+        BlobRepo::get_raw_content(self, node)
+            .join3(
+                BlobRepo::get_parents(self, node),
+                BlobRepo::get_linknode(self, repopath, node),
+            )
+            .map(|(text, parents, linknode)| TreeRevision {
+                parents,
+                linknode,
+                text,
+            })
+            .map_err(repo_err)
+            .boxify()
+    }
+}
+
+/// Build the bundle sent in reply to `gettreepack`.
+pub fn gettreepack(
+    store: Arc<TreeStore>,
+    args: GettreepackArgs,
+) -> hgproto::Result<BoxStream<Bytes, hgproto::Error>> {
+    let mut bundle = Bundle2EncodeBuilder::new_stream();
+    bundle.add_part(parts::changegroup_part(
+        changegroup_entries(store, args),
+        CgVersion::Cg3,
+    )?);
+
+    Ok(bundle.build_stream().from_err().boxify())
+}
+
+/// A tree manifest that still needs to be looked at, along with the revisions of the same
+/// directory in the manifests the client already has.
+struct PendingTree {
+    path: MPath,
+    node: NodeHash,
+    bases: Vec<NodeHash>,
+}
+
+fn changegroup_entries(
+    store: Arc<TreeStore>,
+    args: GettreepackArgs,
+) -> BoxStream<Part, hgproto::Error> {
+    let GettreepackArgs {
+        rootdir,
+        mfnodes,
+        basemfnodes,
+        directories,
+    } = args;
+    let directories: Vec<_> = directories
+        .iter()
+        .map(|dir| rootdir.join(dir))
+        .collect();
+
+    let queue: VecDeque<_> = mfnodes
+        .into_iter()
+        .map(|node| {
+            PendingTree {
+                path: rootdir.clone(),
+                node,
+                bases: basemfnodes.clone(),
+            }
+        })
+        .collect();
+
+    let seen: HashSet<(MPath, NodeHash)> = HashSet::new();
+    let trees: BTreeMap<MPath, Vec<CgDeltaChunk>> = BTreeMap::new();
+    let trees = future::loop_fn(
+        (queue, seen, trees),
+        move |(mut queue, mut seen, mut trees)| {
+            let next = loop {
+                match queue.pop_front() {
+                    Some(tree) => {
+                        let PendingTree { path, node, bases } = tree;
+                        // A subtree that's the same as in a manifest the client has is already
+                        // there, and so is everything under it.
+                        if !bases.contains(&node) && seen.insert((path.clone(), node)) {
+                            break Some((path, node, bases));
+                        }
+                    }
+                    None => break None,
+                }
+            };
+
+            let (path, node, bases) = match next {
+                None => return future::ok(Loop::Break(trees)).boxify(),
+                Some(next) => next,
+            };
+
+            let base_texts: Vec<_> = bases
+                .iter()
+                .map(|base| store.get_tree(&path, base).map(|rev| rev.text))
+                .collect();
+            let directories = directories.clone();
+            store
+                .get_tree(&path, &node)
+                .join(future::join_all(base_texts))
+                .and_then(move |(rev, base_texts)| -> hgproto::Result<_> {
+                    let base_entries = base_texts
+                        .iter()
+                        .map(|text| revlog::parse(text))
+                        .collect::<mercurial::Result<Vec<_>>>()?;
+
+                    for (name, details) in revlog::parse(&rev.text)? {
+                        if !details.is_tree() {
+                            continue;
+                        }
+                        let subdir = path.join(&name);
+                        if !directories.is_empty()
+                            && !directories.iter().any(|dir| is_prefix(&subdir, dir))
+                        {
+                            continue;
+                        }
+                        let bases = base_entries
+                            .iter()
+                            .filter_map(|entries| entries.get(&name))
+                            .filter(|details| details.is_tree())
+                            .map(|details| *details.nodeid())
+                            .collect();
+                        queue.push_back(PendingTree {
+                            path: subdir,
+                            node: *details.nodeid(),
+                            bases,
+                        });
+                    }
+
+                    if directories.is_empty() || directories.contains(&path) {
+                        let chunk = fulltext_chunk(node, &rev.parents, rev.linknode, rev.text);
+                        trees.entry(path).or_insert_with(Vec::new).push(chunk);
+                    }
+                    Ok(Loop::Continue((queue, seen, trees)))
+                })
+                .boxify()
+        },
+    );
+
+    trees
+        .map(|mut trees| {
+            let root = trees.remove(&MPath::new("").expect("empty path is valid"));
+
+            let mut parts = vec![Part::SectionEnd(Section::Changeset)];
+            parts.extend(
+                parents_first(root.unwrap_or_default())
+                    .into_iter()
+                    .map(|chunk| Part::CgChunk(Section::Manifest, chunk)),
+            );
+            parts.push(Part::SectionEnd(Section::Manifest));
+            for (path, chunks) in trees {
+                let section = Section::Treemanifest(path);
+                parts.extend(
+                    parents_first(chunks)
+                        .into_iter()
+                        .map(|chunk| Part::CgChunk(section.clone(), chunk)),
+                );
+                parts.push(Part::SectionEnd(section));
+            }
+            parts.push(Part::End);
+
+            stream::iter_ok(parts)
+        })
+        .flatten_stream()
+        .boxify()
+}
+
+/// Whether `prefix` is `path` or one of the directories it's in.
+fn is_prefix(prefix: &MPath, path: &MPath) -> bool {
+    let mut path = path.into_iter();
+    prefix
+        .into_iter()
+        .all(|elem| path.next().map_or(false, |other| elem == other))
+}
+
+/// Order revisions of the same tree manifest so that parents come before their children, as
+/// the receiving side needs them in that order.
+fn parents_first(chunks: Vec<CgDeltaChunk>) -> Vec<CgDeltaChunk> {
+    fn visit(
+        node: NodeHash,
+        pending: &mut HashMap<NodeHash, CgDeltaChunk>,
+        sorted: &mut Vec<CgDeltaChunk>,
+    ) {
+        if let Some(chunk) = pending.remove(&node) {
+            visit(chunk.p1, pending, sorted);
+            visit(chunk.p2, pending, sorted);
+            sorted.push(chunk);
+        }
+    }
+
+    let order: Vec<_> = chunks.iter().map(|chunk| chunk.node).collect();
+    let mut pending: HashMap<_, _> = chunks
+        .into_iter()
+        .map(|chunk| (chunk.node, chunk))
+        .collect();
+    let mut sorted = Vec::with_capacity(order.len());
+    for node in order {
+        visit(node, &mut pending, &mut sorted);
+    }
+    sorted
+}
+
+#[cfg(test)]
+mod test {
+    use mercurial_types_mocks::nodehash::*;
+
+    use super::*;
+
+    fn path(path: &str) -> MPath {
+        MPath::new(path).unwrap()
+    }
+
+    fn chunk(node: NodeHash, p1: Option<&NodeHash>, p2: Option<&NodeHash>) -> CgDeltaChunk {
+        fulltext_chunk(node, &Parents::new(p1, p2), ONES_HASH, vec![])
+    }
+
+    /// Tree manifests of the directories of a repo, keyed by directory and node.
+    struct TestTrees(HashMap<(MPath, NodeHash), Vec<u8>>);
+
+    impl TestTrees {
+        fn new(trees: Vec<(&str, NodeHash, Vec<(&str, NodeHash, &str)>)>) -> Self {
+            let trees = trees
+                .into_iter()
+                .map(|(dir, node, entries)| {
+                    let mut text = Vec::new();
+                    for (name, entry, flag) in entries {
+                        text.extend(format!("{}\0{}{}\n", name, entry, flag).into_bytes());
+                    }
+                    ((path(dir), node), text)
+                })
+                .collect();
+            TestTrees(trees)
+        }
+    }
+
+    impl TreeStore for TestTrees {
+        fn get_tree(
+            &self,
+            path: &MPath,
+            node: &NodeHash,
+        ) -> BoxFuture<TreeRevision, hgproto::Error> {
+            match self.0.get(&(path.clone(), *node)) {
+                Some(text) => future::ok(TreeRevision {
+                    parents: Parents::None,
+                    linknode: ONES_HASH,
+                    text: text.clone(),
+                }).boxify(),
+                None => future::err(format!("no tree {} of {}", node, path).into()).boxify(),
+            }
+        }
+    }
+
+    /// The directories and nodes of the trees sent by `changegroup_entries`.
+    fn sent_trees(
+        store: TestTrees,
+        mfnodes: Vec<NodeHash>,
+        basemfnodes: Vec<NodeHash>,
+        directories: Vec<&str>,
+    ) -> Vec<(Section, NodeHash)> {
+        let args = GettreepackArgs {
+            rootdir: path(""),
+            mfnodes,
+            basemfnodes,
+            directories: directories.into_iter().map(path).collect(),
+        };
+        let parts = changegroup_entries(Arc::new(store), args)
+            .collect()
+            .wait()
+            .unwrap();
+        assert_eq!(parts.last(), Some(&Part::End));
+        parts
+            .into_iter()
+            .filter_map(|part| match part {
+                Part::CgChunk(section, chunk) => Some((section, chunk.node)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // root (AS) -> a (BS) -> a/x (DS)
+    //           -> b (CS)
+    // base (ES) -> a (FS) -> a/x (DS)
+    //           -> b (CS)
+    fn test_trees() -> TestTrees {
+        TestTrees::new(vec![
+            (
+                "",
+                AS_HASH,
+                vec![("a", BS_HASH, "t"), ("b", CS_HASH, "t"), ("file", ONES_HASH, "")],
+            ),
+            ("", ES_HASH, vec![("a", FS_HASH, "t"), ("b", CS_HASH, "t")]),
+            ("a", BS_HASH, vec![("x", DS_HASH, "t")]),
+            ("a", FS_HASH, vec![("x", DS_HASH, "t"), ("other", ONES_HASH, "")]),
+            ("a/x", DS_HASH, vec![("f", ONES_HASH, "")]),
+            ("b", CS_HASH, vec![("g", ONES_HASH, "")]),
+        ])
+    }
+
+    #[test]
+    fn all_trees() {
+        let sent = sent_trees(test_trees(), vec![AS_HASH], vec![], vec![]);
+        assert_eq!(
+            sent,
+            vec![
+                (Section::Manifest, AS_HASH),
+                (Section::Treemanifest(path("a")), BS_HASH),
+                (Section::Treemanifest(path("a/x")), DS_HASH),
+                (Section::Treemanifest(path("b")), CS_HASH),
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_base_subtrees() {
+        let sent = sent_trees(test_trees(), vec![AS_HASH], vec![ES_HASH], vec![]);
+        assert_eq!(
+            sent,
+            vec![
+                (Section::Manifest, AS_HASH),
+                (Section::Treemanifest(path("a")), BS_HASH),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_directories() {
+        let sent = sent_trees(test_trees(), vec![AS_HASH], vec![], vec!["a/x"]);
+        assert_eq!(sent, vec![(Section::Treemanifest(path("a/x")), DS_HASH)]);
+
+        let sent = sent_trees(test_trees(), vec![AS_HASH], vec![], vec!["b", "a"]);
+        assert_eq!(
+            sent,
+            vec![
+                (Section::Treemanifest(path("a")), BS_HASH),
+                (Section::Treemanifest(path("b")), CS_HASH),
+            ]
+        );
+    }
+
+    #[test]
+    fn prefixes() {
+        assert!(is_prefix(&path(""), &path("a/b")));
+        assert!(is_prefix(&path("a"), &path("a/b")));
+        assert!(is_prefix(&path("a/b"), &path("a/b")));
+        assert!(!is_prefix(&path("a/b/c"), &path("a/b")));
+        assert!(!is_prefix(&path("a/c"), &path("a/b")));
+        // Prefixes are made of whole path elements.
+        assert!(!is_prefix(&path("a"), &path("ab")));
+    }
+
+    #[test]
+    fn parents_before_children() {
+        let chunks = vec![
+            chunk(FOURS_HASH, Some(&TWOS_HASH), Some(&THREES_HASH)),
+            chunk(THREES_HASH, Some(&ONES_HASH), None),
+            chunk(TWOS_HASH, Some(&ONES_HASH), None),
+            chunk(ONES_HASH, None, None),
+            chunk(FIVES_HASH, None, None),
+        ];
+        let sorted: Vec<_> = parents_first(chunks)
+            .into_iter()
+            .map(|chunk| chunk.node)
+            .collect();
+        assert_eq!(
+            sorted,
+            vec![ONES_HASH, TWOS_HASH, THREES_HASH, FOURS_HASH, FIVES_HASH]
+        );
+
+        // Parents that aren't being sent don't matter.
+        let chunks = vec![chunk(TWOS_HASH, Some(&ONES_HASH), None)];
+        assert_eq!(parents_first(chunks)[0].node, TWOS_HASH);
+    }
+}
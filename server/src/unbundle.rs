@@ -0,0 +1,776 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Accepting pushes
+//!
+//! The bundle2 payload of an `unbundle` is decoded, the full texts of all the revisions in its
+//! changegroup are reconstructed from their deltas, and the results are written to the
+//! repo's store, files first and changesets last. The pushed changesets that the repo didn't
+//! already have start out draft, and become heads if nothing was pushed on top of them. Any
+//! changesets the client sent as public heads in a `phase-heads` part are then published, and
+//! the client is sent a `reply:changegroup` part.
+//!
+//! A changegroup sent in a `b2x:rebase` part is rebased first; see `pushrebase`.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
+
+use slog::Logger;
+
+use async_compression::CompressorType;
+use blobrepo::{BlobChangeset, BlobRepo, BlobState, Phase};
+use blobstore::Blobstore;
+use hgproto;
+use mercurial;
+use mercurial::changeset::RevlogChangeset;
+use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item, InnerPart};
+use mercurial_bundles::bundle2::Bundle2Stream;
+use mercurial_bundles::changegroup::{CgDeltaChunk, Part, Section};
+use mercurial_types::{delta, Blob, BlobNode, MPath, NodeHash, Parents, RepoPath, NULL_HASH};
+use repoinfo::RepoGenCache;
+use storage_types::Version;
+
+use phases::RepoPhases;
+use pushrebase::{self, RebaseRequest};
+use repohooks::RepoHooks;
+use repo::{BoxedHgRepo, ChangesetStore};
+
+/// The operations on a repo's underlying storage that are needed to accept a push, or to
+/// write anything else that's generated on the server.
+pub trait PushStore: ChangesetStore {
+    /// Get the revlog text of an existing manifest or file node.
+    fn get_raw_content(&self, node: &NodeHash) -> BoxFuture<Vec<u8>, hgproto::Error>;
+    /// Store a changeset along with the revlog text it was received with.
+    fn put_changeset(
+        &self,
+        node: &NodeHash,
+        cs: RevlogChangeset,
+        text: Vec<u8>,
+    ) -> BoxFuture<(), hgproto::Error>;
+    fn put_node(
+        &self,
+        node: &NodeHash,
+        parents: Parents,
+        content: Vec<u8>,
+    ) -> BoxFuture<(), hgproto::Error>;
+    /// Store a file node along with its metadata, which is kept apart so that copies can be
+    /// found without reading whole files.
+    fn put_file_node(
+        &self,
+        node: &NodeHash,
+        parents: Parents,
+        content: Vec<u8>,
+    ) -> BoxFuture<(), hgproto::Error>;
+    /// Record the changeset that introduced a manifest or file node.
+    fn add_linknode(
+        &self,
+        path: RepoPath,
+        node: &NodeHash,
+        linknode: &NodeHash,
+    ) -> BoxFuture<(), hgproto::Error>;
+    fn add_head(&self, node: &NodeHash) -> BoxFuture<(), hgproto::Error>;
+    fn remove_head(&self, node: &NodeHash) -> BoxFuture<(), hgproto::Error>;
+    /// Compare-and-swap a bookmark. `None` means the bookmark doesn't exist.
+    fn update_bookmark(
+        &self,
+        key: &[u8],
+        old: Option<NodeHash>,
+        new: Option<NodeHash>,
+    ) -> BoxFuture<bool, hgproto::Error>;
+    /// Point an existing bookmark at `value`, provided it's still at `version`.
+    fn set_bookmark(
+        &self,
+        key: &[u8],
+        value: &NodeHash,
+        version: &Version,
+    ) -> BoxFuture<bool, hgproto::Error>;
+    /// Replace the pre-built bundle served to clients through clonebundles.
+    fn put_clonebundle(&self, bundle: Vec<u8>) -> BoxFuture<(), hgproto::Error>;
+    /// Record every changeset in the changeset index. Resolves to the number of changesets.
+    fn index_changesets(&self) -> BoxFuture<usize, hgproto::Error>;
+    /// Record the linknodes that are missing for changesets stored before linknodes were.
+    /// Resolves to the number of changesets.
+    fn backfill_linknodes(&self) -> BoxFuture<usize, hgproto::Error>;
+}
+
+pub fn repo_err<E: error::Error + Send + 'static>(err: E) -> hgproto::Error {
+    hgproto::Error::with_chain(err, hgproto::ErrorKind::Repo)
+}
+
+impl<State> PushStore for BlobRepo<State>
+where
+    State: BlobState,
+    <State::Blobstore as Blobstore>::ValueIn: From<Vec<u8>>,
+{
+    fn get_raw_content(&self, node: &NodeHash) -> BoxFuture<Vec<u8>, hgproto::Error> {
+        BlobRepo::get_raw_content(self, node)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn put_changeset(
+        &self,
+        node: &NodeHash,
+        cs: RevlogChangeset,
+        text: Vec<u8>,
+    ) -> BoxFuture<(), hgproto::Error> {
+        BlobRepo::put_changeset(self, &BlobChangeset::new_with_text(node, cs, text))
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn put_node(
+        &self,
+        node: &NodeHash,
+        parents: Parents,
+        content: Vec<u8>,
+    ) -> BoxFuture<(), hgproto::Error> {
+        BlobRepo::put_node(self, node, parents, content)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn put_file_node(
+        &self,
+        node: &NodeHash,
+        parents: Parents,
+        content: Vec<u8>,
+    ) -> BoxFuture<(), hgproto::Error> {
+        BlobRepo::put_file_node(self, node, parents, content)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn add_linknode(
+        &self,
+        path: RepoPath,
+        node: &NodeHash,
+        linknode: &NodeHash,
+    ) -> BoxFuture<(), hgproto::Error> {
+        BlobRepo::add_linknode(self, path, node, linknode)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn add_head(&self, node: &NodeHash) -> BoxFuture<(), hgproto::Error> {
+        BlobRepo::add_head(self, node).map_err(repo_err).boxify()
+    }
+
+    fn remove_head(&self, node: &NodeHash) -> BoxFuture<(), hgproto::Error> {
+        BlobRepo::remove_head(self, node).map_err(repo_err).boxify()
+    }
+
+    fn update_bookmark(
+        &self,
+        key: &[u8],
+        old: Option<NodeHash>,
+        new: Option<NodeHash>,
+    ) -> BoxFuture<bool, hgproto::Error> {
+        BlobRepo::update_bookmark(self, &key, old, new)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn set_bookmark(
+        &self,
+        key: &[u8],
+        value: &NodeHash,
+        version: &Version,
+    ) -> BoxFuture<bool, hgproto::Error> {
+        BlobRepo::set_bookmark(self, &key, value, version)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn put_clonebundle(&self, bundle: Vec<u8>) -> BoxFuture<(), hgproto::Error> {
+        BlobRepo::put_clonebundle(self, bundle)
+            .map_err(repo_err)
+            .boxify()
+    }
+
+    fn index_changesets(&self) -> BoxFuture<usize, hgproto::Error> {
+        BlobRepo::index_changesets(self).map_err(repo_err).boxify()
+    }
+
+    fn backfill_linknodes(&self) -> BoxFuture<usize, hgproto::Error> {
+        BlobRepo::backfill_linknodes(self).map_err(repo_err).boxify()
+    }
+}
+
+/// A revision whose full text has been reconstructed from a changegroup.
+pub struct Revision {
+    pub node: NodeHash,
+    pub parents: Parents,
+    pub linknode: NodeHash,
+    pub text: Vec<u8>,
+}
+
+impl Revision {
+    fn new(chunk: CgDeltaChunk, base: &[u8]) -> hgproto::Result<Self> {
+        let text = delta::apply(base, chunk.delta);
+        let nodeid = {
+            let blobnode = BlobNode::new(
+                Blob::Dirty(text.as_slice()),
+                non_null(&chunk.p1),
+                non_null(&chunk.p2),
+            );
+            blobnode.nodeid()
+        };
+        if nodeid != Some(chunk.node) {
+            bail!("hash mismatch for pushed revision {}", chunk.node);
+        }
+
+        Ok(Revision {
+            node: chunk.node,
+            parents: Parents::new(non_null(&chunk.p1), non_null(&chunk.p2)),
+            linknode: chunk.linknode,
+            text,
+        })
+    }
+}
+
+fn non_null(node: &NodeHash) -> Option<&NodeHash> {
+    if *node == NULL_HASH {
+        None
+    } else {
+        Some(node)
+    }
+}
+
+/// The contents of the parts of a pushed bundle that are applied to the repo. A bundle that
+/// only moves phases has no changegroup part.
+#[derive(Default)]
+struct Changegroup {
+    part_id: Option<u32>,
+    /// Set if the changegroup came in a `b2x:rebase` part.
+    rebase: Option<RebaseRequest>,
+    changesets: Vec<CgDeltaChunk>,
+    manifests: Vec<CgDeltaChunk>,
+    filelogs: BTreeMap<MPath, Vec<CgDeltaChunk>>,
+    public_heads: Vec<NodeHash>,
+    check_heads: Vec<NodeHash>,
+}
+
+impl Changegroup {
+    fn from_items(items: Vec<Bundle2Item>) -> hgproto::Result<Self> {
+        let mut part_id = None;
+        let mut has_phase_heads = false;
+        let mut cg = Changegroup::default();
+
+        for item in items {
+            match item {
+                Bundle2Item::Header(ref header) => match header.part_type_lower().as_str() {
+                    "changegroup" | "b2x:rebase" => {
+                        if part_id.is_some() {
+                            bail!("bundle contains more than one changegroup part");
+                        }
+                        part_id = Some(header.part_id());
+                        if header.part_type_lower().as_str() == "b2x:rebase" {
+                            cg.rebase = Some(RebaseRequest::from_header(header)?);
+                        }
+                    }
+                    "phase-heads" => has_phase_heads = true,
+                    _ => (),
+                },
+                Bundle2Item::Inner(InnerPart::Cg2(part)) => {
+                    if let Part::CgChunk(_, ref chunk) = part {
+                        if chunk.flags != 0 {
+                            bail!(
+                                "revision {} has unsupported revlog flags {:#x}",
+                                chunk.node,
+                                chunk.flags
+                            );
+                        }
+                    }
+                    match part {
+                        Part::CgChunk(Section::Changeset, chunk) => cg.changesets.push(chunk),
+                        Part::CgChunk(Section::Manifest, chunk) => cg.manifests.push(chunk),
+                        Part::CgChunk(Section::Treemanifest(_), _) => {
+                            bail!("tree manifests are not supported")
+                        }
+                        Part::CgChunk(Section::Filelog(path), chunk) => {
+                            cg.filelogs.entry(path).or_insert_with(Vec::new).push(chunk)
+                        }
+                        Part::SectionEnd(_) | Part::End => (),
+                    }
+                }
+                // Phases only move towards public, so the draft heads a client sends don't
+                // change anything.
+                Bundle2Item::Inner(InnerPart::PhaseHead(phase, node)) => {
+                    if phase == Phase::Public.as_num() {
+                        cg.public_heads.push(node);
+                    }
+                }
+                Bundle2Item::Inner(InnerPart::CheckHead(node)) => cg.check_heads.push(node),
+                _ => (),
+            }
+        }
+
+        if part_id.is_none() && !has_phase_heads {
+            bail!("bundle has no changegroup part");
+        }
+        cg.part_id = part_id;
+        Ok(cg)
+    }
+}
+
+/// The full texts of the revisions in a changegroup.
+pub struct Revisions {
+    pub changesets: Vec<Revision>,
+    pub manifests: Vec<Revision>,
+    pub filelogs: Vec<(MPath, Vec<Revision>)>,
+}
+
+/// Reconstruct the full texts of a sequence of delta chunks, in order. Each delta is either
+/// against an earlier chunk in the sequence, or against an existing revision fetched with
+/// `get_base`.
+fn apply_deltas<F>(
+    chunks: Vec<CgDeltaChunk>,
+    get_base: F,
+) -> BoxFuture<Vec<Revision>, hgproto::Error>
+where
+    F: Fn(&NodeHash) -> BoxFuture<Vec<u8>, hgproto::Error> + Send + 'static,
+{
+    stream::iter_ok::<_, hgproto::Error>(chunks)
+        .fold(
+            (HashMap::new(), Vec::new()),
+            move |(mut texts, mut revs), chunk| {
+                let base = if chunk.base == NULL_HASH {
+                    future::ok(Vec::new()).boxify()
+                } else if let Some(text) = texts.get(&chunk.base) {
+                    future::ok(Vec::clone(text)).boxify()
+                } else {
+                    get_base(&chunk.base)
+                };
+
+                base.and_then(move |base| {
+                    let rev = Revision::new(chunk, &base)?;
+                    texts.insert(rev.node, rev.text.clone());
+                    revs.push(rev);
+                    Ok((texts, revs))
+                })
+            },
+        )
+        .map(|(_, revs)| revs)
+        .boxify()
+}
+
+/// Reconstruct the full texts of all the revisions in a changegroup.
+fn decode_revisions(
+    store: Arc<PushStore>,
+    changesets: Vec<CgDeltaChunk>,
+    manifests: Vec<CgDeltaChunk>,
+    filelogs: BTreeMap<MPath, Vec<CgDeltaChunk>>,
+) -> BoxFuture<Revisions, hgproto::Error> {
+    let filelogs = filelogs.into_iter().map({
+        let store = store.clone();
+        move |(path, chunks)| {
+            let get_base = {
+                let store = store.clone();
+                move |node: &NodeHash| store.get_raw_content(node)
+            };
+            apply_deltas(chunks, get_base).map(move |revs| (path, revs))
+        }
+    });
+    let filelogs = future::join_all(filelogs.collect::<Vec<_>>());
+
+    let manifests = {
+        let store = store.clone();
+        let get_base = move |node: &NodeHash| store.get_raw_content(node);
+        apply_deltas(manifests, get_base)
+    };
+
+    let changesets = {
+        let get_base = move |node: &NodeHash| {
+            store
+                .get_changeset_text(node)
+                .map(|(_parents, text)| text)
+                .boxify()
+        };
+        apply_deltas(changesets, get_base)
+    };
+
+    changesets
+        .join3(manifests, filelogs)
+        .map(|(changesets, manifests, filelogs)| Revisions {
+            changesets,
+            manifests,
+            filelogs,
+        })
+        .boxify()
+}
+
+/// Store the revisions of manifests, without recording the changesets that introduced them.
+pub fn store_nodes(store: Arc<PushStore>, revs: Vec<Revision>) -> BoxFuture<(), hgproto::Error> {
+    let puts: Vec<_> = revs.into_iter()
+        .map(|rev| store.put_node(&rev.node, rev.parents, rev.text))
+        .collect();
+    future::join_all(puts).map(|_| ()).boxify()
+}
+
+/// Record the changesets that introduced the revisions of a file or manifest.
+fn add_linknodes(
+    store: &Arc<PushStore>,
+    path: RepoPath,
+    revs: &[Revision],
+) -> BoxFuture<(), hgproto::Error> {
+    let linknodes: Vec<_> = revs.iter()
+        .map(|rev| store.add_linknode(path.clone(), &rev.node, &rev.linknode))
+        .collect();
+    future::join_all(linknodes).map(|_| ()).boxify()
+}
+
+/// Store the revisions of a manifest, along with the changesets that introduced them.
+fn store_linked_nodes(
+    store: Arc<PushStore>,
+    path: RepoPath,
+    revs: Vec<Revision>,
+) -> BoxFuture<(), hgproto::Error> {
+    let linknodes = add_linknodes(&store, path, &revs);
+    store_nodes(store, revs)
+        .join(linknodes)
+        .map(|_| ())
+        .boxify()
+}
+
+fn put_file_nodes(store: &Arc<PushStore>, revs: Vec<Revision>) -> BoxFuture<(), hgproto::Error> {
+    let puts: Vec<_> = revs.into_iter()
+        .map(|rev| store.put_file_node(&rev.node, rev.parents, rev.text))
+        .collect();
+    future::join_all(puts).map(|_| ()).boxify()
+}
+
+fn store_file_nodes(
+    store: Arc<PushStore>,
+    path: MPath,
+    revs: Vec<Revision>,
+) -> BoxFuture<(), hgproto::Error> {
+    let path = match RepoPath::file(path) {
+        Ok(path) => path,
+        Err(err) => return future::err(repo_err(err)).boxify(),
+    };
+
+    let linknodes = add_linknodes(&store, path, &revs);
+    put_file_nodes(&store, revs)
+        .join(linknodes)
+        .map(|_| ())
+        .boxify()
+}
+
+/// Store the revisions of files, without recording the changesets that introduced them.
+pub fn store_unlinked_files(
+    store: Arc<PushStore>,
+    filelogs: Vec<(MPath, Vec<Revision>)>,
+) -> BoxFuture<(), hgproto::Error> {
+    let puts: Vec<_> = filelogs
+        .into_iter()
+        .map(|(_path, revs)| put_file_nodes(&store, revs))
+        .collect();
+    future::join_all(puts).map(|_| ()).boxify()
+}
+
+/// Store changesets, which should come after everything they refer to. Resolves to the
+/// changesets.
+pub fn store_changesets(
+    store: Arc<PushStore>,
+    changesets: Vec<Revision>,
+) -> BoxFuture<Vec<Revision>, hgproto::Error> {
+    let puts = changesets
+        .iter()
+        .map(|rev| {
+            let (p1, p2) = rev.parents.get_nodes();
+            RevlogChangeset::new(BlobNode::new(Blob::Dirty(rev.text.as_slice()), p1, p2))
+                .map(|cs| store.put_changeset(&rev.node, cs, rev.text.clone()))
+        })
+        .collect::<mercurial::Result<Vec<_>>>();
+    puts.into_future()
+        .from_err()
+        .and_then(future::join_all)
+        .map(move |_| changesets)
+        .boxify()
+}
+
+/// Write revisions to the repo's store, files first and changesets last, so that everything
+/// the changesets refer to is already present once they become visible. Resolves to the
+/// changesets.
+pub fn store_revisions(
+    store: Arc<PushStore>,
+    revs: Revisions,
+) -> BoxFuture<Vec<Revision>, hgproto::Error> {
+    let Revisions {
+        changesets,
+        manifests,
+        filelogs,
+    } = revs;
+
+    let files: Vec<_> = filelogs
+        .into_iter()
+        .map(|(path, revs)| store_file_nodes(store.clone(), path, revs))
+        .collect();
+    let manifests = store_linked_nodes(store.clone(), RepoPath::root(), manifests);
+
+    future::join_all(files)
+        .join(manifests)
+        .and_then(move |_| store_changesets(store, changesets))
+        .boxify()
+}
+
+/// Check that the heads the client based its push on are still the repo's heads. An empty
+/// repo is represented by the null hash.
+fn check_heads(client_heads: &[NodeHash], heads: &HashSet<NodeHash>) -> hgproto::Result<()> {
+    let client_heads: HashSet<_> = client_heads
+        .iter()
+        .filter(|node| **node != NULL_HASH)
+        .cloned()
+        .collect();
+    if client_heads != *heads {
+        bail!("repository changed while pushing - please try again");
+    }
+    Ok(())
+}
+
+/// Apply the bundle in `stream` to the repo, returning the bundle2 reply for the client.
+pub fn unbundle(
+    hgrepo: Arc<BoxedHgRepo>,
+    store: Arc<PushStore>,
+    phases: RepoPhases,
+    hooks: RepoHooks,
+    repo_generation: RepoGenCache<BoxedHgRepo>,
+    client_heads: Vec<NodeHash>,
+    stream: BoxStream<Bytes, hgproto::Error>,
+    logger: Logger,
+) -> BoxFuture<Bytes, hgproto::Error> {
+    let heads = hgrepo
+        .get_heads()
+        .collect()
+        .map(|heads| heads.into_iter().collect::<HashSet<_>>());
+
+    let changegroup = stream
+        .fold(BytesMut::new(), |mut bundle, chunk| {
+            bundle.extend_from_slice(&chunk);
+            Ok::<_, hgproto::Error>(bundle)
+        })
+        .and_then(move |bundle| {
+            Bundle2Stream::new(Cursor::new(bundle.freeze()), logger)
+                .collect()
+                .from_err()
+        })
+        .and_then(Changegroup::from_items);
+
+    heads
+        .join(changegroup)
+        .and_then(move |(heads, cg)| {
+            let Changegroup {
+                part_id,
+                rebase,
+                changesets,
+                manifests,
+                filelogs,
+                public_heads,
+                check_heads: expected_heads,
+            } = cg;
+
+            // Bundle2 clients force the push, and send the heads they expect in a
+            // `check:heads` part instead if they want them checked. Pushrebase doesn't need
+            // them to be.
+            let client_heads = if client_heads.is_empty() {
+                expected_heads
+            } else {
+                client_heads
+            };
+            if rebase.is_none() && !client_heads.is_empty() {
+                if let Err(err) = check_heads(&client_heads, &heads) {
+                    return future::err(err).boxify();
+                }
+            }
+
+            let revs = decode_revisions(store.clone(), changesets, manifests, filelogs);
+            let applied = match rebase {
+                Some(rebase) => {
+                    // Clients that can't read the markers aren't told about the replacements.
+                    let obsmarkers = rebase.obsmarkers;
+                    let phases = phases.clone();
+                    revs.and_then(move |revs| {
+                        pushrebase::pushrebase(
+                            hgrepo,
+                            store,
+                            phases,
+                            hooks,
+                            repo_generation,
+                            rebase,
+                            revs,
+                        )
+                    }).map(move |(ret, mut replacements)| {
+                            if !obsmarkers {
+                                replacements.clear();
+                            }
+                            (ret, replacements)
+                        })
+                        .boxify()
+                }
+                None => {
+                    let phases = phases.clone();
+                    revs.and_then(move |revs| apply(hgrepo, store, phases, heads, revs))
+                        .map(|ret| (ret, vec![]))
+                        .boxify()
+                }
+            };
+
+            applied
+                .and_then(move |(ret, replacements)| {
+                    phases.publish(public_heads).map(move |_| (ret, replacements))
+                })
+                .and_then(move |(ret, replacements)| reply(ret, part_id, &replacements))
+                .boxify()
+        })
+        .boxify()
+}
+
+/// Apply pushed revisions as they are. Pushed changesets that the repo already has are left as
+/// they are, and the rest start out draft. The push is refused if any of their parents are
+/// neither pushed nor in the repo.
+fn apply(
+    hgrepo: Arc<BoxedHgRepo>,
+    store: Arc<PushStore>,
+    phases: RepoPhases,
+    heads: HashSet<NodeHash>,
+    revs: Revisions,
+) -> BoxFuture<i64, hgproto::Error> {
+    let pushed: HashSet<_> = revs.changesets.iter().map(|rev| rev.node).collect();
+    let parents: HashSet<_> = revs.changesets
+        .iter()
+        .flat_map(|rev| rev.parents.into_iter())
+        .filter(|node| !pushed.contains(node))
+        .collect();
+    let exists = |nodes: &HashSet<NodeHash>| {
+        let checks: Vec<_> = nodes
+            .iter()
+            .map(|node| {
+                let node = *node;
+                hgrepo
+                    .changeset_exists(&node)
+                    .map(move |exists| (node, exists))
+            })
+            .collect();
+        future::join_all(checks)
+    };
+
+    exists(&pushed)
+        .join(exists(&parents))
+        .and_then(move |(pushed, parents)| {
+            if let Some(&(missing, _)) = parents.iter().find(|&&(_, exists)| !exists) {
+                let msg = format!("push is missing changeset {}, a parent of one it adds", missing);
+                return future::err(msg.into()).boxify();
+            }
+
+            let (existing, added): (Vec<_>, Vec<_>) =
+                pushed.into_iter().partition(|&(_, exists)| exists);
+            let existing: HashSet<_> = existing.into_iter().map(|(node, _)| node).collect();
+            let added = added.into_iter().map(|(node, _)| node).collect();
+            phases
+                .add_drafts(added)
+                .and_then({
+                    let store = store.clone();
+                    move |_| store_revisions(store, revs)
+                })
+                .and_then(move |revs| {
+                    // Changesets that were pushed again already have whatever children they had.
+                    let revs = revs.into_iter()
+                        .filter(|rev| !existing.contains(&rev.node))
+                        .collect();
+                    update_heads(store, heads, revs)
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+/// Make the pushed changesets without children heads, and remove the heads that now have
+/// children. Returns the value Mercurial expects in `reply:changegroup`: 1 + the number of
+/// added heads, or -1 - the number of removed heads.
+pub fn update_heads(
+    store: Arc<PushStore>,
+    heads: HashSet<NodeHash>,
+    revs: Vec<Revision>,
+) -> BoxFuture<i64, hgproto::Error> {
+    let parents: HashSet<_> = revs.iter()
+        .flat_map(|rev| rev.parents.into_iter())
+        .collect();
+    let new_heads: Vec<_> = revs.iter()
+        .map(|rev| rev.node)
+        .filter(|node| !parents.contains(node))
+        .collect();
+    let old_heads: Vec<_> = parents.intersection(&heads).cloned().collect();
+
+    let delta = new_heads.len() as i64 - old_heads.len() as i64;
+    let ret = if delta < 0 { delta - 1 } else { delta + 1 };
+
+    // Add the new heads before removing the old ones, so the repo is never left without them.
+    let adds: Vec<_> = new_heads.iter().map(|node| store.add_head(node)).collect();
+    future::join_all(adds)
+        .and_then(move |_| {
+            let removes: Vec<_> = old_heads
+                .iter()
+                .map(|node| store.remove_head(node))
+                .collect();
+            future::join_all(removes)
+        })
+        .map(move |_| ret)
+        .boxify()
+}
+
+/// The reply to a push: a `reply:changegroup` part if the bundle had a changegroup part, and
+/// obsolescence markers for the changesets that were replaced by rebasing them.
+fn reply(
+    ret: i64,
+    part_id: Option<u32>,
+    replacements: &[(NodeHash, NodeHash)],
+) -> BoxFuture<Bytes, hgproto::Error> {
+    let writer = Cursor::new(Vec::new());
+    let mut bundle = Bundle2EncodeBuilder::new(writer);
+    bundle.set_compressor_type(CompressorType::Uncompressed);
+
+    if let Some(part_id) = part_id {
+        match parts::replychangegroup_part(ret, part_id) {
+            Ok(part) => {
+                bundle.add_part(part);
+            }
+            Err(err) => return future::err(err.into()).boxify(),
+        }
+    }
+
+    // Changesets that were already based on the bookmark don't change when they're rebased.
+    let replacements: Vec<_> = replacements
+        .iter()
+        .filter(|&&(old, new)| old != new)
+        .cloned()
+        .collect();
+    if !replacements.is_empty() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs() as f64 + now.subsec_nanos() as f64 / 1e9)
+            .unwrap_or(0.0);
+        match parts::obsmarkers_part(&replacements, now) {
+            Ok(part) => {
+                bundle.add_part(part);
+            }
+            Err(err) => return future::err(err.into()).boxify(),
+        }
+    }
+
+    bundle
+        .build()
+        .map(|cursor| Bytes::from(cursor.into_inner()))
+        .from_err()
+        .boxify()
+}
@@ -218,7 +218,7 @@ where
         self.get_path_mutex(key)
             .into_future()
             .and_then(move |mutex| {
-                let future = poll_fn(move || poll_delete(&mutex, &version));
+                let future = poll_fn(move || poll_delete::<V>(&mutex, &version));
                 pool.spawn(future)
             })
     }
@@ -267,7 +267,7 @@ fn poll_set<V>(
     version: &Version,
 ) -> Poll<Option<Version>, Error>
 where
-    V: Serialize,
+    V: Serialize + DeserializeOwned,
 {
     let path = path_mutex.lock().expect("Lock poisoned");
     let mut options = OpenOptions::new();
@@ -290,7 +290,7 @@ where
             } else {
                 let mut buf = Vec::new();
                 let _ = file.read_to_end(&mut buf)?;
-                deserialize::<(String, Version)>(&buf)?.1
+                deserialize::<(V, Version)>(&buf)?.1
             };
 
             // Write out new value if versions match.
@@ -320,10 +320,13 @@ where
 
 /// Synchronous implementation of the delete operation for the bookmark store. Intended to
 /// be used in conjunction with poll_fn() and a CpuPool to dispatch it onto a thread pool.
-fn poll_delete(
+fn poll_delete<V>(
     path_mutex: &Arc<Mutex<PathBuf>>,
     version: &Version,
-) -> Poll<Option<Version>, Error> {
+) -> Poll<Option<Version>, Error>
+where
+    V: DeserializeOwned,
+{
     let path = path_mutex.lock().expect("Lock poisoned");
 
     let result = match File::open(&*path) {
@@ -335,7 +338,7 @@ fn poll_delete(
             // Read version.
             let mut buf = Vec::new();
             let _ = file.read_to_end(&mut buf)?;
-            let file_version = deserialize::<(String, Version)>(&buf)?.1;
+            let file_version = deserialize::<(V, Version)>(&buf)?.1;
 
             // Unlink files if version matches, reporting success if the file
             // has already been deleted by another thread or process.
@@ -406,6 +409,25 @@ mod test {
         assert_eq!(kv.delete(foo, &absent).wait().unwrap().unwrap(), absent);
     }
 
+    #[test]
+    fn non_string_values() {
+        let tmp = TempDir::new("filekv_non_string_values").unwrap();
+        let kv = FileKV::open(tmp.path(), "kv:").unwrap();
+
+        let foo = "foo";
+        let v1 = kv.set_new(foo, &vec![1u64, 2, 3]).wait().unwrap().unwrap();
+        let v2 = kv.set(foo, &vec![4u64], &v1).wait().unwrap().unwrap();
+        assert_eq!(kv.set(foo, &vec![5u64], &v1).wait().unwrap(), None);
+        assert_eq!(kv.get(foo).wait().unwrap(), Some((vec![4u64], v2)));
+
+        assert_eq!(kv.delete(foo, &v1).wait().unwrap(), None);
+        assert_eq!(
+            kv.delete(foo, &v2).wait().unwrap().unwrap(),
+            Version::absent()
+        );
+        assert_eq!(kv.get(foo).wait().unwrap(), None);
+    }
+
     #[test]
     fn persistence() {
         let tmp = TempDir::new("filebookmarks_heads_persistence").unwrap();
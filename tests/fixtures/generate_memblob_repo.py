@@ -43,6 +43,9 @@ extern crate memblob;
 extern crate membookmarks;
 extern crate mercurial_types;
 extern crate memheads;
+extern crate memlinknodes;
+extern crate memphases;
+extern crate memcsindex;
 extern crate blobrepo;
 extern crate blobstore;
 extern crate ascii;
@@ -53,6 +56,9 @@ use memblob::Memblob;
 use membookmarks::MemBookmarks;
 use mercurial_types::NodeHash;
 use memheads::MemHeads;
+use memlinknodes::MemLinknodes;
+use memphases::MemPhases;
+use memcsindex::MemChangesetIndex;
 use blobrepo::{BlobRepo, MemBlobState};
 use ascii::AsciiString;
 use blobstore::Blobstore;
@@ -63,6 +69,9 @@ pub fn getrepo() -> BlobRepo<MemBlobState> {
     let bookmarks: MemBookmarks<NodeHash> = MemBookmarks::new();
     let heads: MemHeads<NodeHash> = MemHeads::new();
     let blobs = Memblob::new();
+    let linknodes = MemLinknodes::new();
+    let phases = MemPhases::new();
+    let csindex = MemChangesetIndex::new();
 
 """
         )
@@ -83,6 +92,6 @@ pub fn getrepo() -> BlobRepo<MemBlobState> {
                     format(key, blobdata)
                 )
         rs.writelines("""
-    BlobRepo::new(MemBlobState::new(heads, bookmarks, blobs))
+    BlobRepo::new(MemBlobState::new(heads, bookmarks, blobs, linknodes, phases, csindex))
 }
 """)